@@ -251,6 +251,12 @@ impl<
         }
     }
 
+    /// True if every key is idle (no key events for IDLE_MS)
+    /// Can be used to generate kll-core Inactive/Active events (see kll_core::system)
+    pub fn idle(&self) -> bool {
+        self.state_matrix.iter().all(|state| state.idle())
+    }
+
     /// Generate event from KeyState
     /// Useful when trying to determine if a key has not been pressed
     pub fn generate_key_event(&self, index: usize) -> Option<KeyEvent> {
@@ -128,6 +128,49 @@ pub struct MouseReport {
     pub horz_wheel: i8, // Scroll left (negative) or right (positive) this many units
 }
 
+/// Gamepad Interface
+/// GamepadReport describes a report and its companion descriptor that can be used
+/// to send gamepad button presses and (absolute) axis positions to a host.
+///
+/// Axes: X/Y (left stick), Z (left trigger), Rx/Ry (right stick), Rz (right trigger)
+#[gen_hid_descriptor(
+    (collection = APPLICATION, usage_page = GENERIC_DESKTOP, usage = 0x05) = {
+        (usage_page = BUTTON, usage_min = 0x01, usage_max = 0x10) = {
+            #[packed_bits 16] #[item_settings data,variable,absolute] buttons=input;
+        };
+        (usage_page = GENERIC_DESKTOP,) = {
+            (usage = X,) = {
+                #[item_settings data,variable,absolute] x=input;
+            };
+            (usage = Y,) = {
+                #[item_settings data,variable,absolute] y=input;
+            };
+            (usage = 0x32,) = {
+                #[item_settings data,variable,absolute] z=input;
+            };
+            (usage = 0x33,) = {
+                #[item_settings data,variable,absolute] rx=input;
+            };
+            (usage = 0x34,) = {
+                #[item_settings data,variable,absolute] ry=input;
+            };
+            (usage = 0x35,) = {
+                #[item_settings data,variable,absolute] rz=input;
+            };
+        };
+    }
+)]
+
+pub struct GamepadReport {
+    pub buttons: [u8; 2],
+    pub x: i8,
+    pub y: i8,
+    pub z: i8,
+    pub rx: i8,
+    pub ry: i8,
+    pub rz: i8,
+}
+
 /// HID-IO Interface
 /// NOTE: tx must use push_raw_input (not push_input) as serde doesn't currently support
 ///       arrays larger than 32 bytes.
@@ -16,7 +16,7 @@ use defmt::*;
 use log::*;
 
 pub use crate::descriptor::{
-    HidioReport, KeyboardNkroReport, MouseReport, SysCtrlConsumerCtrlReport,
+    GamepadReport, HidioReport, KeyboardNkroReport, MouseReport, SysCtrlConsumerCtrlReport,
 };
 use heapless::spsc::{Consumer, Producer};
 use usb_device::bus::{UsbBus, UsbBusAllocator};
@@ -52,11 +52,12 @@ pub enum MouseState {
     Press(u8),
     /// Release the given mouse button (1->8)
     Release(u8),
-    /// Position update
+    /// Relative position update
+    /// Accumulated until the next report is sent
     Position { x: i16, y: i16 },
-    /// Vertical Wheel Increment
+    /// Vertical Wheel Increment (accumulated until the next report)
     VertWheel(i8),
-    /// Horizontal Wheel Increment
+    /// Horizontal Wheel Increment (accumulated until the next report)
     HorzWheel(i8),
     /// Clear all mouse state
     Clear,
@@ -64,6 +65,23 @@ pub enum MouseState {
     Unknown,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum GamepadState {
+    /// Press the given gamepad button (1->16)
+    Press(u8),
+    /// Release the given gamepad button (1->16)
+    Release(u8),
+    /// Half-axis update (value 0->127)
+    /// Axes 0->5 are X, Y, Z, Rx, Ry, Rz. The positive and negative halves of each axis are
+    /// combined, so opposing keys (e.g. A and D) can control the same axis.
+    Axis { axis: u8, negative: bool, value: u8 },
+    /// Clear all gamepad state
+    Clear,
+    /// Unknown state, used for errors
+    Unknown,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum CtrlState {
@@ -125,9 +143,17 @@ pub enum UsbState {
 /// const KBD_LED_QUEUE_SIZE: usize = 3;
 /// const MOUSE_QUEUE_SIZE: usize = 5;
 /// const CTRL_QUEUE_SIZE: usize = 2;
+/// const GAMEPAD_QUEUE_SIZE: usize = 8;
 ///
-/// type HidInterface =
-///     kiibohd_usb::HidInterface<'static, UdpBus, KBD_QUEUE_SIZE, MOUSE_QUEUE_SIZE, CTRL_QUEUE_SIZE>;
+/// type HidInterface = kiibohd_usb::HidInterface<
+///     'static,
+///     UdpBus,
+///     KBD_QUEUE_SIZE,
+///     KBD_LED_QUEUE_SIZE,
+///     MOUSE_QUEUE_SIZE,
+///     CTRL_QUEUE_SIZE,
+///     GAMEPAD_QUEUE_SIZE,
+/// >;
 ///
 /// pub struct HidioInterface<const H: usize> {}
 ///
@@ -155,9 +181,12 @@ pub enum UsbState {
 /// let (kbd_producer, kbd_consumer) = kbd_queue.split();
 /// let (mouse_producer, mouse_consumer) = mouse_queue.split();
 /// let (ctrl_producer, ctrl_consumer) = ctrl_queue.split();
+/// let gamepad_queue: Queue<kiibohd_usb::GamepadState, GAMEPAD_QUEUE_SIZE> = Queue::new();
+/// let (gamepad_producer, gamepad_consumer) = gamepad_queue.split();
 ///
 /// // Setup the interface
 /// // NOTE: Ignoring usb_bus setup in this example, use a compliant usb-device UsbBus interface
+/// //       gamepad_consumer is only needed with the gamepad feature
 /// let usb_hid = HidInterface::new(
 ///     usb_bus,
 ///     HidCountryCode::NotSupported,
@@ -165,6 +194,7 @@ pub enum UsbState {
 ///     kbd_led_producer,
 ///     mouse_consumer,
 ///     ctrl_consumer,
+///     gamepad_consumer,
 /// );
 ///
 /// // Basic CommandInterface
@@ -210,6 +240,7 @@ pub struct HidInterface<
     const KBD_LED_SIZE: usize,
     const MOUSE_SIZE: usize,
     const CTRL_SIZE: usize,
+    const GAMEPAD_SIZE: usize,
 > {
     kbd_6kro: HIDClass<'a, B>,
     kbd_6kro_report: KeyboardReport,
@@ -231,6 +262,17 @@ pub struct HidInterface<
     mouse_report: MouseReport,
     #[cfg(feature = "mouse")]
     mouse_updated: bool,
+    #[cfg(feature = "gamepad")]
+    gamepad: HIDClass<'a, B>,
+    #[cfg(feature = "gamepad")]
+    gamepad_consumer: Consumer<'a, GamepadState, GAMEPAD_SIZE>,
+    #[cfg(feature = "gamepad")]
+    gamepad_report: GamepadReport,
+    /// Positive and negative half of each axis
+    #[cfg(feature = "gamepad")]
+    gamepad_axes: [[u8; 2]; 6],
+    #[cfg(feature = "gamepad")]
+    gamepad_updated: bool,
     #[cfg(feature = "hidio")]
     hidio: HIDClass<'a, B>,
 }
@@ -241,7 +283,8 @@ impl<
         const KBD_LED_SIZE: usize,
         const MOUSE_SIZE: usize,
         const CTRL_SIZE: usize,
-    > HidInterface<'_, B, KBD_SIZE, KBD_LED_SIZE, MOUSE_SIZE, CTRL_SIZE>
+        const GAMEPAD_SIZE: usize,
+    > HidInterface<'_, B, KBD_SIZE, KBD_LED_SIZE, MOUSE_SIZE, CTRL_SIZE, GAMEPAD_SIZE>
 {
     pub fn new<'a>(
         alloc: &'a UsbBusAllocator<B>,
@@ -250,7 +293,8 @@ impl<
         kbd_led_producer: Producer<'a, LedState, KBD_LED_SIZE>,
         #[cfg(feature = "mouse")] mouse_consumer: Consumer<'a, MouseState, MOUSE_SIZE>,
         ctrl_consumer: Consumer<'a, CtrlState, CTRL_SIZE>,
-    ) -> HidInterface<'a, B, KBD_SIZE, KBD_LED_SIZE, MOUSE_SIZE, CTRL_SIZE> {
+        #[cfg(feature = "gamepad")] gamepad_consumer: Consumer<'a, GamepadState, GAMEPAD_SIZE>,
+    ) -> HidInterface<'a, B, KBD_SIZE, KBD_LED_SIZE, MOUSE_SIZE, CTRL_SIZE, GAMEPAD_SIZE> {
         let kbd_6kro = HIDClass::new_ep_in_with_settings(
             alloc,
             KeyboardReport::desc(),
@@ -276,6 +320,8 @@ impl<
         let ctrl = HIDClass::new_ep_in(alloc, SysCtrlConsumerCtrlReport::desc(), 10);
         #[cfg(feature = "mouse")]
         let mouse = HIDClass::new_ep_in(alloc, MouseReport::desc(), 10);
+        #[cfg(feature = "gamepad")]
+        let gamepad = HIDClass::new_ep_in(alloc, GamepadReport::desc(), 10);
         #[cfg(feature = "hidio")]
         let hidio = HIDClass::new(alloc, HidioReport::desc(), 10);
 
@@ -317,6 +363,24 @@ impl<
             },
             #[cfg(feature = "mouse")]
             mouse_updated: true,
+            #[cfg(feature = "gamepad")]
+            gamepad,
+            #[cfg(feature = "gamepad")]
+            gamepad_consumer,
+            #[cfg(feature = "gamepad")]
+            gamepad_report: GamepadReport {
+                buttons: [0; 2],
+                x: 0,
+                y: 0,
+                z: 0,
+                rx: 0,
+                ry: 0,
+                rz: 0,
+            },
+            #[cfg(feature = "gamepad")]
+            gamepad_axes: [[0; 2]; 6],
+            #[cfg(feature = "gamepad")]
+            gamepad_updated: true,
             #[cfg(feature = "hidio")]
             hidio,
         }
@@ -341,7 +405,32 @@ impl<
     }
 
     /// Used to pass all of the interfaces to usb_dev.poll()
-    #[cfg(all(feature = "mouse", feature = "hidio"))]
+    #[cfg(all(feature = "mouse", feature = "gamepad", feature = "hidio"))]
+    pub fn interfaces(&mut self) -> [&'_ mut dyn UsbClass<B>; 6] {
+        [
+            &mut self.kbd_6kro,
+            &mut self.kbd_nkro,
+            &mut self.ctrl,
+            &mut self.mouse,
+            &mut self.gamepad,
+            &mut self.hidio,
+        ]
+    }
+
+    /// Used to pass all of the interfaces to usb_dev.poll()
+    #[cfg(all(feature = "mouse", feature = "gamepad", not(feature = "hidio")))]
+    pub fn interfaces(&mut self) -> [&'_ mut dyn UsbClass<B>; 5] {
+        [
+            &mut self.kbd_6kro,
+            &mut self.kbd_nkro,
+            &mut self.ctrl,
+            &mut self.mouse,
+            &mut self.gamepad,
+        ]
+    }
+
+    /// Used to pass all of the interfaces to usb_dev.poll()
+    #[cfg(all(feature = "mouse", not(feature = "gamepad"), feature = "hidio"))]
     pub fn interfaces(&mut self) -> [&'_ mut dyn UsbClass<B>; 5] {
         [
             &mut self.kbd_6kro,
@@ -353,7 +442,7 @@ impl<
     }
 
     /// Used to pass all of the interfaces to usb_dev.poll()
-    #[cfg(all(feature = "mouse", not(feature = "hidio")))]
+    #[cfg(all(feature = "mouse", not(feature = "gamepad"), not(feature = "hidio")))]
     pub fn interfaces(&mut self) -> [&'_ mut dyn UsbClass<B>; 4] {
         [
             &mut self.kbd_6kro,
@@ -364,7 +453,30 @@ impl<
     }
 
     /// Used to pass all of the interfaces to usb_dev.poll()
-    #[cfg(all(not(feature = "mouse"), feature = "hidio"))]
+    #[cfg(all(not(feature = "mouse"), feature = "gamepad", feature = "hidio"))]
+    pub fn interfaces(&mut self) -> [&'_ mut dyn UsbClass<B>; 5] {
+        [
+            &mut self.kbd_6kro,
+            &mut self.kbd_nkro,
+            &mut self.ctrl,
+            &mut self.gamepad,
+            &mut self.hidio,
+        ]
+    }
+
+    /// Used to pass all of the interfaces to usb_dev.poll()
+    #[cfg(all(not(feature = "mouse"), feature = "gamepad", not(feature = "hidio")))]
+    pub fn interfaces(&mut self) -> [&'_ mut dyn UsbClass<B>; 4] {
+        [
+            &mut self.kbd_6kro,
+            &mut self.kbd_nkro,
+            &mut self.ctrl,
+            &mut self.gamepad,
+        ]
+    }
+
+    /// Used to pass all of the interfaces to usb_dev.poll()
+    #[cfg(all(not(feature = "mouse"), not(feature = "gamepad"), feature = "hidio"))]
     pub fn interfaces(&mut self) -> [&'_ mut dyn UsbClass<B>; 4] {
         [
             &mut self.kbd_6kro,
@@ -375,7 +487,11 @@ impl<
     }
 
     /// Used to pass all of the interfaces to usb_dev.poll()
-    #[cfg(all(not(feature = "mouse"), not(feature = "hidio")))]
+    #[cfg(all(
+        not(feature = "mouse"),
+        not(feature = "gamepad"),
+        not(feature = "hidio")
+    ))]
     pub fn interfaces(&mut self) -> [&'_ mut dyn UsbClass<B>; 3] {
         [&mut self.kbd_6kro, &mut self.kbd_nkro, &mut self.ctrl]
     }
@@ -517,14 +633,14 @@ impl<
                     self.mouse_button_bit(key, false);
                 }
                 MouseState::Position { x, y } => {
-                    self.mouse_report.x = x;
-                    self.mouse_report.y = y;
+                    self.mouse_report.x = self.mouse_report.x.saturating_add(x);
+                    self.mouse_report.y = self.mouse_report.y.saturating_add(y);
                 }
                 MouseState::VertWheel(pos) => {
-                    self.mouse_report.vert_wheel = pos;
+                    self.mouse_report.vert_wheel = self.mouse_report.vert_wheel.saturating_add(pos);
                 }
                 MouseState::HorzWheel(pos) => {
-                    self.mouse_report.horz_wheel = pos;
+                    self.mouse_report.horz_wheel = self.mouse_report.horz_wheel.saturating_add(pos);
                 }
                 MouseState::Clear => {
                     self.mouse_report.buttons = 0;
@@ -567,6 +683,81 @@ impl<
         Ok(())
     }
 
+    /// Updates self.gamepad_report and indicates if there are any changes
+    /// Changes are used to determine if USB Resume is necessary before pushing
+    /// the next packet.
+    #[cfg(feature = "gamepad")]
+    fn update_gamepad(&mut self) {
+        // Empty gamepad queue
+        while let Some(state) = self.gamepad_consumer.dequeue() {
+            self.gamepad_updated = true;
+            debug!("gamepad: {:?}", state);
+            match state {
+                GamepadState::Press(button) => {
+                    self.gamepad_button_bit(button, true);
+                }
+                GamepadState::Release(button) => {
+                    self.gamepad_button_bit(button, false);
+                }
+                GamepadState::Axis {
+                    axis,
+                    negative,
+                    value,
+                } => {
+                    // Ignore axes outside of 0 to 5
+                    if let Some(halves) = self.gamepad_axes.get_mut(axis as usize) {
+                        halves[negative as usize] = value.min(127);
+                    }
+                }
+                GamepadState::Clear => {
+                    self.gamepad_report.buttons = [0; 2];
+                    self.gamepad_axes = [[0; 2]; 6];
+                }
+                GamepadState::Unknown => {}
+            }
+        }
+
+        // Combine the positive and negative halves of each axis
+        let [x, y, z, rx, ry, rz] = self
+            .gamepad_axes
+            .map(|[positive, negative]| (positive as i16 - negative as i16) as i8);
+        self.gamepad_report.x = x;
+        self.gamepad_report.y = y;
+        self.gamepad_report.z = z;
+        self.gamepad_report.rx = rx;
+        self.gamepad_report.ry = ry;
+        self.gamepad_report.rz = rz;
+    }
+
+    #[cfg(feature = "gamepad")]
+    fn gamepad_button_bit(&mut self, button: u8, press: bool) {
+        // Ignore buttons outside of 1 to 16
+        if let 1..=16 = button {
+            let button = button - 1;
+            // Determine position
+            let byte: usize = (button / 8).into();
+            let bit: usize = (button % 8).into();
+
+            // Set/Unset
+            if press {
+                self.gamepad_report.buttons[byte] |= 1 << bit;
+            } else {
+                self.gamepad_report.buttons[byte] &= !(1 << bit);
+            }
+        }
+    }
+
+    #[cfg(feature = "gamepad")]
+    fn push_gamepad(&mut self) -> Result<(), UsbError> {
+        // Push report
+        if let Err(val) = self.gamepad.push_input(&self.gamepad_report) {
+            trace!("Gamepad Buffer Overflow: {:?}", val);
+            Err(val)
+        } else {
+            Ok(())
+        }
+    }
+
     /// Update self.ctrl_report and indicates if there are any changes
     /// Changes are used to determine if USB Resume is necessary before pushing
     /// the next packet.
@@ -618,16 +809,21 @@ impl<
         self.update_ctrl();
         #[cfg(feature = "mouse")]
         self.update_mouse();
+        #[cfg(feature = "gamepad")]
+        self.update_gamepad();
 
         // Collect all report statuses
+        #[allow(unused_mut)]
+        let mut updated = self.kbd_updated || self.ctrl_updated;
         #[cfg(feature = "mouse")]
         {
-            self.kbd_updated || self.ctrl_updated || self.mouse_updated
+            updated |= self.mouse_updated;
         }
-        #[cfg(not(feature = "mouse"))]
+        #[cfg(feature = "gamepad")]
         {
-            self.kbd_updated || self.ctrl_updated
+            updated |= self.gamepad_updated;
         }
+        updated
     }
 
     /// Processes each of the spsc queues and pushes data over USB
@@ -673,6 +869,14 @@ impl<
             self.push_mouse()?;
             self.mouse_updated = false;
         }
+
+        // Push gamepad reports
+        #[cfg(feature = "gamepad")]
+        if self.gamepad_updated {
+            trace!("Gamepad Push");
+            self.push_gamepad()?;
+            self.gamepad_updated = false;
+        }
         Ok(())
     }
 
@@ -881,11 +1085,81 @@ pub fn enqueue_ctrl_event<const CTRL_SIZE: usize>(
 
 #[cfg(feature = "kll-core")]
 pub fn enqueue_mouse_event<const MOUSE_SIZE: usize>(
-    _cap_run: kll_core::CapabilityRun,
-    _mouse_producer: &mut Producer<MouseState, MOUSE_SIZE>,
+    cap_run: kll_core::CapabilityRun,
+    mouse_producer: &mut Producer<MouseState, MOUSE_SIZE>,
 ) -> Result<(), MouseState> {
-    // TODO
-    Err(MouseState::Unknown)
+    // Passthrough events (e.g. held mouse keys) use the state of the switch
+    match cap_run {
+        kll_core::CapabilityRun::MouseButton { state, button } => match state.activation() {
+            kll_core::CapabilityEvent::Initial => mouse_producer.enqueue(MouseState::Press(button)),
+            kll_core::CapabilityEvent::Last => mouse_producer.enqueue(MouseState::Release(button)),
+            _ => Ok(()),
+        },
+        kll_core::CapabilityRun::MouseMove { state, x, y } => match state.activation() {
+            kll_core::CapabilityEvent::Initial | kll_core::CapabilityEvent::Any => {
+                mouse_producer.enqueue(MouseState::Position { x, y })
+            }
+            _ => Ok(()),
+        },
+        kll_core::CapabilityRun::MouseWheel { state, amount } => match state.activation() {
+            kll_core::CapabilityEvent::Initial => {
+                mouse_producer.enqueue(MouseState::VertWheel(amount))
+            }
+            _ => Ok(()),
+        },
+        kll_core::CapabilityRun::MouseHorzWheel { state, amount } => match state.activation() {
+            kll_core::CapabilityEvent::Initial => {
+                mouse_producer.enqueue(MouseState::HorzWheel(amount))
+            }
+            _ => Ok(()),
+        },
+        _ => {
+            error!("Unknown CapabilityRun for Mouse: {:?}", cap_run);
+            Err(MouseState::Unknown)
+        }
+    }
+}
+
+#[cfg(feature = "kll-core")]
+pub fn enqueue_gamepad_event<const GAMEPAD_SIZE: usize>(
+    cap_run: kll_core::CapabilityRun,
+    gamepad_producer: &mut Producer<GamepadState, GAMEPAD_SIZE>,
+) -> Result<(), GamepadState> {
+    match cap_run {
+        kll_core::CapabilityRun::GamepadButton { state, button } => match state.activation() {
+            kll_core::CapabilityEvent::Initial => {
+                gamepad_producer.enqueue(GamepadState::Press(button))
+            }
+            kll_core::CapabilityEvent::Last => {
+                gamepad_producer.enqueue(GamepadState::Release(button))
+            }
+            _ => Ok(()),
+        },
+        kll_core::CapabilityRun::GamepadAxis {
+            state,
+            axis,
+            direction,
+            value,
+        } => {
+            // Passthrough events carry the current position of the key
+            let value = match state.activation() {
+                kll_core::CapabilityEvent::None => {
+                    return Ok(());
+                }
+                kll_core::CapabilityEvent::Last => 0,
+                _ => value,
+            };
+            gamepad_producer.enqueue(GamepadState::Axis {
+                axis: axis as u8,
+                negative: direction == kll_core::gamepad::Direction::Negative,
+                value,
+            })
+        }
+        _ => {
+            error!("Unknown CapabilityRun for Gamepad: {:?}", cap_run);
+            Err(GamepadState::Unknown)
+        }
+    }
 }
 
 #[cfg(feature = "kll-core")]
@@ -7,7 +7,9 @@
 
 #![cfg(test)]
 
-use crate::descriptor::{HidioReport, KeyboardNkroReport, MouseReport, SysCtrlConsumerCtrlReport};
+use crate::descriptor::{
+    GamepadReport, HidioReport, KeyboardNkroReport, MouseReport, SysCtrlConsumerCtrlReport,
+};
 use usbd_hid::descriptor::generator_prelude::*;
 
 #[test]
@@ -131,3 +133,42 @@ fn test_mouse_descriptor() {
     //libc_print::libc_println!("Mouse: {:02X?}", MouseReport::desc());
     assert_eq!(MouseReport::desc(), expected);
 }
+
+#[test]
+fn test_gamepad_descriptor() {
+    let expected = &[
+        0x05, 0x01, // Usage Page (Generic Desktop Ctrls)
+        0x09, 0x05, // Usage (Game Pad)
+        0xA1, 0x01, // Collection (Application)
+        0x05, 0x09, //   Usage Page (Button)
+        0x19, 0x01, //   Usage Minimum (0x01)
+        0x29, 0x10, //   Usage Maximum (0x10)
+        0x15, 0x00, //   Logical Minimum (0)
+        0x25, 0x01, //   Logical Maximum (1)
+        0x75, 0x01, //   Report Size (1)
+        0x95, 0x10, //   Report Count (16)
+        0x81, 0x02, //   Input
+        //               (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+        0x05, 0x01, //   Usage Page (Generic Desktop Ctrls)
+        0x09, 0x30, //   Usage (X)
+        0x17, 0x81, 0xFF, 0xFF, 0xFF, //   Logical Minimum (-128)
+        0x25, 0x7F, //   Logical Maximum (127)
+        0x75, 0x08, //   Report Size (8)
+        0x95, 0x01, //   Report Count (1)
+        0x81, 0x02, //   Input
+        //               (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+        0x09, 0x31, //   Usage (Y)
+        0x81, 0x02, //   Input
+        0x09, 0x32, //   Usage (Z)
+        0x81, 0x02, //   Input
+        0x09, 0x33, //   Usage (Rx)
+        0x81, 0x02, //   Input
+        0x09, 0x34, //   Usage (Ry)
+        0x81, 0x02, //   Input
+        0x09, 0x35, //   Usage (Rz)
+        0x81, 0x02, //   Input
+        0xC0, // End Collection
+    ];
+    //libc_print::libc_println!("Gamepad: {:02X?}", GamepadReport::desc());
+    assert_eq!(GamepadReport::desc(), expected);
+}
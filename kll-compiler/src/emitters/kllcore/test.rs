@@ -8,7 +8,7 @@
 #![cfg(test)]
 
 use crate::emitters::kllcore::{KllCoreData, KllCoreValidation};
-use crate::types::KllFile;
+use crate::types::{KllFile, ResultCapabilitiesList};
 use flexi_logger::Logger;
 use layouts_rs::Layouts;
 use log::*;
@@ -120,6 +120,136 @@ fn trigger_result() {
     // TODO Validate
 }
 
+#[test]
+fn result_capabilities() {
+    let mut layouts = Layouts::from_dir(PathBuf::from("layouts"));
+
+    for (result, capability) in [
+        (
+            "holdTap(10, 0x14)",
+            kll_core::Capability::HoldTap {
+                state: kll_core::CapabilityState::Initial,
+                loop_condition_index: 0,
+                tap: 10,
+                hold: 20,
+            },
+        ),
+        (
+            "tapDance(20, 0x3C)",
+            kll_core::Capability::TapDance {
+                state: kll_core::CapabilityState::Initial,
+                loop_condition_index: 0,
+                taps: 20,
+                holds: 60,
+            },
+        ),
+        (
+            "mouseButton(3)",
+            kll_core::Capability::MouseButton {
+                state: kll_core::CapabilityState::Initial,
+                loop_condition_index: 0,
+                button: 3,
+            },
+        ),
+        (
+            "mouseMove(-4, 2, Linear, 50)",
+            kll_core::Capability::MouseMove {
+                state: kll_core::CapabilityState::Initial,
+                loop_condition_index: 0,
+                x: -4,
+                y: 2,
+                accel: kll_core::mouse::Acceleration::Linear,
+                ramp: 50,
+            },
+        ),
+        (
+            "mouseWheel(-1)",
+            kll_core::Capability::MouseWheel {
+                state: kll_core::CapabilityState::Initial,
+                loop_condition_index: 0,
+                amount: -1,
+            },
+        ),
+        (
+            "mouseHorzWheel(1)",
+            kll_core::Capability::MouseHorzWheel {
+                state: kll_core::CapabilityState::Initial,
+                loop_condition_index: 0,
+                amount: 1,
+            },
+        ),
+        (
+            "gamepadButton(12)",
+            kll_core::Capability::GamepadButton {
+                state: kll_core::CapabilityState::Initial,
+                loop_condition_index: 0,
+                button: 12,
+            },
+        ),
+        (
+            "gamepadAxis(Ry, Negative, 400)",
+            kll_core::Capability::GamepadAxis {
+                state: kll_core::CapabilityState::Initial,
+                loop_condition_index: 0,
+                axis: kll_core::gamepad::Axis::Ry,
+                direction: kll_core::gamepad::Direction::Negative,
+                max: 400,
+            },
+        ),
+        (
+            "autoShift(A, 200)",
+            kll_core::Capability::HidKeyboardAutoShift {
+                state: kll_core::CapabilityState::Initial,
+                loop_condition_index: 0,
+                id: kll_core::kll_hid::Keyboard::A,
+                threshold: 200,
+            },
+        ),
+        (
+            "keyRepeat(0x05, 30, 500)",
+            kll_core::Capability::HidKeyboardRepeat {
+                state: kll_core::CapabilityState::Initial,
+                loop_condition_index: 0,
+                id: kll_core::kll_hid::Keyboard::B,
+                rate: 30,
+                delay: 500,
+            },
+        ),
+        (
+            "capsWord()",
+            kll_core::Capability::CapsWord {
+                state: kll_core::CapabilityState::Initial,
+                loop_condition_index: 0,
+            },
+        ),
+        (
+            "modMorph(Esc, 0x22, 0x4C)",
+            kll_core::Capability::HidKeyboardModMorph {
+                state: kll_core::CapabilityState::Initial,
+                loop_condition_index: 0,
+                id: kll_core::kll_hid::Keyboard::Esc,
+                mods: 0x22,
+                morph: kll_core::kll_hid::Keyboard::Delete,
+            },
+        ),
+    ] {
+        let test = format!("S0x00(P) : {}(P);\n", result);
+        let state = KllFile::from_str(&test).unwrap().into_struct();
+
+        // Generate result guides
+        let guides: Vec<_> = state
+            .result_lists()
+            .map(|result_list| result_list.kll_core_capability_guide(&mut layouts))
+            .collect();
+        assert_eq!(
+            guides,
+            [ResultCapabilitiesList(vec![vec![capability]])],
+            "Unexpected capability for {}",
+            result
+        );
+    }
+}
+
 #[test]
 fn layer_lookup_simple() {
     setup_logging_lite().ok();
@@ -80,15 +80,21 @@ impl KLLParser {
         ))
     }
 
+    fn args(input: Node) -> Result<Vec<&str>> {
+        Ok(match_nodes!(input.into_children();
+            [kv(kv)..] => kv.map(|(k, _v)| k).collect(),
+        ))
+    }
+
     fn function(input: Node) -> Result<Capability> {
         Ok(match_nodes!(input.into_children();
             [name(n)] => Capability {
                 function: n,
                 args: vec![],
             },
-            [name(n), kvmap(args)] => Capability {
+            [name(n), args(args)] => Capability {
                 function: n,
-                args: args.keys().copied().collect(), // xxx
+                args,
             }
         ))
     }
@@ -398,6 +398,171 @@ impl<'a> Capability<'a> {
     pub fn new(function: &'a str, args: Vec<&'a str>) -> Self {
         Capability { function, args }
     }
+
+    /// Converts to a kll-core Capability definition
+    /// Arguments are positional, numbers may be negative or hexadecimal (0x).
+    ///
+    /// | KLL                               | kll-core Capability  |
+    /// |-----------------------------------|----------------------|
+    /// | holdTap(tap, hold)                | HoldTap              |
+    /// | tapDance(taps, holds)             | TapDance             |
+    /// | mouseButton(button)               | MouseButton          |
+    /// | mouseMove(x, y, accel, ramp)      | MouseMove            |
+    /// | mouseWheel(amount)                | MouseWheel           |
+    /// | mouseHorzWheel(amount)            | MouseHorzWheel       |
+    /// | gamepadButton(button)             | GamepadButton        |
+    /// | gamepadAxis(axis, direction, max) | GamepadAxis          |
+    /// | autoShift(key, threshold)         | HidKeyboardAutoShift |
+    /// | keyRepeat(key, rate, delay)       | HidKeyboardRepeat    |
+    /// | capsWord()                        | CapsWord             |
+    /// | modMorph(key, mods, morph)        | HidKeyboardModMorph  |
+    ///
+    /// tap, hold, taps and holds are ResultGuide offsets.
+    /// Keys are USB HID key names (e.g. Esc) or numbers, mods is a bitmask of the modifiers (see
+    /// kll_core::hid::modifier_bit).
+    /// accel is the name of a mouse::Acceleration curve (e.g. Linear), axis and direction are the
+    /// names of a gamepad::Axis (e.g. Rx) and gamepad::Direction (e.g. Negative).
+    pub fn kll_core_capability(
+        &self,
+        state: kll_core::CapabilityState,
+        layout: &Layout,
+    ) -> kll_core::Capability {
+        let loop_condition_index = 0; // TODO
+        match self.function {
+            "holdTap" => kll_core::Capability::HoldTap {
+                state,
+                loop_condition_index,
+                tap: self.num_arg(0),
+                hold: self.num_arg(1),
+            },
+            "tapDance" => kll_core::Capability::TapDance {
+                state,
+                loop_condition_index,
+                taps: self.num_arg(0),
+                holds: self.num_arg(1),
+            },
+            "mouseButton" => kll_core::Capability::MouseButton {
+                state,
+                loop_condition_index,
+                button: self.num_arg(0),
+            },
+            "mouseMove" => kll_core::Capability::MouseMove {
+                state,
+                loop_condition_index,
+                x: self.num_arg(0),
+                y: self.num_arg(1),
+                accel: match self.arg(2) {
+                    "None" => kll_core::mouse::Acceleration::None,
+                    "Linear" => kll_core::mouse::Acceleration::Linear,
+                    "Quadratic" => kll_core::mouse::Acceleration::Quadratic,
+                    accel => {
+                        panic!("{} has an unknown acceleration curve {}.", self, accel);
+                    }
+                },
+                ramp: self.num_arg(3),
+            },
+            "mouseWheel" => kll_core::Capability::MouseWheel {
+                state,
+                loop_condition_index,
+                amount: self.num_arg(0),
+            },
+            "mouseHorzWheel" => kll_core::Capability::MouseHorzWheel {
+                state,
+                loop_condition_index,
+                amount: self.num_arg(0),
+            },
+            "gamepadButton" => kll_core::Capability::GamepadButton {
+                state,
+                loop_condition_index,
+                button: self.num_arg(0),
+            },
+            "gamepadAxis" => kll_core::Capability::GamepadAxis {
+                state,
+                loop_condition_index,
+                axis: match self.arg(0) {
+                    "X" => kll_core::gamepad::Axis::X,
+                    "Y" => kll_core::gamepad::Axis::Y,
+                    "Z" => kll_core::gamepad::Axis::Z,
+                    "Rx" => kll_core::gamepad::Axis::Rx,
+                    "Ry" => kll_core::gamepad::Axis::Ry,
+                    "Rz" => kll_core::gamepad::Axis::Rz,
+                    axis => {
+                        panic!("{} has an unknown axis {}.", self, axis);
+                    }
+                },
+                direction: match self.arg(1) {
+                    "Positive" => kll_core::gamepad::Direction::Positive,
+                    "Negative" => kll_core::gamepad::Direction::Negative,
+                    direction => {
+                        panic!("{} has an unknown direction {}.", self, direction);
+                    }
+                },
+                max: self.num_arg(2),
+            },
+            "autoShift" => kll_core::Capability::HidKeyboardAutoShift {
+                state,
+                loop_condition_index,
+                id: self.key_arg(0, layout),
+                threshold: self.num_arg(1),
+            },
+            "keyRepeat" => kll_core::Capability::HidKeyboardRepeat {
+                state,
+                loop_condition_index,
+                id: self.key_arg(0, layout),
+                rate: self.num_arg(1),
+                delay: self.num_arg(2),
+            },
+            "capsWord" => kll_core::Capability::CapsWord {
+                state,
+                loop_condition_index,
+            },
+            "modMorph" => kll_core::Capability::HidKeyboardModMorph {
+                state,
+                loop_condition_index,
+                id: self.key_arg(0, layout),
+                mods: self.num_arg(1),
+                morph: self.key_arg(2, layout),
+            },
+            _ => {
+                panic!("{} is not a kll-core capability.", self);
+            }
+        }
+    }
+
+    /// Retrieves a positional argument
+    fn arg(&self, pos: usize) -> &'a str {
+        match self.args.get(pos).copied() {
+            Some(arg) => arg,
+            None => {
+                panic!("{} is missing argument {}.", self, pos);
+            }
+        }
+    }
+
+    /// Retrieves a USB HID keyboard positional argument
+    fn key_arg(&self, pos: usize, layout: &Layout) -> kll_core::kll_hid::Keyboard {
+        let id = match Key::Usb(self.arg(pos)).value(layout) {
+            Some(id) => id as u16,
+            None => self.num_arg(pos),
+        };
+        kll_core::kll_hid::Keyboard::from(id)
+    }
+
+    /// Retrieves a numeric positional argument
+    fn num_arg<T: TryFrom<isize>>(&self, pos: usize) -> T {
+        use crate::parser::parse_int;
+        let arg = self.arg(pos);
+        let val = match arg.strip_prefix('-') {
+            Some(val) => -(parse_int(val) as isize),
+            None => parse_int(arg) as isize,
+        };
+        match T::try_from(val) {
+            Ok(val) => val,
+            Err(_) => {
+                panic!("{} argument {} ({}) is out of range.", self, pos, arg);
+            }
+        }
+    }
 }
 
 impl<'a> fmt::Display for Capability<'a> {
@@ -874,9 +1039,12 @@ impl<'a> Action<'a> {
             ResultType::Animation(_animation_result) => {
                 panic!("Incomplete {:?}", &self.result);
             }
-            ResultType::Capability((_capability, _state)) => {
-                panic!("Incomplete {:?}", &self.result);
-            }
+            ResultType::Capability((capability, _state)) => capability.kll_core_capability(
+                self.state.as_ref().unwrap().states[0]
+                    .kind
+                    .capability_state(),
+                &layout,
+            ),
             ResultType::Text(_text) => {
                 panic!("Incomplete {:?}", &self.result);
             }
@@ -0,0 +1,421 @@
+// Copyright 2023 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Versioned binary keymap image
+//!
+//! A single blob holding all of the tables needed to build a LayerLookup, so keymaps can be
+//! loaded at runtime (e.g. from flash or over HID-IO) instead of being compiled in.
+//!
+//! All header fields and tables are little-endian. TriggerGuides and ResultGuides use the
+//! in-memory layout of TriggerCondition and Capability (see the kll-macros crate).
+//!
+//! | Offset | Size   | Field                                                  |
+//! |--------|--------|--------------------------------------------------------|
+//! | 0      | 4      | Magic (`KLLB`)                                         |
+//! | 4      | 2      | Format version (see VERSION)                           |
+//! | 6      | 2      | Header size                                            |
+//! | 8      | 4      | Total size of the blob                                 |
+//! | 12     | 7 x 8  | Section table, (offset: u32, size: u32) for each Section |
+//! | 68     | 4      | CRC-32 of the blob, excluding this field               |
+//!
+//! Sections are 4 byte aligned. String tables are a u32 count, followed by the u32 end
+//! offset of each string and the UTF-8 string data.
+//!
+//! The tables are used in place, so keymap blobs are only supported on little-endian targets.
+
+mod test;
+
+use crate::layout::LayerLookup;
+use crate::{Capability, DecodeError};
+
+/// Identifies a keymap blob
+pub const MAGIC: [u8; 4] = *b"KLLB";
+
+/// Current keymap blob format version
+/// Increment whenever the header, the tables or the layout of TriggerCondition and Capability
+/// change.
+pub const VERSION: u16 = 1;
+
+/// Number of sections
+const SECTIONS: usize = 7;
+
+/// Size of the version 1 header
+pub const HEADER_SIZE: usize = 12 + SECTIONS * 8 + 4;
+
+/// Position of the CRC in the header
+const CRC_OFFSET: usize = HEADER_SIZE - 4;
+
+/// Sections are aligned so the u16 and u32 tables can be used in place
+const SECTION_ALIGN: usize = 4;
+
+/// Keymap blob sections, in section table order
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Section {
+    LayerLookup = 0,
+    TriggerGuides = 1,
+    ResultGuides = 2,
+    TriggerResultMapping = 3,
+    LoopConditionLookup = 4,
+    UnicodeStrings = 5,
+    UrlStrings = 6,
+}
+
+/// Errors when loading or building a keymap blob
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BlobError {
+    /// The blob (or the encode buffer) is too small
+    TooSmall,
+    /// The blob does not start with MAGIC
+    InvalidMagic,
+    /// The blob was built for a different format version
+    UnsupportedVersion(u16),
+    /// The header or total size does not match the blob
+    InvalidSize,
+    /// The section is out of bounds, overlaps the header or is misaligned
+    InvalidSection(Section),
+    /// The blob is not 4 byte aligned in memory
+    Misaligned,
+    /// The CRC does not match the contents of the blob
+    CrcMismatch {
+        /// CRC stored in the header
+        expected: u32,
+        /// CRC of the blob contents
+        found: u32,
+    },
+    /// The string table is malformed or is not valid UTF-8
+    InvalidStringTable(Section),
+    /// The keymap tables failed validation
+    Decode(DecodeError),
+}
+
+impl From<DecodeError> for BlobError {
+    fn from(err: DecodeError) -> Self {
+        BlobError::Decode(err)
+    }
+}
+
+/// CRC-32 (IEEE 802.3), continuing from a previous crc (0 to start)
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// CRC of a blob, skipping the CRC field of the header
+fn blob_crc(blob: &[u8]) -> u32 {
+    crc32(crc32(0, &blob[..CRC_OFFSET]), &blob[HEADER_SIZE..])
+}
+
+fn read_u16(bytes: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([bytes[pos], bytes[pos + 1]])
+}
+
+fn read_u32(bytes: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]])
+}
+
+/// Indexed list of UTF-8 strings (e.g. for HidioUnicodeString and HidioOpenUrl)
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct StringTable<'a> {
+    /// u32 end offset of each string
+    ends: &'a [u8],
+    /// String data
+    data: &'a str,
+}
+
+impl<'a> StringTable<'a> {
+    /// Validates a string table section
+    fn parse(raw: &'a [u8], section: Section) -> Result<Self, BlobError> {
+        let invalid = BlobError::InvalidStringTable(section);
+        if raw.is_empty() {
+            return Ok(Self::default());
+        }
+        let count = read_u32(raw.get(..4).ok_or(invalid)?, 0) as usize;
+        let ends_end = count
+            .checked_mul(4)
+            .and_then(|size| size.checked_add(4))
+            .ok_or(invalid)?;
+        let ends = raw.get(4..ends_end).ok_or(invalid)?;
+        let data = core::str::from_utf8(&raw[4 + ends.len()..]).map_err(|_| invalid)?;
+
+        // Each string must end after the previous string, on a character boundary
+        let mut start = 0;
+        for pos in (0..ends.len()).step_by(4) {
+            let end = read_u32(ends, pos) as usize;
+            if end < start || !data.is_char_boundary(end) {
+                return Err(invalid);
+            }
+            start = end;
+        }
+        Ok(Self { ends, data })
+    }
+
+    /// Number of strings
+    pub fn len(&self) -> usize {
+        self.ends.len() / 4
+    }
+
+    /// Determine if the table has no strings
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    /// Retrieves the string at the given index
+    pub fn get(&self, index: u16) -> Option<&'a str> {
+        let index = index as usize;
+        if index >= self.len() {
+            return None;
+        }
+        let start = if index == 0 {
+            0
+        } else {
+            read_u32(self.ends, (index - 1) * 4) as usize
+        };
+        let end = read_u32(self.ends, index * 4) as usize;
+        Some(&self.data[start..end])
+    }
+}
+
+/// Keymap loaded from a blob
+/// The tables reference the blob directly, nothing is copied.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Keymap<'a> {
+    pub layer_lookup: &'a [u8],
+    pub trigger_guides: &'a [u8],
+    pub result_guides: &'a [u8],
+    pub trigger_result_mapping: &'a [u16],
+    pub loop_condition_lookup: &'a [u32],
+    /// Strings used by HidioUnicodeString
+    pub unicode_strings: StringTable<'a>,
+    /// Strings used by HidioOpenUrl
+    pub url_strings: StringTable<'a>,
+}
+
+impl<'a> Keymap<'a> {
+    /// Loads a keymap blob
+    /// Checks the header, section bounds and CRC. The tables are validated by layer_lookup().
+    /// The blob must be 4 byte aligned as the tables are used in place.
+    pub fn from_blob(blob: &'a [u8]) -> Result<Self, BlobError> {
+        if blob.len() < HEADER_SIZE {
+            return Err(BlobError::TooSmall);
+        }
+        if blob[..4] != MAGIC {
+            return Err(BlobError::InvalidMagic);
+        }
+        let version = read_u16(blob, 4);
+        if version != VERSION {
+            return Err(BlobError::UnsupportedVersion(version));
+        }
+        let total_size = read_u32(blob, 8) as usize;
+        if read_u16(blob, 6) as usize != HEADER_SIZE
+            || total_size < HEADER_SIZE
+            || total_size > blob.len()
+        {
+            return Err(BlobError::InvalidSize);
+        }
+        if blob.as_ptr().align_offset(SECTION_ALIGN) != 0 {
+            return Err(BlobError::Misaligned);
+        }
+
+        // Anything after the total size (e.g. erased flash) is ignored
+        let blob = &blob[..total_size];
+        let expected = read_u32(blob, CRC_OFFSET);
+        let found = blob_crc(blob);
+        if expected != found {
+            return Err(BlobError::CrcMismatch { expected, found });
+        }
+
+        let section = |section: Section| -> Result<&'a [u8], BlobError> {
+            let pos = 12 + section as usize * 8;
+            let offset = read_u32(blob, pos) as usize;
+            let size = read_u32(blob, pos + 4) as usize;
+            let invalid = BlobError::InvalidSection(section);
+            if offset < HEADER_SIZE || !offset.is_multiple_of(SECTION_ALIGN) {
+                return Err(invalid);
+            }
+            blob.get(offset..offset.checked_add(size).ok_or(invalid)?)
+                .ok_or(invalid)
+        };
+
+        // SAFETY: u16 and u32 are valid for any bit pattern, alignment is checked by align_to
+        let trigger_result_mapping =
+            match unsafe { section(Section::TriggerResultMapping)?.align_to::<u16>() } {
+                (&[], table, &[]) => table,
+                _ => {
+                    return Err(BlobError::InvalidSection(Section::TriggerResultMapping));
+                }
+            };
+        let loop_condition_lookup =
+            match unsafe { section(Section::LoopConditionLookup)?.align_to::<u32>() } {
+                (&[], table, &[]) => table,
+                _ => {
+                    return Err(BlobError::InvalidSection(Section::LoopConditionLookup));
+                }
+            };
+
+        Ok(Self {
+            layer_lookup: section(Section::LayerLookup)?,
+            trigger_guides: section(Section::TriggerGuides)?,
+            result_guides: section(Section::ResultGuides)?,
+            trigger_result_mapping,
+            loop_condition_lookup,
+            unicode_strings: StringTable::parse(
+                section(Section::UnicodeStrings)?,
+                Section::UnicodeStrings,
+            )?,
+            url_strings: StringTable::parse(section(Section::UrlStrings)?, Section::UrlStrings)?,
+        })
+    }
+
+    /// Builds a validated LayerLookup from the keymap tables
+    /// HidioUnicodeString and HidioOpenUrl indices are also checked against the string tables.
+    pub fn layer_lookup<const LAYOUT_SIZE: usize>(
+        &self,
+    ) -> Result<LayerLookup<'a, LAYOUT_SIZE>, BlobError> {
+        Ok(LayerLookup::try_new_with(
+            self.layer_lookup,
+            self.trigger_guides,
+            self.result_guides,
+            self.trigger_result_mapping,
+            self.loop_condition_lookup,
+            &|cap| match *cap {
+                Capability::HidioUnicodeString { index, .. }
+                    if index as usize >= self.unicode_strings.len() =>
+                {
+                    Err(DecodeError::InvalidStringIndex(index))
+                }
+                Capability::HidioOpenUrl { index, .. }
+                    if index as usize >= self.url_strings.len() =>
+                {
+                    Err(DecodeError::InvalidStringIndex(index))
+                }
+                _ => Ok(()),
+            },
+        )?)
+    }
+}
+
+/// Tables used to build a keymap blob (e.g. by the KLL compiler or a host tool)
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeymapSource<'a> {
+    pub layer_lookup: &'a [u8],
+    pub trigger_guides: &'a [u8],
+    pub result_guides: &'a [u8],
+    pub trigger_result_mapping: &'a [u16],
+    pub loop_condition_lookup: &'a [u32],
+    pub unicode_strings: &'a [&'a str],
+    pub url_strings: &'a [&'a str],
+}
+
+/// Size of a string table section
+fn string_table_size(strings: &[&str]) -> usize {
+    if strings.is_empty() {
+        return 0;
+    }
+    4 + strings.len() * 4 + strings.iter().map(|s| s.len()).sum::<usize>()
+}
+
+/// Rounds up to the next section alignment
+fn align(pos: usize) -> usize {
+    pos.div_ceil(SECTION_ALIGN) * SECTION_ALIGN
+}
+
+impl KeymapSource<'_> {
+    /// Size of each section, in section table order
+    fn section_sizes(&self) -> [usize; SECTIONS] {
+        [
+            self.layer_lookup.len(),
+            self.trigger_guides.len(),
+            self.result_guides.len(),
+            self.trigger_result_mapping.len() * 2,
+            self.loop_condition_lookup.len() * 4,
+            string_table_size(self.unicode_strings),
+            string_table_size(self.url_strings),
+        ]
+    }
+
+    /// Size of the encoded blob
+    pub fn encoded_len(&self) -> usize {
+        self.section_sizes()
+            .iter()
+            .fold(HEADER_SIZE, |pos, size| align(pos) + size)
+    }
+
+    /// Encodes the keymap blob into buf, returning the size of the blob
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, BlobError> {
+        let total_size = self.encoded_len();
+        let blob = buf.get_mut(..total_size).ok_or(BlobError::TooSmall)?;
+        blob.fill(0);
+
+        blob[..4].copy_from_slice(&MAGIC);
+        blob[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        blob[6..8].copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+        blob[8..12].copy_from_slice(&(total_size as u32).to_le_bytes());
+
+        let mut pos = HEADER_SIZE;
+        for (section, size) in self.section_sizes().into_iter().enumerate() {
+            pos = align(pos);
+            let header = 12 + section * 8;
+            blob[header..header + 4].copy_from_slice(&(pos as u32).to_le_bytes());
+            blob[header + 4..header + 8].copy_from_slice(&(size as u32).to_le_bytes());
+
+            let data = &mut blob[pos..pos + size];
+            match section {
+                0 => data.copy_from_slice(self.layer_lookup),
+                1 => data.copy_from_slice(self.trigger_guides),
+                2 => data.copy_from_slice(self.result_guides),
+                3 => {
+                    for (chunk, val) in data.chunks_exact_mut(2).zip(self.trigger_result_mapping) {
+                        chunk.copy_from_slice(&val.to_le_bytes());
+                    }
+                }
+                4 => {
+                    for (chunk, val) in data.chunks_exact_mut(4).zip(self.loop_condition_lookup) {
+                        chunk.copy_from_slice(&val.to_le_bytes());
+                    }
+                }
+                _ => {
+                    let strings = if section == Section::UnicodeStrings as usize {
+                        self.unicode_strings
+                    } else {
+                        self.url_strings
+                    };
+                    encode_strings(data, strings);
+                }
+            }
+            pos += size;
+        }
+
+        let crc = blob_crc(blob);
+        blob[CRC_OFFSET..HEADER_SIZE].copy_from_slice(&crc.to_le_bytes());
+        Ok(total_size)
+    }
+}
+
+/// Encodes a string table section
+fn encode_strings(data: &mut [u8], strings: &[&str]) {
+    if strings.is_empty() {
+        return;
+    }
+    data[..4].copy_from_slice(&(strings.len() as u32).to_le_bytes());
+    let (ends, text) = data[4..].split_at_mut(strings.len() * 4);
+    let mut end = 0;
+    for (chunk, string) in ends.chunks_exact_mut(4).zip(strings) {
+        text[end..end + string.len()].copy_from_slice(string.as_bytes());
+        end += string.len();
+        chunk.copy_from_slice(&(end as u32).to_le_bytes());
+    }
+}
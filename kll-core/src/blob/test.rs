@@ -0,0 +1,361 @@
+// Copyright 2023 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+#![cfg(test)]
+
+// ----- Crates -----
+
+use super::*;
+use crate::{trigger, CapabilityState, TriggerCondition};
+
+// ----- Constants -----
+
+#[rustfmt::skip]
+const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+    // Layer 0, Switch Type (1), Index 1
+    0, 1, 1, [0],
+);
+
+const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0];
+
+const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+    // Index: 0
+    [[TriggerCondition::Switch {
+        state: trigger::Phro::Press,
+        loop_condition_index: 0,
+        index: 1,
+    },]],
+);
+
+const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+    // Index: 0
+    [[Capability::HidKeyboard {
+        state: CapabilityState::Initial,
+        loop_condition_index: 0,
+        id: kll_hid::Keyboard::A,
+    },]],
+);
+
+const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+const SOURCE: KeymapSource = KeymapSource {
+    layer_lookup: LAYER_LOOKUP,
+    trigger_guides: TRIGGER_GUIDES,
+    result_guides: RESULT_GUIDES,
+    trigger_result_mapping: TRIGGER_RESULT_MAPPING,
+    loop_condition_lookup: LOOP_CONDITION_LOOKUP,
+    unicode_strings: &["ü", "", "€uro"],
+    url_strings: &["https://kiibohd.com"],
+};
+
+const ALL_SECTIONS: [Section; SECTIONS] = [
+    Section::LayerLookup,
+    Section::TriggerGuides,
+    Section::ResultGuides,
+    Section::TriggerResultMapping,
+    Section::LoopConditionLookup,
+    Section::UnicodeStrings,
+    Section::UrlStrings,
+];
+
+// ----- Structs -----
+
+/// Flash is usually word aligned
+#[derive(Copy, Clone)]
+#[repr(align(4))]
+struct Aligned([u8; 256]);
+
+// ----- Functions -----
+
+/// Encodes a keymap blob into an erased (0xFF) buffer, returning the buffer and the blob size
+fn encode(source: &KeymapSource) -> (Aligned, usize) {
+    let mut buf = Aligned([0xFF; 256]);
+    let size = source.encode(&mut buf.0).unwrap();
+    (buf, size)
+}
+
+/// Position of the section in the section table
+fn section_entry(section: Section) -> usize {
+    12 + section as usize * 8
+}
+
+/// (offset, size) of a section
+fn section_bounds(blob: &[u8], section: Section) -> (usize, usize) {
+    let pos = section_entry(section);
+    (
+        read_u32(blob, pos) as usize,
+        read_u32(blob, pos + 4) as usize,
+    )
+}
+
+/// Overwrites the size of a section and updates the CRC so only the section is invalid
+fn set_section_size(blob: &mut [u8], blob_size: usize, section: Section, size: usize) {
+    let pos = section_entry(section) + 4;
+    blob[pos..pos + 4].copy_from_slice(&(size as u32).to_le_bytes());
+    fix_crc(blob, blob_size);
+}
+
+/// Updates the CRC of a modified blob
+fn fix_crc(blob: &mut [u8], size: usize) {
+    let crc = blob_crc(&blob[..size]);
+    blob[CRC_OFFSET..HEADER_SIZE].copy_from_slice(&crc.to_le_bytes());
+}
+
+// ----- Tests -----
+
+#[test]
+fn keymap_blob_round_trip() {
+    let (buf, size) = encode(&SOURCE);
+    assert_eq!(size, SOURCE.encoded_len());
+    assert_eq!(
+        SOURCE.encode(&mut [0; HEADER_SIZE]),
+        Err(BlobError::TooSmall)
+    );
+
+    // Trailing data (e.g. erased flash) is ignored
+    let keymap = Keymap::from_blob(&buf.0).unwrap();
+    assert_eq!(keymap.layer_lookup, LAYER_LOOKUP);
+    assert_eq!(keymap.trigger_guides, TRIGGER_GUIDES);
+    assert_eq!(keymap.result_guides, RESULT_GUIDES);
+    assert_eq!(keymap.trigger_result_mapping, TRIGGER_RESULT_MAPPING);
+    assert_eq!(keymap.loop_condition_lookup, LOOP_CONDITION_LOOKUP);
+    assert_eq!(keymap.unicode_strings.len(), 3);
+    assert_eq!(keymap.unicode_strings.get(0), Some("ü"));
+    assert_eq!(keymap.unicode_strings.get(1), Some(""));
+    assert_eq!(keymap.unicode_strings.get(2), Some("€uro"));
+    assert_eq!(keymap.unicode_strings.get(3), None);
+    assert_eq!(keymap.url_strings.get(0), Some("https://kiibohd.com"));
+
+    // The loaded keymap has the same guides as the compiled in tables
+    let lookup = keymap.layer_lookup::<256>().unwrap();
+    assert_eq!(
+        lookup
+            .result_guide((0, 0), 0)
+            .and_then(|combo| combo.get(0)),
+        Some(Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        })
+    );
+}
+
+#[test]
+fn keymap_blob_header() {
+    let (buf, size) = encode(&SOURCE);
+
+    let mut corrupt = buf;
+    corrupt.0[4] = 2;
+    assert_eq!(
+        Keymap::from_blob(&corrupt.0),
+        Err(BlobError::UnsupportedVersion(2))
+    );
+    corrupt.0[0] = b'X';
+    assert_eq!(Keymap::from_blob(&corrupt.0), Err(BlobError::InvalidMagic));
+
+    // Header size
+    let mut corrupt = buf;
+    corrupt.0[6] += 4;
+    assert_eq!(Keymap::from_blob(&corrupt.0), Err(BlobError::InvalidSize));
+
+    // Total size smaller than the header
+    let mut corrupt = buf;
+    corrupt.0[8..12].copy_from_slice(&(HEADER_SIZE as u32 - 1).to_le_bytes());
+    assert_eq!(Keymap::from_blob(&corrupt.0), Err(BlobError::InvalidSize));
+
+    // Truncated blobs
+    assert_eq!(
+        Keymap::from_blob(&buf.0[..HEADER_SIZE - 1]),
+        Err(BlobError::TooSmall)
+    );
+    assert_eq!(
+        Keymap::from_blob(&buf.0[..size - 1]),
+        Err(BlobError::InvalidSize)
+    );
+    for section in ALL_SECTIONS {
+        let (offset, _size) = section_bounds(&buf.0, section);
+        assert_eq!(
+            Keymap::from_blob(&buf.0[..offset]),
+            Err(BlobError::InvalidSize),
+            "{:?}",
+            section
+        );
+    }
+
+    // The tables are used in place, so the blob must be aligned
+    let mut unaligned = Aligned([0; 256]);
+    unaligned.0[1..].copy_from_slice(&buf.0[..255]);
+    assert_eq!(
+        Keymap::from_blob(&unaligned.0[1..]),
+        Err(BlobError::Misaligned)
+    );
+}
+
+#[test]
+fn keymap_blob_crc() {
+    let (buf, size) = encode(&SOURCE);
+    let crc = read_u32(&buf.0, CRC_OFFSET);
+
+    // Corrupted section table
+    let mut corrupt = buf;
+    corrupt.0[section_entry(Section::UrlStrings)] ^= 0x01;
+    assert!(matches!(
+        Keymap::from_blob(&corrupt.0),
+        Err(BlobError::CrcMismatch { expected, .. }) if expected == crc
+    ));
+
+    // Corrupted data in each of the sections
+    for section in ALL_SECTIONS {
+        let (offset, _size) = section_bounds(&buf.0, section);
+        let mut corrupt = buf;
+        corrupt.0[offset] ^= 0x01;
+        assert!(
+            matches!(
+                Keymap::from_blob(&corrupt.0),
+                Err(BlobError::CrcMismatch { expected, .. }) if expected == crc
+            ),
+            "{:?}",
+            section
+        );
+    }
+
+    // Corrupted CRC
+    let mut corrupt = buf;
+    corrupt.0[CRC_OFFSET] ^= 0x01;
+    assert_eq!(
+        Keymap::from_blob(&corrupt.0),
+        Err(BlobError::CrcMismatch {
+            expected: crc ^ 0x01,
+            found: crc,
+        })
+    );
+
+    // Anything after the total size is not part of the CRC
+    let mut trailing = buf;
+    trailing.0[size] = 0;
+    assert!(Keymap::from_blob(&trailing.0).is_ok());
+}
+
+#[test]
+fn keymap_blob_truncated_sections() {
+    let (buf, size) = encode(&SOURCE);
+
+    // Sections that reach past the end of the blob
+    for section in ALL_SECTIONS {
+        let (offset, _size) = section_bounds(&buf.0, section);
+        let mut corrupt = buf;
+        set_section_size(&mut corrupt.0, size, section, size - offset + 1);
+        assert_eq!(
+            Keymap::from_blob(&corrupt.0),
+            Err(BlobError::InvalidSection(section)),
+        );
+    }
+
+    // Sections that overlap the header or are misaligned
+    let mut corrupt = buf;
+    let pos = section_entry(Section::LayerLookup);
+    corrupt.0[pos..pos + 4].copy_from_slice(&(HEADER_SIZE as u32 - 4).to_le_bytes());
+    fix_crc(&mut corrupt.0, size);
+    assert_eq!(
+        Keymap::from_blob(&corrupt.0),
+        Err(BlobError::InvalidSection(Section::LayerLookup))
+    );
+    let mut corrupt = buf;
+    corrupt.0[pos..pos + 4].copy_from_slice(&(HEADER_SIZE as u32 + 1).to_le_bytes());
+    fix_crc(&mut corrupt.0, size);
+    assert_eq!(
+        Keymap::from_blob(&corrupt.0),
+        Err(BlobError::InvalidSection(Section::LayerLookup))
+    );
+
+    // Sections missing their last byte
+    for section in ALL_SECTIONS {
+        let (_offset, section_size) = section_bounds(&buf.0, section);
+        let mut corrupt = buf;
+        set_section_size(&mut corrupt.0, size, section, section_size - 1);
+        let keymap = Keymap::from_blob(&corrupt.0);
+        match section {
+            // The guide tables are checked when building the LayerLookup
+            Section::LayerLookup | Section::TriggerGuides | Section::ResultGuides => {
+                assert!(
+                    matches!(
+                        keymap.unwrap().layer_lookup::<256>(),
+                        Err(BlobError::Decode(_))
+                    ),
+                    "{:?}",
+                    section
+                );
+            }
+            Section::TriggerResultMapping | Section::LoopConditionLookup => {
+                assert_eq!(keymap, Err(BlobError::InvalidSection(section)));
+            }
+            Section::UnicodeStrings | Section::UrlStrings => {
+                assert_eq!(keymap, Err(BlobError::InvalidStringTable(section)));
+            }
+        }
+    }
+
+    // Section sizes and string counts that would overflow on 32-bit targets
+    let mut corrupt = buf;
+    set_section_size(&mut corrupt.0, size, Section::UrlStrings, u32::MAX as usize);
+    assert_eq!(
+        Keymap::from_blob(&corrupt.0),
+        Err(BlobError::InvalidSection(Section::UrlStrings))
+    );
+    let mut corrupt = buf;
+    let (url_offset, _size) = section_bounds(&buf.0, Section::UrlStrings);
+    corrupt.0[url_offset..url_offset + 4].copy_from_slice(&0x4000_0001u32.to_le_bytes());
+    fix_crc(&mut corrupt.0, size);
+    assert_eq!(
+        Keymap::from_blob(&corrupt.0),
+        Err(BlobError::InvalidStringTable(Section::UrlStrings))
+    );
+}
+
+#[test]
+fn keymap_blob_tables() {
+    // Invalid tables are caught when building the LayerLookup
+    let bad_tables = KeymapSource {
+        trigger_result_mapping: &[0, 4],
+        ..SOURCE
+    };
+    let (buf, _size) = encode(&bad_tables);
+    let keymap = Keymap::from_blob(&buf.0).unwrap();
+    assert!(matches!(
+        keymap.layer_lookup::<256>(),
+        Err(BlobError::Decode(DecodeError::InvalidResultGuide(4)))
+    ));
+
+    // String indices must be inside of the string tables
+    const STRING_RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::HidioUnicodeString {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            index: 3,
+        },]],
+    );
+    let bad_string = KeymapSource {
+        result_guides: STRING_RESULT_GUIDES,
+        ..SOURCE
+    };
+    let (buf, _size) = encode(&bad_string);
+    let keymap = Keymap::from_blob(&buf.0).unwrap();
+    assert!(matches!(
+        keymap.layer_lookup::<256>(),
+        Err(BlobError::Decode(DecodeError::InvalidStringIndex(3)))
+    ));
+    let mut strings = ["", "", "", ""];
+    strings[..3].copy_from_slice(SOURCE.unicode_strings);
+    let good_string = KeymapSource {
+        unicode_strings: &strings,
+        ..bad_string
+    };
+    let (buf, _size) = encode(&good_string);
+    let keymap = Keymap::from_blob(&buf.0).unwrap();
+    assert!(keymap.layer_lookup::<256>().is_ok());
+}
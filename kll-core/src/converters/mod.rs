@@ -11,9 +11,10 @@ mod layer;
 mod led;
 mod rotation;
 mod switch;
+mod system;
 
 mod convert {
-    use crate::converters::{analog, animation, layer, led, rotation, switch};
+    use crate::converters::{analog, animation, layer, led, rotation, switch, system};
     use crate::{Capability, CapabilityEvent, CapabilityRun, TriggerCondition, TriggerEvent};
 
     impl From<TriggerEvent> for CapabilityRun {
@@ -31,13 +32,10 @@ mod convert {
                 TriggerEvent::AnalogVelocity { .. } => analog::convert(event),
                 TriggerEvent::AnalogAcceleration { .. } => analog::convert(event),
                 TriggerEvent::AnalogJerk { .. } => analog::convert(event),
-                // TriggerEvent::Sleep =>,
-                // TriggerEvent::Resume=>,
-                // TriggerEvent::Inactive=>,
-                // TriggerEvent::Active=>,
-                other => {
-                    panic!("*** remove once all events are handled ***\n TriggerEvent {:?} not recognised", other)
-                }
+                TriggerEvent::Sleep { .. } => system::convert(event),
+                TriggerEvent::Resume { .. } => system::convert(event),
+                TriggerEvent::Inactive { .. } => system::convert(event),
+                TriggerEvent::Active { .. } => system::convert(event),
             }
         }
     }
@@ -98,12 +96,56 @@ mod convert {
                     index,
                     last_state: 0,
                 },
-                _ => {
-                    panic!(
-                        "TriggerCondition to TriggerEvent not implemented! {:?}",
-                        cond
-                    );
+                TriggerCondition::HidLed { state, index, .. } => TriggerEvent::HidLed {
+                    state,
+                    index,
+                    last_state: 0,
+                },
+                TriggerCondition::AnalogDistance { index, val, .. } => {
+                    TriggerEvent::AnalogDistance { index, val }
                 }
+                TriggerCondition::AnalogVelocity { index, val, .. } => {
+                    TriggerEvent::AnalogVelocity { index, val }
+                }
+                TriggerCondition::AnalogAcceleration { index, val, .. } => {
+                    TriggerEvent::AnalogAcceleration { index, val }
+                }
+                TriggerCondition::AnalogJerk { index, val, .. } => {
+                    TriggerEvent::AnalogJerk { index, val }
+                }
+                TriggerCondition::Layer { state, layer, .. } => TriggerEvent::Layer {
+                    state,
+                    layer,
+                    last_state: 0,
+                },
+                TriggerCondition::Animation { state, index, .. } => TriggerEvent::Animation {
+                    state,
+                    index,
+                    last_state: 0,
+                },
+                TriggerCondition::Sleep { state, .. } => TriggerEvent::Sleep {
+                    state,
+                    last_state: 0,
+                },
+                TriggerCondition::Resume { state, .. } => TriggerEvent::Resume {
+                    state,
+                    last_state: 0,
+                },
+                TriggerCondition::Inactive { state, .. } => TriggerEvent::Inactive {
+                    state,
+                    last_state: 0,
+                },
+                TriggerCondition::Active { state, .. } => TriggerEvent::Active {
+                    state,
+                    last_state: 0,
+                },
+                TriggerCondition::Rotation {
+                    index, position, ..
+                } => TriggerEvent::Rotation {
+                    index,
+                    position,
+                    last_state: 0,
+                },
             }
         }
     }
@@ -112,19 +154,7 @@ mod convert {
     /// Used for mainly for kll validation
     impl From<Capability> for CapabilityRun {
         fn from(cap: Capability) -> Self {
-            let tevent = TriggerEvent::None;
-            match cap {
-                Capability::NoOp { state, .. } => CapabilityRun::NoOp {
-                    state: state.event(tevent),
-                },
-                Capability::HidKeyboard { state, id, .. } => CapabilityRun::HidKeyboard {
-                    state: state.event(tevent),
-                    id,
-                },
-                _ => {
-                    panic!("Capability to CapabilityRun not implemented! {:?}", cap);
-                }
-            }
+            cap.run(TriggerEvent::None)
         }
     }
 }
@@ -0,0 +1,117 @@
+// Copyright 2021-2023 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::trigger::Aodo;
+use crate::{error, warn};
+use crate::{CapabilityEvent, CapabilityRun, TriggerEvent};
+
+/// Converts the passed system `TriggerEvent` into a `CapabilityRun::NoOp`
+/// There is no matching capability, the NoOp carries the activation state of the event.
+///
+/// # Arguments
+///
+/// * `event`: The TriggerEvent to convert. This should always be `TriggerEvent::Sleep`,
+///   `TriggerEvent::Resume`, `TriggerEvent::Inactive` or `TriggerEvent::Active`, if it is
+///   anything else a CapabilityRun::NoOp with CapabilityEvent::None will be returned
+///
+/// returns: CapabilityRun::NoOp
+pub(super) fn convert(event: TriggerEvent) -> CapabilityRun {
+    match event {
+        TriggerEvent::Sleep { state, .. }
+        | TriggerEvent::Resume { state, .. }
+        | TriggerEvent::Inactive { state, .. }
+        | TriggerEvent::Active { state, .. } => CapabilityRun::NoOp {
+            state: match state {
+                Aodo::Activate => CapabilityEvent::Initial,
+                Aodo::On => CapabilityEvent::Any,
+                Aodo::Deactivate => CapabilityEvent::Last,
+                Aodo::Off => CapabilityEvent::None,
+                _ => {
+                    warn!("Unexpected state {:?}", state);
+                    CapabilityEvent::None
+                }
+            },
+        },
+        _ => {
+            error!("Unexpected event {:?}", event);
+            CapabilityRun::NoOp {
+                state: CapabilityEvent::None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::converters::system::convert;
+    use crate::trigger::Aodo;
+    use crate::{CapabilityEvent, CapabilityRun, TriggerEvent};
+
+    #[test]
+    fn convert_sleep_states() {
+        for (state, expected) in [
+            (Aodo::Activate, CapabilityEvent::Initial),
+            (Aodo::On, CapabilityEvent::Any),
+            (Aodo::Deactivate, CapabilityEvent::Last),
+            (Aodo::Off, CapabilityEvent::None),
+            (Aodo::Passthrough, CapabilityEvent::None),
+        ] {
+            let a = TriggerEvent::Sleep {
+                state,
+                last_state: 0,
+            };
+            assert_eq!(convert(a), CapabilityRun::NoOp { state: expected });
+        }
+    }
+
+    #[test]
+    fn convert_all_system_events() {
+        for a in [
+            TriggerEvent::Sleep {
+                state: Aodo::Activate,
+                last_state: 0,
+            },
+            TriggerEvent::Resume {
+                state: Aodo::Activate,
+                last_state: 0,
+            },
+            TriggerEvent::Inactive {
+                state: Aodo::Activate,
+                last_state: 3,
+            },
+            TriggerEvent::Active {
+                state: Aodo::Activate,
+                last_state: 0,
+            },
+        ] {
+            // Also goes through From<TriggerEvent> (used to panic)
+            assert_eq!(
+                CapabilityRun::from(a),
+                CapabilityRun::NoOp {
+                    state: CapabilityEvent::Initial
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn convert_unexpected_trigger_event_type_returns_noop() {
+        let a = TriggerEvent::HidLed {
+            state: Aodo::Activate,
+            index: 1,
+            last_state: 0,
+        };
+        let result = convert(a);
+
+        assert_eq!(
+            result,
+            CapabilityRun::NoOp {
+                state: CapabilityEvent::None
+            }
+        )
+    }
+}
@@ -0,0 +1,1085 @@
+// Copyright 2023 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Checked decoding of TriggerConditions, Capabilities and TriggerEvents
+//!
+//! The enums are #[repr(u8)], so each variant is laid out as a #[repr(C)] struct starting
+//! with the u8 discriminant. Each field is read from its offset in that struct and validated
+//! instead of transmuting the bytes.
+
+use crate::{
+    gamepad, hid, layer, mouse, pixel, trigger, Capability, CapabilityEvent, CapabilityRun,
+    CapabilityState, TriggerCondition, TriggerEvent,
+};
+use num_traits::FromPrimitive;
+
+/// Errors found while decoding KLL layout data
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecodeError {
+    /// Not enough bytes to decode the TriggerCondition or Capability
+    Truncated,
+    /// Unknown TriggerCondition or Capability discriminant
+    InvalidType(u8),
+    /// Invalid field value (e.g. unknown state or HID id) for the given discriminant
+    InvalidField(u8),
+    /// loop_condition_index is outside of the loop condition lookup
+    InvalidLoopConditionIndex(u16),
+    /// Layer lookup is truncated at the given position
+    InvalidLayerLookup(usize),
+    /// Trigger list index is outside of the trigger:result mapping
+    InvalidMappingIndex(u16),
+    /// Offset does not point to a valid TriggerGuide sequence
+    InvalidTriggerGuide(u16),
+    /// Offset does not point to a valid ResultGuide sequence
+    InvalidResultGuide(u16),
+    /// The layer lookup has more entries than LAYOUT_SIZE
+    LayoutSizeExceeded,
+    /// String index is outside of the string table (HidioUnicodeString or HidioOpenUrl)
+    InvalidStringIndex(u16),
+}
+
+/// Reads fields of a single encoded TriggerCondition or Capability
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn u8(&self, offset: usize) -> u8 {
+        self.bytes[offset]
+    }
+
+    fn i8(&self, offset: usize) -> i8 {
+        self.bytes[offset] as i8
+    }
+
+    fn u16(&self, offset: usize) -> u16 {
+        u16::from_ne_bytes([self.bytes[offset], self.bytes[offset + 1]])
+    }
+
+    fn i16(&self, offset: usize) -> i16 {
+        i16::from_ne_bytes([self.bytes[offset], self.bytes[offset + 1]])
+    }
+
+    fn u32(&self, offset: usize) -> u32 {
+        u32::from_ne_bytes([
+            self.bytes[offset],
+            self.bytes[offset + 1],
+            self.bytes[offset + 2],
+            self.bytes[offset + 3],
+        ])
+    }
+}
+
+/// Writes the fields of a single encoded TriggerEvent or CapabilityRun
+struct Writer<const N: usize> {
+    bytes: [u8; N],
+}
+
+impl<const N: usize> Default for Writer<N> {
+    fn default() -> Self {
+        Self { bytes: [0; N] }
+    }
+}
+
+impl<const N: usize> Writer<N> {
+    fn u8(&mut self, offset: usize, val: u8) {
+        self.bytes[offset] = val;
+    }
+
+    fn u16(&mut self, offset: usize, val: u16) {
+        self.bytes[offset..offset + 2].copy_from_slice(&val.to_ne_bytes());
+    }
+
+    fn u32(&mut self, offset: usize, val: u32) {
+        self.bytes[offset..offset + 4].copy_from_slice(&val.to_ne_bytes());
+    }
+}
+
+/// Converts a field value, failing with InvalidField for the discriminant if it is unknown
+fn field<T>(ty: u8, val: Option<T>) -> Result<T, DecodeError> {
+    val.ok_or(DecodeError::InvalidField(ty))
+}
+
+/// Validates the layer::State of a layer capability (Off cannot be set)
+fn set_layer_state(ty: u8, val: u8) -> Result<layer::State, DecodeError> {
+    match layer::State::from_u8(val) {
+        Some(layer::State::Off) | None => Err(DecodeError::InvalidField(ty)),
+        Some(state) => Ok(state),
+    }
+}
+
+impl TriggerCondition {
+    /// Decode a TriggerCondition from a slice of bytes (e.g. from a TriggerGuide)
+    /// Unlike from_bytes, the discriminant and each of the fields are validated.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let bytes = bytes
+            .get(..core::mem::size_of::<Self>())
+            .ok_or(DecodeError::Truncated)?;
+        let r = Reader { bytes };
+        let ty = r.u8(0);
+        let cond = match ty {
+            0 => TriggerCondition::None,
+            1 => TriggerCondition::Switch {
+                state: field(ty, trigger::Phro::from_u8(r.u8(1)))?,
+                index: r.u16(2),
+                loop_condition_index: r.u16(4),
+            },
+            2 => TriggerCondition::HidLed {
+                state: field(ty, trigger::Aodo::from_u8(r.u8(1)))?,
+                loop_condition_index: r.u16(2),
+                index: r.u8(4),
+            },
+            3 => TriggerCondition::AnalogDistance {
+                mode: analog_mode(ty, r.u8(1))?,
+                index: r.u16(2),
+                val: r.i16(4),
+            },
+            4 => TriggerCondition::AnalogVelocity {
+                mode: analog_mode(ty, r.u8(1))?,
+                index: r.u16(2),
+                val: r.i16(4),
+            },
+            5 => TriggerCondition::AnalogAcceleration {
+                mode: analog_mode(ty, r.u8(1))?,
+                index: r.u16(2),
+                val: r.i16(4),
+            },
+            6 => TriggerCondition::AnalogJerk {
+                mode: analog_mode(ty, r.u8(1))?,
+                index: r.u16(2),
+                val: r.i16(4),
+            },
+            7 => TriggerCondition::Layer {
+                state: field(ty, trigger::LayerState::from_u8(r.u8(1)))?,
+                loop_condition_index: r.u16(2),
+                layer: r.u8(4),
+            },
+            8 => TriggerCondition::Animation {
+                state: field(ty, trigger::Dro::from_u8(r.u8(1)))?,
+                index: r.u16(2),
+                loop_condition_index: r.u16(4),
+            },
+            9 => TriggerCondition::Sleep {
+                state: field(ty, trigger::Aodo::from_u8(r.u8(1)))?,
+                loop_condition_index: r.u16(2),
+            },
+            10 => TriggerCondition::Resume {
+                state: field(ty, trigger::Aodo::from_u8(r.u8(1)))?,
+                loop_condition_index: r.u16(2),
+            },
+            11 => TriggerCondition::Inactive {
+                state: field(ty, trigger::Aodo::from_u8(r.u8(1)))?,
+                loop_condition_index: r.u16(2),
+            },
+            12 => TriggerCondition::Active {
+                state: field(ty, trigger::Aodo::from_u8(r.u8(1)))?,
+                loop_condition_index: r.u16(2),
+            },
+            13 => TriggerCondition::Rotation {
+                index: r.u8(1),
+                loop_condition_index: r.u16(2),
+                position: r.i8(4),
+            },
+            _ => {
+                return Err(DecodeError::InvalidType(ty));
+            }
+        };
+        Ok(cond)
+    }
+
+    /// Scanning loop condition lookup index, None if the condition does not have one
+    pub(crate) fn loop_condition_index(&self) -> Option<u16> {
+        match self {
+            TriggerCondition::Switch {
+                loop_condition_index,
+                ..
+            }
+            | TriggerCondition::HidLed {
+                loop_condition_index,
+                ..
+            }
+            | TriggerCondition::Layer {
+                loop_condition_index,
+                ..
+            }
+            | TriggerCondition::Animation {
+                loop_condition_index,
+                ..
+            }
+            | TriggerCondition::Sleep {
+                loop_condition_index,
+                ..
+            }
+            | TriggerCondition::Resume {
+                loop_condition_index,
+                ..
+            }
+            | TriggerCondition::Inactive {
+                loop_condition_index,
+                ..
+            }
+            | TriggerCondition::Active {
+                loop_condition_index,
+                ..
+            }
+            | TriggerCondition::Rotation {
+                loop_condition_index,
+                ..
+            } => Some(*loop_condition_index),
+            _ => None,
+        }
+    }
+}
+
+impl TriggerEvent {
+    /// Decode a TriggerEvent from a slice of bytes (e.g. from a LayerState snapshot)
+    /// The discriminant and each of the fields are validated.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let bytes = bytes
+            .get(..core::mem::size_of::<Self>())
+            .ok_or(DecodeError::Truncated)?;
+        let r = Reader { bytes };
+        let ty = r.u8(0);
+        let event = match ty {
+            0 => TriggerEvent::None,
+            1 => TriggerEvent::Switch {
+                state: field(ty, trigger::Phro::from_u8(r.u8(1)))?,
+                index: r.u16(2),
+                last_state: r.u32(4),
+            },
+            2 => TriggerEvent::HidLed {
+                state: field(ty, trigger::Aodo::from_u8(r.u8(1)))?,
+                index: r.u8(2),
+                last_state: r.u32(4),
+            },
+            3 => TriggerEvent::AnalogDistance {
+                index: r.u16(2),
+                val: r.i16(4),
+            },
+            4 => TriggerEvent::AnalogVelocity {
+                index: r.u16(2),
+                val: r.i16(4),
+            },
+            5 => TriggerEvent::AnalogAcceleration {
+                index: r.u16(2),
+                val: r.i16(4),
+            },
+            6 => TriggerEvent::AnalogJerk {
+                index: r.u16(2),
+                val: r.i16(4),
+            },
+            7 => TriggerEvent::Layer {
+                state: field(ty, trigger::LayerState::from_u8(r.u8(1)))?,
+                layer: r.u8(2),
+                last_state: r.u32(4),
+            },
+            8 => TriggerEvent::Animation {
+                state: field(ty, trigger::Dro::from_u8(r.u8(1)))?,
+                index: r.u16(2),
+                last_state: r.u32(4),
+            },
+            9 => TriggerEvent::Sleep {
+                state: field(ty, trigger::Aodo::from_u8(r.u8(1)))?,
+                last_state: r.u32(4),
+            },
+            10 => TriggerEvent::Resume {
+                state: field(ty, trigger::Aodo::from_u8(r.u8(1)))?,
+                last_state: r.u32(4),
+            },
+            11 => TriggerEvent::Inactive {
+                state: field(ty, trigger::Aodo::from_u8(r.u8(1)))?,
+                last_state: r.u32(4),
+            },
+            12 => TriggerEvent::Active {
+                state: field(ty, trigger::Aodo::from_u8(r.u8(1)))?,
+                last_state: r.u32(4),
+            },
+            13 => TriggerEvent::Rotation {
+                index: r.u8(1),
+                position: r.i8(2),
+                last_state: r.u32(4),
+            },
+            _ => {
+                return Err(DecodeError::InvalidType(ty));
+            }
+        };
+        Ok(event)
+    }
+
+    /// Encode the TriggerEvent using the same layout as try_from_bytes
+    /// Unlike bytes, padding is always zeroed.
+    pub fn to_bytes(&self) -> [u8; core::mem::size_of::<Self>()] {
+        let mut w = Writer::<{ core::mem::size_of::<Self>() }>::default();
+        match *self {
+            TriggerEvent::None => {
+                w.u8(0, 0);
+            }
+            TriggerEvent::Switch {
+                state,
+                index,
+                last_state,
+            } => {
+                w.u8(0, 1);
+                w.u8(1, state as u8);
+                w.u16(2, index);
+                w.u32(4, last_state);
+            }
+            TriggerEvent::HidLed {
+                state,
+                index,
+                last_state,
+            } => {
+                w.u8(0, 2);
+                w.u8(1, state as u8);
+                w.u8(2, index);
+                w.u32(4, last_state);
+            }
+            TriggerEvent::AnalogDistance { index, val } => {
+                w.u8(0, 3);
+                w.u16(2, index);
+                w.u16(4, val as u16);
+            }
+            TriggerEvent::AnalogVelocity { index, val } => {
+                w.u8(0, 4);
+                w.u16(2, index);
+                w.u16(4, val as u16);
+            }
+            TriggerEvent::AnalogAcceleration { index, val } => {
+                w.u8(0, 5);
+                w.u16(2, index);
+                w.u16(4, val as u16);
+            }
+            TriggerEvent::AnalogJerk { index, val } => {
+                w.u8(0, 6);
+                w.u16(2, index);
+                w.u16(4, val as u16);
+            }
+            TriggerEvent::Layer {
+                state,
+                layer,
+                last_state,
+            } => {
+                w.u8(0, 7);
+                w.u8(1, state as u8);
+                w.u8(2, layer);
+                w.u32(4, last_state);
+            }
+            TriggerEvent::Animation {
+                state,
+                index,
+                last_state,
+            } => {
+                w.u8(0, 8);
+                w.u8(1, state as u8);
+                w.u16(2, index);
+                w.u32(4, last_state);
+            }
+            TriggerEvent::Sleep { state, last_state } => {
+                w.u8(0, 9);
+                w.u8(1, state as u8);
+                w.u32(4, last_state);
+            }
+            TriggerEvent::Resume { state, last_state } => {
+                w.u8(0, 10);
+                w.u8(1, state as u8);
+                w.u32(4, last_state);
+            }
+            TriggerEvent::Inactive { state, last_state } => {
+                w.u8(0, 11);
+                w.u8(1, state as u8);
+                w.u32(4, last_state);
+            }
+            TriggerEvent::Active { state, last_state } => {
+                w.u8(0, 12);
+                w.u8(1, state as u8);
+                w.u32(4, last_state);
+            }
+            TriggerEvent::Rotation {
+                index,
+                position,
+                last_state,
+            } => {
+                w.u8(0, 13);
+                w.u8(1, index);
+                w.u8(2, position as u8);
+                w.u32(4, last_state);
+            }
+        }
+        w.bytes
+    }
+}
+
+impl Capability {
+    /// Decode a Capability from a slice of bytes (e.g. from a ResultGuide)
+    /// Unlike from_bytes, the discriminant and each of the fields are validated.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let bytes = bytes
+            .get(..core::mem::size_of::<Self>())
+            .ok_or(DecodeError::Truncated)?;
+        let r = Reader { bytes };
+        let ty = r.u8(0);
+
+        // All capabilities start with the state and the loop condition index
+        let state = field(ty, CapabilityState::from_u8(r.u8(1)))?;
+        let loop_condition_index = r.u16(2);
+
+        let cap = match ty {
+            0 => Capability::NoOp {
+                state,
+                loop_condition_index,
+            },
+            1 => Capability::Rotate {
+                state,
+                loop_condition_index,
+                index: r.u8(4),
+                increment: r.i8(5),
+            },
+            2 => Capability::LayerClear {
+                state,
+                loop_condition_index,
+            },
+            3 => Capability::LayerState {
+                state,
+                loop_condition_index,
+                layer: r.u8(4),
+                layer_state: set_layer_state(ty, r.u8(5))?,
+            },
+            4 => Capability::LayerRotate {
+                state,
+                loop_condition_index,
+                direction: field(ty, layer::Direction::from_u8(r.u8(4)))?,
+            },
+            5 => Capability::HidProtocol {
+                state,
+                loop_condition_index,
+                mode: field(ty, hid::Protocol::from_u8(r.u8(4)))?,
+            },
+            6 => Capability::HidKeyboard {
+                state,
+                loop_condition_index,
+                id: keyboard(ty, r.u8(4))?,
+            },
+            7 => Capability::HidKeyboardState {
+                state,
+                loop_condition_index,
+                id: keyboard(ty, r.u8(4))?,
+                key_state: field(ty, hid::State::from_u8(r.u8(5)))?,
+            },
+            8 => Capability::HidConsumerControl {
+                state,
+                loop_condition_index,
+                id: field(ty, kll_hid::ConsumerControl::from_u16(r.u16(4)))?,
+            },
+            9 => Capability::HidSystemControl {
+                state,
+                loop_condition_index,
+                id: field(ty, kll_hid::SystemControl::from_u8(r.u8(4)))?,
+            },
+            10 => Capability::McuFlashMode {
+                state,
+                loop_condition_index,
+            },
+            11 => Capability::PixelAnimationControl {
+                state,
+                loop_condition_index,
+                mode: field(ty, pixel::AnimationControl::from_u8(r.u8(4)))?,
+            },
+            12 => Capability::PixelAnimationIndex {
+                state,
+                loop_condition_index,
+                index: r.u16(4),
+            },
+            13 => Capability::PixelFadeControl {
+                state,
+                loop_condition_index,
+                profile: r.u8(4),
+                command: field(ty, pixel::FadeCommand::from_u8(r.u8(5)))?,
+                arg: r.u8(6),
+            },
+            14 => Capability::PixelFadeLayer {
+                state,
+                loop_condition_index,
+                layer: r.u8(4),
+            },
+            15 => Capability::PixelFadeSet {
+                state,
+                loop_condition_index,
+                profile: r.u8(4),
+                config: r.u8(5),
+                period: r.u8(6),
+            },
+            16 => Capability::PixelGammaControl {
+                state,
+                loop_condition_index,
+                mode: field(ty, pixel::GammaControl::from_u8(r.u8(4)))?,
+            },
+            17 => Capability::PixelLedControl {
+                state,
+                loop_condition_index,
+                mode: field(ty, pixel::LedControl::from_u8(r.u8(4)))?,
+                amount: r.u8(5),
+            },
+            18 => Capability::PixelTest {
+                state,
+                loop_condition_index,
+                test: field(ty, pixel::PixelTest::from_u8(r.u8(4)))?,
+                index: r.u16(6),
+            },
+            19 => Capability::HidioOpenUrl {
+                state,
+                loop_condition_index,
+                index: r.u16(4),
+            },
+            20 => Capability::HidioUnicodeString {
+                state,
+                loop_condition_index,
+                index: r.u16(4),
+            },
+            21 => Capability::HidioUnicodeState {
+                state,
+                loop_condition_index,
+                unicode: field(ty, char::from_u32(r.u32(4)))?,
+            },
+            22 => Capability::HoldTap {
+                state,
+                loop_condition_index,
+                tap: r.u16(4),
+                hold: r.u16(6),
+            },
+            23 => Capability::TapDance {
+                state,
+                loop_condition_index,
+                taps: r.u16(4),
+                holds: r.u16(6),
+            },
+            24 => Capability::HidKeyboardLatch {
+                state,
+                loop_condition_index,
+                id: keyboard(ty, r.u8(4))?,
+            },
+            25 => Capability::MacroRecord {
+                state,
+                loop_condition_index,
+                slot: r.u8(4),
+            },
+            26 => Capability::MacroPlay {
+                state,
+                loop_condition_index,
+                slot: r.u8(4),
+                delay: r.u16(6),
+            },
+            27 => Capability::MouseButton {
+                state,
+                loop_condition_index,
+                button: button(ty, r.u8(4), 8)?,
+            },
+            28 => Capability::MouseMove {
+                state,
+                loop_condition_index,
+                x: r.i8(4),
+                y: r.i8(5),
+                accel: field(ty, mouse::Acceleration::from_u8(r.u8(6)))?,
+                ramp: r.u8(7),
+            },
+            29 => Capability::MouseWheel {
+                state,
+                loop_condition_index,
+                amount: r.i8(4),
+            },
+            30 => Capability::MouseHorzWheel {
+                state,
+                loop_condition_index,
+                amount: r.i8(4),
+            },
+            31 => Capability::GamepadButton {
+                state,
+                loop_condition_index,
+                button: button(ty, r.u8(4), 16)?,
+            },
+            32 => Capability::GamepadAxis {
+                state,
+                loop_condition_index,
+                axis: field(ty, gamepad::Axis::from_u8(r.u8(4)))?,
+                direction: field(ty, gamepad::Direction::from_u8(r.u8(5)))?,
+                max: r.u16(6),
+            },
+            33 => Capability::HidKeyboardAutoShift {
+                state,
+                loop_condition_index,
+                threshold: r.u16(4),
+                id: keyboard(ty, r.u8(6))?,
+            },
+            34 => Capability::HidKeyboardRepeat {
+                state,
+                loop_condition_index,
+                id: keyboard(ty, r.u8(4))?,
+                rate: r.u8(5),
+                delay: r.u16(6),
+            },
+            35 => Capability::CapsWord {
+                state,
+                loop_condition_index,
+            },
+            36 => Capability::HidKeyboardModMorph {
+                state,
+                loop_condition_index,
+                id: keyboard(ty, r.u8(4))?,
+                mods: r.u8(5),
+                morph: keyboard(ty, r.u8(6))?,
+            },
+            _ => {
+                return Err(DecodeError::InvalidType(ty));
+            }
+        };
+        Ok(cap)
+    }
+
+    /// ResultGuide offsets referenced by the Capability (e.g. the tap and hold results)
+    pub(crate) fn result_references(&self) -> Option<[u16; 2]> {
+        match self {
+            Capability::HoldTap { tap, hold, .. } => Some([*tap, *hold]),
+            Capability::TapDance { taps, holds, .. } => Some([*taps, *holds]),
+            _ => None,
+        }
+    }
+}
+
+/// Validates the mode byte of an analog TriggerCondition
+fn analog_mode(ty: u8, mode: u8) -> Result<u8, DecodeError> {
+    field(ty, trigger::AnalogCompare::from_mode(mode))?;
+    Ok(mode)
+}
+
+/// Validates a HID keyboard id before converting it
+fn keyboard(ty: u8, id: u8) -> Result<kll_hid::Keyboard, DecodeError> {
+    let id = u16::from(id);
+    if kll_hid::Keyboard::is_valid(id) {
+        Ok(id.into())
+    } else {
+        Err(DecodeError::InvalidField(ty))
+    }
+}
+
+/// Validates a u16 HID keyboard id (CapabilityRun encoding)
+fn keyboard_u16(ty: u8, id: u16) -> Result<kll_hid::Keyboard, DecodeError> {
+    if kll_hid::Keyboard::is_valid(id) {
+        Ok(id.into())
+    } else {
+        Err(DecodeError::InvalidField(ty))
+    }
+}
+
+/// Validates a HID LED indicator id
+fn led_indicator(ty: u8, id: u8) -> Result<kll_hid::LedIndicator, DecodeError> {
+    if matches!(id, 0x00..=0x39 | 0x4B..=0x4D) {
+        Ok(id.into())
+    } else {
+        Err(DecodeError::InvalidField(ty))
+    }
+}
+
+/// Validates a HID button number (1 to count)
+fn button(ty: u8, button: u8, count: u8) -> Result<u8, DecodeError> {
+    if (1..=count).contains(&button) {
+        Ok(button)
+    } else {
+        Err(DecodeError::InvalidField(ty))
+    }
+}
+
+/// Size of an encoded CapabilityRun (see CapabilityRun::to_bytes)
+pub const CAPABILITY_RUN_SIZE: usize = 16;
+
+impl CapabilityRun {
+    /// Decode a CapabilityRun encoded by to_bytes (e.g. from a replay trace)
+    /// The discriminant, the CapabilityEvent and each of the fields are validated.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let bytes = bytes
+            .get(..CAPABILITY_RUN_SIZE)
+            .ok_or(DecodeError::Truncated)?;
+        let r = Reader { bytes };
+        let ty = r.u8(0);
+
+        let state = match r.u8(1) {
+            0 => CapabilityEvent::None,
+            1 => CapabilityEvent::Initial,
+            2 => CapabilityEvent::Last,
+            3 => CapabilityEvent::Any,
+            4 => CapabilityEvent::Passthrough(TriggerEvent::try_from_bytes(&bytes[8..])?),
+            _ => {
+                return Err(DecodeError::InvalidField(ty));
+            }
+        };
+
+        let run = match ty {
+            0 => CapabilityRun::NoOp { state },
+            1 => CapabilityRun::Rotate {
+                state,
+                index: r.u8(2),
+                increment: r.i8(3),
+            },
+            2 => CapabilityRun::LayerClear { state },
+            3 => CapabilityRun::LayerState {
+                state,
+                layer: r.u8(2),
+                layer_state: field(ty, layer::State::from_u8(r.u8(3)))?,
+            },
+            4 => CapabilityRun::LayerRotate {
+                state,
+                direction: field(ty, layer::Direction::from_u8(r.u8(2)))?,
+            },
+            5 => CapabilityRun::HidProtocol {
+                state,
+                mode: field(ty, hid::Protocol::from_u8(r.u8(2)))?,
+            },
+            6 => CapabilityRun::HidKeyboard {
+                state,
+                id: keyboard_u16(ty, r.u16(2))?,
+            },
+            7 => CapabilityRun::HidKeyboardState {
+                state,
+                id: keyboard_u16(ty, r.u16(2))?,
+                key_state: field(ty, hid::State::from_u8(r.u8(4)))?,
+            },
+            8 => CapabilityRun::HidConsumerControl {
+                state,
+                id: field(ty, kll_hid::ConsumerControl::from_u16(r.u16(2)))?,
+            },
+            9 => CapabilityRun::HidSystemControl {
+                state,
+                id: field(ty, kll_hid::SystemControl::from_u8(r.u8(2)))?,
+            },
+            10 => CapabilityRun::McuFlashMode { state },
+            11 => CapabilityRun::HidLed {
+                state,
+                id: led_indicator(ty, r.u8(2))?,
+            },
+            12 => CapabilityRun::PixelAnimationControl {
+                state,
+                mode: field(ty, pixel::AnimationControl::from_u8(r.u8(2)))?,
+            },
+            13 => CapabilityRun::PixelAnimationIndex {
+                state,
+                index: r.u16(2),
+            },
+            14 => CapabilityRun::PixelFadeControl {
+                state,
+                profile: r.u8(2),
+                command: field(ty, pixel::FadeCommand::from_u8(r.u8(3)))?,
+                arg: r.u8(4),
+            },
+            15 => CapabilityRun::PixelFadeLayer {
+                state,
+                layer: r.u8(2),
+            },
+            16 => CapabilityRun::PixelFadeSet {
+                state,
+                profile: r.u8(2),
+                config: r.u8(3),
+                period: r.u8(4),
+            },
+            17 => CapabilityRun::PixelGammaControl {
+                state,
+                mode: field(ty, pixel::GammaControl::from_u8(r.u8(2)))?,
+            },
+            18 => CapabilityRun::PixelLedControl {
+                state,
+                mode: field(ty, pixel::LedControl::from_u8(r.u8(2)))?,
+                amount: r.u8(3),
+            },
+            19 => CapabilityRun::PixelTest {
+                state,
+                test: field(ty, pixel::PixelTest::from_u8(r.u8(2)))?,
+                index: r.u16(4),
+            },
+            20 => CapabilityRun::Analog { state },
+            21 => CapabilityRun::HidioOpenUrl {
+                state,
+                index: r.u16(2),
+            },
+            22 => CapabilityRun::HidioUnicodeString {
+                state,
+                index: r.u16(2),
+            },
+            23 => CapabilityRun::HidioUnicodeState {
+                state,
+                unicode: field(ty, char::from_u32(r.u32(4)))?,
+            },
+            24 => CapabilityRun::HoldTap {
+                state,
+                tap: r.u16(2),
+                hold: r.u16(4),
+            },
+            25 => CapabilityRun::TapDance {
+                state,
+                taps: r.u16(2),
+                holds: r.u16(4),
+            },
+            26 => CapabilityRun::HidKeyboardLatch {
+                state,
+                id: keyboard_u16(ty, r.u16(2))?,
+            },
+            27 => CapabilityRun::MacroRecord {
+                state,
+                slot: r.u8(2),
+            },
+            28 => CapabilityRun::MacroPlay {
+                state,
+                slot: r.u8(2),
+                delay: r.u16(4),
+            },
+            29 => CapabilityRun::MouseButton {
+                state,
+                button: button(ty, r.u8(2), 8)?,
+            },
+            30 => CapabilityRun::MouseMove {
+                state,
+                x: r.i16(2),
+                y: r.i16(4),
+            },
+            31 => CapabilityRun::MouseWheel {
+                state,
+                amount: r.i8(2),
+            },
+            32 => CapabilityRun::MouseHorzWheel {
+                state,
+                amount: r.i8(2),
+            },
+            33 => CapabilityRun::GamepadButton {
+                state,
+                button: button(ty, r.u8(2), 16)?,
+            },
+            34 => CapabilityRun::GamepadAxis {
+                state,
+                axis: field(ty, gamepad::Axis::from_u8(r.u8(2)))?,
+                direction: field(ty, gamepad::Direction::from_u8(r.u8(3)))?,
+                value: r.u8(4),
+            },
+            35 => CapabilityRun::HidKeyboardAutoShift {
+                state,
+                id: keyboard_u16(ty, r.u16(2))?,
+                shift: match r.u8(4) {
+                    0 => false,
+                    1 => true,
+                    _ => {
+                        return Err(DecodeError::InvalidField(ty));
+                    }
+                },
+            },
+            36 => CapabilityRun::CapsWord { state },
+            37 => CapabilityRun::HidKeyboardModMorph {
+                state,
+                id: keyboard_u16(ty, r.u16(2))?,
+                mods: r.u8(4),
+                morph: keyboard_u16(ty, r.u16(6))?,
+            },
+            _ => {
+                return Err(DecodeError::InvalidType(ty));
+            }
+        };
+        Ok(run)
+    }
+
+    /// Encode the CapabilityRun
+    /// Unlike TriggerEvents and Capabilities, CapabilityRuns are not used in layouts so they do
+    /// not use the in-memory layout. The discriminant is followed by the CapabilityEvent, the
+    /// fields from byte 2 and the TriggerEvent of Passthrough events from byte 8.
+    pub fn to_bytes(&self) -> [u8; CAPABILITY_RUN_SIZE] {
+        let mut w = Writer::<CAPABILITY_RUN_SIZE>::default();
+        match self.state() {
+            CapabilityEvent::None => w.u8(1, 0),
+            CapabilityEvent::Initial => w.u8(1, 1),
+            CapabilityEvent::Last => w.u8(1, 2),
+            CapabilityEvent::Any => w.u8(1, 3),
+            CapabilityEvent::Passthrough(event) => {
+                w.u8(1, 4);
+                w.bytes[8..].copy_from_slice(&event.to_bytes());
+            }
+        }
+
+        match *self {
+            CapabilityRun::NoOp { .. } => w.u8(0, 0),
+            CapabilityRun::Rotate {
+                index, increment, ..
+            } => {
+                w.u8(0, 1);
+                w.u8(2, index);
+                w.u8(3, increment as u8);
+            }
+            CapabilityRun::LayerClear { .. } => w.u8(0, 2),
+            CapabilityRun::LayerState {
+                layer, layer_state, ..
+            } => {
+                w.u8(0, 3);
+                w.u8(2, layer);
+                w.u8(3, layer_state as u8);
+            }
+            CapabilityRun::LayerRotate { direction, .. } => {
+                w.u8(0, 4);
+                w.u8(2, direction as u8);
+            }
+            CapabilityRun::HidProtocol { mode, .. } => {
+                w.u8(0, 5);
+                w.u8(2, mode as u8);
+            }
+            CapabilityRun::HidKeyboard { id, .. } => {
+                w.u8(0, 6);
+                w.u16(2, id.into());
+            }
+            CapabilityRun::HidKeyboardState { id, key_state, .. } => {
+                w.u8(0, 7);
+                w.u16(2, id.into());
+                w.u8(4, key_state as u8);
+            }
+            CapabilityRun::HidConsumerControl { id, .. } => {
+                w.u8(0, 8);
+                w.u16(2, id as u16);
+            }
+            CapabilityRun::HidSystemControl { id, .. } => {
+                w.u8(0, 9);
+                w.u8(2, id as u8);
+            }
+            CapabilityRun::McuFlashMode { .. } => w.u8(0, 10),
+            CapabilityRun::HidLed { id, .. } => {
+                w.u8(0, 11);
+                w.u8(2, id.into());
+            }
+            CapabilityRun::PixelAnimationControl { mode, .. } => {
+                w.u8(0, 12);
+                w.u8(2, mode as u8);
+            }
+            CapabilityRun::PixelAnimationIndex { index, .. } => {
+                w.u8(0, 13);
+                w.u16(2, index);
+            }
+            CapabilityRun::PixelFadeControl {
+                profile,
+                command,
+                arg,
+                ..
+            } => {
+                w.u8(0, 14);
+                w.u8(2, profile);
+                w.u8(3, command as u8);
+                w.u8(4, arg);
+            }
+            CapabilityRun::PixelFadeLayer { layer, .. } => {
+                w.u8(0, 15);
+                w.u8(2, layer);
+            }
+            CapabilityRun::PixelFadeSet {
+                profile,
+                config,
+                period,
+                ..
+            } => {
+                w.u8(0, 16);
+                w.u8(2, profile);
+                w.u8(3, config);
+                w.u8(4, period);
+            }
+            CapabilityRun::PixelGammaControl { mode, .. } => {
+                w.u8(0, 17);
+                w.u8(2, mode as u8);
+            }
+            CapabilityRun::PixelLedControl { mode, amount, .. } => {
+                w.u8(0, 18);
+                w.u8(2, mode as u8);
+                w.u8(3, amount);
+            }
+            CapabilityRun::PixelTest { test, index, .. } => {
+                w.u8(0, 19);
+                w.u8(2, test as u8);
+                w.u16(4, index);
+            }
+            CapabilityRun::Analog { .. } => w.u8(0, 20),
+            CapabilityRun::HidioOpenUrl { index, .. } => {
+                w.u8(0, 21);
+                w.u16(2, index);
+            }
+            CapabilityRun::HidioUnicodeString { index, .. } => {
+                w.u8(0, 22);
+                w.u16(2, index);
+            }
+            CapabilityRun::HidioUnicodeState { unicode, .. } => {
+                w.u8(0, 23);
+                w.u32(4, unicode as u32);
+            }
+            CapabilityRun::HoldTap { tap, hold, .. } => {
+                w.u8(0, 24);
+                w.u16(2, tap);
+                w.u16(4, hold);
+            }
+            CapabilityRun::TapDance { taps, holds, .. } => {
+                w.u8(0, 25);
+                w.u16(2, taps);
+                w.u16(4, holds);
+            }
+            CapabilityRun::HidKeyboardLatch { id, .. } => {
+                w.u8(0, 26);
+                w.u16(2, id.into());
+            }
+            CapabilityRun::MacroRecord { slot, .. } => {
+                w.u8(0, 27);
+                w.u8(2, slot);
+            }
+            CapabilityRun::MacroPlay { slot, delay, .. } => {
+                w.u8(0, 28);
+                w.u8(2, slot);
+                w.u16(4, delay);
+            }
+            CapabilityRun::MouseButton { button, .. } => {
+                w.u8(0, 29);
+                w.u8(2, button);
+            }
+            CapabilityRun::MouseMove { x, y, .. } => {
+                w.u8(0, 30);
+                w.u16(2, x as u16);
+                w.u16(4, y as u16);
+            }
+            CapabilityRun::MouseWheel { amount, .. } => {
+                w.u8(0, 31);
+                w.u8(2, amount as u8);
+            }
+            CapabilityRun::MouseHorzWheel { amount, .. } => {
+                w.u8(0, 32);
+                w.u8(2, amount as u8);
+            }
+            CapabilityRun::GamepadButton { button, .. } => {
+                w.u8(0, 33);
+                w.u8(2, button);
+            }
+            CapabilityRun::GamepadAxis {
+                axis,
+                direction,
+                value,
+                ..
+            } => {
+                w.u8(0, 34);
+                w.u8(2, axis as u8);
+                w.u8(3, direction as u8);
+                w.u8(4, value);
+            }
+            CapabilityRun::HidKeyboardAutoShift { id, shift, .. } => {
+                w.u8(0, 35);
+                w.u16(2, id.into());
+                w.u8(4, shift as u8);
+            }
+            CapabilityRun::CapsWord { .. } => w.u8(0, 36),
+            CapabilityRun::HidKeyboardModMorph {
+                id, mods, morph, ..
+            } => {
+                w.u8(0, 37);
+                w.u16(2, id.into());
+                w.u8(4, mods);
+                w.u16(6, morph.into());
+            }
+        }
+        w.bytes
+    }
+}
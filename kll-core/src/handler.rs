@@ -0,0 +1,255 @@
+// Copyright 2021-2023 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! CapabilityRun dispatching
+//!
+//! Firmware implements CapabilityHandler with a hook for each family of capabilities it
+//! supports (e.g. HID queues, LED drivers, HID-IO) and uses CapabilityHandler::dispatch to send
+//! the results of LayerState::finalize_triggers to each backend.
+//!
+//! ```rust,ignore
+//! struct Handler<'a> {
+//!     kbd_producer: Producer<'a, kiibohd_usb::KeyState, KBD_SIZE>,
+//!     ctrl_producer: Producer<'a, kiibohd_usb::CtrlState, CTRL_SIZE>,
+//! }
+//!
+//! impl CapabilityHandler for Handler<'_> {
+//!     fn hid(&mut self, run: CapabilityRun) -> Result<(), HandlerError> {
+//!         match run {
+//!             CapabilityRun::HidKeyboard { .. } | CapabilityRun::HidKeyboardState { .. } => {
+//!                 kiibohd_usb::enqueue_keyboard_event(run, &mut self.kbd_producer)
+//!                     .map_err(|_| HandlerError::Failed)
+//!             }
+//!             CapabilityRun::HidConsumerControl { .. } | CapabilityRun::HidSystemControl { .. } => {
+//!                 kiibohd_usb::enqueue_ctrl_event(run, &mut self.ctrl_producer)
+//!                     .map_err(|_| HandlerError::Failed)
+//!             }
+//!             _ => Err(HandlerError::Unhandled),
+//!         }
+//!     }
+//! }
+//!
+//! let results = layer_state.finalize_triggers::<LSIZE>();
+//! handler.dispatch(&results);
+//! ```
+
+#[cfg(feature = "defmt")]
+use defmt::warn;
+#[cfg(not(feature = "defmt"))]
+use log::warn;
+
+use crate::CapabilityRun;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HandlerError {
+    /// No backend for the capability
+    Unhandled,
+    /// The backend could not process the capability (e.g. queue is full)
+    Failed,
+}
+
+/// Capability families, each is sent to a different CapabilityHandler hook
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CapabilityFamily {
+    /// USB HID keyboard, consumer/system control, LEDs, mouse and gamepad
+    Hid,
+    /// Layers, latches, auto-shift, caps word, mod-morph and hold-tap/tap-dance
+    /// Applied internally by layout::LayerState, these are not returned by finalize_triggers
+    Layer,
+    /// LED animations and control
+    Pixel,
+    /// HID-IO (e.g. unicode strings)
+    Hidio,
+    /// Microcontroller control (e.g. flash mode)
+    Mcu,
+    /// Everything else (e.g. rotations, analog and dynamic macros)
+    Other,
+}
+
+impl CapabilityRun {
+    /// Family of the capability (see CapabilityHandler)
+    pub fn family(&self) -> CapabilityFamily {
+        match self {
+            CapabilityRun::HidProtocol { .. }
+            | CapabilityRun::HidKeyboard { .. }
+            | CapabilityRun::HidKeyboardState { .. }
+            | CapabilityRun::HidConsumerControl { .. }
+            | CapabilityRun::HidSystemControl { .. }
+            | CapabilityRun::HidLed { .. }
+            | CapabilityRun::MouseButton { .. }
+            | CapabilityRun::MouseMove { .. }
+            | CapabilityRun::MouseWheel { .. }
+            | CapabilityRun::MouseHorzWheel { .. }
+            | CapabilityRun::GamepadButton { .. }
+            | CapabilityRun::GamepadAxis { .. } => CapabilityFamily::Hid,
+            CapabilityRun::LayerClear { .. }
+            | CapabilityRun::LayerState { .. }
+            | CapabilityRun::LayerRotate { .. }
+            | CapabilityRun::HidKeyboardLatch { .. }
+            | CapabilityRun::HidKeyboardAutoShift { .. }
+            | CapabilityRun::CapsWord { .. }
+            | CapabilityRun::HidKeyboardModMorph { .. }
+            | CapabilityRun::HoldTap { .. }
+            | CapabilityRun::TapDance { .. } => CapabilityFamily::Layer,
+            CapabilityRun::PixelAnimationControl { .. }
+            | CapabilityRun::PixelAnimationIndex { .. }
+            | CapabilityRun::PixelFadeControl { .. }
+            | CapabilityRun::PixelFadeLayer { .. }
+            | CapabilityRun::PixelFadeSet { .. }
+            | CapabilityRun::PixelGammaControl { .. }
+            | CapabilityRun::PixelLedControl { .. }
+            | CapabilityRun::PixelTest { .. } => CapabilityFamily::Pixel,
+            CapabilityRun::HidioOpenUrl { .. }
+            | CapabilityRun::HidioUnicodeString { .. }
+            | CapabilityRun::HidioUnicodeState { .. } => CapabilityFamily::Hidio,
+            CapabilityRun::McuFlashMode { .. } => CapabilityFamily::Mcu,
+            CapabilityRun::NoOp { .. }
+            | CapabilityRun::Rotate { .. }
+            | CapabilityRun::Analog { .. }
+            | CapabilityRun::MacroRecord { .. }
+            | CapabilityRun::MacroPlay { .. } => CapabilityFamily::Other,
+        }
+    }
+}
+
+/// Routes CapabilityRuns to firmware backends
+/// Each hook handles a CapabilityFamily, hooks that are not implemented leave the capability
+/// unhandled.
+pub trait CapabilityHandler {
+    /// See CapabilityFamily::Hid
+    fn hid(&mut self, _run: CapabilityRun) -> Result<(), HandlerError> {
+        Err(HandlerError::Unhandled)
+    }
+
+    /// See CapabilityFamily::Layer
+    fn layer(&mut self, _run: CapabilityRun) -> Result<(), HandlerError> {
+        Err(HandlerError::Unhandled)
+    }
+
+    /// See CapabilityFamily::Pixel
+    fn pixel(&mut self, _run: CapabilityRun) -> Result<(), HandlerError> {
+        Err(HandlerError::Unhandled)
+    }
+
+    /// See CapabilityFamily::Hidio
+    fn hidio(&mut self, _run: CapabilityRun) -> Result<(), HandlerError> {
+        Err(HandlerError::Unhandled)
+    }
+
+    /// See CapabilityFamily::Mcu
+    fn mcu(&mut self, _run: CapabilityRun) -> Result<(), HandlerError> {
+        Err(HandlerError::Unhandled)
+    }
+
+    /// See CapabilityFamily::Other
+    fn other(&mut self, _run: CapabilityRun) -> Result<(), HandlerError> {
+        Err(HandlerError::Unhandled)
+    }
+
+    /// Called for each CapabilityRun that could not be handled
+    fn unhandled(&mut self, run: CapabilityRun, error: HandlerError) {
+        warn!("{:?} CapabilityRun: {:?}", error, run);
+    }
+
+    /// Sends each CapabilityRun (e.g. from LayerState::finalize_triggers) to the hook of its
+    /// family. NoOps are skipped.
+    ///
+    /// Returns the number of CapabilityRuns that could not be handled
+    fn dispatch(&mut self, runs: &[CapabilityRun]) -> usize {
+        let mut unhandled = 0;
+        for run in runs {
+            let ret = match run.family() {
+                CapabilityFamily::Hid => self.hid(*run),
+                CapabilityFamily::Layer => self.layer(*run),
+                CapabilityFamily::Pixel => self.pixel(*run),
+                CapabilityFamily::Hidio => self.hidio(*run),
+                CapabilityFamily::Mcu => self.mcu(*run),
+                CapabilityFamily::Other => {
+                    if let CapabilityRun::NoOp { .. } = run {
+                        continue;
+                    }
+                    self.other(*run)
+                }
+            };
+            if let Err(error) = ret {
+                unhandled += 1;
+                self.unhandled(*run, error);
+            }
+        }
+        unhandled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::Vec;
+    use kll_hid::Keyboard;
+
+    use crate::handler::{CapabilityFamily, CapabilityHandler, HandlerError};
+    use crate::{pixel, CapabilityEvent, CapabilityRun};
+
+    #[derive(Default)]
+    struct TestHandler {
+        hid: Vec<CapabilityRun, 8>,
+        pixel: Vec<CapabilityRun, 8>,
+        unhandled: Vec<(CapabilityRun, HandlerError), 8>,
+        full: bool,
+    }
+
+    impl CapabilityHandler for TestHandler {
+        fn hid(&mut self, run: CapabilityRun) -> Result<(), HandlerError> {
+            if self.full {
+                return Err(HandlerError::Failed);
+            }
+            self.hid.push(run).unwrap();
+            Ok(())
+        }
+
+        fn pixel(&mut self, run: CapabilityRun) -> Result<(), HandlerError> {
+            self.pixel.push(run).unwrap();
+            Ok(())
+        }
+
+        fn unhandled(&mut self, run: CapabilityRun, error: HandlerError) {
+            self.unhandled.push((run, error)).unwrap();
+        }
+    }
+
+    #[test]
+    fn dispatch() {
+        let key = CapabilityRun::HidKeyboard {
+            state: CapabilityEvent::Initial,
+            id: Keyboard::A,
+        };
+        let leds = CapabilityRun::PixelLedControl {
+            state: CapabilityEvent::Initial,
+            mode: pixel::LedControl::ToggleLeds,
+            amount: 0,
+        };
+        let flash = CapabilityRun::McuFlashMode {
+            state: CapabilityEvent::Initial,
+        };
+        let noop = CapabilityRun::NoOp {
+            state: CapabilityEvent::None,
+        };
+        assert_eq!(key.family(), CapabilityFamily::Hid);
+        assert_eq!(flash.family(), CapabilityFamily::Mcu);
+
+        let mut handler = TestHandler::default();
+        assert_eq!(handler.dispatch(&[key, noop, leds, flash]), 1);
+        assert_eq!(handler.hid, [key]);
+        assert_eq!(handler.pixel, [leds]);
+        assert_eq!(handler.unhandled, [(flash, HandlerError::Unhandled)]);
+
+        // Backend failures are reported too
+        handler.full = true;
+        assert_eq!(handler.dispatch(&[key]), 1);
+        assert_eq!(handler.unhandled[1], (key, HandlerError::Failed));
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright 2021-2023 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// ----- Crates -----
+
+use super::*;
+
+// ----- Structs -----
+
+/// Previous analog event values, used by the crossing analog comparisons
+/// MAX_ANALOG_INPUTS is the number of analog inputs (away from rest) with a tracked previous value.
+pub(super) struct AnalogState<const MAX_ANALOG_INPUTS: usize> {
+    /// (TriggerEvent type, index) -> value of the previous event
+    /// Inputs at rest (0) are not stored.
+    pub previous: FnvIndexMap<(u8, u16), i16, MAX_ANALOG_INPUTS>,
+}
+
+impl<const MAX_ANALOG_INPUTS: usize> AnalogState<MAX_ANALOG_INPUTS> {
+    pub fn new() -> Self {
+        Self {
+            previous: FnvIndexMap::new(),
+        }
+    }
+
+    /// Value of the previous event of the same analog input (0 if at rest)
+    pub fn previous(&self, event: TriggerEvent) -> i16 {
+        self.previous
+            .get(&(u8::from(event), event.index()))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Stores the value of an analog event
+    pub fn update(&mut self, event: TriggerEvent) -> Result<(), ProcessError> {
+        let Some(val) = event.analog_val() else {
+            return Ok(());
+        };
+
+        let key = (u8::from(event), event.index());
+        if val == 0 {
+            self.previous.remove(&key);
+        } else if self.previous.insert(key, val).is_err() {
+            return Err(ProcessError::FailedAnalogInsert);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,38 @@
+// Copyright 2021-2023 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// ----- Crates -----
+
+use super::*;
+
+// ----- Structs -----
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(super) struct AutoShiftKey {
+    pub id: kll_hid::Keyboard,
+    /// The key was held past the threshold and has been pressed with LeftShift
+    pub shifted: bool,
+}
+
+/// Held auto-shift keys and taps waiting for their release for LayerState
+/// MAX_AUTO_SHIFT_KEYS is the number of simultaneously held auto-shift keys.
+pub(super) struct AutoShiftState<const MAX_AUTO_SHIFT_KEYS: usize> {
+    /// Auto-shift keys that are currently held
+    pub keys: Vec<AutoShiftKey, MAX_AUTO_SHIFT_KEYS>,
+    /// Tapped (unshifted) keys, released on the next processing loop
+    pub tap_release: Vec<kll_hid::Keyboard, MAX_AUTO_SHIFT_KEYS>,
+}
+
+impl<const MAX_AUTO_SHIFT_KEYS: usize> AutoShiftState<MAX_AUTO_SHIFT_KEYS> {
+    pub fn new() -> Self {
+        Self {
+            keys: Vec::new(),
+            tap_release: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright 2021-2023 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// ----- Crates -----
+
+use super::*;
+
+// ----- Enums -----
+
+/// Which guides of an event are evaluated
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(super) enum GuideFilter {
+    All,
+    /// Only guides currently at a combo or partway through a sequence
+    Combos,
+    /// Only guides not currently at a combo or partway through a sequence
+    Others,
+}
+
+/// Result of evaluating an event against a single combo
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(super) enum ComboMatch {
+    /// Event is not part of the combo (or the combo was abandoned)
+    None,
+    /// Event matched the combo, which still needs more conditions
+    Pending,
+    /// Event completed the combo
+    Complete,
+}
+
+// ----- Structs -----
+
+/// Evaluation state of a partially matched combo
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(super) struct ComboEval {
+    /// Number of conditions remaining in the combo
+    pub remaining: u8,
+    /// Bitmask of already matched conditions (first 32 conditions of the combo)
+    pub matched: u32,
+    /// Time instance of the first matched condition
+    pub time_instance: u32,
+}
+
+impl ComboEval {
+    pub fn new(len: usize, time_instance: u32) -> Self {
+        Self {
+            remaining: len as u8,
+            matched: 0,
+            time_instance,
+        }
+    }
+
+    /// Bit used to track the condition at the given position in the combo
+    /// Conditions past the first 32 are not tracked.
+    pub fn bit(pos: usize) -> u32 {
+        1u32.checked_shl(pos as u32).unwrap_or(0)
+    }
+
+    /// Determine if the combo has waited longer than the timeout
+    pub fn expired(&self, timeout: u32, time_instance: u32) -> bool {
+        time_instance.wrapping_sub(self.time_instance) >= timeout
+    }
+}
+
+/// Combo configuration and suppressed switch events for LayerState
+/// MAX_SUPPRESSED_EVENTS is the number of switch presses that can be suppressed by pending
+/// combos. If full, presses are no longer suppressed.
+pub(super) struct ComboState<const MAX_SUPPRESSED_EVENTS: usize> {
+    /// Number of processing loops a partially matched combo waits for the remaining conditions
+    pub timeout: u32,
+    /// Suppress the guides of a switch while a combo it is part of is pending
+    pub suppress: bool,
+    /// Switch presses suppressed by a pending combo
+    /// (event, combo trigger:result guide)
+    pub suppressed: Vec<(TriggerEvent, (u16, u16)), MAX_SUPPRESSED_EVENTS>,
+    /// Switches with a press consumed by a completed combo (or leader sequence)
+    /// The remaining events of the switch are only evaluated by combo and sequence guides.
+    pub consumed: Vec<u16, MAX_SUPPRESSED_EVENTS>,
+    /// Releases of suppressed switches, processed on the next processing loop so they are
+    /// not sent in the same loop as the replayed press.
+    /// (event, time instance)
+    pub delayed: Vec<(TriggerEvent, u32), MAX_SUPPRESSED_EVENTS>,
+}
+
+impl<const MAX_SUPPRESSED_EVENTS: usize> ComboState<MAX_SUPPRESSED_EVENTS> {
+    pub fn new() -> Self {
+        Self {
+            timeout: 0,
+            suppress: false,
+            suppressed: Vec::new(),
+            consumed: Vec::new(),
+            delayed: Vec::new(),
+        }
+    }
+
+    /// Determine if the given switch index has a suppressed press
+    pub fn is_suppressed(&self, index: u16) -> bool {
+        self.suppressed
+            .iter()
+            .any(|(event, _guide)| event.index() == index)
+    }
+
+    /// Marks a switch as consumed by a completed combo (or leader sequence)
+    pub fn consume(&mut self, index: u16) -> Result<(), ProcessError> {
+        if !self.consumed.contains(&index) && self.consumed.push(index).is_err() {
+            return Err(ProcessError::FailedSuppressedEventPush);
+        }
+        Ok(())
+    }
+
+    /// Removes the suppressed presses of a combo, returning them
+    pub fn take_suppressed(
+        &mut self,
+        guide: (u16, u16),
+    ) -> Vec<TriggerEvent, MAX_SUPPRESSED_EVENTS> {
+        let mut events = Vec::new();
+        for (event, _guide) in self.suppressed.iter().filter(|(_, g)| *g == guide) {
+            // Same capacity, cannot fail
+            events.push(*event).ok();
+        }
+        self.suppressed.retain(|(_, g)| *g != guide);
+        events
+    }
+}
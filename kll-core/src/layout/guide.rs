@@ -0,0 +1,132 @@
+// Copyright 2021-2023 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// ----- Crates -----
+
+use super::*;
+use core::marker::PhantomData;
+
+// ----- Traits -----
+
+/// Element of a TriggerGuide or ResultGuide combo
+pub trait GuideElement: Sized {
+    /// Decodes an element from (unaligned) guide bytes
+    fn decode(bytes: &[u8]) -> Result<Self, DecodeError>;
+}
+
+impl GuideElement for TriggerCondition {
+    fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        TriggerCondition::try_from_bytes(bytes)
+    }
+}
+
+impl GuideElement for Capability {
+    fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        Capability::try_from_bytes(bytes)
+    }
+}
+
+// ----- Structs -----
+
+/// A single combo of a TriggerGuide (TriggerConditions) or ResultGuide (Capabilities)
+///
+/// Guides are u8 arrays without any alignment guarantees, so each element is decoded (copied)
+/// on access rather than cast in place.
+/// Every element has been decoded successfully once when the GuideCombo was created.
+#[derive(Copy, Clone)]
+pub struct GuideCombo<'a, T> {
+    bytes: &'a [u8],
+    element: PhantomData<T>,
+}
+
+impl<'a, T: GuideElement> GuideCombo<'a, T> {
+    /// Creates a combo of count elements from the start of bytes
+    /// Returns None if bytes is too short or any of the elements is invalid.
+    pub(super) fn new(bytes: &'a [u8], count: usize) -> Option<Self> {
+        let bytes = bytes.get(..count.checked_mul(core::mem::size_of::<T>())?)?;
+        let combo = Self {
+            bytes,
+            element: PhantomData,
+        };
+        if let Some(Err(err)) = combo.chunks().map(T::decode).find(|elem| elem.is_err()) {
+            error!("Invalid guide combo: {:?}", err);
+            return None;
+        }
+        Some(combo)
+    }
+
+    fn chunks(&self) -> core::slice::ChunksExact<'a, u8> {
+        self.bytes.chunks_exact(core::mem::size_of::<T>())
+    }
+
+    /// Number of elements in the combo
+    pub fn len(&self) -> usize {
+        self.bytes.len() / core::mem::size_of::<T>()
+    }
+
+    /// Determine if the combo has no elements
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Decodes the element at the given position
+    pub fn get(&self, pos: usize) -> Option<T> {
+        self.chunks()
+            .nth(pos)
+            .and_then(|chunk| T::decode(chunk).ok())
+    }
+
+    /// Iterates over the decoded elements of the combo
+    pub fn iter(&self) -> GuideComboIter<'a, T> {
+        GuideComboIter {
+            chunks: self.chunks(),
+            element: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: GuideElement> IntoIterator for GuideCombo<'a, T> {
+    type Item = T;
+    type IntoIter = GuideComboIter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T: GuideElement + Copy + PartialEq> PartialEq<[T]> for GuideCombo<'_, T> {
+    fn eq(&self, other: &[T]) -> bool {
+        self.iter().eq(other.iter().copied())
+    }
+}
+
+impl<T: GuideElement + core::fmt::Debug> core::fmt::Debug for GuideCombo<'_, T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// Iterator over the decoded elements of a GuideCombo
+pub struct GuideComboIter<'a, T> {
+    chunks: core::slice::ChunksExact<'a, u8>,
+    element: PhantomData<T>,
+}
+
+impl<T: GuideElement> Iterator for GuideComboIter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Elements were validated by GuideCombo::new
+        self.chunks.find_map(|chunk| T::decode(chunk).ok())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+}
+
+impl<T: GuideElement> ExactSizeIterator for GuideComboIter<'_, T> {}
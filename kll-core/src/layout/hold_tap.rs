@@ -0,0 +1,202 @@
+// Copyright 2021-2023 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// ----- Crates -----
+
+use super::*;
+use heapless::Deque;
+
+// ----- Constants -----
+
+/// Default number of scanning loops before a pending hold-tap may resolve as a hold
+/// Also used as the tap-dance timeout between taps.
+pub const DEFAULT_HOLD_TAP_THRESHOLD: u32 = 200;
+
+// ----- Enums -----
+
+/// Determines how a pending hold-tap is resolved when it is interrupted by other switches
+/// A hold-tap is always resolved as a tap if it is released while still pending.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HoldTapFlavor {
+    /// Hold once the threshold is reached or another switch is pressed
+    #[default]
+    HoldPreferred,
+    /// Hold once the threshold is reached or another switch is pressed and released
+    Balanced,
+    /// Hold only once the threshold is reached
+    TapPreferred,
+    /// Hold only when another switch is pressed and released
+    /// The threshold is ignored.
+    PermissiveHold,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(super) enum HoldTapDecision {
+    /// Neither tap nor hold has been decided yet, other switch events are deferred
+    Pending,
+    /// Tap-dance has been released, waiting for another tap, other switch events are deferred
+    Released,
+    /// Hold result has been started, waiting on release
+    /// (ResultGuide offset, combo index)
+    Hold { result: u16, combo: u8 },
+}
+
+// ----- Structs -----
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(super) struct HoldTap {
+    /// Triggering event (used for passthrough and switch index)
+    pub event: TriggerEvent,
+    /// Time instance of the last press (or release while waiting for another tap)
+    pub time_instance: u32,
+    /// ResultGuide offset of the tap result(s)
+    pub tap: u16,
+    /// ResultGuide offset of the hold result(s)
+    pub hold: u16,
+    /// Number of taps, selects the combo of the tap and hold results
+    /// Always 1 for hold-taps
+    pub count: u8,
+    /// Tap-dance, another tap may follow the release
+    pub dance: bool,
+    pub decision: HoldTapDecision,
+    /// First switch pressed while the hold-tap was pending
+    pub interrupt: Option<u16>,
+    /// The interrupting switch was also released while the hold-tap was pending
+    pub nested: bool,
+}
+
+impl HoldTap {
+    /// Neither tap nor hold has been decided yet
+    pub fn undecided(&self) -> bool {
+        matches!(
+            self.decision,
+            HoldTapDecision::Pending | HoldTapDecision::Released
+        )
+    }
+
+    /// Determine whether a pending hold-tap should now be resolved as a hold
+    pub fn resolve_hold(&self, flavor: HoldTapFlavor, threshold: u32, time_instance: u32) -> bool {
+        let elapsed = time_instance.wrapping_sub(self.time_instance) >= threshold;
+        match flavor {
+            HoldTapFlavor::HoldPreferred => elapsed || self.interrupt.is_some(),
+            HoldTapFlavor::Balanced => elapsed || self.nested,
+            HoldTapFlavor::TapPreferred => elapsed,
+            HoldTapFlavor::PermissiveHold => self.nested,
+        }
+    }
+
+    /// Determine whether a released tap-dance should now be resolved as a tap
+    /// Happens when the timeout is reached or another switch is pressed.
+    pub fn resolve_tap(&self, threshold: u32, time_instance: u32) -> bool {
+        time_instance.wrapping_sub(self.time_instance) >= threshold || self.interrupt.is_some()
+    }
+}
+
+/// Hold-tap bookkeeping for LayerState
+/// MAX_HOLD_TAPS is the number of simultaneously active hold-tap and tap-dance switches.
+/// MAX_DEFERRED_EVENTS is the number of switch events that can be deferred while a hold-tap is
+/// pending. If the queue is full, events are processed immediately instead.
+pub(super) struct HoldTapState<const MAX_HOLD_TAPS: usize, const MAX_DEFERRED_EVENTS: usize> {
+    pub flavor: HoldTapFlavor,
+    /// Number of scanning loops before a pending hold-tap may resolve as a hold
+    pub threshold: u32,
+    /// Active hold-taps and tap-dances (pending, released or held)
+    pub active: Vec<HoldTap, MAX_HOLD_TAPS>,
+    /// Switch events deferred while a hold-tap is pending
+    /// Replayed in order once no hold-taps are pending.
+    pub deferred: Deque<TriggerEvent, MAX_DEFERRED_EVENTS>,
+    /// Switch indices with a deferred press that has not been replayed with a release yet
+    pub deferred_index: Vec<u16, MAX_DEFERRED_EVENTS>,
+    /// Tap results waiting to be released on the next processing loop
+    /// (tap result guide, combo index, event)
+    pub tap_release: Vec<(u16, u8, TriggerEvent), MAX_HOLD_TAPS>,
+}
+
+impl<const MAX_HOLD_TAPS: usize, const MAX_DEFERRED_EVENTS: usize>
+    HoldTapState<MAX_HOLD_TAPS, MAX_DEFERRED_EVENTS>
+{
+    pub fn new() -> Self {
+        Self {
+            flavor: HoldTapFlavor::default(),
+            threshold: DEFAULT_HOLD_TAP_THRESHOLD,
+            active: Vec::new(),
+            deferred: Deque::new(),
+            deferred_index: Vec::new(),
+            tap_release: Vec::new(),
+        }
+    }
+
+    /// Determine if any hold-taps are still waiting on a decision
+    pub fn pending(&self) -> bool {
+        self.active.iter().any(|ht| ht.undecided())
+    }
+
+    /// Defers switch events that arrive while a hold-tap is pending (or while older deferred
+    /// events are still being replayed) and records them as interruptions.
+    /// Events from the hold-tap switches themselves are never deferred.
+    /// Returns true if the event was deferred, an error if it must be processed immediately
+    /// because the deferred queue is full.
+    pub fn defer(&mut self, event: TriggerEvent) -> Result<bool, ProcessError> {
+        let (state, index) = if let TriggerEvent::Switch { state, index, .. } = event {
+            (state, index)
+        } else {
+            return Ok(false);
+        };
+
+        if !self.pending() && self.deferred.is_empty() {
+            // All deferred events have been replayed, nothing left to keep in order
+            self.deferred_index.clear();
+            return Ok(false);
+        }
+        if self.active.iter().any(|ht| ht.event.index() == index) {
+            return Ok(false);
+        }
+
+        // Only presses start deferring a switch, follow-up events of switches pressed before
+        // the hold-tap are processed normally
+        let tracked = self.deferred_index.contains(&index);
+        match state {
+            trigger::Phro::Press => {}
+            trigger::Phro::Release if tracked => {}
+            // Hold events of a deferred switch would only fill up the queue, drop them
+            trigger::Phro::Hold if tracked => {
+                return Ok(true);
+            }
+            _ => {
+                return Ok(false);
+            }
+        }
+
+        if !tracked && self.deferred_index.push(index).is_err() {
+            return Err(ProcessError::FailedDeferredEventPush);
+        }
+        if self.deferred.push_back(event).is_err() {
+            if !tracked {
+                self.deferred_index.pop();
+            }
+            return Err(ProcessError::FailedDeferredEventPush);
+        }
+
+        // Record the interruption
+        for ht in self.active.iter_mut().filter(|ht| ht.undecided()) {
+            match state {
+                trigger::Phro::Press if ht.interrupt.is_none() => {
+                    ht.interrupt = Some(index);
+                }
+                trigger::Phro::Release if ht.interrupt == Some(index) => {
+                    ht.nested = true;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(true)
+    }
+}
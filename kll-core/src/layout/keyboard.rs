@@ -0,0 +1,110 @@
+// Copyright 2021-2023 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// ----- Crates -----
+
+use super::*;
+
+// ----- Enumerations -----
+
+/// How a key press affects caps word
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(super) enum CapsWordKey {
+    /// Shifted while caps word is enabled
+    Shifted,
+    /// Part of the word, but not shifted
+    Continue,
+    /// Does not affect caps word
+    Ignored,
+    /// Ends the word (disables caps word)
+    Break,
+}
+
+impl CapsWordKey {
+    pub fn new(id: kll_hid::Keyboard) -> Self {
+        use kll_hid::Keyboard;
+
+        let range =
+            |first: Keyboard, last: Keyboard| (first as u8..=last as u8).contains(&(id as u8));
+        if range(Keyboard::A, Keyboard::Z) || id == Keyboard::Minus {
+            CapsWordKey::Shifted
+        } else if range(Keyboard::_1, Keyboard::_0)
+            || id == Keyboard::Backspace
+            || id == Keyboard::Delete
+        {
+            CapsWordKey::Continue
+        } else if hid::modifier_bit(id) != 0 {
+            CapsWordKey::Ignored
+        } else {
+            CapsWordKey::Break
+        }
+    }
+}
+
+// ----- Structs -----
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(super) struct ModMorphKey {
+    pub id: kll_hid::Keyboard,
+    /// Key that was sent (id or the morph)
+    pub sent: kll_hid::Keyboard,
+    /// Held modifiers that were released while the morph is pressed
+    pub masked: u8,
+}
+
+/// Held modifiers, caps word and mod-morph bookkeeping for LayerState
+/// MAX_MOD_MORPH_KEYS is the number of simultaneously pressed mod-morph keys.
+pub(super) struct KeyboardState<const MAX_MOD_MORPH_KEYS: usize> {
+    /// Modifiers currently held by HidKeyboard results (see hid::modifier_bit)
+    pub modifiers: u8,
+    /// Caps word is enabled
+    pub caps_word: bool,
+    /// LeftShift is pressed by caps word
+    pub caps_shift: bool,
+    /// Pressed mod-morph keys
+    pub morphs: Vec<ModMorphKey, MAX_MOD_MORPH_KEYS>,
+}
+
+impl<const MAX_MOD_MORPH_KEYS: usize> KeyboardState<MAX_MOD_MORPH_KEYS> {
+    pub fn new() -> Self {
+        Self {
+            modifiers: 0,
+            caps_word: false,
+            caps_shift: false,
+            morphs: Vec::new(),
+        }
+    }
+
+    /// Updates the held modifiers using the HidKeyboard results of a processing loop
+    pub fn track_modifiers(&mut self, results: &[CapabilityRun]) {
+        for run in results {
+            let (pressed, id) = match *run {
+                CapabilityRun::HidKeyboard { state, id } => match state.activation() {
+                    CapabilityEvent::Initial => (true, id),
+                    CapabilityEvent::Last => (false, id),
+                    _ => continue,
+                },
+                CapabilityRun::HidKeyboardState {
+                    state,
+                    id,
+                    key_state,
+                } if state.activation() == CapabilityEvent::Initial => {
+                    (key_state == hid::State::Active, id)
+                }
+                _ => continue,
+            };
+            let bit = hid::modifier_bit(id);
+            if pressed {
+                self.modifiers |= bit;
+            } else {
+                self.modifiers &= !bit;
+            }
+        }
+    }
+}
@@ -0,0 +1,50 @@
+// Copyright 2021-2023 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// ----- Crates -----
+
+use super::*;
+
+// ----- Structs -----
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(super) struct LatchedKey {
+    pub id: kll_hid::Keyboard,
+    /// Time instance the key was latched
+    pub time_instance: u32,
+    /// The key has been applied to a switch press and is released on the next processing loop
+    pub used: bool,
+}
+
+/// Latched (one-shot) layer and key bookkeeping for LayerState
+/// MAX_LATCHED_KEYS is the number of simultaneously latched (one-shot) keys.
+pub(super) struct LatchState<const MAX_LATCHED_KEYS: usize> {
+    /// Number of processing loops before an unused latch is released
+    /// None disables the timeout.
+    pub timeout: Option<u32>,
+    /// A switch press was looked up during the current processing loop
+    pub pressed: bool,
+    /// Latched keys
+    pub keys: Vec<LatchedKey, MAX_LATCHED_KEYS>,
+}
+
+impl<const MAX_LATCHED_KEYS: usize> LatchState<MAX_LATCHED_KEYS> {
+    pub fn new() -> Self {
+        Self {
+            timeout: None,
+            pressed: false,
+            keys: Vec::new(),
+        }
+    }
+
+    /// Determine if a latch set at the given time instance has expired
+    pub fn expired(&self, latch_time: u32, time_instance: u32) -> bool {
+        self.timeout
+            .is_some_and(|timeout| time_instance.wrapping_sub(latch_time) >= timeout)
+    }
+}
@@ -0,0 +1,64 @@
+// Copyright 2021-2023 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+// ----- Crates -----
+
+use super::*;
+
+// ----- Constants -----
+
+/// Maximum number of layer TriggerEvents generated by layer capabilities in a single
+/// processing loop
+/// If full, further events are dropped (the layer state is still updated).
+pub(super) const MAX_LAYER_EVENTS: usize = 8;
+
+/// Default maximum number of conditional layer rules (see LayerState::add_layer_rule)
+#[deprecated(note = "set the MAX_LAYER_RULES const generic of LayerState instead")]
+pub const MAX_LAYER_RULES: usize = 4;
+
+// ----- Structs -----
+
+/// Conditional layer rule
+/// Enables layer with layer_state while every condition is met.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(super) struct LayerRule<'a> {
+    pub conditions: &'a [TriggerCondition],
+    pub layer: u8,
+    pub layer_state: layer::State,
+    /// The rule currently has the layer enabled
+    pub active: bool,
+}
+
+/// Layer capability bookkeeping for LayerState
+/// MAX_LAYER_RULES is the number of conditional layer rules (see LayerState::add_layer_rule).
+pub(super) struct LayerControlState<'a, const MAX_LAYER_RULES: usize> {
+    /// Layer TriggerEvents generated by layer capabilities
+    /// Processed at the start of the next processing loop.
+    pub events: Vec<TriggerEvent, MAX_LAYER_EVENTS>,
+    /// Layer currently selected by LayerRotate (0 if none)
+    pub rotate: u8,
+    /// Conditional layer rules
+    pub rules: Vec<LayerRule<'a>, MAX_LAYER_RULES>,
+}
+
+impl<'a, const MAX_LAYER_RULES: usize> LayerControlState<'a, MAX_LAYER_RULES> {
+    pub fn new() -> Self {
+        Self {
+            events: Vec::new(),
+            rotate: 0,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Queues a layer TriggerEvent for the next processing loop
+    pub fn push(&mut self, event: TriggerEvent) -> Result<(), ProcessError> {
+        self.events
+            .push(event)
+            .map_err(|_| ProcessError::FailedLayerEventPush)
+    }
+}
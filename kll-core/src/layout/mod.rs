@@ -5,13 +5,35 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+mod analog;
+mod auto_shift;
+mod combo;
+mod guide;
+mod hold_tap;
+mod keyboard;
+mod latch;
+mod layer_control;
+mod snapshot;
 mod test;
 
 // ----- Crates -----
 
 use super::*;
+use analog::AnalogState;
+use auto_shift::{AutoShiftKey, AutoShiftState};
+use combo::{ComboEval, ComboMatch, ComboState, GuideFilter};
 use core::cmp::Ordering;
 use heapless::{FnvIndexMap, Vec};
+use hold_tap::{HoldTap, HoldTapDecision, HoldTapState};
+use keyboard::{CapsWordKey, KeyboardState, ModMorphKey};
+use latch::{LatchState, LatchedKey};
+use layer_control::{LayerControlState, LayerRule};
+
+pub use guide::{GuideCombo, GuideComboIter, GuideElement};
+pub use hold_tap::{HoldTapFlavor, DEFAULT_HOLD_TAP_THRESHOLD};
+#[allow(deprecated)]
+pub use layer_control::MAX_LAYER_RULES;
+pub use snapshot::{SnapshotError, SNAPSHOT_VERSION};
 
 // ----- Enums -----
 
@@ -40,8 +62,11 @@ enum StateStatus {
     },
     /// Capability + u8 offset position + last TriggerEvent
     ResultPos {
-        /// Time instance when the offset is updated (on combo increment).
-        /// This value is set on increment and is set for the first combo eval
+        /// Time instance when the combo at offset starts.
+        /// Set by the trigger for the first combo, every following combo starts on the
+        /// processing loop after the previous combo has finished.
+        /// Capabilities are scheduled relative to this time instance using their
+        /// loop_condition_index.
         time_instance: u32,
         /// TriggerEvent that initiated the Result Capability
         event: TriggerEvent,
@@ -54,17 +79,74 @@ enum StateStatus {
     Done,
 }
 
+/// Capacity overflows and invalid layout data
+/// Whatever did not fit (or is invalid) is dropped (see each variant) and the error is counted,
+/// see LayerState::overflow_count.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum ProcessError {
     /// MAX_OFF_STATE_LOOKUP is too small
+    /// The trigger guide is not evaluated any further for the event.
     FailedOffStatePush,
     /// STATE_SIZE is too small
+    /// The trigger guide is not advanced.
     FailedLookupStateInsert,
     /// MAX_ACTIVE_TRIGGERS is too small
+    /// The partially matched combo is dropped.
     FailedTriggerComboEvalStateInsert,
+    /// MAX_LAYER_RULES is too small
+    /// The rule is not added.
+    FailedLayerRulePush,
+    /// LSIZE of finalize_triggers is too small
+    /// The result is dropped.
+    FailedResultPush,
+    /// LSIZE of process_trigger is too small
+    /// Trigger guides past LSIZE are not evaluated.
+    FailedGuideLookupPush,
+    /// MAX_LAYERS is smaller than the number of layers in the layout
+    /// Layers past MAX_LAYERS cannot be enabled.
+    FailedLayerInit,
+    /// MAX_ACTIVE_LAYERS is too small
+    /// The layer state is not changed.
+    FailedLayerStackPush,
+    /// MAX_LAYER_STACK_CACHE is too small
+    /// The following events of the trigger are looked up using the current layer stack.
+    FailedLayerStackCacheInsert,
+    /// MAX_LAYER_EVENTS is too small
+    /// The layer TriggerEvent is dropped, the layer state is still changed.
+    FailedLayerEventPush,
+    /// MAX_LATCHED_KEYS is too small
+    /// The key is not latched.
+    FailedLatchPush,
+    /// MAX_HOLD_TAPS is too small
+    /// The hold-tap (or tap-dance) is ignored, taps are released immediately.
+    FailedHoldTapPush,
+    /// MAX_DEFERRED_EVENTS is too small
+    /// The event is processed immediately instead of after the pending hold-tap.
+    FailedDeferredEventPush,
+    /// MAX_SUPPRESSED_EVENTS is too small
+    /// The switch event is not suppressed (or consumed) by the combo and is processed normally.
+    FailedSuppressedEventPush,
+    /// MAX_LAYER_LOOKUP_SIZE of process_off_state_lookups is too small
+    /// Off state events past the limit are dropped.
+    FailedOffStateEventPush,
+    /// MAX_ANALOG_INPUTS is too small
+    /// Crossing conditions of the analog input compare against the input at rest (0).
+    FailedAnalogInsert,
+    /// MAX_AUTO_SHIFT_KEYS is too small
+    /// The key is sent without auto-shift.
+    FailedAutoShiftPush,
+    /// MAX_MOD_MORPH_KEYS is too small
+    /// The key is sent without mod-morph.
+    FailedModMorphPush,
+    /// Layer 0 or a layer past the number of layers was given
+    /// The layer state is not changed.
+    InvalidLayer,
 }
 
+/// Number of ProcessError variants
+const PROCESS_ERRORS: usize = ProcessError::InvalidLayer as usize + 1;
+
 // ----- Structs -----
 
 pub struct TriggerEventIterator<const MAX_EVENTS: usize> {
@@ -85,6 +167,50 @@ impl<const MAX_EVENTS: usize> Iterator for TriggerEventIterator<MAX_EVENTS> {
     }
 }
 
+/// Number of times each ProcessError has occurred
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+struct OverflowCounters([u32; PROCESS_ERRORS]);
+
+impl OverflowCounters {
+    /// Counts a capacity overflow (or invalid layout data)
+    fn record(&mut self, error: ProcessError) -> ProcessError {
+        warn!("Process error: {:?}", error);
+        let count = &mut self.0[error as usize];
+        *count = count.saturating_add(1);
+        error
+    }
+
+    /// Adds the counts of other (e.g. overflows counted while decoding a snapshot)
+    fn add(&mut self, other: &OverflowCounters) {
+        for (count, other) in self.0.iter_mut().zip(other.0) {
+            *count = count.saturating_add(other);
+        }
+    }
+}
+
+/// Adds a result, dropping it if LSIZE is too small
+fn push_result<const LSIZE: usize>(
+    results: &mut heapless::Vec<CapabilityRun, LSIZE>,
+    run: CapabilityRun,
+    overflows: &mut OverflowCounters,
+) {
+    if results.push(run).is_err() {
+        overflows.record(ProcessError::FailedResultPush);
+    }
+}
+
+/// Trigger sequence configuration
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+struct SequenceConfig {
+    /// Number of processing loops an in-progress sequence waits for the next combo
+    timeout: Option<u32>,
+    /// Cancel in-progress sequences on a switch press that does not advance them
+    cancel: bool,
+    /// Suppress the other guides of a switch press that advances an in-progress sequence
+    leader: bool,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 struct Layer {
@@ -93,6 +219,19 @@ struct Layer {
     last_time_instance: u32,
 }
 
+/// KLL layout processing state
+/// The trailing capacities are optional, set them to tune the RAM used by LayerState:
+/// - MAX_HOLD_TAPS: simultaneously active hold-tap and tap-dance switches
+/// - MAX_DEFERRED_EVENTS: switch events deferred while a hold-tap is pending
+/// - MAX_SUPPRESSED_EVENTS: switch presses suppressed by pending combos
+/// - MAX_LATCHED_KEYS: simultaneously latched (one-shot) keys
+/// - MAX_LAYER_RULES: conditional layer rules (see add_layer_rule)
+/// - MAX_ANALOG_INPUTS: analog inputs (away from rest) with a tracked previous value, must be a
+///   power of two (raise it for keyboards with analog switches)
+/// - MAX_AUTO_SHIFT_KEYS: simultaneously held auto-shift keys
+/// - MAX_MOD_MORPH_KEYS: simultaneously pressed mod-morph keys
+///
+/// See ProcessError for what happens when a capacity is too small.
 pub struct LayerState<
     'a,
     const LAYOUT_SIZE: usize,
@@ -102,6 +241,14 @@ pub struct LayerState<
     const MAX_ACTIVE_TRIGGERS: usize,
     const MAX_LAYER_STACK_CACHE: usize,
     const MAX_OFF_STATE_LOOKUP: usize,
+    const MAX_HOLD_TAPS: usize = 4,
+    const MAX_DEFERRED_EVENTS: usize = 8,
+    const MAX_SUPPRESSED_EVENTS: usize = 4,
+    const MAX_LATCHED_KEYS: usize = 4,
+    const MAX_LAYER_RULES: usize = 4,
+    const MAX_ANALOG_INPUTS: usize = 4,
+    const MAX_AUTO_SHIFT_KEYS: usize = 4,
+    const MAX_MOD_MORPH_KEYS: usize = 4,
 > {
     /// KLL guide lookup
     layer_lookup: LayerLookup<'a, LAYOUT_SIZE>,
@@ -123,9 +270,9 @@ pub struct LayerState<
     /// (ttype, index) -> (layer index, Layer {layer state, time instance})
     layer_stack_cache: FnvIndexMap<(u8, u16), (u8, Layer), MAX_LAYER_STACK_CACHE>,
     /// Maintains the combo state when evaluating a list of TriggerEvents
-    /// This hash table is cleared when finalizing a scan loop
-    /// Maps (trigger_guide, result_guide) -> (combo evaluations remaining)
-    trigger_combo_eval_state: FnvIndexMap<(u16, u16), u8, MAX_ACTIVE_TRIGGERS>,
+    /// Entries are removed when finalizing a scan loop once the combo timeout has been reached
+    /// Maps (trigger_guide, result_guide) -> (combo evaluation state)
+    trigger_combo_eval_state: FnvIndexMap<(u16, u16), ComboEval, MAX_ACTIVE_TRIGGERS>,
     /// time_instance is a dumb counter used to keep track of processing instances.
     /// Yes, the counter will rollover but generally this shouldn't matter
     /// Used to calculate produced Layer TriggerEvents, is generally set once per processing loop
@@ -135,6 +282,24 @@ pub struct LayerState<
     /// Cleared each processing loop.
     /// ((trigger_guide, result_guide), ttype, index)
     off_state_lookups: Vec<((u16, u16), u8, u16), MAX_OFF_STATE_LOOKUP>,
+    /// Hold-tap configuration, active hold-taps and deferred switch events
+    hold_tap: HoldTapState<MAX_HOLD_TAPS, MAX_DEFERRED_EVENTS>,
+    /// Combo configuration and suppressed switch events
+    combo: ComboState<MAX_SUPPRESSED_EVENTS>,
+    /// Trigger sequence configuration
+    sequence: SequenceConfig,
+    /// Layer TriggerEvents generated by layer capabilities and the LayerRotate position
+    layer_control: LayerControlState<'a, MAX_LAYER_RULES>,
+    /// Latched (one-shot) keys and latch configuration
+    latch: LatchState<MAX_LATCHED_KEYS>,
+    /// Held auto-shift keys
+    auto_shift: AutoShiftState<MAX_AUTO_SHIFT_KEYS>,
+    /// Held modifiers, caps word and mod-morph keys
+    keyboard: KeyboardState<MAX_MOD_MORPH_KEYS>,
+    /// Previous analog event values
+    analog: AnalogState<MAX_ANALOG_INPUTS>,
+    /// Number of times each capacity limit has been hit
+    overflows: OverflowCounters,
 }
 
 impl<
@@ -146,6 +311,14 @@ impl<
         const MAX_ACTIVE_TRIGGERS: usize,
         const MAX_LAYER_STACK_CACHE: usize,
         const MAX_OFF_STATE_LOOKUP: usize,
+        const MAX_HOLD_TAPS: usize,
+        const MAX_DEFERRED_EVENTS: usize,
+        const MAX_SUPPRESSED_EVENTS: usize,
+        const MAX_LATCHED_KEYS: usize,
+        const MAX_LAYER_RULES: usize,
+        const MAX_ANALOG_INPUTS: usize,
+        const MAX_AUTO_SHIFT_KEYS: usize,
+        const MAX_MOD_MORPH_KEYS: usize,
     >
     LayerState<
         'a,
@@ -156,6 +329,14 @@ impl<
         MAX_ACTIVE_TRIGGERS,
         MAX_LAYER_STACK_CACHE,
         MAX_OFF_STATE_LOOKUP,
+        MAX_HOLD_TAPS,
+        MAX_DEFERRED_EVENTS,
+        MAX_SUPPRESSED_EVENTS,
+        MAX_LATCHED_KEYS,
+        MAX_LAYER_RULES,
+        MAX_ANALOG_INPUTS,
+        MAX_AUTO_SHIFT_KEYS,
+        MAX_MOD_MORPH_KEYS,
     >
 {
     pub fn new(layer_lookup: LayerLookup<'a, LAYOUT_SIZE>, time_instance: u32) -> Self {
@@ -165,23 +346,30 @@ impl<
         //   (trigger guide, result guide) -> Type(offset)
         let lookup_state = FnvIndexMap::<(u16, u16), StateStatus, STATE_SIZE>::new();
 
+        // Layers that do not fit are never enabled
+        let mut overflows = OverflowCounters::default();
+        let max_layers = layer_lookup.max_layers() as usize;
+        if max_layers > MAX_LAYERS {
+            overflows.record(ProcessError::FailedLayerInit);
+        }
         let mut layer = Vec::new();
         layer
             .resize(
-                layer_lookup.max_layers() as usize,
+                max_layers.min(MAX_LAYERS),
                 Layer {
                     state: layer::State::Off,
                     last_time_instance: 0u32,
                 },
             )
-            .unwrap();
+            .ok();
 
         // Layer 0 is always enabled by default
         layer[0].state = layer::State::Shift;
 
         let layer_stack = Vec::new();
         let layer_stack_cache = FnvIndexMap::<(u8, u16), (u8, Layer), MAX_LAYER_STACK_CACHE>::new();
-        let trigger_combo_eval_state = FnvIndexMap::<(u16, u16), u8, MAX_ACTIVE_TRIGGERS>::new();
+        let trigger_combo_eval_state =
+            FnvIndexMap::<(u16, u16), ComboEval, MAX_ACTIVE_TRIGGERS>::new();
         let off_state_lookups = Vec::new();
 
         Self {
@@ -193,6 +381,19 @@ impl<
             trigger_combo_eval_state,
             time_instance,
             off_state_lookups,
+            hold_tap: HoldTapState::new(),
+            combo: ComboState::new(),
+            sequence: SequenceConfig {
+                timeout: None,
+                cancel: false,
+                leader: false,
+            },
+            layer_control: LayerControlState::new(),
+            latch: LatchState::new(),
+            auto_shift: AutoShiftState::new(),
+            keyboard: KeyboardState::new(),
+            analog: AnalogState::new(),
+            overflows,
         }
     }
 
@@ -206,24 +407,200 @@ impl<
         self.time_instance = val;
     }
 
+    /// Current time instance used for produced Layer TriggerEvents
+    pub fn time_instance(&self) -> u32 {
+        self.time_instance
+    }
+
+    /// USB HID modifiers currently held by the results of finalize_triggers
+    /// LeftControl is bit 0 through RightGUI at bit 7 (see hid::modifier_bit).
+    pub fn held_modifiers(&self) -> u8 {
+        self.keyboard.modifiers
+    }
+
+    /// Determine if caps word is enabled
+    pub fn caps_word(&self) -> bool {
+        self.keyboard.caps_word
+    }
+
+    /// Number of times the capacity limit of the given ProcessError has been hit
+    pub fn overflow_count(&self, error: ProcessError) -> u32 {
+        self.overflows.0[error as usize]
+    }
+
+    /// Resets all of the overflow counters
+    pub fn clear_overflow_counts(&mut self) {
+        self.overflows = OverflowCounters::default();
+    }
+
+    /// Set how pending hold-taps are resolved when interrupted by other switches
+    pub fn set_hold_tap_flavor(&mut self, flavor: HoldTapFlavor) {
+        self.hold_tap.flavor = flavor;
+    }
+
+    /// Set the number of scanning loops before a pending hold-tap may resolve as a hold
+    pub fn set_hold_tap_threshold(&mut self, threshold: u32) {
+        self.hold_tap.threshold = threshold;
+    }
+
+    /// Set the number of processing loops a partially matched combo waits for the remaining
+    /// conditions (e.g. the time between the first and last press of a chord)
+    /// 0 (default) requires every condition of a combo in the same processing loop.
+    pub fn set_combo_timeout(&mut self, timeout: u32) {
+        self.combo.timeout = timeout;
+    }
+
+    /// When enabled, the guides of a switch are not evaluated while a combo it is part of is
+    /// pending. If the combo is abandoned (timeout or release) the press is processed instead.
+    pub fn set_combo_suppression(&mut self, suppress: bool) {
+        self.combo.suppress = suppress;
+    }
+
+    /// Set the number of processing loops an in-progress sequence (e.g. S1, S2) waits for the
+    /// next combo before it is abandoned. None (default) waits indefinitely.
+    pub fn set_sequence_timeout(&mut self, timeout: Option<u32>) {
+        self.sequence.timeout = timeout;
+    }
+
+    /// When enabled, a switch press that does not advance an in-progress sequence cancels the
+    /// sequence. Disabled by default, in-progress sequences are only abandoned by the timeout.
+    pub fn set_sequence_cancel(&mut self, cancel: bool) {
+        self.sequence.cancel = cancel;
+    }
+
+    /// Leader key mode
+    /// When enabled, a switch press that advances an in-progress sequence does not evaluate
+    /// any of the other guides of the switch (e.g. typing the keys of a leader sequence).
+    pub fn set_sequence_leader(&mut self, leader: bool) {
+        self.sequence.leader = leader;
+    }
+
+    /// Set the number of processing loops before an unused latched (one-shot) layer or key is
+    /// released. None (default) keeps latches until the next switch press.
+    pub fn set_latch_timeout(&mut self, timeout: Option<u32>) {
+        self.latch.timeout = timeout;
+    }
+
     /// Set layer state
     /// If layer already has the state enable, disable and vice versa
-    pub fn set_layer(&mut self, layer: u8, state: layer::State) -> TriggerEvent {
-        // Make sure the layer is valid
-        assert!(
-            layer as usize >= self.layer.len(),
-            "Invalid layer set: {} {:?}",
+    /// Layer changes caused by conditional layer rules are processed on the next processing loop.
+    /// Returns ProcessError::InvalidLayer for layer 0, layers outside of the layout or
+    /// layer::State::Off.
+    pub fn set_layer(
+        &mut self,
+        layer: u8,
+        state: layer::State,
+    ) -> Result<TriggerEvent, ProcessError> {
+        let event = self.update_layer(layer, state)?;
+        self.apply_layer_rules();
+        Ok(event)
+    }
+
+    /// Sets the layer state, queueing the layer TriggerEvent for the next processing loop
+    fn queue_layer(&mut self, layer: u8, state: layer::State) {
+        if let Ok(event) = self.update_layer(layer, state) {
+            self.queue_layer_event(event);
+            self.apply_layer_rules();
+        }
+    }
+
+    /// Queues a layer TriggerEvent for the next processing loop
+    fn queue_layer_event(&mut self, event: TriggerEvent) {
+        if let Err(err) = self.layer_control.push(event) {
+            self.overflows.record(err);
+        }
+    }
+
+    /// Adds a conditional layer rule (e.g. tri-layer)
+    /// While every condition is met, layer is enabled using layer_state. Once any condition is no
+    /// longer met the layer state is removed again.
+    /// Only TriggerCondition::Layer conditions are supported, compared against the current layer
+    /// state (e.g. LayerState::ShiftOn for layer 1 and layer 2 to enable layer 3).
+    /// Rules are evaluated in order whenever a layer changes.
+    /// Returns ProcessError::InvalidLayer for layer 0, layers outside of the layout or
+    /// layer::State::Off.
+    pub fn add_layer_rule(
+        &mut self,
+        conditions: &'a [TriggerCondition],
+        layer: u8,
+        layer_state: layer::State,
+    ) -> Result<(), ProcessError> {
+        if layer == 0 || layer as usize >= self.layer.len() || layer_state == layer::State::Off {
+            warn!("Invalid layer rule: {} {:?}", layer, layer_state);
+            return Err(self.overflows.record(ProcessError::InvalidLayer));
+        }
+
+        let rule = LayerRule {
+            conditions,
             layer,
-            state,
-        );
+            layer_state,
+            active: false,
+        };
+        if self.layer_control.rules.push(rule).is_err() {
+            return Err(self.overflows.record(ProcessError::FailedLayerRulePush));
+        }
+        self.apply_layer_rules();
+        Ok(())
+    }
+
+    /// Enables (or disables) the layers of conditional layer rules that changed
+    /// The layer TriggerEvents are processed on the next processing loop.
+    fn apply_layer_rules(&mut self) {
+        for pos in 0..self.layer_control.rules.len() {
+            let rule = self.layer_control.rules[pos];
+            let met = self.layer_rule_met(rule);
+            if met == rule.active {
+                continue;
+            }
+            trace!("Layer rule {}: {}", pos, met);
+            self.layer_control.rules[pos].active = met;
+
+            // The layer state may have also been changed by something else
+            if self.layer[rule.layer as usize]
+                .state
+                .is_set(rule.layer_state)
+                != met
+                && let Ok(event) = self.update_layer(rule.layer, rule.layer_state)
+            {
+                self.queue_layer_event(event);
+            }
+        }
+    }
 
-        // Cannot set layer 0
-        assert!(layer != 0, "Cannot change layer 0 state");
+    /// Determine if every condition of a conditional layer rule is met
+    fn layer_rule_met(&self, rule: LayerRule) -> bool {
+        rule.conditions.iter().all(|cond| {
+            if let TriggerCondition::Layer { state, layer, .. } = cond {
+                self.layer_condition_event(*layer, *state)
+                    .is_some_and(|event| {
+                        matches!(
+                            cond.evaluate(event, self.layer_lookup.loop_condition_lookup),
+                            Vote::Positive
+                        )
+                    })
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Updates the layer state and builds the layer TriggerEvent
+    fn update_layer(
+        &mut self,
+        layer: u8,
+        state: layer::State,
+    ) -> Result<TriggerEvent, ProcessError> {
+        // Make sure the layer is valid, layer 0 cannot be changed
+        // Off is not a state that can be toggled
+        if layer == 0 || layer as usize >= self.layer.len() || state == layer::State::Off {
+            warn!("Invalid layer set: {} {:?}", layer, state);
+            return Err(self.overflows.record(ProcessError::InvalidLayer));
+        }
 
         // Check to see if the layer is already in the stack, add it if not
         let layer_in_stack = self.is_layer_in_stack(layer);
-        if !layer_in_stack {
-            self.layer_stack.push(layer).unwrap();
+        if !layer_in_stack && self.layer_stack.push(layer).is_err() {
+            return Err(self.overflows.record(ProcessError::FailedLayerStackPush));
         }
 
         // Store previous state for event generation
@@ -265,39 +642,464 @@ impl<
         }
 
         // Build layer trigger event
-        let state = trigger::LayerState::from_layer(cur_state, activity_state);
+        // Fully deactivated layers use the removed state (e.g. ShiftDeactivate)
+        let event_state = if cur_state == layer::State::Off {
+            prev_state
+        } else {
+            cur_state
+        };
+        // event_state is never Off, a layer state was either added or removed
+        let state = trigger::LayerState::from_layer(event_state, activity_state)
+            .ok_or(ProcessError::InvalidLayer)?;
 
         // Send signal for layer state change
-        TriggerEvent::Layer {
+        Ok(TriggerEvent::Layer {
             state,
             layer,
             last_state: 0u32, // Initial events always start at 0
+        })
+    }
+
+    /// Layer currently selected by LayerRotate (0 if no layer has been rotated to)
+    pub fn rotate_layer(&self) -> u8 {
+        self.layer_control.rotate
+    }
+
+    /// Applies a layer capability (LayerState, LayerClear or LayerRotate)
+    /// Shift layers are enabled on press and disabled on release, latch and lock layers are
+    /// toggled on press.
+    /// The generated layer TriggerEvents are processed on the next processing loop.
+    fn layer_run(&mut self, run: CapabilityRun) {
+        let activation = run.state().activation();
+        match run {
+            CapabilityRun::LayerState {
+                layer, layer_state, ..
+            } => {
+                if layer == 0 || layer as usize >= self.layer.len() {
+                    warn!("Invalid layer capability: {:?}", run);
+                    self.overflows.record(ProcessError::InvalidLayer);
+                    return;
+                }
+
+                let set = self.layer[layer as usize].state.is_set(layer_state);
+                let toggle = match activation {
+                    CapabilityEvent::Initial => layer_state != layer::State::Shift || !set,
+                    CapabilityEvent::Last => layer_state == layer::State::Shift && set,
+                    _ => false,
+                };
+                if toggle {
+                    self.queue_layer(layer, layer_state);
+                }
+            }
+            CapabilityRun::LayerClear { .. } if activation == CapabilityEvent::Initial => {
+                self.clear_layers();
+            }
+            CapabilityRun::LayerRotate { direction, .. }
+                if activation == CapabilityEvent::Initial =>
+            {
+                self.rotate(direction);
+            }
+            _ => {}
+        }
+    }
+
+    /// Disables every layer except layer 0
+    /// The layer TriggerEvents are processed on the next processing loop, latched keys are
+    /// released at the end of this processing loop.
+    fn clear_layers(&mut self) {
+        for layer in 1..self.layer.len() {
+            for state in [layer::State::Shift, layer::State::Latch, layer::State::Lock] {
+                if self.layer[layer].state.is_set(state)
+                    && let Ok(event) = self.update_layer(layer as u8, state)
+                {
+                    self.queue_layer_event(event);
+                }
+            }
+        }
+        self.layer_stack_cache.clear();
+        self.layer_control.rotate = 0;
+        for rule in self.layer_control.rules.iter_mut() {
+            rule.active = false;
+        }
+        for key in self.latch.keys.iter_mut() {
+            key.used = true;
+        }
+    }
+
+    /// Locks the next (or previous) layer, unlocking the layer previously selected by LayerRotate
+    /// Rotating past the last layer (or before layer 1) selects layer 0.
+    fn rotate(&mut self, direction: layer::Direction) {
+        let layers = self.layer.len();
+        let current = self.layer_control.rotate as usize;
+        let next = match direction {
+            layer::Direction::Next => (current + 1) % layers,
+            layer::Direction::Previous => (current + layers - 1) % layers,
+        };
+        trace!("Rotate layer: {} -> {}", current, next);
+
+        if current != 0 && self.layer[current].state.is_set(layer::State::Lock) {
+            self.queue_layer(current as u8, layer::State::Lock);
+        }
+        if next != 0 && !self.layer[next].state.is_set(layer::State::Lock) {
+            self.queue_layer(next as u8, layer::State::Lock);
+        }
+        self.layer_control.rotate = next as u8;
+    }
+
+    /// Applies a HidKeyboardLatch capability
+    /// Latches (presses) the key on activation, or releases it if it is already latched.
+    fn latch_run<const LSIZE: usize>(
+        &mut self,
+        run: CapabilityRun,
+        results: &mut heapless::Vec<CapabilityRun, LSIZE>,
+    ) {
+        let (state, id) = if let CapabilityRun::HidKeyboardLatch { state, id } = run {
+            (state, id)
+        } else {
+            return;
+        };
+        if state.activation() != CapabilityEvent::Initial {
+            return;
+        }
+
+        let key_state = match self.latch.keys.iter().position(|key| key.id == id) {
+            Some(pos) if !self.latch.keys[pos].used => {
+                self.latch.keys.remove(pos);
+                CapabilityEvent::Last
+            }
+            Some(pos) => {
+                // Latched again before the release, keep the key pressed
+                self.latch.keys[pos].used = false;
+                self.latch.keys[pos].time_instance = self.time_instance;
+                return;
+            }
+            None => {
+                let key = LatchedKey {
+                    id,
+                    time_instance: self.time_instance,
+                    used: false,
+                };
+                if self.latch.keys.push(key).is_err() {
+                    self.overflows.record(ProcessError::FailedLatchPush);
+                    return;
+                }
+                CapabilityEvent::Initial
+            }
+        };
+        let run = CapabilityRun::HidKeyboard {
+            state: key_state,
+            id,
+        };
+        push_result(results, run, &mut self.overflows);
+    }
+
+    /// Applies a HidKeyboardAutoShift capability
+    /// The key is sent with LeftShift once held past the threshold, releasing it before then
+    /// taps the unshifted key instead.
+    fn auto_shift_run<const LSIZE: usize>(
+        &mut self,
+        run: CapabilityRun,
+        results: &mut heapless::Vec<CapabilityRun, LSIZE>,
+    ) {
+        let (state, id, shift) =
+            if let CapabilityRun::HidKeyboardAutoShift { state, id, shift } = run {
+                (state, id, shift)
+            } else {
+                return;
+            };
+        let pos = self.auto_shift.keys.iter().position(|key| key.id == id);
+
+        let keys: &[(kll_hid::Keyboard, CapabilityEvent)] = match (state.activation(), pos) {
+            (CapabilityEvent::Initial, None) => {
+                let key = AutoShiftKey { id, shifted: false };
+                if self.auto_shift.keys.push(key).is_err() {
+                    self.overflows.record(ProcessError::FailedAutoShiftPush);
+                    &[(id, CapabilityEvent::Initial)]
+                } else {
+                    &[]
+                }
+            }
+            (CapabilityEvent::Any, Some(pos)) if shift && !self.auto_shift.keys[pos].shifted => {
+                self.auto_shift.keys[pos].shifted = true;
+                &[
+                    (kll_hid::Keyboard::LeftShift, CapabilityEvent::Initial),
+                    (id, CapabilityEvent::Initial),
+                ]
+            }
+            (CapabilityEvent::Last, Some(pos)) => {
+                if self.auto_shift.keys.remove(pos).shifted {
+                    &[
+                        (id, CapabilityEvent::Last),
+                        (kll_hid::Keyboard::LeftShift, CapabilityEvent::Last),
+                    ]
+                } else if self.auto_shift.tap_release.push(id).is_err() {
+                    // Release immediately rather than leaving the tap stuck
+                    self.overflows.record(ProcessError::FailedAutoShiftPush);
+                    &[(id, CapabilityEvent::Initial), (id, CapabilityEvent::Last)]
+                } else {
+                    &[(id, CapabilityEvent::Initial)]
+                }
+            }
+            // Pressed without auto-shift (see FailedAutoShiftPush)
+            (CapabilityEvent::Last, None) => &[(id, CapabilityEvent::Last)],
+            _ => &[],
+        };
+        for (id, state) in keys {
+            let run = CapabilityRun::HidKeyboard {
+                state: *state,
+                id: *id,
+            };
+            push_result(results, run, &mut self.overflows);
+        }
+    }
+
+    /// Presses or releases the LeftShift applied by caps word
+    /// LeftShift is not released while it is also held by a key (see held_modifiers).
+    fn caps_word_shift<const LSIZE: usize>(
+        &mut self,
+        shift: bool,
+        results: &mut heapless::Vec<CapabilityRun, LSIZE>,
+    ) {
+        if shift == self.keyboard.caps_shift {
+            return;
+        }
+        self.keyboard.caps_shift = shift;
+        if !shift && self.keyboard.modifiers & hid::modifier_bit(kll_hid::Keyboard::LeftShift) != 0
+        {
+            trace!("Caps word shift released, LeftShift is still held");
+            return;
+        }
+        let run = CapabilityRun::HidKeyboard {
+            state: if shift {
+                CapabilityEvent::Initial
+            } else {
+                CapabilityEvent::Last
+            },
+            id: kll_hid::Keyboard::LeftShift,
+        };
+        push_result(results, run, &mut self.overflows);
+    }
+
+    /// Applies a CapsWord capability (toggles caps word)
+    fn caps_word_run<const LSIZE: usize>(
+        &mut self,
+        run: CapabilityRun,
+        results: &mut heapless::Vec<CapabilityRun, LSIZE>,
+    ) {
+        if run.state().activation() != CapabilityEvent::Initial {
+            return;
+        }
+        self.keyboard.caps_word = !self.keyboard.caps_word;
+        trace!("Caps word: {}", self.keyboard.caps_word);
+        if !self.keyboard.caps_word {
+            self.caps_word_shift(false, results);
+        }
+    }
+
+    /// Applies caps word to the keys pressed during this processing loop
+    /// LeftShift is held while the most recently pressed key is shifted (see CapsWordKey).
+    fn process_caps_word<const LSIZE: usize>(
+        &mut self,
+        results: &mut heapless::Vec<CapabilityRun, LSIZE>,
+    ) {
+        if !self.keyboard.caps_word {
+            return;
+        }
+
+        let mut shift = self.keyboard.caps_shift;
+        for run in results.iter() {
+            let id = match *run {
+                CapabilityRun::HidKeyboard { state, id }
+                    if state.activation() == CapabilityEvent::Initial =>
+                {
+                    id
+                }
+                _ => continue,
+            };
+            match CapsWordKey::new(id) {
+                CapsWordKey::Shifted => shift = true,
+                CapsWordKey::Continue => shift = false,
+                CapsWordKey::Ignored => {}
+                CapsWordKey::Break => {
+                    trace!("Caps word ended by {:?}", id);
+                    self.keyboard.caps_word = false;
+                    shift = false;
+                    break;
+                }
+            }
+        }
+        self.caps_word_shift(shift, results);
+    }
+
+    /// Applies a HidKeyboardModMorph capability
+    /// The morph is sent (with the matching mods released) if any of the mods are held when the
+    /// key is pressed. The released mods are pressed again with the release of the key if they
+    /// are still held.
+    fn mod_morph_run<const LSIZE: usize>(
+        &mut self,
+        run: CapabilityRun,
+        results: &mut heapless::Vec<CapabilityRun, LSIZE>,
+    ) {
+        let (state, id, mods, morph) = if let CapabilityRun::HidKeyboardModMorph {
+            state,
+            id,
+            mods,
+            morph,
+        } = run
+        {
+            (state, id, mods, morph)
+        } else {
+            return;
+        };
+
+        let (sent, masked, modifier_state) = match state.activation() {
+            CapabilityEvent::Initial => {
+                let masked = if self.keyboard.morphs.is_full() {
+                    self.overflows.record(ProcessError::FailedModMorphPush);
+                    0
+                } else {
+                    self.keyboard.modifiers & mods
+                };
+                let sent = if masked != 0 { morph } else { id };
+                // Only fails when full (already counted above)
+                self.keyboard
+                    .morphs
+                    .push(ModMorphKey { id, sent, masked })
+                    .ok();
+                (sent, masked, CapabilityEvent::Last)
+            }
+            CapabilityEvent::Last => {
+                match self.keyboard.morphs.iter().position(|key| key.id == id) {
+                    Some(pos) => {
+                        let key = self.keyboard.morphs.remove(pos);
+                        // Modifiers released in the meantime stay released
+                        (
+                            key.sent,
+                            key.masked & self.keyboard.modifiers,
+                            CapabilityEvent::Initial,
+                        )
+                    }
+                    // Pressed without mod-morph (see FailedModMorphPush)
+                    None => (id, 0, CapabilityEvent::Initial),
+                }
+            }
+            _ => {
+                return;
+            }
+        };
+
+        let mut runs = heapless::Vec::<_, 9>::new();
+        for bit in 0..8 {
+            if masked & (1 << bit) != 0 {
+                runs.push(CapabilityRun::HidKeyboard {
+                    state: modifier_state,
+                    id: (kll_hid::Keyboard::LeftControl as u16 + bit).into(),
+                })
+                .ok();
+            }
+        }
+        let run = CapabilityRun::HidKeyboard {
+            state: state.activation(),
+            id: sent,
+        };
+        // Modifiers are released before the morph is pressed, and pressed after it is released
+        if modifier_state == CapabilityEvent::Last {
+            runs.push(run).ok();
+        } else {
+            runs.insert(0, run).ok();
+        }
+        for run in runs {
+            push_result(results, run, &mut self.overflows);
+        }
+    }
+
+    /// Releases latched keys and layers once they have been used by a switch press or have
+    /// expired
+    /// Latched keys are released on the loop after the press so the press still applies them.
+    fn process_latches<const LSIZE: usize>(
+        &mut self,
+        results: &mut heapless::Vec<CapabilityRun, LSIZE>,
+    ) {
+        let pressed = core::mem::take(&mut self.latch.pressed);
+
+        let mut pos = 0;
+        while pos < self.latch.keys.len() {
+            let key = self.latch.keys[pos];
+            if key.used || self.latch.expired(key.time_instance, self.time_instance) {
+                self.latch.keys.remove(pos);
+                let run = CapabilityRun::HidKeyboard {
+                    state: CapabilityEvent::Last,
+                    id: key.id,
+                };
+                push_result(results, run, &mut self.overflows);
+                continue;
+            }
+            // Latches set during this loop are used by the next press
+            if pressed && key.time_instance != self.time_instance {
+                self.latch.keys[pos].used = true;
+            }
+            pos += 1;
+        }
+
+        // Latched layers, the release of the switch that used the latch is still looked up on
+        // the latched layer (see layer_stack_cache)
+        for layer in 1..self.layer.len() {
+            let Layer {
+                state,
+                last_time_instance,
+            } = self.layer[layer];
+            if state.is_set(layer::State::Latch)
+                && ((pressed && last_time_instance != self.time_instance)
+                    || self.latch.expired(last_time_instance, self.time_instance))
+            {
+                self.queue_layer(layer as u8, layer::State::Latch);
+            }
+        }
+    }
+
+    /// Processes the layer TriggerEvents generated during the previous processing loop
+    fn process_layer_events<const LSIZE: usize>(&mut self) {
+        let events = core::mem::take(&mut self.layer_control.events);
+        for event in events {
+            if let Err(err) = self.process_trigger::<LSIZE>(event) {
+                error!("Failed to process layer event {:?}: {:?}", event, err);
+            }
         }
     }
 
     /// Attempts to lookup a trigger list given a layer and given state
+    /// Layers are searched in stack order (most recently activated first), then layer 0.
     fn layer_lookup_search<const LSIZE: usize>(
-        &self,
+        &mut self,
         ttype: u8,
         index: u16,
     ) -> Option<(u8, heapless::Vec<(u16, u16), LSIZE>)> {
-        // Start from the top of the stack
-        for (layer, state) in self.layer.iter().rev().enumerate() {
-            let layer = layer as u8;
+        let mut found = None;
+        let mut overflow = None;
+
+        // Start from the most recently activated layer, layer 0 is always the last fallback
+        for layer in self.layer_stack.iter().rev().chain(core::iter::once(&0)) {
             // Check if effective state is valid
-            if state.state.effective() {
-                let guides = self
+            if self.layer[*layer as usize].state.effective() {
+                let mut guides = heapless::Vec::new();
+                if let Err(err) = self
                     .layer_lookup
-                    .lookup_guides::<LSIZE>((layer, ttype, index));
+                    .collect_guides((*layer, ttype, index), &mut guides)
+                {
+                    overflow = Some(err);
+                }
                 // If guides were found, we can stop here
                 if !guides.is_empty() {
-                    return Some((layer, guides));
+                    found = Some((*layer, guides));
+                    break;
                 }
             }
         }
 
-        // No matches
-        None
+        if let Some(err) = overflow {
+            self.overflows.record(err);
+        }
+        found
     }
 
     /// Lookup effective layer for scancode
@@ -309,8 +1111,17 @@ impl<
         &mut self,
         event: TriggerEvent,
     ) -> Option<(u8, heapless::Vec<(u16, u16), LSIZE>)> {
+        // Any switch press uses up latched layers and keys
+        if let TriggerEvent::Switch {
+            state: trigger::Phro::Press,
+            ..
+        } = event
+        {
+            self.latch.pressed = true;
+        }
+
         let cache_lookup = (u8::from(event), event.index());
-        let cache_hit = self.layer_stack_cache.get(&cache_lookup);
+        let cache_hit = self.layer_stack_cache.get(&cache_lookup).copied();
         trace!("Lookup cache hit: {:?}", cache_hit);
 
         // Convert to CapabilityRun to determine how to evaluate trigger
@@ -319,14 +1130,19 @@ impl<
         trace!("Converted capability_state: {:?}", capability_state);
 
         // Do cached lookup if not the initial event for the trigger and present in the cache
-        let layer_guides = if capability_state != CapabilityEvent::Initial && let Some((layer, _layer_state)) = cache_hit {
+        let layer_guides = if capability_state != CapabilityEvent::Initial
+            && let Some((layer, _layer_state)) = cache_hit
+        {
             // Retrieve layer, and build guide lookup
-            let guide_lookup = (*layer, cache_lookup.0, cache_lookup.1);
+            let guide_lookup = (layer, cache_lookup.0, cache_lookup.1);
 
             // We can do a direct lookup as we're hitting a cache
-            let guides = self.layer_lookup.lookup_guides::<LSIZE>(guide_lookup);
+            let mut guides = heapless::Vec::new();
+            if let Err(err) = self.layer_lookup.collect_guides(guide_lookup, &mut guides) {
+                self.overflows.record(err);
+            }
 
-            Some((*layer, guides))
+            Some((layer, guides))
 
         // Do full lookup if this is the initial event for the trigger or was not in the cache
         } else {
@@ -352,9 +1168,15 @@ impl<
                 },
             );
 
-            self.layer_stack_cache
+            // Without a cache entry, the following events use the current layer stack
+            if self
+                .layer_stack_cache
                 .insert(cache_lookup, cache_key)
-                .unwrap();
+                .is_err()
+            {
+                self.overflows
+                    .record(ProcessError::FailedLayerStackCacheInsert);
+            }
         }
 
         layer_guides
@@ -374,127 +1196,520 @@ impl<
         event: TriggerEvent,
     ) -> Result<(), ProcessError> {
         trace!("Event: {:?}", event);
-        // Lookup guide
-        if let Some((_layer, guides)) = self.lookup::<LSIZE>(event) {
-            trace!("Event guides: {:?}", guides);
-            // Process each of the guides
-            for guide in guides {
-                // Lookup the state of each of the guides
-                let state = if let Some(state) = self.lookup_state.get(&guide) {
-                    *state
-                } else {
-                    StateStatus::TriggerPos {
-                        time_instance: self.time_instance,
-                        offset: 0,
-                    }
-                };
-                trace!("guide state: {:?}", state);
-
-                // Determine if this trigger is valid
-                // If we have a new trigger on a state that is processing a result, ignore this
-                // event. We don't ignore result events, they are just queued up.
-                let pos = match state {
-                    StateStatus::TriggerPos { offset, .. } => offset,
-                    _ => {
-                        continue;
-                    }
-                };
-
-                // Lookup trigger guide
-                if let Some(trigger_guide) = self.layer_lookup.trigger_guide(guide, pos) {
-                    // Check for already evaluated trigger state for this processing loop
-                    let mut remaining =
-                        if let Some(remaining) = self.trigger_combo_eval_state.get(&guide) {
-                            *remaining
-                        } else {
-                            // Lookup size of this trigger list combo
-                            trigger_guide.len() as u8
-                        };
+        // Switch events that interrupt a pending hold-tap are processed once it is resolved
+        match self.hold_tap.defer(event) {
+            Ok(true) => {
+                trace!("Deferred event: {:?}", event);
+                return Ok(());
+            }
+            Ok(false) => {}
+            // Processed immediately instead
+            Err(err) => {
+                self.overflows.record(err);
+            }
+        }
 
-                    // Verify that we actually match the condition
-                    // e.g. Press vs. Release
-                    let mut removed_lookup_state = false;
-                    for cond in trigger_guide {
-                        match cond.evaluate(event, self.layer_lookup.loop_condition_lookup) {
-                            Vote::Positive => {
-                                trace!("eval({:?}): Positive", cond);
-                                remaining -= 1;
-                            }
-                            Vote::Negative => {
-                                trace!("eval({:?}): Negative", cond);
-                                // Remove lookup state entry, continue to next guide
-                                self.lookup_state.remove(&guide);
-                                removed_lookup_state = true;
-                                break;
-                            }
-                            Vote::Insufficient => {
-                                trace!("eval({:?}): Insufficient", cond);
-                                // Do nothing
-                            }
-                            Vote::OffState => {
-                                trace!("eval({:?}): OffState", cond);
-                                // Attempt to push a reverse lookup query
-                                // The results of the query will be another set of TriggerEvents
-                                if self
-                                    .off_state_lookups
-                                    .push((guide, u8::from(*cond), cond.index()))
-                                    .is_err()
-                                {
-                                    return Err(ProcessError::FailedOffStatePush);
-                                }
-                            }
-                        }
-                    }
+        let ret = self.process_event::<LSIZE>(event);
 
-                    // Don't insert a new lookup_state entry if we're removed it on purpose
-                    if removed_lookup_state {
-                        continue;
-                    }
+        // Crossing conditions compare against the previous event of the analog input
+        if let Err(err) = self.analog.update(event) {
+            self.overflows.record(err);
+        }
+        ret
+    }
 
-                    // Check if there are no remaining evaluations
-                    if remaining == 0 {
-                        // Determine the next offset
-                        let next_status = if let Some(next_offset) =
-                            self.layer_lookup.next_trigger_combo(guide, pos)
-                        {
-                            StateStatus::TriggerPos {
-                                time_instance: self.time_instance,
-                                offset: next_offset,
-                            }
-                        } else {
-                            StateStatus::ResultPos {
-                                time_instance: self.time_instance,
-                                event,
-                                offset: 0,
-                            }
-                        };
+    /// Handles switch events of suppressed combo switches before evaluating the event
+    fn process_event<const LSIZE: usize>(
+        &mut self,
+        event: TriggerEvent,
+    ) -> Result<(), ProcessError> {
+        // Switch was part of a completed combo, its own guides stay suppressed until released
+        if let TriggerEvent::Switch { state, index, .. } = event
+            && self.combo.consumed.contains(&index)
+        {
+            match state {
+                trigger::Phro::Hold => {
+                    return Ok(());
+                }
+                trigger::Phro::Release => {
+                    self.combo.consumed.retain(|consumed| *consumed != index);
+                    return self.process_guides::<LSIZE>(event, GuideFilter::Combos);
+                }
+                _ => {}
+            }
+        }
 
-                        // Update lookup state
-                        if self.lookup_state.insert(guide, next_status).is_err() {
-                            return Err(ProcessError::FailedLookupStateInsert);
+        if let TriggerEvent::Switch { state, index, .. } = event
+            && self.combo.is_suppressed(index)
+        {
+            match state {
+                // Press is still suppressed, nothing to do
+                trigger::Phro::Hold => {
+                    return Ok(());
+                }
+                // Switch released before the combo finished, abandon the combo
+                trigger::Phro::Release => {
+                    let guides: Vec<(u16, u16), MAX_SUPPRESSED_EVENTS> = self
+                        .combo
+                        .suppressed
+                        .iter()
+                        .filter(|(event, _guide)| event.index() == index)
+                        .map(|(_event, guide)| *guide)
+                        .collect();
+                    // The release is queued even if a replay fails, otherwise the replayed
+                    // presses would stay pressed
+                    let mut error = None;
+                    for guide in guides {
+                        self.trigger_combo_eval_state.remove(&guide);
+                        if let Err(err) = self.replay_suppressed::<LSIZE>(guide) {
+                            error.get_or_insert(err);
                         }
-                    } else {
-                        // Update trigger_combo_eval_state
-                        if self
-                            .trigger_combo_eval_state
-                            .insert(guide, remaining)
-                            .is_err()
-                        {
-                            return Err(ProcessError::FailedTriggerComboEvalStateInsert);
+                    }
+                    if self
+                        .combo
+                        .delayed
+                        .push((event, self.time_instance))
+                        .is_err()
+                    {
+                        // Processed immediately instead
+                        self.overflows
+                            .record(ProcessError::FailedSuppressedEventPush);
+                        if let Err(err) = self.process_guides::<LSIZE>(event, GuideFilter::All) {
+                            error.get_or_insert(err);
                         }
                     }
+                    return error.map_or(Ok(()), Err);
                 }
+                _ => {}
             }
-        } else {
-            trace!("No event mapping for: {:?}", event);
         }
 
-        Ok(())
+        self.process_guides::<LSIZE>(event, GuideFilter::All)
     }
 
-    /// Off state lookups
-    /// Used to keep track of possibly off-states that need a reverse lookup
-    /// Cleared each processing loop.
+    /// Processes switch presses suppressed by an abandoned combo
+    /// Combo guides are skipped so the presses cannot restart the same combo.
+    fn replay_suppressed<const LSIZE: usize>(
+        &mut self,
+        guide: (u16, u16),
+    ) -> Result<(), ProcessError> {
+        // The remaining events are still replayed if one of them fails
+        let mut error = None;
+        for event in self.combo.take_suppressed(guide) {
+            trace!("Replaying suppressed combo event: {:?}", event);
+            if let Err(err) = self.process_guides::<LSIZE>(event, GuideFilter::Others) {
+                error.get_or_insert(err);
+            }
+        }
+        error.map_or(Ok(()), Err)
+    }
+
+    /// Determine if the guide is currently at a combo (more than one condition)
+    fn is_combo(&self, guide: (u16, u16)) -> bool {
+        let pos = match self.lookup_state.get(&guide) {
+            Some(StateStatus::TriggerPos { offset, .. }) => *offset,
+            Some(_) => {
+                return false;
+            }
+            None => 0,
+        };
+        self.layer_lookup
+            .trigger_guide(guide, pos)
+            .is_some_and(|conds| conds.len() > 1)
+    }
+
+    /// Determine if the guide is partway through a sequence
+    fn in_sequence(&self, guide: (u16, u16)) -> bool {
+        matches!(
+            self.lookup_state.get(&guide),
+            Some(StateStatus::TriggerPos { offset, .. }) if *offset > 0
+        )
+    }
+
+    /// Evaluate the TriggerGuides of an event and update the lookup state
+    /// Combos and in-progress sequences are evaluated first so they can suppress the other
+    /// guides of a switch.
+    fn process_guides<const LSIZE: usize>(
+        &mut self,
+        event: TriggerEvent,
+        filter: GuideFilter,
+    ) -> Result<(), ProcessError> {
+        // Guides matched by the event, used to cancel other in-progress sequences
+        let mut matched = heapless::Vec::<(u16, u16), LSIZE>::new();
+
+        // The remaining guides are still evaluated if one of them fails
+        let mut error = None;
+
+        // Lookup guide
+        if let Some((_layer, guides)) = self.lookup::<LSIZE>(event) {
+            trace!("Event guides: {:?}", guides);
+            let mut first_guides = heapless::Vec::<(u16, u16), LSIZE>::new();
+            let mut other_guides = heapless::Vec::<(u16, u16), LSIZE>::new();
+            for guide in guides {
+                // Same capacity, cannot fail
+                if self.is_combo(guide) || self.in_sequence(guide) {
+                    first_guides.push(guide).ok();
+                } else {
+                    other_guides.push(guide).ok();
+                }
+            }
+
+            // Process each of the combo and in-progress sequence guides
+            let mut pending = None;
+            let mut complete = false;
+            let mut advanced = false;
+            if filter != GuideFilter::Others {
+                for guide in first_guides {
+                    let combo = self.is_combo(guide);
+                    let sequence = self.in_sequence(guide);
+                    let result = self.process_guide(guide, event).unwrap_or_else(|err| {
+                        error.get_or_insert(err);
+                        ComboMatch::None
+                    });
+                    if result != ComboMatch::None {
+                        matched.push(guide).ok();
+                    }
+                    match result {
+                        ComboMatch::Pending => {
+                            pending.get_or_insert(guide);
+                        }
+                        ComboMatch::Complete => {
+                            if combo {
+                                // Suppressed presses are consumed by the combo
+                                for suppressed in self.combo.take_suppressed(guide) {
+                                    if let Err(err) = self.combo.consume(suppressed.index()) {
+                                        self.overflows.record(err);
+                                    }
+                                }
+                                complete = true;
+                            }
+                            advanced |= sequence;
+                        }
+                        ComboMatch::None => {}
+                    }
+                }
+            }
+
+            // Suppress the switch's own guides if it's part of a combo or leader sequence
+            let mut suppressed = false;
+            if let TriggerEvent::Switch {
+                state: trigger::Phro::Press,
+                index,
+                ..
+            } = event
+            {
+                if (self.combo.suppress && complete) || (self.sequence.leader && advanced) {
+                    trace!("Consumed event: {:?}", event);
+                    if let Err(err) = self.combo.consume(index) {
+                        self.overflows.record(err);
+                    }
+                    suppressed = true;
+                } else if self.combo.suppress
+                    && let Some(guide) = pending
+                {
+                    if self.combo.suppressed.push((event, guide)).is_ok() {
+                        trace!("Suppressed combo event: {:?}", event);
+                        suppressed = true;
+                    } else {
+                        self.overflows
+                            .record(ProcessError::FailedSuppressedEventPush);
+                    }
+                }
+            }
+
+            // Process each of the remaining guides
+            if !suppressed && filter != GuideFilter::Combos {
+                for guide in other_guides {
+                    match self.process_guide(guide, event) {
+                        Ok(ComboMatch::None) => {}
+                        Ok(_) => {
+                            matched.push(guide).ok();
+                        }
+                        Err(err) => {
+                            error.get_or_insert(err);
+                        }
+                    }
+                }
+            }
+        } else {
+            trace!("No event mapping for: {:?}", event);
+        }
+
+        // Replayed events have already been taken into account
+        if filter == GuideFilter::All {
+            self.cancel_sequences(event, &matched);
+        }
+
+        error.map_or(Ok(()), Err)
+    }
+
+    /// Cancels in-progress sequences that were not advanced by a switch press
+    fn cancel_sequences(&mut self, event: TriggerEvent, matched: &[(u16, u16)]) {
+        if !self.sequence.cancel
+            || !matches!(
+                event,
+                TriggerEvent::Switch {
+                    state: trigger::Phro::Press,
+                    ..
+                }
+            )
+        {
+            return;
+        }
+
+        let mut cancelled = heapless::Vec::<(u16, u16), STATE_SIZE>::new();
+        for (guide, status) in self.lookup_state.iter() {
+            if let StateStatus::TriggerPos { offset, .. } = status
+                && *offset > 0
+                && !matched.contains(guide)
+            {
+                // Same capacity, cannot fail
+                cancelled.push(*guide).ok();
+            }
+        }
+
+        for guide in cancelled {
+            trace!("Cancelled sequence: {:?}", guide);
+            self.lookup_state.remove(&guide);
+            self.trigger_combo_eval_state.remove(&guide);
+        }
+    }
+
+    /// Removes in-progress sequences that have not advanced within the sequence timeout
+    fn expire_sequences(&mut self) {
+        let timeout = if let Some(timeout) = self.sequence.timeout {
+            timeout
+        } else {
+            return;
+        };
+
+        let mut expired = heapless::Vec::<(u16, u16), STATE_SIZE>::new();
+        for (guide, status) in self.lookup_state.iter() {
+            if let StateStatus::TriggerPos {
+                time_instance,
+                offset,
+            } = status
+                && *offset > 0
+                && self.time_instance.wrapping_sub(*time_instance) >= timeout
+            {
+                // Same capacity, cannot fail
+                expired.push(*guide).ok();
+            }
+        }
+
+        for guide in expired {
+            trace!("Expired sequence: {:?}", guide);
+            self.lookup_state.remove(&guide);
+            self.trigger_combo_eval_state.remove(&guide);
+        }
+    }
+
+    /// Evaluate a single TriggerGuide against an event
+    fn process_guide(
+        &mut self,
+        guide: (u16, u16),
+        event: TriggerEvent,
+    ) -> Result<ComboMatch, ProcessError> {
+        // Lookup the state of each of the guides
+        let state = if let Some(state) = self.lookup_state.get(&guide) {
+            *state
+        } else {
+            StateStatus::TriggerPos {
+                time_instance: self.time_instance,
+                offset: 0,
+            }
+        };
+        trace!("guide state: {:?}", state);
+
+        // Determine if this trigger is valid
+        // If we have a new trigger on a state that is processing a result, ignore this
+        // event. We don't ignore result events, they are just queued up.
+        let pos = match state {
+            StateStatus::TriggerPos { offset, .. } => offset,
+            _ => {
+                return Ok(ComboMatch::None);
+            }
+        };
+
+        // Lookup trigger guide
+        let trigger_guide = if let Some(trigger_guide) = self.layer_lookup.trigger_guide(guide, pos)
+        {
+            trigger_guide
+        } else {
+            return Ok(ComboMatch::None);
+        };
+
+        // Check for already evaluated trigger state (partially matched combo)
+        let mut eval = if let Some(eval) = self.trigger_combo_eval_state.get(&guide) {
+            *eval
+        } else {
+            ComboEval::new(trigger_guide.len(), self.time_instance)
+        };
+        let mut matched = false;
+        let mut off_lookups = 0u32;
+
+        // Verify that we actually match the condition
+        // e.g. Press vs. Release
+        for (cond_pos, cond) in trigger_guide.iter().enumerate() {
+            let bit = ComboEval::bit(cond_pos);
+            if eval.matched & bit != 0 {
+                // Releasing an already matched switch abandons the combo
+                if let (
+                    TriggerCondition::Switch { index, .. },
+                    TriggerEvent::Switch {
+                        state: trigger::Phro::Release,
+                        index: e_index,
+                        ..
+                    },
+                ) = (cond, event)
+                    && index == e_index
+                {
+                    trace!("Abandoned combo: {:?}", guide);
+                    self.trigger_combo_eval_state.remove(&guide);
+                    return Ok(ComboMatch::None);
+                }
+                continue;
+            }
+
+            match cond.evaluate_analog(
+                event,
+                self.analog.previous(event),
+                self.layer_lookup.loop_condition_lookup,
+            ) {
+                Vote::Positive => {
+                    trace!("eval({:?}): Positive", cond);
+                    eval.remaining -= 1;
+                    eval.matched |= bit;
+                    matched = true;
+                }
+                Vote::Negative => {
+                    trace!("eval({:?}): Negative", cond);
+                    // Remove lookup state entry, continue to next guide
+                    self.lookup_state.remove(&guide);
+                    self.trigger_combo_eval_state.remove(&guide);
+                    return Ok(ComboMatch::None);
+                }
+                Vote::Insufficient
+                    if cond.off_state()
+                        && (u8::from(cond), cond.index()) != (u8::from(event), event.index()) =>
+                {
+                    trace!("eval({:?}): OffState lookup", cond);
+                    // Off state conditions of other inputs in the combo never see an event
+                    // Query their current state using a reverse lookup once the combo has started
+                    off_lookups |= bit;
+                }
+                Vote::Insufficient => {
+                    trace!("eval({:?}): Insufficient", cond);
+                    // Do nothing
+                }
+                Vote::OffState => {
+                    trace!("eval({:?}): OffState", cond);
+                    // Attempt to push a reverse lookup query
+                    // The results of the query will be another set of TriggerEvents
+                    if self
+                        .off_state_lookups
+                        .push((guide, u8::from(cond), cond.index()))
+                        .is_err()
+                    {
+                        return Err(self.overflows.record(ProcessError::FailedOffStatePush));
+                    }
+                }
+            }
+        }
+
+        // Request off state lookups for the rest of a started combo
+        if eval.matched != 0 {
+            for (cond_pos, cond) in trigger_guide.iter().enumerate() {
+                let lookup = (guide, u8::from(cond), cond.index());
+                if off_lookups & ComboEval::bit(cond_pos) == 0
+                    || self.off_state_lookups.contains(&lookup)
+                {
+                    continue;
+                }
+                if self.off_state_lookups.push(lookup).is_err() {
+                    return Err(self.overflows.record(ProcessError::FailedOffStatePush));
+                }
+            }
+        }
+
+        // Check if there are no remaining evaluations
+        if eval.remaining == 0 {
+            self.trigger_combo_eval_state.remove(&guide);
+
+            // Determine the next offset
+            let next_status =
+                if let Some(next_offset) = self.layer_lookup.next_trigger_combo(guide, pos) {
+                    StateStatus::TriggerPos {
+                        time_instance: self.time_instance,
+                        offset: next_offset,
+                    }
+                } else {
+                    StateStatus::ResultPos {
+                        time_instance: self.time_instance,
+                        event,
+                        offset: 0,
+                    }
+                };
+
+            // Update lookup state
+            if self.lookup_state.insert(guide, next_status).is_err() {
+                return Err(self.overflows.record(ProcessError::FailedLookupStateInsert));
+            }
+            Ok(ComboMatch::Complete)
+        } else if matched {
+            // Update trigger_combo_eval_state
+            if self.trigger_combo_eval_state.insert(guide, eval).is_err() {
+                return Err(self
+                    .overflows
+                    .record(ProcessError::FailedTriggerComboEvalStateInsert));
+            }
+            Ok(ComboMatch::Pending)
+        } else {
+            Ok(ComboMatch::None)
+        }
+    }
+
+    /// Abandons combos that have waited longer than the combo timeout
+    /// Any presses suppressed by an abandoned combo are processed instead.
+    fn expire_combos<const LSIZE: usize>(&mut self) {
+        let mut expired = heapless::Vec::<(u16, u16), MAX_ACTIVE_TRIGGERS>::new();
+        for (guide, eval) in self.trigger_combo_eval_state.iter() {
+            if eval.expired(self.combo.timeout, self.time_instance) {
+                // Same capacity, cannot fail
+                expired.push(*guide).ok();
+            }
+        }
+
+        for guide in expired {
+            trace!("Expired combo: {:?}", guide);
+            self.trigger_combo_eval_state.remove(&guide);
+            if let Err(err) = self.replay_suppressed::<LSIZE>(guide) {
+                error!("Failed to replay suppressed combo events: {:?}", err);
+            }
+        }
+
+        // Releases of replayed presses from the previous processing loop
+        let time_instance = self.time_instance;
+        let mut delayed = heapless::Vec::<_, MAX_SUPPRESSED_EVENTS>::new();
+        self.combo.delayed.retain(|(event, event_time)| {
+            if *event_time == time_instance {
+                true
+            } else {
+                // Same capacity, cannot fail
+                delayed.push(*event).ok();
+                false
+            }
+        });
+        for event in delayed {
+            if let Err(err) = self.process_event::<LSIZE>(event) {
+                error!("Failed to process delayed combo event: {:?}", err);
+            }
+        }
+    }
+
+    /// Off state lookups
+    /// Used to keep track of possibly off-states that need a reverse lookup
+    /// Cleared each processing loop.
     /// ((trigger_guide, result_guide), ttype, index)
     pub fn off_state_lookups(&self) -> &[((u16, u16), u8, u16)] {
         &self.off_state_lookups
@@ -502,40 +1717,371 @@ impl<
 
     /// Process off state lookups
     /// To maintain state use a callback function to evaluate input off states
+    /// The callback is given the trigger type (see TriggerCondition) and index of each lookup and
+    /// returns the current state of that input (e.g. Switch Off, HidLed Off, the current
+    /// AnalogDistance value or an idle Rotation).
+    /// Layer off states are resolved internally using the current layer state.
+    /// All of the events are processed even if one fails, the first error is returned.
     pub fn process_off_state_lookups<
         const MAX_LAYER_LOOKUP_SIZE: usize,
         const MAX_EVENTS: usize,
     >(
         &mut self,
-        generate_event: &dyn Fn(usize) -> TriggerEventIterator<MAX_EVENTS>,
-    ) {
+        generate_event: &dyn Fn(u8, u16) -> TriggerEventIterator<MAX_EVENTS>,
+    ) -> Result<(), ProcessError> {
+        let mut error = None;
         let mut events: heapless::Vec<TriggerEvent, MAX_LAYER_LOOKUP_SIZE> = heapless::Vec::new();
-        for lookup in &self.off_state_lookups {
-            // TODO support non-keyboard TriggerConditions
-            assert!(
-                lookup.1 == 1,
-                "Currently only keyboard TriggerConditions are supported"
-            );
-            for gen_event in generate_event(lookup.2.into()) {
-                events.push(gen_event).unwrap();
+        let mut queried: heapless::Vec<(u8, u16), MAX_OFF_STATE_LOOKUP> = heapless::Vec::new();
+        for (guide, ttype, index) in &self.off_state_lookups {
+            // Multiple guides may need the same input, only query it once
+            if queried.contains(&(*ttype, *index)) {
+                continue;
+            }
+            // Same capacity as off_state_lookups, cannot fail
+            queried.push((*ttype, *index)).ok();
+
+            let layer_event = self.layer_off_state_event(*guide, *ttype, *index);
+            let generated = if layer_event.is_none() {
+                Some(generate_event(*ttype, *index))
+            } else {
+                None
+            };
+            for event in layer_event
+                .into_iter()
+                .chain(generated.into_iter().flatten())
+            {
+                if events.push(event).is_err() {
+                    error.get_or_insert(ProcessError::FailedOffStateEventPush);
+                }
             }
         }
+        if let Some(err) = error {
+            self.overflows.record(err);
+        }
 
         for event in events {
-            let ret = self.process_trigger::<MAX_LAYER_LOOKUP_SIZE>(event);
-            assert!(
-                ret.is_ok(),
-                "Failed to enqueue offstate: {:?} - {:?}",
+            if let Err(err) = self.process_trigger::<MAX_LAYER_LOOKUP_SIZE>(event) {
+                error!("Failed to process off state: {:?} - {:?}", event, err);
+                error.get_or_insert(err);
+            }
+        }
+        error.map_or(Ok(()), Err)
+    }
+
+    /// Builds the TriggerEvent for a layer off state lookup from the current layer state
+    /// Returns None if the lookup is not for a layer condition.
+    fn layer_off_state_event(
+        &self,
+        guide: (u16, u16),
+        ttype: u8,
+        index: u16,
+    ) -> Option<TriggerEvent> {
+        // Find the condition that requested the lookup
+        let pos = match self.lookup_state.get(&guide) {
+            Some(StateStatus::TriggerPos { offset, .. }) => *offset,
+            Some(_) => {
+                return None;
+            }
+            None => 0,
+        };
+        let cond_state = self
+            .layer_lookup
+            .trigger_guide(guide, pos)?
+            .iter()
+            .find_map(|cond| match cond {
+                TriggerCondition::Layer { state, .. }
+                    if u8::from(cond) == ttype && cond.index() == index =>
+                {
+                    Some(state)
+                }
+                _ => None,
+            })?;
+        self.layer_condition_event(index as u8, cond_state)
+    }
+
+    /// Builds a TriggerEvent from the current layer state used to evaluate a layer condition
+    fn layer_condition_event(
+        &self,
+        index: u8,
+        cond_state: trigger::LayerState,
+    ) -> Option<TriggerEvent> {
+        // Layers without any mappings are always off
+        let (layer_state, last_time_instance) = self
+            .layer
+            .get(index as usize)
+            .map_or((layer::State::Off, 0), |layer| {
+                (layer.state, layer.last_time_instance)
+            });
+
+        // Report the layer as On if it has the layer state of the condition, otherwise as Off
+        // using the layer state of the condition (there is no event for a fully off layer)
+        let cond_layer_state = cond_state.layer_state();
+        let state = if layer_state.is_set(cond_layer_state) {
+            trigger::LayerState::from_layer(layer_state, trigger::Aodo::On)?
+        } else if cond_layer_state != layer::State::Off {
+            trigger::LayerState::from_layer(cond_layer_state, trigger::Aodo::Off)?
+        } else {
+            return None;
+        };
+
+        Some(TriggerEvent::Layer {
+            state,
+            layer: index,
+            last_state: self.time_instance.wrapping_sub(last_time_instance),
+        })
+    }
+
+    /// Retrieves the combo at the given combo index of a ResultGuide
+    fn result_combo(&self, result: u16, combo: u8) -> Option<GuideCombo<'a, Capability>> {
+        let mut offset = 0;
+        for _ in 0..combo {
+            offset = self.layer_lookup.next_result_combo((0, result), offset)?;
+        }
+        self.layer_lookup.result_guide((0, result), offset)
+    }
+
+    /// Runs a single combo of a ResultGuide using the given state
+    /// Used by hold-taps and tap-dances, which decide the state rather than the ResultGuide.
+    fn run_result<const LSIZE: usize>(
+        &mut self,
+        (result, combo): (u16, u8),
+        event: TriggerEvent,
+        state: CapabilityEvent,
+        results: &mut heapless::Vec<CapabilityRun, LSIZE>,
+    ) {
+        if let Some(result_guide) = self.result_combo(result, combo) {
+            for cap in result_guide {
+                let mut run = cap.run(event);
+                run.set_state(state);
+                push_result(results, run, &mut self.overflows);
+            }
+        }
+    }
+
+    /// Runs the tap result of a hold-tap (or tap-dance) and releases it on the next loop
+    fn run_tap<const LSIZE: usize>(
+        &mut self,
+        ht: HoldTap,
+        results: &mut heapless::Vec<CapabilityRun, LSIZE>,
+    ) {
+        trace!("Hold-tap resolved as tap: {:?}", ht);
+        let tap = (ht.tap, ht.count - 1);
+        self.run_result(tap, ht.event, CapabilityEvent::Initial, results);
+        if self
+            .hold_tap
+            .tap_release
+            .push((tap.0, tap.1, ht.event))
+            .is_err()
+        {
+            // Release immediately rather than leaving the tap stuck
+            self.overflows.record(ProcessError::FailedHoldTapPush);
+            self.run_result(tap, ht.event, CapabilityEvent::Last, results);
+        }
+    }
+
+    /// Releases taps from the previous processing loop and resolves pending hold-taps
+    /// and tap-dances
+    fn process_hold_taps<const LSIZE: usize>(
+        &mut self,
+        results: &mut heapless::Vec<CapabilityRun, LSIZE>,
+    ) {
+        // Taps are always released on the processing loop after they were pressed
+        for (tap, combo, event) in core::mem::take(&mut self.hold_tap.tap_release) {
+            self.run_result((tap, combo), event, CapabilityEvent::Last, results);
+        }
+
+        let mut pos = 0;
+        while pos < self.hold_tap.active.len() {
+            let ht = self.hold_tap.active[pos];
+            match ht.decision {
+                HoldTapDecision::Pending
+                    if ht.resolve_hold(
+                        self.hold_tap.flavor,
+                        self.hold_tap.threshold,
+                        self.time_instance,
+                    ) =>
+                {
+                    trace!("Hold-tap resolved as hold: {:?}", ht);
+                    // Tap-dance counts without a hold result hold the tap result instead
+                    let combo = ht.count - 1;
+                    let result = if self.result_combo(ht.hold, combo).is_some() {
+                        ht.hold
+                    } else {
+                        ht.tap
+                    };
+                    self.hold_tap.active[pos].decision = HoldTapDecision::Hold { result, combo };
+                    self.run_result((result, combo), ht.event, CapabilityEvent::Initial, results);
+                }
+                HoldTapDecision::Released
+                    if ht.resolve_tap(self.hold_tap.threshold, self.time_instance) =>
+                {
+                    self.hold_tap.active.remove(pos);
+                    self.run_tap(ht, results);
+                    continue;
+                }
+                _ => {}
+            }
+            pos += 1;
+        }
+    }
+
+    /// Replays switch events deferred by hold-taps once no hold-taps are pending
+    /// Stops after a release so a deferred press and release are never sent in the same
+    /// processing loop.
+    fn replay_deferred_events<const LSIZE: usize>(&mut self) {
+        if self.hold_tap.pending() {
+            return;
+        }
+
+        let mut replayed = false;
+        while let Some(event) = self.hold_tap.deferred.front().copied() {
+            let release = matches!(
                 event,
-                ret
+                TriggerEvent::Switch {
+                    state: trigger::Phro::Release,
+                    ..
+                }
             );
+            if release && replayed {
+                break;
+            }
+
+            self.hold_tap.deferred.pop_front();
+            if release {
+                self.hold_tap
+                    .deferred_index
+                    .retain(|index| *index != event.index());
+            }
+            if let Err(err) = self.process_event::<LSIZE>(event) {
+                error!("Failed to replay deferred event {:?}: {:?}", event, err);
+            }
+            replayed = true;
+
+            if release {
+                break;
+            }
+        }
+    }
+
+    /// Handles HoldTap and TapDance CapabilityRuns
+    /// The press starts (or continues) a pending hold-tap, the release runs either the tap or
+    /// the end of the hold depending on how the hold-tap was resolved.
+    /// A released tap-dance waits for another tap if there is a tap result for the next count.
+    fn hold_tap_run<const LSIZE: usize>(
+        &mut self,
+        run: CapabilityRun,
+        event: TriggerEvent,
+        results: &mut heapless::Vec<CapabilityRun, LSIZE>,
+    ) {
+        let (state, tap, hold, dance) = match run {
+            CapabilityRun::HoldTap { state, tap, hold } => (state, tap, hold, false),
+            CapabilityRun::TapDance { state, taps, holds } => (state, taps, holds, true),
+            _ => {
+                return;
+            }
+        };
+
+        let press = match state {
+            CapabilityEvent::Initial => true,
+            CapabilityEvent::Last => false,
+            CapabilityEvent::Passthrough(TriggerEvent::Switch { state, .. }) => match state {
+                trigger::Phro::Press => true,
+                trigger::Phro::Release => false,
+                _ => {
+                    return;
+                }
+            },
+            _ => {
+                return;
+            }
+        };
+
+        let index = event.index();
+        let pos = self
+            .hold_tap
+            .active
+            .iter()
+            .position(|ht| ht.event.index() == index);
+
+        if press {
+            if let Some(pos) = pos {
+                // Another tap of a tap-dance
+                let ht = &mut self.hold_tap.active[pos];
+                if ht.decision == HoldTapDecision::Released {
+                    ht.count += 1;
+                    ht.decision = HoldTapDecision::Pending;
+                    ht.time_instance = self.time_instance;
+                    ht.event = event;
+                }
+                return;
+            }
+            let ht = HoldTap {
+                event,
+                time_instance: self.time_instance,
+                tap,
+                hold,
+                count: 1,
+                dance,
+                decision: HoldTapDecision::Pending,
+                interrupt: None,
+                nested: false,
+            };
+            if self.hold_tap.active.push(ht).is_err() {
+                self.overflows.record(ProcessError::FailedHoldTapPush);
+            }
+        } else if let Some(pos) = pos {
+            let ht = self.hold_tap.active[pos];
+            match ht.decision {
+                HoldTapDecision::Pending => {
+                    // Only wait for another tap if it has a result and nothing interrupted
+                    if ht.dance
+                        && ht.interrupt.is_none()
+                        && self.result_combo(ht.tap, ht.count).is_some()
+                    {
+                        let ht = &mut self.hold_tap.active[pos];
+                        ht.decision = HoldTapDecision::Released;
+                        ht.time_instance = self.time_instance;
+                    } else {
+                        self.hold_tap.active.remove(pos);
+                        self.run_tap(ht, results);
+                    }
+                }
+                HoldTapDecision::Hold { result, combo } => {
+                    self.hold_tap.active.remove(pos);
+                    self.run_result((result, combo), ht.event, CapabilityEvent::Last, results);
+                }
+                HoldTapDecision::Released => {}
+            }
         }
     }
 
     /// Finalize incoming triggers, update internal state and generate outgoing results
+    /// Layer capabilities are applied to the layer state and are not included in the results.
     pub fn finalize_triggers<const LSIZE: usize>(&mut self) -> heapless::Vec<CapabilityRun, LSIZE> {
         let mut results = heapless::Vec::<_, LSIZE>::new();
 
+        // Layer changes from the previous loop are evaluated before anything else
+        self.process_layer_events::<LSIZE>();
+
+        // Expired combos and hold-taps are resolved first so any held back events can be
+        // processed in this loop
+        self.expire_combos::<LSIZE>();
+        self.expire_sequences();
+        self.process_hold_taps(&mut results);
+        self.replay_deferred_events::<LSIZE>();
+
+        // Auto-shift taps are always released on the processing loop after they were pressed
+        for id in core::mem::take(&mut self.auto_shift.tap_release) {
+            let run = CapabilityRun::HidKeyboard {
+                state: CapabilityEvent::Last,
+                id,
+            };
+            push_result(&mut results, run, &mut self.overflows);
+        }
+
+        // HoldTap and TapDance capabilities are handled after the lookup_state has been processed
+        let mut hold_tap_runs = heapless::Vec::<_, MAX_HOLD_TAPS>::new();
+
         // Iterate over lookup_state, looking for ResultPos entries
         for (guide, status) in self.lookup_state.iter_mut() {
             // Process results
@@ -546,7 +2092,8 @@ impl<
             } = status
             {
                 // Time offset, used to compare against the timing conditions
-                let time_offset = self.time_instance - *time_instance;
+                // time_instance is the start of the current combo
+                let time_offset = self.time_instance.wrapping_sub(*time_instance);
 
                 // Lookup ResultGuide
                 if let Some(result_guide) = self.layer_lookup.result_guide(*guide, *offset) {
@@ -555,21 +2102,25 @@ impl<
 
                     // For each element in the combo
                     for cap in result_guide {
-                        let time_cond = self.layer_lookup.loop_condition_lookup
-                            [cap.loop_condition_index() as usize];
+                        let Some(time_cond) =
+                            cap.loop_condition(self.layer_lookup.loop_condition_lookup)
+                        else {
+                            // Capability is never scheduled, skip it so the combo can complete
+                            completed_cond += 1;
+                            continue;
+                        };
                         match time_offset.cmp(&time_cond) {
                             Ordering::Equal => {
                                 // Convert the Capability into a CapabilityRun and enqueue it
-                                if results
-                                    .push(
-                                        cap.generate(
-                                            *event,
-                                            self.layer_lookup.loop_condition_lookup,
-                                        ),
-                                    )
-                                    .is_err()
+                                let run = cap.run(*event);
+                                if let CapabilityRun::HoldTap { .. }
+                                | CapabilityRun::TapDance { .. } = run
                                 {
-                                    panic!("finalize_triggers LSIZE is too small!");
+                                    if hold_tap_runs.push((run, *event)).is_err() {
+                                        self.overflows.record(ProcessError::FailedHoldTapPush);
+                                    }
+                                } else {
+                                    push_result(&mut results, run, &mut self.overflows);
                                 }
 
                                 // Increment completion
@@ -579,39 +2130,79 @@ impl<
                                 // Capability has already been scheduled, mark as completed
                                 completed_cond += 1;
                             }
-                            _ => {}
+                            Ordering::Less => {}
                         }
                     }
 
-                    // Update status position
-                    // Check to see if the time_instance is 0, so we can set it
-                    if *offset == 0 && completed_cond != result_guide.len() {
-                        *status = StateStatus::ResultPos {
-                            time_instance: self.time_instance,
-                            event: *event,
-                            offset: *offset,
-                        };
-                    } else {
-                        // Only increment combo if combo has been fully executed/processed
-                        if completed_cond == result_guide.len() {
-                            if let Some(next_pos) =
-                                self.layer_lookup.next_result_combo(*guide, *offset)
-                            {
-                                *status = StateStatus::ResultPos {
-                                    time_instance: 0, // Set to 0, indicates new combo
-                                    event: *event,
-                                    offset: next_pos,
-                                };
-                            } else {
-                                // No more combos, remove entry
-                                *status = StateStatus::Done;
-                            }
+                    // Only increment combo if combo has been fully executed/processed
+                    if completed_cond == result_guide.len() {
+                        if let Some(next_pos) = self.layer_lookup.next_result_combo(*guide, *offset)
+                        {
+                            // The next combo starts on the next processing loop
+                            *status = StateStatus::ResultPos {
+                                time_instance: self.time_instance.wrapping_add(1),
+                                event: *event,
+                                offset: next_pos,
+                            };
+                        } else {
+                            // No more combos, remove entry
+                            *status = StateStatus::Done;
                         }
                     }
                 }
             }
         }
 
+        for (run, event) in hold_tap_runs {
+            self.hold_tap_run(run, event, &mut results);
+        }
+
+        // Layer, latch and auto-shift capabilities are applied here rather than returned
+        let mut layer_runs = heapless::Vec::<_, LSIZE>::new();
+        results.retain(|run| {
+            if let CapabilityRun::LayerState { .. }
+            | CapabilityRun::LayerClear { .. }
+            | CapabilityRun::LayerRotate { .. }
+            | CapabilityRun::HidKeyboardLatch { .. }
+            | CapabilityRun::HidKeyboardAutoShift { .. }
+            | CapabilityRun::CapsWord { .. }
+            | CapabilityRun::HidKeyboardModMorph { .. } = run
+            {
+                // Same capacity, cannot fail
+                layer_runs.push(*run).ok();
+                false
+            } else {
+                true
+            }
+        });
+        let mut keyboard_runs = heapless::Vec::<_, LSIZE>::new();
+        for run in layer_runs {
+            match run {
+                CapabilityRun::HidKeyboardLatch { .. } => self.latch_run(run, &mut results),
+                CapabilityRun::HidKeyboardAutoShift { .. } => {
+                    self.auto_shift_run(run, &mut results)
+                }
+                CapabilityRun::CapsWord { .. } | CapabilityRun::HidKeyboardModMorph { .. } => {
+                    // Same capacity, cannot fail
+                    keyboard_runs.push(run).ok();
+                }
+                _ => self.layer_run(run),
+            }
+        }
+        self.process_latches(&mut results);
+
+        // Caps word and mod-morph use the modifiers held after this processing loop, their own
+        // modifier changes are not tracked
+        self.keyboard.track_modifiers(&results);
+        for run in keyboard_runs {
+            if let CapabilityRun::CapsWord { .. } = run {
+                self.caps_word_run(run, &mut results);
+            } else {
+                self.mod_morph_run(run, &mut results);
+            }
+        }
+        self.process_caps_word(&mut results);
+
         // Clear out StateStatus::Done entries
         // TODO(HaaTa): Is this optimal?
         for (guide, status) in self.lookup_state.clone().iter() {
@@ -620,9 +2211,6 @@ impl<
             }
         }
 
-        // Clear the trigger_combo_eval_state for the next scan iteration
-        self.trigger_combo_eval_state.clear();
-
         // Clear the off_state_lookups for the next scan iteration
         self.off_state_lookups.clear();
 
@@ -739,6 +2327,181 @@ impl<'a, const LAYOUT_SIZE: usize> LayerLookup<'a, LAYOUT_SIZE> {
         }
     }
 
+    /// Builds a LayerLookup from untrusted data (e.g. loaded from storage)
+    /// Unlike new, the layer lookup, every TriggerGuide and ResultGuide reachable from it and
+    /// the loop condition indices are validated first so the guides can be decoded safely.
+    pub fn try_new(
+        raw_layer_lookup: &'a [u8],
+        trigger_guides: &'a [u8],
+        result_guides: &'a [u8],
+        trigger_result_mapping: &'a [u16],
+        loop_condition_lookup: &'a [u32],
+    ) -> Result<Self, DecodeError> {
+        Self::try_new_with(
+            raw_layer_lookup,
+            trigger_guides,
+            result_guides,
+            trigger_result_mapping,
+            loop_condition_lookup,
+            &|_| Ok(()),
+        )
+    }
+
+    /// Same as try_new, check is also called for every Capability reachable from the layer
+    /// lookup (e.g. to validate string indices against the tables of a keymap blob)
+    pub(crate) fn try_new_with(
+        raw_layer_lookup: &'a [u8],
+        trigger_guides: &'a [u8],
+        result_guides: &'a [u8],
+        trigger_result_mapping: &'a [u16],
+        loop_condition_lookup: &'a [u32],
+        check: &impl Fn(&Capability) -> Result<(), DecodeError>,
+    ) -> Result<Self, DecodeError> {
+        let mut layer_lookup = FnvIndexMap::<(u8, u8, u16), usize, LAYOUT_SIZE>::new();
+        let mut max_layer = 0;
+
+        // Each entry is <layer>, <ttype>, <index:u16 le>, <size>, <size x trigger list id:u16 le>
+        let mut pos = 0;
+        while pos < raw_layer_lookup.len() {
+            let header = raw_layer_lookup
+                .get(pos..pos + 5)
+                .ok_or(DecodeError::InvalidLayerLookup(pos))?;
+            let (layer, ttype) = (header[0], header[1]);
+            let index = u16::from_le_bytes([header[2], header[3]]);
+            let size = header[4] as usize;
+            let triggers = raw_layer_lookup
+                .get(pos + 5..pos + 5 + size * 2)
+                .ok_or(DecodeError::InvalidLayerLookup(pos))?;
+
+            for chunk in triggers.chunks_exact(2) {
+                let id = u16::from_le_bytes([chunk[0], chunk[1]]);
+                let (trigger, result) =
+                    match trigger_result_mapping.get(id as usize..id as usize + 2) {
+                        Some(&[trigger, result]) => (trigger, result),
+                        _ => {
+                            return Err(DecodeError::InvalidMappingIndex(id));
+                        }
+                    };
+                Self::validate_trigger_guide(trigger_guides, trigger, loop_condition_lookup)?;
+                Self::validate_result_guide(
+                    result_guides,
+                    result,
+                    loop_condition_lookup,
+                    true,
+                    check,
+                )?;
+            }
+
+            if size > 0 {
+                if layer_lookup.insert((layer, ttype, index), pos + 4).is_err() {
+                    return Err(DecodeError::LayoutSizeExceeded);
+                }
+                max_layer = max_layer.max(layer);
+            }
+            pos += 5 + size * 2;
+        }
+
+        Ok(Self {
+            layer_lookup,
+            raw_layer_lookup,
+            trigger_guides,
+            result_guides,
+            trigger_result_mapping,
+            loop_condition_lookup,
+            max_layer,
+        })
+    }
+
+    /// Validates the TriggerGuide sequence starting at the given offset
+    /// A sequence is a list of combos (<count>, <count x TriggerCondition>) ending with a 0 count.
+    fn validate_trigger_guide(
+        trigger_guides: &[u8],
+        trigger: u16,
+        loop_condition_lookup: &[u32],
+    ) -> Result<(), DecodeError> {
+        let invalid = DecodeError::InvalidTriggerGuide(trigger);
+        let size = core::mem::size_of::<TriggerCondition>();
+        let mut pos = trigger as usize;
+        let mut combos = 0;
+        loop {
+            let count = *trigger_guides.get(pos).ok_or(invalid)? as usize;
+            if count == 0 {
+                break;
+            }
+            let combo = trigger_guides
+                .get(pos + 1..pos + 1 + count * size)
+                .ok_or(invalid)?;
+            for bytes in combo.chunks_exact(size) {
+                let cond = TriggerCondition::try_from_bytes(bytes)?;
+                if let Some(index) = cond.loop_condition_index()
+                    && index as usize >= loop_condition_lookup.len()
+                {
+                    return Err(DecodeError::InvalidLoopConditionIndex(index));
+                }
+            }
+            pos += 1 + count * size;
+            combos += 1;
+        }
+
+        // Sequences need at least one combo
+        if combos == 0 {
+            return Err(invalid);
+        }
+        Ok(())
+    }
+
+    /// Validates the ResultGuide sequence starting at the given offset
+    /// ResultGuides referenced by hold-taps and tap-dances are also validated if follow is set.
+    fn validate_result_guide(
+        result_guides: &[u8],
+        result: u16,
+        loop_condition_lookup: &[u32],
+        follow: bool,
+        check: &impl Fn(&Capability) -> Result<(), DecodeError>,
+    ) -> Result<(), DecodeError> {
+        let invalid = DecodeError::InvalidResultGuide(result);
+        let size = core::mem::size_of::<Capability>();
+        let mut pos = result as usize;
+        let mut combos = 0;
+        loop {
+            let count = *result_guides.get(pos).ok_or(invalid)? as usize;
+            if count == 0 {
+                break;
+            }
+            let combo = result_guides
+                .get(pos + 1..pos + 1 + count * size)
+                .ok_or(invalid)?;
+            for bytes in combo.chunks_exact(size) {
+                let cap = Capability::try_from_bytes(bytes)?;
+                let index = cap.loop_condition_index();
+                if index as usize >= loop_condition_lookup.len() {
+                    return Err(DecodeError::InvalidLoopConditionIndex(index));
+                }
+                check(&cap)?;
+                // Hold-taps do not nest, so references are only followed once
+                if follow && let Some(references) = cap.result_references() {
+                    for reference in references {
+                        Self::validate_result_guide(
+                            result_guides,
+                            reference,
+                            loop_condition_lookup,
+                            false,
+                            check,
+                        )?;
+                    }
+                }
+            }
+            pos += 1 + count * size;
+            combos += 1;
+        }
+
+        // Sequences need at least one combo
+        if combos == 0 {
+            return Err(invalid);
+        }
+        Ok(())
+    }
+
     /// Retrieves a TriggerList
     /// A TriggerList is a list of indices that correspond to a specific TriggerGuide -> ResultGuide
     /// mapping.
@@ -749,7 +2512,7 @@ impl<'a, const LAYOUT_SIZE: usize> LayerLookup<'a, LAYOUT_SIZE> {
             Some(lookup) => {
                 // Determine size of trigger list
                 trace!("raw_layer_lookup: {:?}", self.raw_layer_lookup);
-                let size: usize = self.raw_layer_lookup[*lookup].into();
+                let size: usize = (*self.raw_layer_lookup.get(*lookup)?).into();
 
                 // If the size is 0, just return None
                 if size == 0 {
@@ -761,7 +2524,7 @@ impl<'a, const LAYOUT_SIZE: usize> LayerLookup<'a, LAYOUT_SIZE> {
 
                 // Build TriggerList slice
                 let initial: usize = lookup + 1;
-                Some(&self.raw_layer_lookup[initial..initial + size])
+                self.raw_layer_lookup.get(initial..initial + size)
             }
             None => None,
         }
@@ -774,129 +2537,126 @@ impl<'a, const LAYOUT_SIZE: usize> LayerLookup<'a, LAYOUT_SIZE> {
         (layer, ttype, index): (u8, u8, u16),
     ) -> heapless::Vec<(u16, u16), LSIZE> {
         let mut guides = heapless::Vec::<_, LSIZE>::new();
+        if self
+            .collect_guides((layer, ttype, index), &mut guides)
+            .is_err()
+        {
+            error!("lookup_guides vector is full, increase LSIZE: {}", LSIZE);
+        }
+        guides
+    }
 
+    /// Adds the TriggerGuide:ResultGuide pairs of a (layer, ttype, index) tuple to guides
+    /// Stops once guides is full, the remaining pairs are dropped.
+    fn collect_guides<const LSIZE: usize>(
+        &self,
+        (layer, ttype, index): (u8, u8, u16),
+        guides: &mut heapless::Vec<(u16, u16), LSIZE>,
+    ) -> Result<(), ProcessError> {
         // Lookup TriggerList
-        match self.trigger_list((layer, ttype, index)) {
-            Some(mlookup) => {
-                // Iterate over each trigger to locate guides
-                // Each value is a u16 (hence chunking by 2)
-                trace!("mlookup: {:?}", mlookup);
-                for chunk in mlookup.chunks_exact(2) {
-                    // Determine guide lookup index
-                    let index = u16::from_le_bytes([chunk[0], chunk[1]]) as usize;
-
-                    // Push guide pair
-                    assert!(
-                        guides
-                            .push((
-                                self.trigger_result_mapping[index],
-                                self.trigger_result_mapping[index + 1]
-                            ))
-                            .is_ok(),
-                        "lookup_guides vector is full, increase LSIZE: {}",
-                        LSIZE
-                    );
-                }
-                trace!("guides: {:?}", guides);
-                guides
-            }
-            None => guides,
+        if let Some(mlookup) = self.trigger_list((layer, ttype, index)) {
+            // Iterate over each trigger to locate guides
+            // Each value is a u16 (hence chunking by 2)
+            trace!("mlookup: {:?}", mlookup);
+            for chunk in mlookup.chunks_exact(2) {
+                // Determine guide lookup index
+                let index = u16::from_le_bytes([chunk[0], chunk[1]]) as usize;
+
+                // Invalid mapping indices are skipped (see LayerLookup::try_new)
+                let guide = match self.trigger_result_mapping.get(index..index + 2) {
+                    Some(&[trigger, result]) => (trigger, result),
+                    _ => {
+                        error!("Invalid trigger:result mapping index: {}", index);
+                        continue;
+                    }
+                };
+
+                // Push guide pair
+                if guides.push(guide).is_err() {
+                    return Err(ProcessError::FailedGuideLookupPush);
+                }
+            }
+            trace!("guides: {:?}", guides);
         }
+        Ok(())
     }
 
     /// Retrieves the TriggerGuide for a given TriggerGuide:ResultGuide pair
     ///
     /// offset indicates the number of u8 positions the sequence is currently at.
     /// trigger + offset will always point to the start of a combination
+    /// Returns None at the end of the sequence, or if the combo is invalid.
     pub fn trigger_guide(
         &self,
         (trigger, _result): (u16, u16),
         offset: u16,
-    ) -> Option<&[TriggerCondition]> {
-        // Determine size of offset combo in the sequence
-        let count = self.trigger_guides[trigger as usize + offset as usize] as usize;
-        if count == 0 {
-            return None;
-        }
-
-        // Determine starting position of combo
-        let start = trigger as usize + offset as usize + 1;
-
-        // Convert u8 combo list to TriggerCondition list
-        let ptr: *const u8 =
-            self.trigger_guides[start..start + core::mem::size_of::<TriggerCondition>()].as_ptr();
-        let cond = unsafe { core::slice::from_raw_parts(ptr as *const TriggerCondition, count) };
-        Some(cond)
+    ) -> Option<GuideCombo<'a, TriggerCondition>> {
+        Self::combo(self.trigger_guides, trigger, offset)
     }
 
     /// Retrieves the ResultGuide for a given TriggerGuide:ResultGuide pair
     ///
     /// offset indicates the number of u8 positions the sequence is currently at.
     /// result + offset will always point to the start of a combination
+    /// Returns None at the end of the sequence, or if the combo is invalid.
     pub fn result_guide(
         &self,
         (_trigger, result): (u16, u16),
         offset: u16,
-    ) -> Option<&[Capability]> {
+    ) -> Option<GuideCombo<'a, Capability>> {
+        Self::combo(self.result_guides, result, offset)
+    }
+
+    /// Retrieves the combo of a TriggerGuide or ResultGuide at the given offset
+    fn combo<T: GuideElement>(
+        guides: &'a [u8],
+        guide: u16,
+        offset: u16,
+    ) -> Option<GuideCombo<'a, T>> {
         // Determine size of offset combo in the sequence
-        let count = self.result_guides[result as usize + offset as usize] as usize;
+        let pos = guide as usize + offset as usize;
+        let count = *guides.get(pos)? as usize;
         if count == 0 {
             return None;
         }
 
-        // Determine starting position of combo
-        let start = result as usize + offset as usize + 1;
-
-        // Convert u8 combo list to Capability list
-        let ptr: *const u8 =
-            self.result_guides[start..start + core::mem::size_of::<Capability>()].as_ptr();
-        let cond = unsafe { core::slice::from_raw_parts(ptr as *const Capability, count) };
-        Some(cond)
+        // Combo starts after the count
+        GuideCombo::new(guides.get(pos + 1..)?, count)
     }
 
     /// Determines the next trigger guide combo offset
     /// Returns Some if there is a next offset, None if the next combo is 0 length
     /// Will also return None if the current offset is also 0 (shouldn't be a common use case)
     pub fn next_trigger_combo(&self, (trigger, _result): (u16, u16), offset: u16) -> Option<u16> {
-        // Determine size of offset combo in the sequence
-        let count = self.trigger_guides[trigger as usize + offset as usize] as usize;
-        if count == 0 {
-            return None;
-        }
-
-        // New offset position
-        // +1 is added as the combo length count uses 1 byte
-        let offset = offset as usize + count * core::mem::size_of::<TriggerCondition>() + 1;
-
-        // Determine size of next combo
-        let count = self.trigger_guides[trigger as usize + offset] as usize;
-        if count == 0 {
-            None
-        } else {
-            Some(offset as u16)
-        }
+        Self::next_combo::<TriggerCondition>(self.trigger_guides, trigger, offset)
     }
 
     /// Determine the next result guide combo offset
     /// Returns Some if there is a next offset, None if the next combo is 0 length
     /// Will also return None if the current offset is also 0 (shouldn't be a common use case)
     pub fn next_result_combo(&self, (_trigger, result): (u16, u16), offset: u16) -> Option<u16> {
+        Self::next_combo::<Capability>(self.result_guides, result, offset)
+    }
+
+    /// Determines the offset of the combo following the given offset of a TriggerGuide or
+    /// ResultGuide
+    fn next_combo<T>(guides: &[u8], guide: u16, offset: u16) -> Option<u16> {
         // Determine size of offset combo in the sequence
-        let count = self.result_guides[result as usize + offset as usize] as usize;
+        let count = *guides.get(guide as usize + offset as usize)? as usize;
         if count == 0 {
             return None;
         }
 
         // New offset position
         // +1 is added as the combo length count uses 1 byte
-        let offset = offset as usize + count * core::mem::size_of::<Capability>() + 1;
+        let offset = offset as usize + count * core::mem::size_of::<T>() + 1;
 
         // Determine size of next combo
-        let count = self.result_guides[result as usize + offset] as usize;
+        let count = *guides.get(guide as usize + offset)?;
         if count == 0 {
             None
         } else {
-            Some(offset as u16)
+            u16::try_from(offset).ok()
         }
     }
 
@@ -0,0 +1,506 @@
+// Copyright 2021-2023 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! LayerState snapshots
+//!
+//! Serializes the dynamic state of a LayerState so it can be restored after deep sleep, a USB
+//! re-enumeration or a keymap hot-swap (or to replay a captured state in a test).
+//!
+//! Format (little-endian)
+//! - version: u8
+//! - time_instance: u32
+//! - layer count: u8, then per layer: state u8, last_time_instance u32
+//! - layer_stack length: u8, then one u8 layer per entry
+//! - LayerRotate position: u8
+//! - layer_stack_cache length: u16, then per entry:
+//!   ttype u8, index u16, layer u8, state u8, last_time_instance u32
+//! - lookup_state length: u16, then per entry:
+//!   trigger u16, result u16, kind u8, time_instance u32, offset u16
+//!   and for kind 1 (ResultPos) the initiating TriggerEvent (8 bytes)
+//!
+//! Pending hold-taps, combos, suppressed/deferred events, latched, auto-shift and mod-morph
+//! keys, held modifiers, caps word and previous analog values are not part of the snapshot.
+//! Keys pressed by that state are released by restore.
+
+// ----- Crates -----
+
+use super::*;
+use crate::handler::CapabilityFamily;
+use num_traits::FromPrimitive;
+
+// ----- Constants -----
+
+/// Current snapshot format version
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+const TRIGGER_POS: u8 = 0;
+const RESULT_POS: u8 = 1;
+
+const LAYER_SIZE: usize = 5;
+const CACHE_ENTRY_SIZE: usize = 9;
+const STATE_ENTRY_SIZE: usize = 11;
+const EVENT_SIZE: usize = core::mem::size_of::<TriggerEvent>();
+
+// ----- Enums -----
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SnapshotError {
+    /// Buffer is too small to hold the snapshot
+    BufferTooSmall,
+    /// Snapshot ends before all of the entries were read
+    Truncated,
+    /// Snapshot was written by an unsupported format version
+    UnsupportedVersion(u8),
+    /// Layer state could not be decoded
+    InvalidLayerState(u8),
+    /// The LayerState has more layers than the snapshot format supports (256)
+    TooManyLayers,
+    /// lookup_state entry kind could not be decoded
+    InvalidStateStatus(u8),
+    /// TriggerEvent of a lookup_state entry could not be decoded
+    Decode(DecodeError),
+}
+
+impl From<DecodeError> for SnapshotError {
+    fn from(err: DecodeError) -> Self {
+        SnapshotError::Decode(err)
+    }
+}
+
+// ----- Structs -----
+
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        self.buf
+            .get_mut(self.pos..self.pos + data.len())
+            .ok_or(SnapshotError::BufferTooSmall)?
+            .copy_from_slice(data);
+        self.pos += data.len();
+        Ok(())
+    }
+
+    fn u8(&mut self, val: u8) -> Result<(), SnapshotError> {
+        self.bytes(&[val])
+    }
+
+    fn u16(&mut self, val: u16) -> Result<(), SnapshotError> {
+        self.bytes(&val.to_le_bytes())
+    }
+
+    fn u32(&mut self, val: u32) -> Result<(), SnapshotError> {
+        self.bytes(&val.to_le_bytes())
+    }
+}
+
+struct Reader<'b> {
+    buf: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> Reader<'b> {
+    fn bytes(&mut self, len: usize) -> Result<&'b [u8], SnapshotError> {
+        let data = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or(SnapshotError::Truncated)?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        let data = self.bytes(2)?;
+        Ok(u16::from_le_bytes([data[0], data[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        let data = self.bytes(4)?;
+        Ok(u32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+    }
+
+    fn layer(&mut self) -> Result<Layer, SnapshotError> {
+        let state = self.u8()?;
+        Ok(Layer {
+            state: layer::State::from_u8(state).ok_or(SnapshotError::InvalidLayerState(state))?,
+            last_time_instance: self.u32()?,
+        })
+    }
+}
+
+// ----- Functions -----
+
+/// Determine if offset is the start of a combo in the guide sequence starting at start
+/// Every read is bounds checked as the offset may come from a different keymap.
+fn is_combo_offset(guides: &[u8], start: u16, size: usize, offset: u16) -> bool {
+    let mut pos = 0;
+    while let Some(&count) = guides.get(start as usize + pos) {
+        if count == 0 || pos > offset as usize {
+            return false;
+        }
+        if pos == offset as usize {
+            return true;
+        }
+        pos += count as usize * size + 1;
+    }
+    false
+}
+
+impl<
+        'a,
+        const LAYOUT_SIZE: usize,
+        const STATE_SIZE: usize,
+        const MAX_LAYERS: usize,
+        const MAX_ACTIVE_LAYERS: usize,
+        const MAX_ACTIVE_TRIGGERS: usize,
+        const MAX_LAYER_STACK_CACHE: usize,
+        const MAX_OFF_STATE_LOOKUP: usize,
+        const MAX_HOLD_TAPS: usize,
+        const MAX_DEFERRED_EVENTS: usize,
+        const MAX_SUPPRESSED_EVENTS: usize,
+        const MAX_LATCHED_KEYS: usize,
+        const MAX_LAYER_RULES: usize,
+        const MAX_ANALOG_INPUTS: usize,
+        const MAX_AUTO_SHIFT_KEYS: usize,
+        const MAX_MOD_MORPH_KEYS: usize,
+    >
+    LayerState<
+        'a,
+        LAYOUT_SIZE,
+        STATE_SIZE,
+        MAX_LAYERS,
+        MAX_ACTIVE_LAYERS,
+        MAX_ACTIVE_TRIGGERS,
+        MAX_LAYER_STACK_CACHE,
+        MAX_OFF_STATE_LOOKUP,
+        MAX_HOLD_TAPS,
+        MAX_DEFERRED_EVENTS,
+        MAX_SUPPRESSED_EVENTS,
+        MAX_LATCHED_KEYS,
+        MAX_LAYER_RULES,
+        MAX_ANALOG_INPUTS,
+        MAX_AUTO_SHIFT_KEYS,
+        MAX_MOD_MORPH_KEYS,
+    >
+{
+    /// Number of bytes needed to snapshot the current state
+    pub fn snapshot_len(&self) -> usize {
+        let results = self
+            .lookup_state
+            .values()
+            .filter(|status| matches!(status, StateStatus::ResultPos { .. }))
+            .count();
+        let states = self
+            .lookup_state
+            .values()
+            .filter(|status| !matches!(status, StateStatus::Done))
+            .count();
+
+        1 + 4
+            + 1
+            + self.layer.len() * LAYER_SIZE
+            + 1
+            + self.layer_stack.len()
+            + 1
+            + 2
+            + self.layer_stack_cache.len() * CACHE_ENTRY_SIZE
+            + 2
+            + states * STATE_ENTRY_SIZE
+            + results * EVENT_SIZE
+    }
+
+    /// Serializes the dynamic state (layers, layer stack and cache, in-flight trigger and
+    /// result positions) into buf
+    /// Returns the number of bytes written, see snapshot_len.
+    pub fn snapshot(&self, buf: &mut [u8]) -> Result<usize, SnapshotError> {
+        let mut w = Writer { buf, pos: 0 };
+        w.u8(SNAPSHOT_VERSION)?;
+        w.u32(self.time_instance)?;
+
+        w.u8(u8::try_from(self.layer.len()).map_err(|_| SnapshotError::TooManyLayers)?)?;
+        for layer in &self.layer {
+            w.u8(layer.state as u8)?;
+            w.u32(layer.last_time_instance)?;
+        }
+
+        w.u8(self.layer_stack.len() as u8)?;
+        w.bytes(&self.layer_stack)?;
+        w.u8(self.layer_control.rotate)?;
+
+        w.u16(self.layer_stack_cache.len() as u16)?;
+        for ((ttype, index), (layer, state)) in &self.layer_stack_cache {
+            w.u8(*ttype)?;
+            w.u16(*index)?;
+            w.u8(*layer)?;
+            w.u8(state.state as u8)?;
+            w.u32(state.last_time_instance)?;
+        }
+
+        // Finished entries are reaped on the next processing loop anyways
+        let states = self
+            .lookup_state
+            .values()
+            .filter(|status| !matches!(status, StateStatus::Done))
+            .count();
+        w.u16(states as u16)?;
+        for ((trigger, result), status) in &self.lookup_state {
+            match status {
+                StateStatus::TriggerPos {
+                    time_instance,
+                    offset,
+                } => {
+                    w.u16(*trigger)?;
+                    w.u16(*result)?;
+                    w.u8(TRIGGER_POS)?;
+                    w.u32(*time_instance)?;
+                    w.u16(*offset)?;
+                }
+                StateStatus::ResultPos {
+                    time_instance,
+                    event,
+                    offset,
+                } => {
+                    w.u16(*trigger)?;
+                    w.u16(*result)?;
+                    w.u8(RESULT_POS)?;
+                    w.u32(*time_instance)?;
+                    w.u16(*offset)?;
+                    w.bytes(&event.to_bytes())?;
+                }
+                StateStatus::Done => {}
+            }
+        }
+
+        Ok(w.pos)
+    }
+
+    /// Restores the dynamic state from a snapshot
+    /// The snapshot is fully decoded before anything is changed.
+    ///
+    /// The snapshot may have been taken with a different keymap (hot-swap), entries that do not
+    /// fit the current keymap (layers, trigger:result guides and offsets) are dropped.
+    /// Entries that do not fit the LayerState capacities are dropped and counted (see
+    /// overflow_count).
+    /// Pending hold-taps, combos, queued layer events, latched, auto-shift and mod-morph keys,
+    /// held modifiers, caps word and previous analog values are cleared.
+    ///
+    /// Returns the releases of the keys pressed by the cleared state, these are sent the same
+    /// way as the results of finalize_triggers.
+    pub fn restore<const LSIZE: usize>(
+        &mut self,
+        buf: &[u8],
+    ) -> Result<heapless::Vec<CapabilityRun, LSIZE>, SnapshotError> {
+        let mut r = Reader { buf, pos: 0 };
+        let version = r.u8()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let time_instance = r.u32()?;
+        // Only counted once the snapshot has been fully decoded
+        let mut overflows = OverflowCounters::default();
+
+        // Layers that no longer exist are ignored
+        let mut layers = self.layer.clone();
+        for pos in 0..r.u8()? as usize {
+            let layer = r.layer()?;
+            if let Some(cur) = layers.get_mut(pos) {
+                *cur = layer;
+            }
+        }
+        // Layer 0 is always enabled
+        layers[0].state = layer::State::Shift;
+
+        let mut layer_stack = Vec::<u8, MAX_ACTIVE_LAYERS>::new();
+        let stack_len = r.u8()? as usize;
+        for &layer in r.bytes(stack_len)? {
+            if layer == 0 || layer_stack.contains(&layer) {
+                continue;
+            }
+            if !layers
+                .get(layer as usize)
+                .is_some_and(|layer| layer.state.active())
+            {
+                continue;
+            }
+            if layer_stack.push(layer).is_err() {
+                overflows.record(ProcessError::FailedLayerStackPush);
+            }
+        }
+        // Layers that did not make it into the stack cannot stay enabled
+        for (pos, layer) in layers.iter_mut().enumerate().skip(1) {
+            if !layer_stack.contains(&(pos as u8)) {
+                layer.state = layer::State::Off;
+            }
+        }
+        // LayerRotate selects layer 0 if the layer no longer exists
+        let rotate = Some(r.u8()?)
+            .filter(|&rotate| (rotate as usize) < layers.len())
+            .unwrap_or(0);
+
+        let mut layer_stack_cache =
+            FnvIndexMap::<(u8, u16), (u8, Layer), MAX_LAYER_STACK_CACHE>::new();
+        for _ in 0..r.u16()? {
+            let ttype = r.u8()?;
+            let index = r.u16()?;
+            let layer = r.u8()?;
+            let state = r.layer()?;
+            if layer as usize >= layers.len() {
+                continue;
+            }
+            if layer_stack_cache
+                .insert((ttype, index), (layer, state))
+                .is_err()
+            {
+                overflows.record(ProcessError::FailedLayerStackCacheInsert);
+            }
+        }
+
+        let mut lookup_state = FnvIndexMap::<(u16, u16), StateStatus, STATE_SIZE>::new();
+        for _ in 0..r.u16()? {
+            let guide = (r.u16()?, r.u16()?);
+            let kind = r.u8()?;
+            let time_instance = r.u32()?;
+            let offset = r.u16()?;
+            let (status, valid) = match kind {
+                TRIGGER_POS => (
+                    StateStatus::TriggerPos {
+                        time_instance,
+                        offset,
+                    },
+                    is_combo_offset(
+                        self.layer_lookup.trigger_guides,
+                        guide.0,
+                        core::mem::size_of::<TriggerCondition>(),
+                        offset,
+                    ),
+                ),
+                RESULT_POS => (
+                    StateStatus::ResultPos {
+                        time_instance,
+                        event: TriggerEvent::try_from_bytes(r.bytes(EVENT_SIZE)?)?,
+                        offset,
+                    },
+                    is_combo_offset(
+                        self.layer_lookup.result_guides,
+                        guide.1,
+                        core::mem::size_of::<Capability>(),
+                        offset,
+                    ),
+                ),
+                _ => {
+                    return Err(SnapshotError::InvalidStateStatus(kind));
+                }
+            };
+            let mapped = self
+                .layer_lookup
+                .trigger_result_mapping
+                .chunks_exact(2)
+                .any(|pair| (pair[0], pair[1]) == guide);
+            if !valid || !mapped {
+                warn!("Dropping snapshot lookup_state: {:?} {:?}", guide, status);
+                continue;
+            }
+            if lookup_state.insert(guide, status).is_err() {
+                overflows.record(ProcessError::FailedLookupStateInsert);
+            }
+        }
+
+        // Everything decoded, apply the snapshot
+        let mut results = heapless::Vec::new();
+        self.release_unsaved(&mut results);
+        self.overflows.add(&overflows);
+        self.time_instance = time_instance;
+        self.layer = layers;
+        self.layer_stack = layer_stack;
+        self.layer_stack_cache = layer_stack_cache;
+        self.lookup_state = lookup_state;
+        self.layer_control.rotate = rotate;
+
+        self.trigger_combo_eval_state.clear();
+        self.off_state_lookups.clear();
+        self.hold_tap.active.clear();
+        self.hold_tap.deferred.clear();
+        self.hold_tap.deferred_index.clear();
+        self.hold_tap.tap_release.clear();
+        self.combo.suppressed.clear();
+        self.combo.consumed.clear();
+        self.combo.delayed.clear();
+        self.layer_control.events.clear();
+        self.latch.keys.clear();
+        self.latch.pressed = false;
+        self.auto_shift.keys.clear();
+        self.auto_shift.tap_release.clear();
+        self.keyboard = KeyboardState::new();
+        self.analog.previous.clear();
+
+        // The layers of active rules are part of the snapshot, only the rule state is rebuilt
+        for pos in 0..self.layer_control.rules.len() {
+            self.layer_control.rules[pos].active =
+                self.layer_rule_met(self.layer_control.rules[pos]);
+        }
+        Ok(results)
+    }
+
+    /// Releases the keys pressed by the state that is not part of the snapshot
+    /// (held hold-taps and taps, latched, auto-shift and mod-morph keys, held modifiers and the
+    /// caps word LeftShift)
+    /// Layer capabilities are not released, the layers are part of the snapshot.
+    fn release_unsaved<const LSIZE: usize>(
+        &mut self,
+        results: &mut heapless::Vec<CapabilityRun, LSIZE>,
+    ) {
+        let mut held = heapless::Vec::<_, LSIZE>::new();
+        for ht in self.hold_tap.active.clone() {
+            if let HoldTapDecision::Hold { result, combo } = ht.decision {
+                self.run_result((result, combo), ht.event, CapabilityEvent::Last, &mut held);
+            }
+        }
+        for (tap, combo, event) in self.hold_tap.tap_release.clone() {
+            self.run_result((tap, combo), event, CapabilityEvent::Last, &mut held);
+        }
+        for run in held {
+            if run.family() != CapabilityFamily::Layer {
+                push_result(results, run, &mut self.overflows);
+            }
+        }
+
+        // Unshifted auto-shift keys are only pressed once released (tap_release)
+        let shifted = self.auto_shift.keys.iter().filter(|key| key.shifted);
+        let shift = self.keyboard.caps_shift || shifted.clone().next().is_some();
+        let modifiers = (0..8)
+            .filter(|bit| self.keyboard.modifiers & (1 << bit) != 0)
+            .map(|bit| (kll_hid::Keyboard::LeftControl as u16 + bit).into());
+        let keys = self
+            .latch
+            .keys
+            .iter()
+            .map(|key| key.id)
+            .chain(shifted.map(|key| key.id))
+            .chain(self.auto_shift.tap_release.iter().copied())
+            .chain(self.keyboard.morphs.iter().map(|key| key.sent))
+            .chain(modifiers)
+            .chain(shift.then_some(kll_hid::Keyboard::LeftShift));
+        for id in keys {
+            let run = CapabilityRun::HidKeyboard {
+                state: CapabilityEvent::Last,
+                id,
+            };
+            // Keys may be pressed by more than one of these (e.g. LeftShift)
+            if !results.contains(&run) {
+                push_result(results, run, &mut self.overflows);
+            }
+        }
+    }
+}
@@ -91,6 +91,48 @@ macro_rules! trigger_guide_alt {
     }
 }
 
+/// LayerState sizing used by the layout processing tests
+type TestLayerState<'a> = LayerState<'a, 256, 256, 8, 8, 8, 8, 8>;
+
+/// Runs a single scan loop
+/// Processes each of the events then finalizes the results
+fn scan_loop(
+    state: &mut TestLayerState,
+    events: &[TriggerEvent],
+) -> heapless::Vec<CapabilityRun, 16> {
+    for event in events {
+        let ret = state.process_trigger::<16>(*event);
+        assert!(ret.is_ok(), "Failed to process {:?} - {:?}", event, ret);
+    }
+    let results = state.finalize_triggers::<16>();
+    state.increment_time();
+    trace!("Results: {:?}", results);
+    results
+}
+
+/// Switch press event
+fn press(index: u16) -> TriggerEvent {
+    TriggerEvent::Switch {
+        state: trigger::Phro::Press,
+        index,
+        last_state: 0,
+    }
+}
+
+/// Switch release event
+fn release(index: u16) -> TriggerEvent {
+    TriggerEvent::Switch {
+        state: trigger::Phro::Release,
+        index,
+        last_state: 0,
+    }
+}
+
+/// HID keyboard result
+fn key(state: CapabilityEvent, id: kll_hid::Keyboard) -> CapabilityRun {
+    CapabilityRun::HidKeyboard { state, id }
+}
+
 // ----- Tests -----
 
 #[test]
@@ -233,6 +275,8 @@ fn result_guide_macro() {
     const RESULT_GUIDE_COMPARE: &[u8] = &[
         // A + Shift
         2, 6, 1, 0, 0, 4, 0, 0, 0, 6, 1, 0, 0, 225, 0, 0, 0,
+        // End
+        0,
         // B
         1, 6, 2, 0, 0, 5, 0, 0, 0,
         // End
@@ -276,10 +320,10 @@ fn basic_init_test() {
     const TRIGGER_RESULT_MAPPING: &[u16] = &[
         // index: TriggerGuideIndex => ResultGuideIndex
         0, 0, // 0: 0 => 0
-        13, 35, // 2: 13 => 35
-        20, 44, // 4: 20 => 44
-        27, 0, // 6: 27 => 0
-        13, 44, // 8: 13 => 44
+        14, 36, // 2: 14 => 36
+        22, 46, // 4: 22 => 46
+        30, 0, // 6: 30 => 0
+        14, 46, // 8: 14 => 46
     ];
 
     // TriggerGuide layout
@@ -299,21 +343,21 @@ fn basic_init_test() {
                 loop_condition_index: 0,
             },
         ]],
-        // Index: 13
+        // Index: 14
         [[TriggerCondition::Switch {
             state: trigger::Phro::Hold,
             index: 6,
             loop_condition_index: 0,
         },]],
-        // Index: 20
+        // Index: 22
         [[TriggerCondition::Layer {
             state: trigger::LayerState::ShiftActivate,
             layer: 3,
             loop_condition_index: 0,
         },]],
-        // Index: 27
+        // Index: 30
         [[TriggerCondition::AnalogDistance {
-            reserved: 0,
+            mode: 0,
             index: 8,
             val: 1500,
         },]],
@@ -349,14 +393,14 @@ fn basic_init_test() {
             },],
         ],
         // Press B
-        // Index: 35
+        // Index: 36
         [[Capability::HidKeyboard {
             state: CapabilityState::Initial,
             loop_condition_index: 0,
             id: kll_hid::Keyboard::B,
         },]],
         // Release B
-        // Index: 44
+        // Index: 46
         [[Capability::HidKeyboard {
             state: CapabilityState::Last,
             loop_condition_index: 0,
@@ -378,6 +422,18 @@ fn basic_init_test() {
         LOOP_CONDITION_LOOKUP,
     );
 
+    // The validated lookup must match
+    let checked = LayerLookup::<256>::try_new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    )
+    .unwrap();
+    assert_eq!(checked.layer_lookup(), lookup.layer_lookup());
+    assert_eq!(checked.max_layers(), lookup.max_layers());
+
     // Print out valid lookups
     trace!("layer_lookup: {:?}", LAYER_LOOKUP);
     for ((layer, ttype, index), mlookup) in &lookup.layer_lookup {
@@ -477,18 +533,3748 @@ fn basic_init_test() {
             );
             assert_eq!(
                 trigger.unwrap(),
-                &triggers[index].unwrap()[0..trigger.unwrap().len()],
+                triggers[index].unwrap()[0..trigger.unwrap().len()],
                 "TriggerGuide did not match"
             );
             assert_eq!(
                 result.unwrap(),
-                &results[index].unwrap()[0..result.unwrap().len()],
+                results[index].unwrap()[0..result.unwrap().len()],
                 "ResultGuide did not match"
             );
         }
     }
 }
 
+#[test]
+fn non_switch_trigger_conditions() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, HidLed Type (2), NumLock (1)
+        0, 2, 1, [0],
+        // Layer 0, Layer Type (7), Layer 1
+        0, 7, 1, [2],
+        // Layer 0, Rotation Type (13), Index 0
+        0, 13, 0, [4],
+        // Layer 0, AnalogDistance Type (3), Index 8
+        0, 3, 8, [6],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 10, 16, 20, 24, 30];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::HidLed {
+            state: trigger::Aodo::Activate,
+            loop_condition_index: 0,
+            index: 1,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Layer {
+            state: trigger::LayerState::ShiftActivate,
+            loop_condition_index: 0,
+            layer: 1,
+        },]],
+        // Index: 16
+        [[TriggerCondition::Rotation {
+            index: 0,
+            loop_condition_index: 0,
+            position: 1,
+        },]],
+        // Index: 24
+        [[TriggerCondition::AnalogDistance {
+            mode: 0,
+            index: 8,
+            val: 1500,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 10
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+        },]],
+        // Index: 20
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::C,
+        },]],
+        // Index: 30
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::D,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+    let mut state = TestLayerState::new(lookup, 0);
+
+    let initial = |id| CapabilityRun::HidKeyboard {
+        state: CapabilityEvent::Initial,
+        id,
+    };
+
+    // Lock LED activation
+    let results = scan_loop(
+        &mut state,
+        &[TriggerEvent::HidLed {
+            state: trigger::Aodo::Activate,
+            index: 1,
+            last_state: 0,
+        }],
+    );
+    assert_eq!(results, [initial(kll_hid::Keyboard::A)]);
+
+    // Layer activation
+    let results = scan_loop(
+        &mut state,
+        &[TriggerEvent::Layer {
+            state: trigger::LayerState::ShiftActivate,
+            layer: 1,
+            last_state: 0,
+        }],
+    );
+    assert_eq!(results, [initial(kll_hid::Keyboard::B)]);
+
+    // Rotation in the wrong direction is ignored
+    let results = scan_loop(
+        &mut state,
+        &[TriggerEvent::Rotation {
+            index: 0,
+            position: -1,
+            last_state: 0,
+        }],
+    );
+    assert!(results.is_empty(), "Unexpected results: {:?}", results);
+
+    let results = scan_loop(
+        &mut state,
+        &[TriggerEvent::Rotation {
+            index: 0,
+            position: 1,
+            last_state: 0,
+        }],
+    );
+    assert_eq!(results, [initial(kll_hid::Keyboard::C)]);
+
+    // Analog threshold must be reached
+    let results = scan_loop(
+        &mut state,
+        &[TriggerEvent::AnalogDistance {
+            index: 8,
+            val: 1000,
+        }],
+    );
+    assert!(results.is_empty(), "Unexpected results: {:?}", results);
+
+    let results = scan_loop(
+        &mut state,
+        &[TriggerEvent::AnalogDistance {
+            index: 8,
+            val: 1600,
+        }],
+    );
+    assert_eq!(results, [initial(kll_hid::Keyboard::D)]);
+}
+
+#[test]
+fn result_loop_condition_timing() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1
+        0, 1, 1, [0],
+        // Layer 0, Switch Type (1), Index 2
+        0, 1, 2, [2],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 18];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            index: 1,
+            loop_condition_index: 0,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            index: 2,
+            loop_condition_index: 0,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Press A, release A after 3 loops
+        // Index: 0
+        [[
+            Capability::HidKeyboard {
+                state: CapabilityState::Initial,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::A,
+            },
+            Capability::HidKeyboard {
+                state: CapabilityState::Last,
+                loop_condition_index: 1,
+                id: kll_hid::Keyboard::A,
+            },
+        ]],
+        // Press B; wait 2 loops, press C; release B; release C
+        // Index: 18
+        [
+            [Capability::HidKeyboard {
+                state: CapabilityState::Initial,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::B,
+            },],
+            [Capability::HidKeyboard {
+                state: CapabilityState::Initial,
+                loop_condition_index: 2,
+                id: kll_hid::Keyboard::C,
+            },],
+            [Capability::HidKeyboard {
+                state: CapabilityState::Last,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::B,
+            },],
+            [Capability::HidKeyboard {
+                state: CapabilityState::Last,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::C,
+            },],
+        ],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0, 3, 2];
+
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+    let mut state = TestLayerState::new(lookup, 0);
+
+    let run = |state, id| CapabilityRun::HidKeyboard { state, id };
+    let press = |index| TriggerEvent::Switch {
+        state: trigger::Phro::Press,
+        index,
+        last_state: 0,
+    };
+
+    // Hold A for 3 scan loops
+    let mut loops = heapless::Vec::<_, 8>::new();
+    loops.push(scan_loop(&mut state, &[press(1)])).unwrap();
+    for _ in 0..4 {
+        loops.push(scan_loop(&mut state, &[])).unwrap();
+    }
+    assert_eq!(
+        loops[0],
+        [run(CapabilityEvent::Initial, kll_hid::Keyboard::A)]
+    );
+    assert!(loops[1].is_empty());
+    assert!(loops[2].is_empty());
+    assert_eq!(loops[3], [run(CapabilityEvent::Last, kll_hid::Keyboard::A)]);
+    assert!(loops[4].is_empty());
+
+    // Each combo starts after the previous one has finished
+    let mut loops = heapless::Vec::<_, 8>::new();
+    loops.push(scan_loop(&mut state, &[press(2)])).unwrap();
+    for _ in 0..6 {
+        loops.push(scan_loop(&mut state, &[])).unwrap();
+    }
+    assert_eq!(
+        loops[0],
+        [run(CapabilityEvent::Initial, kll_hid::Keyboard::B)]
+    );
+    assert!(loops[1].is_empty());
+    assert!(loops[2].is_empty());
+    assert_eq!(
+        loops[3],
+        [run(CapabilityEvent::Initial, kll_hid::Keyboard::C)]
+    );
+    assert_eq!(loops[4], [run(CapabilityEvent::Last, kll_hid::Keyboard::B)]);
+    assert_eq!(loops[5], [run(CapabilityEvent::Last, kll_hid::Keyboard::C)]);
+    assert!(loops[6].is_empty());
+}
+
+/// Hold-tap fixture
+/// Switch 1 is a hold-tap (tap A, hold LeftShift), Switch 2 is B
+/// Resolves a hold after 3 scan loops
+fn hold_tap_state(flavor: HoldTapFlavor) -> TestLayerState<'static> {
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1
+        0, 1, 1, [0, 2],
+        // Layer 0, Switch Type (1), Index 2
+        0, 1, 2, [4, 6],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 10, 16, 20, 24, 30];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            index: 1,
+            loop_condition_index: 0,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Release,
+            index: 1,
+            loop_condition_index: 0,
+        },]],
+        // Index: 16
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            index: 2,
+            loop_condition_index: 0,
+        },]],
+        // Index: 24
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Release,
+            index: 2,
+            loop_condition_index: 0,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::HoldTap {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            tap: 40,
+            hold: 50,
+        },]],
+        // Index: 10
+        [[Capability::HoldTap {
+            state: CapabilityState::Last,
+            loop_condition_index: 0,
+            tap: 40,
+            hold: 50,
+        },]],
+        // Index: 20
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+        },]],
+        // Index: 30
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Last,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+        },]],
+        // Tap result
+        // Index: 40
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Hold result
+        // Index: 50
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::LeftShift,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+    let mut state = TestLayerState::new(lookup, 0);
+    state.set_hold_tap_flavor(flavor);
+    state.set_hold_tap_threshold(3);
+    state
+}
+
+#[test]
+fn hold_tap_tap() {
+    setup_logging_lite().ok();
+
+    // Tap, released before the threshold
+    let mut state = hold_tap_state(HoldTapFlavor::HoldPreferred);
+    assert!(scan_loop(&mut state, &[press(1)]).is_empty());
+    assert_eq!(
+        scan_loop(&mut state, &[release(1)]),
+        [key(CapabilityEvent::Initial, kll_hid::Keyboard::A)]
+    );
+    assert_eq!(
+        scan_loop(&mut state, &[]),
+        [key(CapabilityEvent::Last, kll_hid::Keyboard::A)]
+    );
+    assert!(scan_loop(&mut state, &[]).is_empty());
+}
+
+#[test]
+fn hold_tap_hold() {
+    setup_logging_lite().ok();
+
+    // Hold, threshold reached
+    let mut state = hold_tap_state(HoldTapFlavor::HoldPreferred);
+    assert!(scan_loop(&mut state, &[press(1)]).is_empty());
+    assert!(scan_loop(&mut state, &[]).is_empty());
+    assert!(scan_loop(&mut state, &[]).is_empty());
+    assert_eq!(
+        scan_loop(&mut state, &[]),
+        [key(CapabilityEvent::Initial, kll_hid::Keyboard::LeftShift)]
+    );
+    assert_eq!(
+        scan_loop(&mut state, &[release(1)]),
+        [key(CapabilityEvent::Last, kll_hid::Keyboard::LeftShift)]
+    );
+}
+
+#[test]
+fn hold_tap_hold_preferred_interrupt() {
+    setup_logging_lite().ok();
+
+    // Another press resolves the hold immediately
+    let mut state = hold_tap_state(HoldTapFlavor::HoldPreferred);
+    assert!(scan_loop(&mut state, &[press(1)]).is_empty());
+    assert_eq!(
+        scan_loop(&mut state, &[press(2)]),
+        [
+            key(CapabilityEvent::Initial, kll_hid::Keyboard::LeftShift),
+            key(CapabilityEvent::Initial, kll_hid::Keyboard::B),
+        ]
+    );
+    assert_eq!(
+        scan_loop(&mut state, &[release(2)]),
+        [key(CapabilityEvent::Last, kll_hid::Keyboard::B)]
+    );
+    assert_eq!(
+        scan_loop(&mut state, &[release(1)]),
+        [key(CapabilityEvent::Last, kll_hid::Keyboard::LeftShift)]
+    );
+}
+
+#[test]
+fn hold_tap_balanced_nested_tap() {
+    setup_logging_lite().ok();
+
+    // A nested tap resolves the hold, the deferred release follows a loop later
+    let mut state = hold_tap_state(HoldTapFlavor::Balanced);
+    assert!(scan_loop(&mut state, &[press(1)]).is_empty());
+    assert!(scan_loop(&mut state, &[press(2)]).is_empty());
+    assert_eq!(
+        scan_loop(&mut state, &[release(2)]),
+        [
+            key(CapabilityEvent::Initial, kll_hid::Keyboard::LeftShift),
+            key(CapabilityEvent::Initial, kll_hid::Keyboard::B),
+        ]
+    );
+    assert_eq!(
+        scan_loop(&mut state, &[]),
+        [key(CapabilityEvent::Last, kll_hid::Keyboard::B)]
+    );
+    assert_eq!(
+        scan_loop(&mut state, &[release(1)]),
+        [key(CapabilityEvent::Last, kll_hid::Keyboard::LeftShift)]
+    );
+}
+
+#[test]
+fn hold_tap_tap_preferred_interrupt() {
+    setup_logging_lite().ok();
+
+    // Interrupted but released before the threshold is still a tap
+    let mut state = hold_tap_state(HoldTapFlavor::TapPreferred);
+    assert!(scan_loop(&mut state, &[press(1)]).is_empty());
+    assert!(scan_loop(&mut state, &[press(2)]).is_empty());
+    assert_eq!(
+        scan_loop(&mut state, &[release(1)]),
+        [key(CapabilityEvent::Initial, kll_hid::Keyboard::A)]
+    );
+    assert_eq!(
+        scan_loop(&mut state, &[]),
+        [
+            key(CapabilityEvent::Last, kll_hid::Keyboard::A),
+            key(CapabilityEvent::Initial, kll_hid::Keyboard::B),
+        ]
+    );
+    assert_eq!(
+        scan_loop(&mut state, &[release(2)]),
+        [key(CapabilityEvent::Last, kll_hid::Keyboard::B)]
+    );
+}
+
+#[test]
+fn hold_tap_permissive_hold_threshold() {
+    setup_logging_lite().ok();
+
+    // The threshold alone does not resolve a hold
+    let mut state = hold_tap_state(HoldTapFlavor::PermissiveHold);
+    assert!(scan_loop(&mut state, &[press(1)]).is_empty());
+    for _ in 0..4 {
+        assert!(scan_loop(&mut state, &[]).is_empty());
+    }
+    assert_eq!(
+        scan_loop(&mut state, &[release(1)]),
+        [key(CapabilityEvent::Initial, kll_hid::Keyboard::A)]
+    );
+    assert_eq!(
+        scan_loop(&mut state, &[]),
+        [key(CapabilityEvent::Last, kll_hid::Keyboard::A)]
+    );
+}
+
+#[test]
+fn hold_tap_permissive_hold_nested_tap() {
+    setup_logging_lite().ok();
+
+    // A nested tap resolves the hold
+    let mut state = hold_tap_state(HoldTapFlavor::PermissiveHold);
+    assert!(scan_loop(&mut state, &[press(1)]).is_empty());
+    assert!(scan_loop(&mut state, &[press(2)]).is_empty());
+    assert_eq!(
+        scan_loop(&mut state, &[release(2)]),
+        [
+            key(CapabilityEvent::Initial, kll_hid::Keyboard::LeftShift),
+            key(CapabilityEvent::Initial, kll_hid::Keyboard::B),
+        ]
+    );
+    assert_eq!(
+        scan_loop(&mut state, &[]),
+        [key(CapabilityEvent::Last, kll_hid::Keyboard::B)]
+    );
+    assert_eq!(
+        scan_loop(&mut state, &[release(1)]),
+        [key(CapabilityEvent::Last, kll_hid::Keyboard::LeftShift)]
+    );
+}
+
+/// Tap-dance fixture
+/// Switch 1 is a tap-dance (tap A/C, hold LeftShift/LeftControl), Switch 2 is B
+/// Resolves a hold after 3 scan loops
+fn tap_dance_state() -> TestLayerState<'static> {
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1
+        0, 1, 1, [0, 2],
+        // Layer 0, Switch Type (1), Index 2
+        0, 1, 2, [4, 6],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 10, 16, 20, 24, 30];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            index: 1,
+            loop_condition_index: 0,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Release,
+            index: 1,
+            loop_condition_index: 0,
+        },]],
+        // Index: 16
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            index: 2,
+            loop_condition_index: 0,
+        },]],
+        // Index: 24
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Release,
+            index: 2,
+            loop_condition_index: 0,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::TapDance {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            taps: 40,
+            holds: 59,
+        },]],
+        // Index: 10
+        [[Capability::TapDance {
+            state: CapabilityState::Last,
+            loop_condition_index: 0,
+            taps: 40,
+            holds: 59,
+        },]],
+        // Index: 20
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+        },]],
+        // Index: 30
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Last,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+        },]],
+        // Tap results (single, double)
+        // Index: 40
+        [
+            [Capability::HidKeyboard {
+                state: CapabilityState::Initial,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::A,
+            },],
+            [Capability::HidKeyboard {
+                state: CapabilityState::Initial,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::C,
+            },],
+        ],
+        // Hold results (single, double)
+        // Index: 59
+        [
+            [Capability::HidKeyboard {
+                state: CapabilityState::Initial,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::LeftShift,
+            },],
+            [Capability::HidKeyboard {
+                state: CapabilityState::Initial,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::LeftControl,
+            },],
+        ],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+    let mut state = TestLayerState::new(lookup, 0);
+    state.set_hold_tap_threshold(3);
+    state
+}
+
+#[test]
+fn tap_dance_single_tap() {
+    setup_logging_lite().ok();
+
+    // Single tap, resolved once the timeout after the release is reached
+    let mut state = tap_dance_state();
+    assert!(scan_loop(&mut state, &[press(1)]).is_empty());
+    assert!(scan_loop(&mut state, &[release(1)]).is_empty());
+    assert!(scan_loop(&mut state, &[]).is_empty());
+    assert!(scan_loop(&mut state, &[]).is_empty());
+    assert_eq!(
+        scan_loop(&mut state, &[]),
+        [key(CapabilityEvent::Initial, kll_hid::Keyboard::A)]
+    );
+    assert_eq!(
+        scan_loop(&mut state, &[]),
+        [key(CapabilityEvent::Last, kll_hid::Keyboard::A)]
+    );
+}
+
+#[test]
+fn tap_dance_double_tap() {
+    setup_logging_lite().ok();
+
+    // Double tap, resolved on release as there is no third tap result
+    let mut state = tap_dance_state();
+    assert!(scan_loop(&mut state, &[press(1)]).is_empty());
+    assert!(scan_loop(&mut state, &[release(1)]).is_empty());
+    assert!(scan_loop(&mut state, &[press(1)]).is_empty());
+    assert_eq!(
+        scan_loop(&mut state, &[release(1)]),
+        [key(CapabilityEvent::Initial, kll_hid::Keyboard::C)]
+    );
+    assert_eq!(
+        scan_loop(&mut state, &[]),
+        [key(CapabilityEvent::Last, kll_hid::Keyboard::C)]
+    );
+}
+
+#[test]
+fn tap_dance_hold() {
+    setup_logging_lite().ok();
+
+    // Single hold
+    let mut state = tap_dance_state();
+    assert!(scan_loop(&mut state, &[press(1)]).is_empty());
+    assert!(scan_loop(&mut state, &[]).is_empty());
+    assert!(scan_loop(&mut state, &[]).is_empty());
+    assert_eq!(
+        scan_loop(&mut state, &[]),
+        [key(CapabilityEvent::Initial, kll_hid::Keyboard::LeftShift)]
+    );
+    assert_eq!(
+        scan_loop(&mut state, &[release(1)]),
+        [key(CapabilityEvent::Last, kll_hid::Keyboard::LeftShift)]
+    );
+}
+
+#[test]
+fn tap_dance_tap_hold() {
+    setup_logging_lite().ok();
+
+    // Tap then hold
+    let mut state = tap_dance_state();
+    assert!(scan_loop(&mut state, &[press(1)]).is_empty());
+    assert!(scan_loop(&mut state, &[release(1)]).is_empty());
+    assert!(scan_loop(&mut state, &[press(1)]).is_empty());
+    assert!(scan_loop(&mut state, &[]).is_empty());
+    assert!(scan_loop(&mut state, &[]).is_empty());
+    assert_eq!(
+        scan_loop(&mut state, &[]),
+        [key(
+            CapabilityEvent::Initial,
+            kll_hid::Keyboard::LeftControl
+        )]
+    );
+    assert_eq!(
+        scan_loop(&mut state, &[release(1)]),
+        [key(CapabilityEvent::Last, kll_hid::Keyboard::LeftControl)]
+    );
+}
+
+#[test]
+fn tap_dance_interrupt() {
+    setup_logging_lite().ok();
+
+    // Another switch interrupts the dance, resolving the tap before the deferred press
+    let mut state = tap_dance_state();
+    assert!(scan_loop(&mut state, &[press(1)]).is_empty());
+    assert!(scan_loop(&mut state, &[release(1)]).is_empty());
+    assert_eq!(
+        scan_loop(&mut state, &[press(2)]),
+        [
+            key(CapabilityEvent::Initial, kll_hid::Keyboard::A),
+            key(CapabilityEvent::Initial, kll_hid::Keyboard::B),
+        ]
+    );
+    assert_eq!(
+        scan_loop(&mut state, &[]),
+        [key(CapabilityEvent::Last, kll_hid::Keyboard::A)]
+    );
+    assert_eq!(
+        scan_loop(&mut state, &[release(2)]),
+        [key(CapabilityEvent::Last, kll_hid::Keyboard::B)]
+    );
+}
+
+#[test]
+fn combo_timeout() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1
+        0, 1, 1, [0, 2, 8],
+        // Layer 0, Switch Type (1), Index 2
+        0, 1, 2, [4, 6, 8],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 10, 16, 20, 24, 30, 32, 40];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            index: 1,
+            loop_condition_index: 0,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Release,
+            index: 1,
+            loop_condition_index: 0,
+        },]],
+        // Index: 16
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            index: 2,
+            loop_condition_index: 0,
+        },]],
+        // Index: 24
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Release,
+            index: 2,
+            loop_condition_index: 0,
+        },]],
+        // S1 + S2
+        // Index: 32
+        [[
+            TriggerCondition::Switch {
+                state: trigger::Phro::Press,
+                index: 1,
+                loop_condition_index: 0,
+            },
+            TriggerCondition::Switch {
+                state: trigger::Phro::Press,
+                index: 2,
+                loop_condition_index: 0,
+            },
+        ]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 10
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Last,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 20
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+        },]],
+        // Index: 30
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Last,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+        },]],
+        // Index: 40
+        [[
+            Capability::HidKeyboard {
+                state: CapabilityState::Initial,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::C,
+            },
+            Capability::HidKeyboard {
+                state: CapabilityState::Last,
+                loop_condition_index: 1,
+                id: kll_hid::Keyboard::C,
+            },
+        ]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0, 1];
+
+    let new_state = |timeout, suppress| {
+        let lookup = LayerLookup::<256>::new(
+            LAYER_LOOKUP,
+            TRIGGER_GUIDES,
+            RESULT_GUIDES,
+            TRIGGER_RESULT_MAPPING,
+            LOOP_CONDITION_LOOKUP,
+        );
+        let mut state = TestLayerState::new(lookup, 0);
+        state.set_combo_timeout(timeout);
+        state.set_combo_suppression(suppress);
+        state
+    };
+
+    let run = |state, id| CapabilityRun::HidKeyboard { state, id };
+    let switch = |state, index| TriggerEvent::Switch {
+        state,
+        index,
+        last_state: 0,
+    };
+    let press = |index| switch(trigger::Phro::Press, index);
+    let release = |index| switch(trigger::Phro::Release, index);
+
+    let a_initial = run(CapabilityEvent::Initial, kll_hid::Keyboard::A);
+    let a_last = run(CapabilityEvent::Last, kll_hid::Keyboard::A);
+    let b_initial = run(CapabilityEvent::Initial, kll_hid::Keyboard::B);
+    let c_initial = run(CapabilityEvent::Initial, kll_hid::Keyboard::C);
+    let c_last = run(CapabilityEvent::Last, kll_hid::Keyboard::C);
+
+    // Default, combo conditions must be in the same processing loop
+    let mut state = new_state(0, false);
+    assert_eq!(scan_loop(&mut state, &[press(1)]), [a_initial]);
+    assert_eq!(scan_loop(&mut state, &[press(2)]), [b_initial]);
+
+    // Within the timeout, the individual switches still run
+    let mut state = new_state(2, false);
+    assert_eq!(scan_loop(&mut state, &[press(1)]), [a_initial]);
+    let results = scan_loop(&mut state, &[press(2)]);
+    assert_eq!(results.len(), 2);
+    assert!(results.contains(&b_initial));
+    assert!(results.contains(&c_initial));
+    assert_eq!(scan_loop(&mut state, &[]), [c_last]);
+
+    // Past the timeout
+    let mut state = new_state(2, false);
+    assert_eq!(scan_loop(&mut state, &[press(1)]), [a_initial]);
+    assert!(scan_loop(&mut state, &[]).is_empty());
+    assert!(scan_loop(&mut state, &[]).is_empty());
+    assert_eq!(scan_loop(&mut state, &[press(2)]), [b_initial]);
+
+    // Suppressed, only the combo runs (including the releases)
+    let mut state = new_state(2, true);
+    assert!(scan_loop(&mut state, &[press(1)]).is_empty());
+    assert_eq!(scan_loop(&mut state, &[press(2)]), [c_initial]);
+    assert_eq!(scan_loop(&mut state, &[]), [c_last]);
+    assert!(scan_loop(&mut state, &[release(1), release(2)]).is_empty());
+
+    // Suppressed, the press is replayed once the combo times out
+    let mut state = new_state(2, true);
+    assert!(scan_loop(&mut state, &[press(1)]).is_empty());
+    assert!(scan_loop(&mut state, &[]).is_empty());
+    assert_eq!(scan_loop(&mut state, &[]), [a_initial]);
+    assert_eq!(scan_loop(&mut state, &[release(1)]), [a_last]);
+
+    // Suppressed, releasing the switch abandons the combo
+    // The release is sent on the following processing loop
+    let mut state = new_state(2, true);
+    assert!(scan_loop(&mut state, &[press(1)]).is_empty());
+    assert_eq!(scan_loop(&mut state, &[release(1)]), [a_initial]);
+    assert_eq!(scan_loop(&mut state, &[]), [a_last]);
+}
+
+#[test]
+fn combo_replay_overflow() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1
+        0, 1, 1, [0, 6],
+        // Layer 0, Switch Type (1), Index 2
+        0, 1, 2, [6],
+        // Layer 0, Switch Type (1), Index 3
+        0, 1, 3, [2],
+        // Layer 0, Switch Type (1), Index 4
+        0, 1, 4, [4],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 10, 16, 28, 24, 46];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            index: 1,
+            loop_condition_index: 0,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            index: 3,
+            loop_condition_index: 0,
+        },]],
+        // Index: 16
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            index: 4,
+            loop_condition_index: 0,
+        },]],
+        // S1 + S2
+        // Index: 24
+        [[
+            TriggerCondition::Switch {
+                state: trigger::Phro::Press,
+                index: 1,
+                loop_condition_index: 0,
+            },
+            TriggerCondition::Switch {
+                state: trigger::Phro::Press,
+                index: 2,
+                loop_condition_index: 0,
+            },
+        ]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 10
+        [[
+            Capability::HidKeyboard {
+                state: CapabilityState::Initial,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::B,
+            },
+            Capability::HidKeyboard {
+                state: CapabilityState::Last,
+                loop_condition_index: 1,
+                id: kll_hid::Keyboard::B,
+            },
+        ]],
+        // Index: 28
+        [[
+            Capability::HidKeyboard {
+                state: CapabilityState::Initial,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::D,
+            },
+            Capability::HidKeyboard {
+                state: CapabilityState::Last,
+                loop_condition_index: 1,
+                id: kll_hid::Keyboard::D,
+            },
+        ]],
+        // Index: 46
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::C,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0, 100];
+
+    let lookup = LayerLookup::<256>::try_new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    )
+    .unwrap();
+    // Lookup state only has room for the results of S3 and S4
+    let mut state = LayerState::<256, 2, 8, 8, 8, 8, 8>::new(lookup, 0);
+    state.set_combo_timeout(2);
+    state.set_combo_suppression(true);
+
+    let switch = |state, index| TriggerEvent::Switch {
+        state,
+        index,
+        last_state: 0,
+    };
+    let press = |index| switch(trigger::Phro::Press, index);
+    let release = |index| switch(trigger::Phro::Release, index);
+
+    assert_eq!(state.process_trigger::<16>(press(3)), Ok(()));
+    assert_eq!(state.process_trigger::<16>(press(4)), Ok(()));
+    assert_eq!(state.process_trigger::<16>(press(1)), Ok(()));
+    assert_eq!(state.finalize_triggers::<16>().len(), 2);
+    state.increment_time();
+
+    // Replaying the suppressed press fails, the release is still queued
+    assert_eq!(
+        state.process_trigger::<16>(release(1)),
+        Err(ProcessError::FailedLookupStateInsert)
+    );
+    assert_eq!(
+        state.overflow_count(ProcessError::FailedLookupStateInsert),
+        1
+    );
+    assert_eq!(state.combo.delayed.len(), 1);
+    assert_eq!(state.combo.delayed[0].0, release(1));
+}
+
+#[test]
+fn sequence_timeout() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1
+        0, 1, 1, [0, 2, 8],
+        // Layer 0, Switch Type (1), Index 2
+        0, 1, 2, [4, 6, 8],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 10, 16, 20, 24, 30, 32, 40];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            index: 1,
+            loop_condition_index: 0,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Release,
+            index: 1,
+            loop_condition_index: 0,
+        },]],
+        // Index: 16
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            index: 2,
+            loop_condition_index: 0,
+        },]],
+        // Index: 24
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Release,
+            index: 2,
+            loop_condition_index: 0,
+        },]],
+        // S1, S2
+        // Index: 32
+        [
+            [TriggerCondition::Switch {
+                state: trigger::Phro::Press,
+                index: 1,
+                loop_condition_index: 0,
+            },],
+            [TriggerCondition::Switch {
+                state: trigger::Phro::Press,
+                index: 2,
+                loop_condition_index: 0,
+            },],
+        ],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 10
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Last,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 20
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+        },]],
+        // Index: 30
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Last,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+        },]],
+        // Index: 40
+        [[
+            Capability::HidKeyboard {
+                state: CapabilityState::Initial,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::C,
+            },
+            Capability::HidKeyboard {
+                state: CapabilityState::Last,
+                loop_condition_index: 1,
+                id: kll_hid::Keyboard::C,
+            },
+        ]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0, 1];
+
+    let new_state = || {
+        let lookup = LayerLookup::<256>::new(
+            LAYER_LOOKUP,
+            TRIGGER_GUIDES,
+            RESULT_GUIDES,
+            TRIGGER_RESULT_MAPPING,
+            LOOP_CONDITION_LOOKUP,
+        );
+        TestLayerState::new(lookup, 0)
+    };
+
+    let run = |state, id| CapabilityRun::HidKeyboard { state, id };
+    let switch = |state, index| TriggerEvent::Switch {
+        state,
+        index,
+        last_state: 0,
+    };
+    let press = |index| switch(trigger::Phro::Press, index);
+    let release = |index| switch(trigger::Phro::Release, index);
+
+    let a_initial = run(CapabilityEvent::Initial, kll_hid::Keyboard::A);
+    let a_last = run(CapabilityEvent::Last, kll_hid::Keyboard::A);
+    let b_initial = run(CapabilityEvent::Initial, kll_hid::Keyboard::B);
+    let c_initial = run(CapabilityEvent::Initial, kll_hid::Keyboard::C);
+    let c_last = run(CapabilityEvent::Last, kll_hid::Keyboard::C);
+
+    // Sequence, without a timeout
+    let mut state = new_state();
+    assert_eq!(scan_loop(&mut state, &[press(1)]), [a_initial]);
+    assert_eq!(scan_loop(&mut state, &[release(1)]), [a_last]);
+    for _ in 0..10 {
+        assert!(scan_loop(&mut state, &[]).is_empty());
+    }
+    let results = scan_loop(&mut state, &[press(2)]);
+    assert_eq!(results.len(), 2);
+    assert!(results.contains(&b_initial));
+    assert!(results.contains(&c_initial));
+    assert_eq!(scan_loop(&mut state, &[]), [c_last]);
+
+    // Unmatched switch press does not cancel the sequence by default
+    let mut state = new_state();
+    assert_eq!(scan_loop(&mut state, &[press(1)]), [a_initial]);
+    assert_eq!(scan_loop(&mut state, &[release(1)]), [a_last]);
+    assert!(scan_loop(&mut state, &[press(3)]).is_empty());
+    assert!(scan_loop(&mut state, &[press(2)]).contains(&c_initial));
+
+    // Unmatched switch press cancels the sequence
+    let mut state = new_state();
+    state.set_sequence_cancel(true);
+    assert_eq!(scan_loop(&mut state, &[press(1)]), [a_initial]);
+    assert_eq!(scan_loop(&mut state, &[release(1)]), [a_last]);
+    assert!(scan_loop(&mut state, &[press(3)]).is_empty());
+    assert_eq!(scan_loop(&mut state, &[press(2)]), [b_initial]);
+
+    // Within the timeout
+    let mut state = new_state();
+    state.set_sequence_timeout(Some(2));
+    assert_eq!(scan_loop(&mut state, &[press(1)]), [a_initial]);
+    assert_eq!(scan_loop(&mut state, &[release(1)]), [a_last]);
+    assert!(scan_loop(&mut state, &[press(2)]).contains(&c_initial));
+
+    // Past the timeout
+    let mut state = new_state();
+    state.set_sequence_timeout(Some(2));
+    assert_eq!(scan_loop(&mut state, &[press(1)]), [a_initial]);
+    assert_eq!(scan_loop(&mut state, &[release(1)]), [a_last]);
+    assert!(scan_loop(&mut state, &[]).is_empty());
+    assert_eq!(scan_loop(&mut state, &[press(2)]), [b_initial]);
+
+    // Leader mode, switches advancing the sequence only run the sequence
+    let mut state = new_state();
+    state.set_sequence_leader(true);
+    assert_eq!(scan_loop(&mut state, &[press(1)]), [a_initial]);
+    assert_eq!(scan_loop(&mut state, &[release(1)]), [a_last]);
+    assert_eq!(scan_loop(&mut state, &[press(2)]), [c_initial]);
+    assert_eq!(scan_loop(&mut state, &[]), [c_last]);
+    assert!(scan_loop(&mut state, &[release(2)]).is_empty());
+}
+
+#[test]
+fn off_state_lookups() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1
+        0, 1, 1, [0],
+        // Layer 0, HidLed Type (2), NumLock (1)
+        0, 2, 1, [0],
+        // Layer 0, Switch Type (1), Index 2
+        0, 1, 2, [2],
+        // Layer 0, Layer Type (7), Layer 1
+        0, 7, 1, [2],
+        // Layer 1, Switch Type (1), Index 3
+        1, 1, 3, [0],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 14, 10];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[
+            TriggerCondition::Switch {
+                state: trigger::Phro::Press,
+                loop_condition_index: 0,
+                index: 1,
+            },
+            TriggerCondition::HidLed {
+                state: trigger::Aodo::Off,
+                loop_condition_index: 0,
+                index: 1,
+            },
+        ]],
+        // Index: 14
+        [[
+            TriggerCondition::Switch {
+                state: trigger::Phro::Press,
+                loop_condition_index: 0,
+                index: 2,
+            },
+            TriggerCondition::Layer {
+                state: trigger::LayerState::ShiftOff,
+                loop_condition_index: 0,
+                layer: 1,
+            },
+        ]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 10
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+    let mut state = TestLayerState::new(lookup, 0);
+
+    let initial = |id| CapabilityRun::HidKeyboard {
+        state: CapabilityEvent::Initial,
+        id,
+    };
+    let press = |index| TriggerEvent::Switch {
+        state: trigger::Phro::Press,
+        index,
+        last_state: 0,
+    };
+    let release = |index| TriggerEvent::Switch {
+        state: trigger::Phro::Release,
+        index,
+        last_state: 0,
+    };
+    // Reports the given NumLock LED state, layer lookups must not reach the callback
+    let led = |led_state| {
+        move |ttype: u8, index: u16| {
+            assert_eq!((ttype, index), (2, 1), "Unexpected off state lookup");
+            let mut events = heapless::Vec::<_, 4>::new();
+            events
+                .push(TriggerEvent::HidLed {
+                    state: led_state,
+                    index: index as u8,
+                    last_state: 0,
+                })
+                .unwrap();
+            TriggerEventIterator::new(events)
+        }
+    };
+
+    // LED off, combo completes using the callback
+    state.process_trigger::<16>(press(1)).unwrap();
+    assert_eq!(state.off_state_lookups(), [((0, 0), 2, 1)]);
+    state
+        .process_off_state_lookups::<16, 4>(&led(trigger::Aodo::Off))
+        .unwrap();
+    let results = scan_loop(&mut state, &[]);
+    assert_eq!(results, [initial(kll_hid::Keyboard::A)]);
+    scan_loop(&mut state, &[release(1)]);
+    scan_loop(&mut state, &[]);
+
+    // LED on, combo does not complete
+    state.process_trigger::<16>(press(1)).unwrap();
+    state
+        .process_off_state_lookups::<16, 4>(&led(trigger::Aodo::On))
+        .unwrap();
+    let results = scan_loop(&mut state, &[]);
+    assert!(results.is_empty(), "Unexpected results: {:?}", results);
+    scan_loop(&mut state, &[release(1)]);
+
+    // Layer 1 off, resolved from the layer state
+    state.process_trigger::<16>(press(2)).unwrap();
+    assert_eq!(state.off_state_lookups(), [((14, 10), 7, 1)]);
+    state
+        .process_off_state_lookups::<16, 4>(&led(trigger::Aodo::Off))
+        .unwrap();
+    let results = scan_loop(&mut state, &[]);
+    assert_eq!(results, [initial(kll_hid::Keyboard::B)]);
+    scan_loop(&mut state, &[release(2)]);
+    scan_loop(&mut state, &[]);
+
+    // Layer 1 shifted, combo does not complete
+    state.set_layer(1, layer::State::Shift).unwrap();
+    state.process_trigger::<16>(press(2)).unwrap();
+    state
+        .process_off_state_lookups::<16, 4>(&led(trigger::Aodo::Off))
+        .unwrap();
+    let results = scan_loop(&mut state, &[]);
+    assert!(results.is_empty(), "Unexpected results: {:?}", results);
+}
+
+#[test]
+fn layer_stack_lookup() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1
+        0, 1, 1, [0],
+        // Layer 1, Switch Type (1), Index 1
+        1, 1, 1, [2],
+        // Layer 2, Switch Type (1), Index 1
+        2, 1, 1, [4],
+        // Layer 3, Switch Type (1), Index 1
+        3, 1, 1, [6],
+        // Layer 3, Switch Type (1), Index 2
+        3, 1, 2, [6],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 0, 10, 0, 20, 0, 30];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 1,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 10
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+        },]],
+        // Index: 20
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::C,
+        },]],
+        // Index: 30
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::D,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+    let mut state = TestLayerState::new(lookup, 0);
+
+    let switch = |state, index| TriggerEvent::Switch {
+        state,
+        index,
+        last_state: 0,
+    };
+    // Layer used for a press and its release
+    let tap = |state: &mut TestLayerState, index| {
+        let press = state
+            .lookup::<8>(switch(trigger::Phro::Press, index))
+            .map(|(layer, _guides)| layer);
+        let release = state
+            .lookup::<8>(switch(trigger::Phro::Release, index))
+            .map(|(layer, _guides)| layer);
+        assert_eq!(press, release, "Release used a different layer");
+        press
+    };
+
+    // Layer 0 is the fallback
+    assert_eq!(tap(&mut state, 1), Some(0));
+    assert_eq!(tap(&mut state, 2), None);
+
+    // Most recently activated layer wins, regardless of layer number or state
+    state.set_layer(3, layer::State::Lock).unwrap();
+    assert_eq!(tap(&mut state, 1), Some(3));
+    state.set_layer(2, layer::State::Latch).unwrap();
+    assert_eq!(tap(&mut state, 1), Some(2));
+    state.set_layer(1, layer::State::Shift).unwrap();
+    assert_eq!(tap(&mut state, 1), Some(1));
+
+    // Layers without a mapping fall through to the next layer in the stack
+    assert_eq!(tap(&mut state, 2), Some(3));
+
+    // Shift+Lock is not effective, continue down the stack
+    state.set_layer(1, layer::State::Lock).unwrap();
+    assert_eq!(tap(&mut state, 1), Some(2));
+
+    // Changing the state of a layer does not change its priority
+    state.set_layer(1, layer::State::Shift).unwrap();
+    assert_eq!(tap(&mut state, 1), Some(1));
+
+    // Deactivated layers are removed from the stack
+    state.set_layer(1, layer::State::Lock).unwrap();
+    assert_eq!(tap(&mut state, 1), Some(2));
+    state.set_layer(2, layer::State::Latch).unwrap();
+    assert_eq!(tap(&mut state, 1), Some(3));
+
+    // Reactivating a layer moves it to the top of the stack
+    state.set_layer(2, layer::State::Shift).unwrap();
+    assert_eq!(tap(&mut state, 1), Some(2));
+    state.set_layer(1, layer::State::Latch).unwrap();
+    assert_eq!(tap(&mut state, 1), Some(1));
+    state.set_layer(3, layer::State::Lock).unwrap();
+    state.set_layer(3, layer::State::Lock).unwrap();
+    assert_eq!(tap(&mut state, 1), Some(3));
+
+    // Releases use the layer of the press, even if the layer has been deactivated
+    let press = state.lookup::<8>(switch(trigger::Phro::Press, 1));
+    assert_eq!(press.map(|(layer, _guides)| layer), Some(3));
+    state.set_layer(3, layer::State::Lock).unwrap();
+    let release = state.lookup::<8>(switch(trigger::Phro::Release, 1));
+    assert_eq!(release.map(|(layer, _guides)| layer), Some(3));
+    assert_eq!(tap(&mut state, 1), Some(1));
+}
+
+#[test]
+fn layer_capabilities() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1-5
+        0, 1, 1, [0],
+        0, 1, 2, [2],
+        0, 1, 3, [4],
+        0, 1, 4, [6],
+        0, 1, 5, [8],
+        // Layer 1, Switch Type (1), Index 5
+        1, 1, 5, [10],
+        // Layer 2, Switch Type (1), Index 5
+        2, 1, 5, [12],
+        // Layer 0, Layer Type (7), Layer 1
+        0, 7, 1, [14],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] =
+        &[0, 0, 8, 10, 16, 20, 24, 30, 32, 40, 32, 50, 32, 60, 40, 70];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 1,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 2,
+        },]],
+        // Index: 16
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 3,
+        },]],
+        // Index: 24
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 4,
+        },]],
+        // Index: 32
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 5,
+        },]],
+        // Index: 40
+        [[TriggerCondition::Layer {
+            state: trigger::LayerState::ShiftActivate,
+            loop_condition_index: 0,
+            layer: 1,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::LayerState {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            layer: 1,
+            layer_state: layer::State::Shift,
+        },]],
+        // Index: 10
+        [[Capability::LayerState {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            layer: 2,
+            layer_state: layer::State::Lock,
+        },]],
+        // Index: 20
+        [[Capability::LayerRotate {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            direction: layer::Direction::Next,
+        },]],
+        // Index: 30
+        [[Capability::LayerClear {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+        },]],
+        // Index: 40
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 50
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+        },]],
+        // Index: 60
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::C,
+        },]],
+        // Index: 70
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::D,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+    let mut state = TestLayerState::new(lookup, 0);
+
+    let initial = |id| CapabilityRun::HidKeyboard {
+        state: CapabilityEvent::Initial,
+        id,
+    };
+    let switch = |state, index| TriggerEvent::Switch {
+        state,
+        index,
+        last_state: 0,
+    };
+    let press = |index| switch(trigger::Phro::Press, index);
+    let hold = |index| switch(trigger::Phro::Hold, index);
+    let release = |index| switch(trigger::Phro::Release, index);
+    // Result of tapping switch 5
+    let tap = |state: &mut TestLayerState| {
+        let results = scan_loop(state, &[press(5)]);
+        scan_loop(state, &[release(5)]);
+        results
+    };
+
+    // Shift layer 1 while switch 1 is held, layer capabilities are not returned
+    let results = scan_loop(&mut state, &[press(1)]);
+    assert!(results.is_empty(), "Unexpected results: {:?}", results);
+
+    // Layer event is processed on the next loop
+    let results = scan_loop(&mut state, &[hold(1)]);
+    assert_eq!(results, [initial(kll_hid::Keyboard::D)]);
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::B)]);
+
+    scan_loop(&mut state, &[release(1)]);
+    let results = scan_loop(&mut state, &[]);
+    assert!(results.is_empty(), "Unexpected results: {:?}", results);
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::A)]);
+
+    // Lock toggles on press
+    scan_loop(&mut state, &[press(2)]);
+    scan_loop(&mut state, &[release(2)]);
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::C)]);
+    scan_loop(&mut state, &[press(2)]);
+    scan_loop(&mut state, &[release(2)]);
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::A)]);
+
+    // Rotate through each layer, back to layer 0
+    let rotate = |state: &mut TestLayerState| {
+        scan_loop(state, &[press(3)]);
+        scan_loop(state, &[release(3)]);
+        state.rotate_layer()
+    };
+    assert_eq!(rotate(&mut state), 1);
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::B)]);
+    assert_eq!(rotate(&mut state), 2);
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::C)]);
+    assert_eq!(rotate(&mut state), 0);
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::A)]);
+
+    // Clear disables every layer
+    scan_loop(&mut state, &[press(2)]);
+    scan_loop(&mut state, &[release(2)]);
+    scan_loop(&mut state, &[press(1)]);
+    let results = scan_loop(&mut state, &[hold(1)]);
+    assert_eq!(results, [initial(kll_hid::Keyboard::D)]);
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::B)]);
+    scan_loop(&mut state, &[press(4)]);
+    // The deactivate events of each cleared layer are processed on the next loop
+    let layer_event = |state, layer| TriggerEvent::Layer {
+        state,
+        layer,
+        last_state: 0,
+    };
+    assert_eq!(
+        state.layer_control.events,
+        [
+            layer_event(trigger::LayerState::ShiftDeactivate, 1),
+            layer_event(trigger::LayerState::LockDeactivate, 2),
+        ]
+    );
+    assert!(state.layer_stack_cache.is_empty());
+    scan_loop(&mut state, &[release(4)]);
+    assert!(state.layer_control.events.is_empty());
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::A)]);
+
+    // Releasing the shift after the clear does not enable the layer again
+    scan_loop(&mut state, &[release(1)]);
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::A)]);
+}
+
+#[test]
+fn latch() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1-3
+        0, 1, 1, [0],
+        0, 1, 2, [2],
+        0, 1, 3, [4],
+        // Layer 1, Switch Type (1), Index 3
+        1, 1, 3, [6],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 10, 16, 20, 16, 30];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 1,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 2,
+        },]],
+        // Index: 16
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 3,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::LayerState {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            layer: 1,
+            layer_state: layer::State::Latch,
+        },]],
+        // Index: 10
+        [[Capability::HidKeyboardLatch {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::LeftShift,
+        },]],
+        // Index: 20
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 30
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+    let mut state = TestLayerState::new(lookup, 0);
+
+    let run = |state, id| CapabilityRun::HidKeyboard { state, id };
+    let switch = |state, index| TriggerEvent::Switch {
+        state,
+        index,
+        last_state: 0,
+    };
+    let press = |index| switch(trigger::Phro::Press, index);
+    let release = |index| switch(trigger::Phro::Release, index);
+    let passthrough = |event, id| run(CapabilityEvent::Passthrough(event), id);
+    let shift_initial = run(CapabilityEvent::Initial, kll_hid::Keyboard::LeftShift);
+    let shift_last = run(CapabilityEvent::Last, kll_hid::Keyboard::LeftShift);
+
+    // One-shot layer, only applies to the next press
+    scan_loop(&mut state, &[press(1)]);
+    scan_loop(&mut state, &[release(1)]);
+    scan_loop(&mut state, &[]);
+    let results = scan_loop(&mut state, &[press(3)]);
+    assert_eq!(results, [passthrough(press(3), kll_hid::Keyboard::B)]);
+
+    // Release is still routed to the latched layer
+    let results = scan_loop(&mut state, &[release(3)]);
+    assert_eq!(results, [passthrough(release(3), kll_hid::Keyboard::B)]);
+    let results = scan_loop(&mut state, &[press(3)]);
+    assert_eq!(results, [passthrough(press(3), kll_hid::Keyboard::A)]);
+    scan_loop(&mut state, &[release(3)]);
+
+    // Latching the layer again before using it unlatches it
+    scan_loop(&mut state, &[press(1)]);
+    scan_loop(&mut state, &[release(1)]);
+    scan_loop(&mut state, &[press(1)]);
+    scan_loop(&mut state, &[release(1)]);
+    let results = scan_loop(&mut state, &[press(3)]);
+    assert_eq!(results, [passthrough(press(3), kll_hid::Keyboard::A)]);
+    scan_loop(&mut state, &[release(3)]);
+
+    // One-shot modifier, released the loop after the next press
+    let results = scan_loop(&mut state, &[press(2)]);
+    assert_eq!(results, [shift_initial]);
+    let results = scan_loop(&mut state, &[release(2)]);
+    assert!(results.is_empty(), "Unexpected results: {:?}", results);
+    let results = scan_loop(&mut state, &[press(3)]);
+    assert_eq!(results, [passthrough(press(3), kll_hid::Keyboard::A)]);
+    let results = scan_loop(&mut state, &[release(3)]);
+    assert_eq!(
+        results,
+        [passthrough(release(3), kll_hid::Keyboard::A), shift_last]
+    );
+
+    // Latching the modifier again releases it
+    scan_loop(&mut state, &[press(2)]);
+    scan_loop(&mut state, &[release(2)]);
+    let results = scan_loop(&mut state, &[press(2)]);
+    assert_eq!(results, [shift_last]);
+    scan_loop(&mut state, &[release(2)]);
+    let results = scan_loop(&mut state, &[press(3)]);
+    assert_eq!(results, [passthrough(press(3), kll_hid::Keyboard::A)]);
+    scan_loop(&mut state, &[release(3)]);
+
+    // Unused latches expire
+    state.set_latch_timeout(Some(3));
+    scan_loop(&mut state, &[press(1), press(2)]);
+    scan_loop(&mut state, &[release(1), release(2)]);
+    let results = scan_loop(&mut state, &[]);
+    assert!(results.is_empty(), "Unexpected results: {:?}", results);
+    let results = scan_loop(&mut state, &[]);
+    assert_eq!(results, [shift_last]);
+    let results = scan_loop(&mut state, &[press(3)]);
+    assert_eq!(results, [passthrough(press(3), kll_hid::Keyboard::A)]);
+}
+
+#[test]
+fn layer_rules() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1-3
+        0, 1, 1, [0],
+        0, 1, 2, [2],
+        0, 1, 3, [4],
+        // Layer 1-3, Switch Type (1), Index 3
+        1, 1, 3, [6],
+        2, 1, 3, [8],
+        3, 1, 3, [10],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 10, 16, 20, 16, 30, 16, 40, 16, 50];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 1,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 2,
+        },]],
+        // Index: 16
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 3,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::LayerState {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            layer: 1,
+            layer_state: layer::State::Shift,
+        },]],
+        // Index: 10
+        [[Capability::LayerState {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            layer: 2,
+            layer_state: layer::State::Shift,
+        },]],
+        // Index: 20
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 30
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+        },]],
+        // Index: 40
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::C,
+        },]],
+        // Index: 50
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::D,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    // Lower (1) + Raise (2) -> Adjust (3)
+    const TRI_LAYER: &[TriggerCondition] = &[
+        TriggerCondition::Layer {
+            state: trigger::LayerState::ShiftOn,
+            loop_condition_index: 0,
+            layer: 1,
+        },
+        TriggerCondition::Layer {
+            state: trigger::LayerState::ShiftOn,
+            loop_condition_index: 0,
+            layer: 2,
+        },
+    ];
+
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+    let mut state = TestLayerState::new(lookup, 0);
+    state
+        .add_layer_rule(TRI_LAYER, 3, layer::State::Shift)
+        .unwrap();
+
+    // Rules cannot change layer 0 or layers outside of the layout, or set Off
+    for layer in [0, u8::MAX] {
+        assert_eq!(
+            state.add_layer_rule(TRI_LAYER, layer, layer::State::Shift),
+            Err(ProcessError::InvalidLayer)
+        );
+    }
+    assert_eq!(
+        state.add_layer_rule(TRI_LAYER, 3, layer::State::Off),
+        Err(ProcessError::InvalidLayer)
+    );
+    assert_eq!(state.overflow_count(ProcessError::InvalidLayer), 3);
+
+    let initial = |id| CapabilityRun::HidKeyboard {
+        state: CapabilityEvent::Initial,
+        id,
+    };
+    let switch = |state, index| TriggerEvent::Switch {
+        state,
+        index,
+        last_state: 0,
+    };
+    let press = |index| switch(trigger::Phro::Press, index);
+    let release = |index| switch(trigger::Phro::Release, index);
+    // Result of tapping switch 3
+    let tap = |state: &mut TestLayerState| {
+        let results = scan_loop(state, &[press(3)]);
+        scan_loop(state, &[release(3)]);
+        results
+    };
+
+    // Layer capabilities
+    scan_loop(&mut state, &[press(1)]);
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::B)]);
+    scan_loop(&mut state, &[press(2)]);
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::D)]);
+
+    // Releasing either layer disables the rule layer
+    scan_loop(&mut state, &[release(1)]);
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::C)]);
+    scan_loop(&mut state, &[press(1)]);
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::D)]);
+    scan_loop(&mut state, &[release(2)]);
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::B)]);
+    scan_loop(&mut state, &[release(1)]);
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::A)]);
+
+    // set_layer
+    state.set_layer(2, layer::State::Shift).unwrap();
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::C)]);
+    state.set_layer(1, layer::State::Shift).unwrap();
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::D)]);
+    state.set_layer(2, layer::State::Shift).unwrap();
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::B)]);
+    state.set_layer(1, layer::State::Shift).unwrap();
+    assert_eq!(tap(&mut state), [initial(kll_hid::Keyboard::A)]);
+}
+
+#[test]
+fn overflow_counters() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1
+        0, 1, 1, [0, 2, 4],
+        // Layer 1-3, Switch Type (1), Index 2
+        1, 1, 2, [6],
+        2, 1, 2, [6],
+        3, 1, 2, [6],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 0, 10, 0, 10, 8, 0];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 1,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 2,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 10
+        [[
+            Capability::HidKeyboard {
+                state: CapabilityState::Initial,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::B,
+            },
+            Capability::HidKeyboard {
+                state: CapabilityState::Initial,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::C,
+            },
+        ]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+
+    // Only room for 3 layers, a single active layer and a single layer rule
+    let mut state = LayerState::<256, 256, 3, 1, 8, 8, 8, 4, 8, 4, 4, 1>::new(lookup, 0);
+    assert_eq!(state.overflow_count(ProcessError::FailedLayerInit), 1);
+
+    // The third guide does not fit and is not evaluated
+    let press = TriggerEvent::Switch {
+        state: trigger::Phro::Press,
+        index: 1,
+        last_state: 0,
+    };
+    state.process_trigger::<2>(press).unwrap();
+    assert_eq!(state.overflow_count(ProcessError::FailedGuideLookupPush), 1);
+
+    // Only the first two of the three results fit
+    let initial = |id| CapabilityRun::HidKeyboard {
+        state: CapabilityEvent::Initial,
+        id,
+    };
+    let results = state.finalize_triggers::<2>();
+    assert_eq!(
+        results,
+        [initial(kll_hid::Keyboard::A), initial(kll_hid::Keyboard::B)]
+    );
+    assert_eq!(state.overflow_count(ProcessError::FailedResultPush), 1);
+    state.increment_time();
+
+    // The layer stack is full, the second layer is not changed
+    state.set_layer(1, layer::State::Shift).unwrap();
+    assert_eq!(
+        state.set_layer(2, layer::State::Shift),
+        Err(ProcessError::FailedLayerStackPush)
+    );
+    assert_eq!(state.overflow_count(ProcessError::FailedLayerStackPush), 1);
+    assert!(!state.is_layer_in_stack(2));
+
+    // Invalid layers are rejected and counted
+    for layer in [0, u8::MAX] {
+        assert_eq!(
+            state.set_layer(layer, layer::State::Lock),
+            Err(ProcessError::InvalidLayer)
+        );
+    }
+    assert_eq!(state.overflow_count(ProcessError::InvalidLayer), 2);
+
+    // Off cannot be set, on inactive or active layers
+    for layer in [2, 1] {
+        assert_eq!(
+            state.set_layer(layer, layer::State::Off),
+            Err(ProcessError::InvalidLayer)
+        );
+    }
+    assert_eq!(state.overflow_count(ProcessError::InvalidLayer), 4);
+    assert!(state.is_layer_in_stack(1));
+
+    // Only a single layer rule fits
+    const CONDITIONS: &[TriggerCondition] = &[TriggerCondition::Layer {
+        state: trigger::LayerState::ShiftOn,
+        loop_condition_index: 0,
+        layer: 1,
+    }];
+    state
+        .add_layer_rule(CONDITIONS, 2, layer::State::Shift)
+        .unwrap();
+    assert_eq!(
+        state.add_layer_rule(CONDITIONS, 2, layer::State::Lock),
+        Err(ProcessError::FailedLayerRulePush)
+    );
+    assert_eq!(state.overflow_count(ProcessError::FailedLayerRulePush), 1);
+
+    state.clear_overflow_counts();
+    assert_eq!(state.overflow_count(ProcessError::FailedLayerInit), 0);
+    assert_eq!(state.overflow_count(ProcessError::FailedResultPush), 0);
+}
+
+#[test]
+fn invalid_layer_capability() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1
+        0, 1, 1, [0],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 1,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::LayerState {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            layer: 5,
+            layer_state: layer::State::Shift,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+    let mut state = TestLayerState::new(lookup, 0);
+
+    // The layer is outside of the layout, the capability is ignored and counted
+    let press = TriggerEvent::Switch {
+        state: trigger::Phro::Press,
+        index: 1,
+        last_state: 0,
+    };
+    assert!(scan_loop(&mut state, &[press]).is_empty());
+    assert!(scan_loop(&mut state, &[]).is_empty());
+    assert_eq!(state.overflow_count(ProcessError::InvalidLayer), 1);
+}
+
+#[test]
+fn invalid_loop_condition_index() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1
+        0, 1, 1, [0],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 1,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[
+            Capability::HidKeyboard {
+                state: CapabilityState::Initial,
+                loop_condition_index: 5,
+                id: kll_hid::Keyboard::A,
+            },
+            Capability::HidKeyboard {
+                state: CapabilityState::Initial,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::B,
+            },
+        ]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    // LayerLookup::new skips validation, the loop condition index is outside of the lookup
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+    let mut state = TestLayerState::new(lookup, 0);
+
+    // The unscheduled capability is skipped
+    let press = TriggerEvent::Switch {
+        state: trigger::Phro::Press,
+        index: 1,
+        last_state: 0,
+    };
+    assert_eq!(
+        scan_loop(&mut state, &[press]),
+        [CapabilityRun::HidKeyboard {
+            state: CapabilityEvent::Initial,
+            id: kll_hid::Keyboard::B,
+        }]
+    );
+    assert!(scan_loop(&mut state, &[]).is_empty());
+}
+
+#[test]
+fn layer_lookup_validation() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1-3
+        0, 1, 1, [0],
+        0, 1, 2, [2],
+        0, 1, 3, [0],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 10];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 1,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 1,
+            index: 2,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 10
+        [[Capability::HoldTap {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            tap: 0,
+            hold: 0,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0, 2];
+
+    let try_new = |layer_lookup, trigger_guides, result_guides, mapping, loop_conditions| {
+        LayerLookup::<256>::try_new(
+            layer_lookup,
+            trigger_guides,
+            result_guides,
+            mapping,
+            loop_conditions,
+        )
+        .map(|lookup| lookup.max_layers())
+    };
+
+    assert_eq!(
+        try_new(
+            LAYER_LOOKUP,
+            TRIGGER_GUIDES,
+            RESULT_GUIDES,
+            TRIGGER_RESULT_MAPPING,
+            LOOP_CONDITION_LOOKUP
+        ),
+        Ok(1)
+    );
+
+    // Truncated trigger list
+    assert_eq!(
+        try_new(
+            &LAYER_LOOKUP[..LAYER_LOOKUP.len() - 1],
+            TRIGGER_GUIDES,
+            RESULT_GUIDES,
+            TRIGGER_RESULT_MAPPING,
+            LOOP_CONDITION_LOOKUP
+        ),
+        Err(DecodeError::InvalidLayerLookup(14))
+    );
+
+    // Trigger list index outside of the mapping
+    assert_eq!(
+        try_new(
+            LAYER_LOOKUP,
+            TRIGGER_GUIDES,
+            RESULT_GUIDES,
+            &TRIGGER_RESULT_MAPPING[..3],
+            LOOP_CONDITION_LOOKUP
+        ),
+        Err(DecodeError::InvalidMappingIndex(2))
+    );
+
+    // Guide offsets that do not point to a sequence
+    assert_eq!(
+        try_new(
+            LAYER_LOOKUP,
+            TRIGGER_GUIDES,
+            RESULT_GUIDES,
+            &[0, 0, 7, 10],
+            LOOP_CONDITION_LOOKUP
+        ),
+        Err(DecodeError::InvalidTriggerGuide(7))
+    );
+    assert_eq!(
+        try_new(
+            LAYER_LOOKUP,
+            TRIGGER_GUIDES,
+            RESULT_GUIDES,
+            &[0, 0, 8, 30],
+            LOOP_CONDITION_LOOKUP
+        ),
+        Err(DecodeError::InvalidResultGuide(30))
+    );
+
+    // Hold-tap result references are validated too
+    let mut result_guides = [0; 20];
+    result_guides.copy_from_slice(RESULT_GUIDES);
+    result_guides[15] = 19;
+    assert_eq!(
+        try_new(
+            LAYER_LOOKUP,
+            TRIGGER_GUIDES,
+            &result_guides,
+            TRIGGER_RESULT_MAPPING,
+            LOOP_CONDITION_LOOKUP
+        ),
+        Err(DecodeError::InvalidResultGuide(19))
+    );
+
+    // Unknown TriggerCondition
+    let mut trigger_guides = [0; 16];
+    trigger_guides.copy_from_slice(TRIGGER_GUIDES);
+    trigger_guides[9] = 0xF0;
+    assert_eq!(
+        try_new(
+            LAYER_LOOKUP,
+            &trigger_guides,
+            RESULT_GUIDES,
+            TRIGGER_RESULT_MAPPING,
+            LOOP_CONDITION_LOOKUP
+        ),
+        Err(DecodeError::InvalidType(0xF0))
+    );
+
+    // loop_condition_index outside of the loop condition lookup
+    assert_eq!(
+        try_new(
+            LAYER_LOOKUP,
+            TRIGGER_GUIDES,
+            RESULT_GUIDES,
+            TRIGGER_RESULT_MAPPING,
+            &[0]
+        ),
+        Err(DecodeError::InvalidLoopConditionIndex(1))
+    );
+
+    // Too many layer lookup entries for LAYOUT_SIZE
+    assert!(matches!(
+        LayerLookup::<2>::try_new(
+            LAYER_LOOKUP,
+            TRIGGER_GUIDES,
+            RESULT_GUIDES,
+            TRIGGER_RESULT_MAPPING,
+            LOOP_CONDITION_LOOKUP
+        ),
+        Err(DecodeError::LayoutSizeExceeded)
+    ));
+
+    // Unchecked lookups decode each combo on access, invalid combos and offsets are None
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        &trigger_guides,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+    assert_eq!(
+        lookup.trigger_guide((0, 0), 0).map(|combo| combo.len()),
+        Some(1)
+    );
+    assert!(lookup.trigger_guide((8, 10), 0).is_none());
+    assert!(lookup.trigger_guide((0, 0), 100).is_none());
+    assert!(lookup.result_guide((0, 10), 100).is_none());
+    assert!(lookup.next_result_combo((0, 10), 100).is_none());
+    assert_eq!(
+        lookup
+            .result_guide((0, 0), 0)
+            .and_then(|combo| combo.get(0)),
+        Some(Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        })
+    );
+}
+
+#[test]
+fn snapshot_restore() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1-3
+        0, 1, 1, [0],
+        0, 1, 2, [2],
+        0, 1, 3, [6],
+        0, 1, 4, [8],
+        // Layer 1, Switch Type (1), Index 2
+        1, 1, 2, [4],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 10, 8, 20, 16, 30, 24, 67];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 1,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 2,
+        },]],
+        // Index: 16
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 3,
+        },]],
+        // Index: 24
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 4,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::LayerState {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            layer: 1,
+            layer_state: layer::State::Lock,
+        },]],
+        // Index: 10
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 20
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::D,
+        },]],
+        // Press B; wait 2 loops, press C; release B; release C
+        // Index: 30
+        [
+            [Capability::HidKeyboard {
+                state: CapabilityState::Initial,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::B,
+            },],
+            [Capability::HidKeyboard {
+                state: CapabilityState::Initial,
+                loop_condition_index: 1,
+                id: kll_hid::Keyboard::C,
+            },],
+            [Capability::HidKeyboard {
+                state: CapabilityState::Last,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::B,
+            },],
+            [Capability::HidKeyboard {
+                state: CapabilityState::Last,
+                loop_condition_index: 0,
+                id: kll_hid::Keyboard::C,
+            },],
+        ],
+        // Index: 67
+        [[Capability::HidKeyboardLatch {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::LeftShift,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0, 2];
+
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+    let mut state = TestLayerState::new(lookup.clone(), 0);
+
+    let run = |state, id| CapabilityRun::HidKeyboard { state, id };
+    let switch = |state, index| TriggerEvent::Switch {
+        state,
+        index,
+        last_state: 0,
+    };
+    let press = |index| switch(trigger::Phro::Press, index);
+    let release = |index| switch(trigger::Phro::Release, index);
+
+    // Lock layer 1 and start the macro
+    scan_loop(&mut state, &[press(1)]);
+    scan_loop(&mut state, &[release(1)]);
+    let results = scan_loop(&mut state, &[press(3)]);
+    assert_eq!(
+        results,
+        [run(CapabilityEvent::Initial, kll_hid::Keyboard::B)]
+    );
+    scan_loop(&mut state, &[release(3)]);
+
+    let mut buf = [0u8; 128];
+    let len = state.snapshot(&mut buf).unwrap();
+    assert_eq!(len, state.snapshot_len());
+    assert_eq!(
+        state.snapshot(&mut buf[..len - 1]),
+        Err(SnapshotError::BufferTooSmall)
+    );
+
+    // Restoring into a fresh LayerState gives the same snapshot
+    let mut restored = TestLayerState::new(lookup.clone(), 0);
+    restored.restore::<8>(&buf[..len]).unwrap();
+    let mut check = [0u8; 128];
+    assert_eq!(restored.snapshot(&mut check), Ok(len));
+    assert_eq!(buf[..len], check[..len]);
+
+    // Both continue the macro and keep layer 1 locked
+    for state in [&mut state, &mut restored] {
+        let mut loops = heapless::Vec::<_, 8>::new();
+        for _ in 0..4 {
+            loops.push(scan_loop(state, &[])).unwrap();
+        }
+        assert!(loops[0].is_empty());
+        assert_eq!(
+            loops[1],
+            [run(CapabilityEvent::Initial, kll_hid::Keyboard::C)]
+        );
+        assert_eq!(loops[2], [run(CapabilityEvent::Last, kll_hid::Keyboard::B)]);
+        assert_eq!(loops[3], [run(CapabilityEvent::Last, kll_hid::Keyboard::C)]);
+
+        let results = scan_loop(state, &[press(2)]);
+        assert_eq!(
+            results,
+            [run(CapabilityEvent::Initial, kll_hid::Keyboard::D)]
+        );
+        scan_loop(state, &[release(2)]);
+    }
+
+    // Invalid snapshots do not change the state
+    let len = state.snapshot(&mut buf).unwrap();
+    let mut invalid = buf;
+    invalid[0] = SNAPSHOT_VERSION + 1;
+    assert_eq!(
+        restored.restore::<8>(&invalid[..len]),
+        Err(SnapshotError::UnsupportedVersion(SNAPSHOT_VERSION + 1))
+    );
+    assert_eq!(
+        restored.restore::<8>(&buf[..len - 1]),
+        Err(SnapshotError::Truncated)
+    );
+    invalid = buf;
+    invalid[6] = 0x08;
+    assert_eq!(
+        restored.restore::<8>(&invalid[..len]),
+        Err(SnapshotError::InvalidLayerState(0x08))
+    );
+    assert_eq!(restored.snapshot(&mut check), Ok(len));
+    assert_eq!(buf[..len], check[..len]);
+
+    // Keys pressed by state that is not part of the snapshot are released
+    let mut latched = TestLayerState::new(lookup.clone(), 0);
+    let results = scan_loop(&mut latched, &[press(4)]);
+    assert_eq!(
+        results,
+        [run(CapabilityEvent::Initial, kll_hid::Keyboard::LeftShift)]
+    );
+    scan_loop(&mut latched, &[release(4)]);
+    assert_eq!(
+        latched.restore::<8>(&buf[..len]).unwrap(),
+        [run(CapabilityEvent::Last, kll_hid::Keyboard::LeftShift)]
+    );
+    let results = scan_loop(&mut latched, &[press(2)]);
+    assert_eq!(
+        results,
+        [run(CapabilityEvent::Initial, kll_hid::Keyboard::D)]
+    );
+
+    // Guides that are not part of the keymap are dropped
+    const OTHER_MAPPING: &[u16] = &[0, 0, 8, 10, 8, 20];
+    let other = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        OTHER_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+    let mut state = TestLayerState::new(lookup, 0);
+    scan_loop(&mut state, &[press(3)]);
+    let len = state.snapshot(&mut buf).unwrap();
+    let mut swapped = TestLayerState::new(other, 0);
+    swapped.restore::<8>(&buf[..len]).unwrap();
+    assert!(swapped.snapshot_len() < len);
+    for _ in 0..4 {
+        let results = scan_loop(&mut swapped, &[]);
+        assert!(results.is_empty(), "Unexpected results: {:?}", results);
+    }
+}
+
+#[test]
+fn trace_replay() {
+    use crate::replay::{format_diffs, replay, Recorder, Trace, TraceError};
+    use std::string::ToString;
+
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1-2
+        0, 1, 1, [0],
+        0, 1, 2, [2],
+        // Layer 1, Switch Type (1), Index 2
+        1, 1, 2, [4],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 10, 8, 20];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 1,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 2,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::LayerState {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            layer: 1,
+            layer_state: layer::State::Shift,
+        },]],
+        // Index: 10
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 20
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    const FIXTURE: &str = include_str!("traces/layer_shift.trace");
+
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+
+    let switch = |state, index| TriggerEvent::Switch {
+        state,
+        index,
+        last_state: 0,
+    };
+    let press = |index| switch(trigger::Phro::Press, index);
+    let hold = |index| switch(trigger::Phro::Hold, index);
+    let release = |index| switch(trigger::Phro::Release, index);
+
+    // Record a session
+    let mut state = TestLayerState::new(lookup.clone(), 0);
+    let mut recorder = Recorder::new(&mut state);
+    for events in [
+        &[press(1)][..],
+        &[hold(1), press(2)],
+        &[hold(1), release(2)],
+        &[release(1)],
+        &[],
+        &[press(2)],
+        &[release(2)],
+    ] {
+        for event in events {
+            assert!(recorder.process_trigger::<16>(*event).is_ok());
+        }
+        recorder.finalize_triggers::<16>();
+        recorder.state().increment_time();
+    }
+    let trace = recorder.into_trace();
+    assert_eq!(Trace::parse(&trace.to_string()), Ok(trace.clone()));
+    assert_eq!(Trace::parse(FIXTURE), Ok(trace.clone()), "{}", trace);
+
+    // Replay the fixture
+    let trace = Trace::parse(FIXTURE).unwrap();
+    let mut state = TestLayerState::new(lookup.clone(), 0);
+    let diffs = replay::<16, _, _, _, _, _, _, _, _, _, _, _, _, _, _, _>(&mut state, &trace);
+    assert!(diffs.is_empty(), "{}", format_diffs(&diffs));
+
+    // Changed results are reported
+    let mut changed = trace.clone();
+    changed.loops[1].results.clear();
+    let mut state = TestLayerState::new(lookup, 0);
+    let diffs = replay::<16, _, _, _, _, _, _, _, _, _, _, _, _, _, _, _>(&mut state, &changed);
+    assert_eq!(diffs.len(), 1, "{}", format_diffs(&diffs));
+    assert_eq!(diffs[0].loop_index, 1);
+    assert!(diffs[0].expected.is_empty());
+    assert_eq!(diffs[0].found, trace.loops[1].results);
+
+    // Invalid traces
+    assert_eq!(Trace::parse("L 0\nE 00"), Err(TraceError::InvalidLine(2)));
+    assert_eq!(
+        Trace::parse("E 0000000000000000"),
+        Err(TraceError::MissingLoop(1))
+    );
+    assert_eq!(
+        Trace::parse("L 0\n\nE ff00000000000000"),
+        Err(TraceError::Decode(3, DecodeError::InvalidType(0xff)))
+    );
+    assert_eq!(
+        Trace::parse("L 0\nR ff000000000000000000000000000000"),
+        Err(TraceError::Decode(2, DecodeError::InvalidType(0xff)))
+    );
+}
+
+#[test]
+fn analog_compare() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, AnalogDistance Type (3), Index 1
+        0, 3, 1, [0, 2],
+        // Layer 0, AnalogVelocity Type (4), Index 1
+        0, 4, 1, [4],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 10, 16, 20];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::AnalogDistance {
+            mode: trigger::AnalogCompare::CrossUp.mode(0),
+            index: 1,
+            val: 350,
+        },]],
+        // Index: 8
+        [[TriggerCondition::AnalogDistance {
+            mode: trigger::AnalogCompare::CrossDown.mode(0),
+            index: 1,
+            val: 100,
+        },]],
+        // Index: 16
+        [[TriggerCondition::AnalogVelocity {
+            mode: trigger::AnalogCompare::Band.mode(4),
+            index: 1,
+            val: 1000,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 10
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+        },]],
+        // Index: 20
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::C,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    let lookup = LayerLookup::<256>::try_new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    )
+    .unwrap();
+    let mut state = TestLayerState::new(lookup, 0);
+
+    let initial = |id| CapabilityRun::HidKeyboard {
+        state: CapabilityEvent::Initial,
+        id,
+    };
+    let distance = |val| TriggerEvent::AnalogDistance { index: 1, val };
+    let velocity = |val| TriggerEvent::AnalogVelocity { index: 1, val };
+
+    // Crossing conditions only match once per crossing
+    for (event, expected) in [
+        (distance(100), None),
+        (distance(300), None),
+        (distance(360), Some(kll_hid::Keyboard::A)),
+        (distance(400), None),
+        (distance(200), None),
+        (distance(90), Some(kll_hid::Keyboard::B)),
+        (distance(50), None),
+        (distance(0), None),
+        (distance(350), Some(kll_hid::Keyboard::A)),
+        (velocity(980), None),
+        (velocity(1010), Some(kll_hid::Keyboard::C)),
+        (velocity(1016), Some(kll_hid::Keyboard::C)),
+        (velocity(1017), None),
+    ] {
+        let results = scan_loop(&mut state, &[event]);
+        match expected {
+            Some(id) => assert_eq!(results, [initial(id)], "{:?}", event),
+            None => assert!(results.is_empty(), "{:?}: {:?}", event, results),
+        }
+    }
+}
+
+#[test]
+fn mouse_keys() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1
+        0, 1, 1, [0],
+        // Layer 1, Switch Type (1), Index 2-4
+        1, 1, 2, [2],
+        1, 1, 3, [4],
+        1, 1, 4, [6],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 10, 16, 20, 24, 30];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 1,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 2,
+        },]],
+        // Index: 16
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 3,
+        },]],
+        // Index: 24
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 4,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::LayerState {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            layer: 1,
+            layer_state: layer::State::Shift,
+        },]],
+        // Index: 10
+        [[Capability::MouseMove {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            x: 1,
+            y: 0,
+            accel: mouse::Acceleration::Linear,
+            ramp: 4,
+        },]],
+        // Index: 20
+        [[Capability::MouseButton {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            button: 1,
+        },]],
+        // Index: 30
+        [[Capability::MouseWheel {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            amount: -1,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+    let mut state = TestLayerState::new(lookup, 0);
+
+    let switch = |state, index, last_state| TriggerEvent::Switch {
+        state,
+        index,
+        last_state,
+    };
+    let press = |index| switch(trigger::Phro::Press, index, 0);
+    let hold = |index, last_state| switch(trigger::Phro::Hold, index, last_state);
+    let release = |index, last_state| switch(trigger::Phro::Release, index, last_state);
+    let movement = |event, x| CapabilityRun::MouseMove {
+        state: CapabilityEvent::Passthrough(event),
+        x,
+        y: 0,
+    };
+
+    // Mouse keys are only available on the mouse layer
+    let results = scan_loop(&mut state, &[press(2)]);
+    assert!(results.is_empty(), "Unexpected results: {:?}", results);
+    scan_loop(&mut state, &[release(2, 1)]);
+    scan_loop(&mut state, &[press(1)]);
+
+    // Movement ramps up while held, then stays at the maximum speed
+    let results = scan_loop(&mut state, &[hold(1, 1), press(2)]);
+    assert_eq!(results, [movement(press(2), 1)]);
+    for (last_state, x) in [(1, 2), (2, 4), (3, 6), (4, 8), (10, 8)] {
+        let results = scan_loop(&mut state, &[hold(1, last_state + 1), hold(2, last_state)]);
+        assert_eq!(results, [movement(hold(2, last_state), x)]);
+    }
+    let results = scan_loop(&mut state, &[hold(1, 12), release(2, 11)]);
+    assert_eq!(results, [movement(release(2, 11), 8)]);
+
+    // Buttons follow the switch, the wheel scrolls once per press
+    let results = scan_loop(&mut state, &[hold(1, 13), press(3), press(4)]);
+    assert_eq!(
+        results,
+        [
+            CapabilityRun::MouseButton {
+                state: CapabilityEvent::Passthrough(press(3)),
+                button: 1,
+            },
+            CapabilityRun::MouseWheel {
+                state: CapabilityEvent::Initial,
+                amount: -1,
+            },
+        ]
+    );
+    let results = scan_loop(&mut state, &[hold(1, 14), hold(3, 1), hold(4, 1)]);
+    assert_eq!(
+        results,
+        [CapabilityRun::MouseButton {
+            state: CapabilityEvent::Passthrough(hold(3, 1)),
+            button: 1,
+        }]
+    );
+}
+
+#[test]
+fn gamepad_axis() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, AnalogDistance Type (3), Index 1-2
+        0, 3, 1, [0],
+        0, 3, 2, [2],
+        // Layer 0, Switch Type (1), Index 3
+        0, 1, 3, [4],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 10, 16, 20];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::AnalogDistance {
+            mode: trigger::AnalogCompare::GreaterEqual.mode(0),
+            index: 1,
+            val: 0,
+        },]],
+        // Index: 8
+        [[TriggerCondition::AnalogDistance {
+            mode: trigger::AnalogCompare::GreaterEqual.mode(0),
+            index: 2,
+            val: 0,
+        },]],
+        // Index: 16
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 3,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::GamepadAxis {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            axis: gamepad::Axis::X,
+            direction: gamepad::Direction::Negative,
+            max: 400,
+        },]],
+        // Index: 10
+        [[Capability::GamepadAxis {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            axis: gamepad::Axis::X,
+            direction: gamepad::Direction::Positive,
+            max: 400,
+        },]],
+        // Index: 20
+        [[Capability::GamepadAxis {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            axis: gamepad::Axis::Y,
+            direction: gamepad::Direction::Negative,
+            max: 0,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    let lookup = LayerLookup::<256>::try_new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    )
+    .unwrap();
+    let mut state = TestLayerState::new(lookup, 0);
+
+    let distance = |index, val| TriggerEvent::AnalogDistance { index, val };
+    let axis = |event, axis, direction, value| CapabilityRun::GamepadAxis {
+        state: CapabilityEvent::Passthrough(event),
+        axis,
+        direction,
+        value,
+    };
+
+    // Analog keys drive their half of the axis proportionally
+    for (event, direction, value) in [
+        (distance(1, 100), gamepad::Direction::Negative, 31),
+        (distance(1, 200), gamepad::Direction::Negative, 63),
+        (distance(2, 400), gamepad::Direction::Positive, 127),
+        (distance(2, 800), gamepad::Direction::Positive, 127),
+        (distance(1, 0), gamepad::Direction::Negative, 0),
+    ] {
+        let results = scan_loop(&mut state, &[event]);
+        assert_eq!(results, [axis(event, gamepad::Axis::X, direction, value)]);
+    }
+
+    // Switches are fully deflected while held
+    for (event, value) in [
+        (
+            TriggerEvent::Switch {
+                state: trigger::Phro::Press,
+                index: 3,
+                last_state: 0,
+            },
+            127,
+        ),
+        (
+            TriggerEvent::Switch {
+                state: trigger::Phro::Hold,
+                index: 3,
+                last_state: 1,
+            },
+            127,
+        ),
+        (
+            TriggerEvent::Switch {
+                state: trigger::Phro::Release,
+                index: 3,
+                last_state: 2,
+            },
+            0,
+        ),
+    ] {
+        let results = scan_loop(&mut state, &[event]);
+        assert_eq!(
+            results,
+            [axis(
+                event,
+                gamepad::Axis::Y,
+                gamepad::Direction::Negative,
+                value
+            )]
+        );
+    }
+}
+
+#[test]
+fn system_events() {
+    setup_logging_lite().ok();
+
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1
+        0, 1, 1, [0],
+        // Layer 0, Sleep Type (9), Resume Type (10)
+        0, 9, 0, [2],
+        0, 10, 0, [4],
+        // Layer 0, Inactive Type (11), Active Type (12)
+        0, 11, 0, [6],
+        0, 12, 0, [8],
+        // Layer 1, Switch Type (1), Index 1
+        1, 1, 1, [10],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 10, 16, 20, 24, 30, 32, 40, 0, 50];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 1,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Sleep {
+            state: trigger::Aodo::Activate,
+            loop_condition_index: 0,
+        },]],
+        // Index: 16
+        [[TriggerCondition::Resume {
+            state: trigger::Aodo::Activate,
+            loop_condition_index: 0,
+        },]],
+        // Index: 24
+        [[TriggerCondition::Inactive {
+            state: trigger::Aodo::Activate,
+            loop_condition_index: 0,
+        },]],
+        // Index: 32
+        [[TriggerCondition::Active {
+            state: trigger::Aodo::Activate,
+            loop_condition_index: 0,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 10
+        [[Capability::PixelLedControl {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            mode: pixel::LedControl::DisableLeds,
+            amount: 0,
+        },]],
+        // Index: 20
+        [[Capability::PixelLedControl {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            mode: pixel::LedControl::EnableLeds,
+            amount: 0,
+        },]],
+        // Index: 30
+        [[Capability::LayerState {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+            layer: 1,
+            layer_state: layer::State::Lock,
+        },]],
+        // Index: 40
+        [[Capability::LayerClear {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+        },]],
+        // Index: 50
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    let lookup = LayerLookup::<256>::try_new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    )
+    .unwrap();
+    let mut state = TestLayerState::new(lookup, 0);
+    let mut system = crate::system::SystemState::new();
+
+    let leds = |mode| CapabilityRun::PixelLedControl {
+        state: CapabilityEvent::Initial,
+        mode,
+        amount: 0,
+    };
+    let press = TriggerEvent::Switch {
+        state: trigger::Phro::Press,
+        index: 1,
+        last_state: 0,
+    };
+    let key = |id| CapabilityRun::HidKeyboard {
+        state: CapabilityEvent::Passthrough(press),
+        id,
+    };
+    let mut system_loop = |state: &mut TestLayerState, suspended, idle| {
+        let events: heapless::Vec<TriggerEvent, 4> = system.update(suspended, idle).collect();
+        scan_loop(state, &events)
+    };
+
+    // LEDs are turned off while suspended
+    let results = system_loop(&mut state, true, false);
+    assert_eq!(results, [leds(pixel::LedControl::DisableLeds)]);
+    let results = system_loop(&mut state, true, false);
+    assert!(results.is_empty(), "Unexpected results: {:?}", results);
+    let results = system_loop(&mut state, false, false);
+    assert_eq!(results, [leds(pixel::LedControl::EnableLeds)]);
+
+    // Switch layers after inactivity, until a key is used again
+    // (layer capabilities are applied internally, not returned)
+    let results = system_loop(&mut state, false, true);
+    assert!(results.is_empty(), "Unexpected results: {:?}", results);
+    let results = scan_loop(&mut state, &[press]);
+    assert_eq!(results, [key(kll_hid::Keyboard::B)]);
+    let results = system_loop(&mut state, false, false);
+    assert!(results.is_empty(), "Unexpected results: {:?}", results);
+    let results = scan_loop(&mut state, &[press]);
+    assert_eq!(results, [key(kll_hid::Keyboard::A)]);
+}
+
+/// Auto-shift and key repeat fixture
+/// Switch 1 auto-shifts A after 3 loops, Switch 2 repeats B every 2 loops after a 3 loop delay
+fn auto_shift_state() -> TestLayerState<'static> {
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1-2
+        0, 1, 1, [0],
+        0, 1, 2, [2],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 10];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 1,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 2,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::HidKeyboardAutoShift {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+            threshold: 3,
+        },]],
+        // Index: 10
+        [[Capability::HidKeyboardRepeat {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::B,
+            rate: 2,
+            delay: 3,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+    TestLayerState::new(lookup, 0)
+}
+
+/// Switch hold event
+fn hold(index: u16, last_state: u32) -> TriggerEvent {
+    TriggerEvent::Switch {
+        state: trigger::Phro::Hold,
+        index,
+        last_state,
+    }
+}
+
+#[test]
+fn auto_shift_tap() {
+    setup_logging_lite().ok();
+
+    // Tapping the auto-shift key sends the unshifted key on release
+    let mut state = auto_shift_state();
+    let results = scan_loop(&mut state, &[press(1)]);
+    assert!(results.is_empty(), "Unexpected results: {:?}", results);
+    let results = scan_loop(&mut state, &[hold(1, 1)]);
+    assert!(results.is_empty(), "Unexpected results: {:?}", results);
+    let results = scan_loop(&mut state, &[release(1)]);
+    assert_eq!(
+        results,
+        [key(CapabilityEvent::Initial, kll_hid::Keyboard::A)]
+    );
+    let results = scan_loop(&mut state, &[]);
+    assert_eq!(results, [key(CapabilityEvent::Last, kll_hid::Keyboard::A)]);
+}
+
+#[test]
+fn auto_shift_hold() {
+    setup_logging_lite().ok();
+
+    // Holding it past the threshold sends the shifted key until released
+    let mut state = auto_shift_state();
+    let results = scan_loop(&mut state, &[press(1)]);
+    assert!(results.is_empty(), "Unexpected results: {:?}", results);
+    for last_state in 1..3 {
+        let results = scan_loop(&mut state, &[hold(1, last_state)]);
+        assert!(results.is_empty(), "Unexpected results: {:?}", results);
+    }
+    let results = scan_loop(&mut state, &[hold(1, 3)]);
+    assert_eq!(
+        results,
+        [
+            key(CapabilityEvent::Initial, kll_hid::Keyboard::LeftShift),
+            key(CapabilityEvent::Initial, kll_hid::Keyboard::A)
+        ]
+    );
+    let results = scan_loop(&mut state, &[hold(1, 4)]);
+    assert!(results.is_empty(), "Unexpected results: {:?}", results);
+    let results = scan_loop(&mut state, &[release(1)]);
+    assert_eq!(
+        results,
+        [
+            key(CapabilityEvent::Last, kll_hid::Keyboard::A),
+            key(CapabilityEvent::Last, kll_hid::Keyboard::LeftShift)
+        ]
+    );
+}
+
+#[test]
+fn key_repeat() {
+    setup_logging_lite().ok();
+
+    // The repeat key is released and pressed again after the delay
+    let mut state = auto_shift_state();
+    let results = scan_loop(&mut state, &[press(2)]);
+    assert_eq!(
+        results,
+        [key(CapabilityEvent::Initial, kll_hid::Keyboard::B)]
+    );
+    for (last_state, run) in [
+        (1, key(CapabilityEvent::None, kll_hid::Keyboard::B)),
+        (2, key(CapabilityEvent::None, kll_hid::Keyboard::B)),
+        (3, key(CapabilityEvent::Last, kll_hid::Keyboard::B)),
+        (4, key(CapabilityEvent::Initial, kll_hid::Keyboard::B)),
+        (5, key(CapabilityEvent::Last, kll_hid::Keyboard::B)),
+        (6, key(CapabilityEvent::Initial, kll_hid::Keyboard::B)),
+    ] {
+        let results = scan_loop(&mut state, &[hold(2, last_state)]);
+        assert_eq!(results, [run], "last_state {}", last_state);
+    }
+    let results = scan_loop(&mut state, &[release(2)]);
+    assert_eq!(results, [key(CapabilityEvent::Last, kll_hid::Keyboard::B)]);
+}
+
+/// Caps word and mod-morph fixture
+/// Switch 1 is LeftShift, Switch 2 morphs Backspace to Delete with Shift, Switch 3 toggles caps word
+/// Switch 4 is A, Switch 5 is Space
+fn caps_word_state() -> TestLayerState<'static> {
+    #[rustfmt::skip]
+    const LAYER_LOOKUP: &[u8] = kll_macros::layer_lookup!(
+        // Layer 0, Switch Type (1), Index 1-5
+        0, 1, 1, [0],
+        0, 1, 2, [2],
+        0, 1, 3, [4],
+        0, 1, 4, [6],
+        0, 1, 5, [8],
+    );
+
+    const TRIGGER_RESULT_MAPPING: &[u16] = &[0, 0, 8, 10, 16, 20, 24, 30, 32, 40];
+
+    const TRIGGER_GUIDES: &[u8] = kll_macros::trigger_guide!(
+        // Index: 0
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 1,
+        },]],
+        // Index: 8
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 2,
+        },]],
+        // Index: 16
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Press,
+            loop_condition_index: 0,
+            index: 3,
+        },]],
+        // Index: 24
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 4,
+        },]],
+        // Index: 32
+        [[TriggerCondition::Switch {
+            state: trigger::Phro::Passthrough,
+            loop_condition_index: 0,
+            index: 5,
+        },]],
+    );
+
+    const RESULT_GUIDES: &[u8] = kll_macros::result_guide!(
+        // Index: 0
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::LeftShift,
+        },]],
+        // Index: 10
+        [[Capability::HidKeyboardModMorph {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::Backspace,
+            mods: 0x22,
+            morph: kll_hid::Keyboard::Delete,
+        },]],
+        // Index: 20
+        [[Capability::CapsWord {
+            state: CapabilityState::Initial,
+            loop_condition_index: 0,
+        },]],
+        // Index: 30
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::A,
+        },]],
+        // Index: 40
+        [[Capability::HidKeyboard {
+            state: CapabilityState::Passthrough,
+            loop_condition_index: 0,
+            id: kll_hid::Keyboard::Space,
+        },]],
+    );
+
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    let lookup = LayerLookup::<256>::new(
+        LAYER_LOOKUP,
+        TRIGGER_GUIDES,
+        RESULT_GUIDES,
+        TRIGGER_RESULT_MAPPING,
+        LOOP_CONDITION_LOOKUP,
+    );
+    TestLayerState::new(lookup, 0)
+}
+
+/// Passthrough HID keyboard result
+fn passthrough(event: TriggerEvent, id: kll_hid::Keyboard) -> CapabilityRun {
+    CapabilityRun::HidKeyboard {
+        state: CapabilityEvent::Passthrough(event),
+        id,
+    }
+}
+
+#[test]
+fn mod_morph_unmodified() {
+    setup_logging_lite().ok();
+
+    // Without a modifier the mod-morph key sends the original key
+    let mut state = caps_word_state();
+    let results = scan_loop(&mut state, &[press(2)]);
+    assert_eq!(
+        results,
+        [key(CapabilityEvent::Initial, kll_hid::Keyboard::Backspace)]
+    );
+    let results = scan_loop(&mut state, &[release(2)]);
+    assert_eq!(
+        results,
+        [key(CapabilityEvent::Last, kll_hid::Keyboard::Backspace)]
+    );
+}
+
+#[test]
+fn mod_morph_shift() {
+    setup_logging_lite().ok();
+
+    // Shift+Backspace sends Delete, Shift is released while Delete is pressed
+    let mut state = caps_word_state();
+    scan_loop(&mut state, &[press(1)]);
+    assert_eq!(state.held_modifiers(), 0x02);
+    let results = scan_loop(&mut state, &[press(2)]);
+    assert_eq!(
+        results,
+        [
+            key(CapabilityEvent::Last, kll_hid::Keyboard::LeftShift),
+            key(CapabilityEvent::Initial, kll_hid::Keyboard::Delete)
+        ]
+    );
+    let results = scan_loop(&mut state, &[release(2)]);
+    assert_eq!(
+        results,
+        [
+            key(CapabilityEvent::Last, kll_hid::Keyboard::Delete),
+            key(CapabilityEvent::Initial, kll_hid::Keyboard::LeftShift)
+        ]
+    );
+    let results = scan_loop(&mut state, &[release(1)]);
+    assert_eq!(
+        results,
+        [passthrough(release(1), kll_hid::Keyboard::LeftShift)]
+    );
+    assert_eq!(state.held_modifiers(), 0);
+}
+
+#[test]
+fn caps_word_alpha() {
+    setup_logging_lite().ok();
+
+    // Caps word shifts alphas
+    let mut state = caps_word_state();
+    scan_loop(&mut state, &[press(3)]);
+    assert!(state.caps_word());
+    let results = scan_loop(&mut state, &[press(4)]);
+    assert_eq!(
+        results,
+        [
+            passthrough(press(4), kll_hid::Keyboard::A),
+            key(CapabilityEvent::Initial, kll_hid::Keyboard::LeftShift)
+        ]
+    );
+    let results = scan_loop(&mut state, &[release(4)]);
+    assert_eq!(results, [passthrough(release(4), kll_hid::Keyboard::A)]);
+    assert!(state.caps_word());
+}
+
+#[test]
+fn caps_word_backspace() {
+    setup_logging_lite().ok();
+
+    // Backspace continues the word without shift
+    let mut state = caps_word_state();
+    scan_loop(&mut state, &[press(3)]);
+    scan_loop(&mut state, &[press(4)]);
+    scan_loop(&mut state, &[release(4)]);
+    let results = scan_loop(&mut state, &[press(2)]);
+    assert_eq!(
+        results,
+        [
+            key(CapabilityEvent::Initial, kll_hid::Keyboard::Backspace),
+            key(CapabilityEvent::Last, kll_hid::Keyboard::LeftShift)
+        ]
+    );
+    scan_loop(&mut state, &[release(2)]);
+    assert!(state.caps_word());
+}
+
+#[test]
+fn caps_word_break() {
+    setup_logging_lite().ok();
+
+    // A word-breaking key ends caps word, alphas are no longer shifted
+    let mut state = caps_word_state();
+    scan_loop(&mut state, &[press(3)]);
+    assert!(state.caps_word());
+    let results = scan_loop(&mut state, &[press(5)]);
+    assert_eq!(results, [passthrough(press(5), kll_hid::Keyboard::Space)]);
+    assert!(!state.caps_word());
+    scan_loop(&mut state, &[release(5)]);
+    let results = scan_loop(&mut state, &[press(4)]);
+    assert_eq!(results, [passthrough(press(4), kll_hid::Keyboard::A)]);
+    scan_loop(&mut state, &[release(4)]);
+}
+
+#[test]
+fn caps_word_held_shift() {
+    setup_logging_lite().ok();
+
+    // A held LeftShift is not released when caps word ends
+    let mut state = caps_word_state();
+    scan_loop(&mut state, &[press(3)]);
+    let results = scan_loop(&mut state, &[press(4)]);
+    assert!(results.contains(&key(CapabilityEvent::Initial, kll_hid::Keyboard::LeftShift)));
+    scan_loop(&mut state, &[release(4), press(1)]);
+    assert_eq!(state.held_modifiers(), 0x02);
+    let results = scan_loop(&mut state, &[press(5)]);
+    assert_eq!(results, [passthrough(press(5), kll_hid::Keyboard::Space)]);
+    assert!(!state.caps_word());
+    let results = scan_loop(&mut state, &[release(5), release(1)]);
+    assert_eq!(
+        results,
+        [
+            passthrough(release(5), kll_hid::Keyboard::Space),
+            passthrough(release(1), kll_hid::Keyboard::LeftShift)
+        ]
+    );
+    assert_eq!(state.held_modifiers(), 0);
+}
+
 // TODO Tests
 // - Basic trigger -> result capability validation test
 // - Import KLL file and do a handful of manual validation (positive test cases)
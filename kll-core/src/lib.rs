@@ -13,10 +13,22 @@ extern crate static_assertions;
 #[macro_use]
 extern crate enum_primitive_derive;
 extern crate num_traits;
+#[cfg(any(test, feature = "std"))]
+extern crate std;
 
+// Keymap blob tables are used in place
+#[cfg(target_endian = "little")]
+pub mod blob;
 mod converters;
+mod decode;
+pub mod handler;
 pub mod layout;
 pub mod macros;
+#[cfg(any(test, feature = "std"))]
+pub mod replay;
+pub mod system;
+mod test;
+pub use decode::{DecodeError, CAPABILITY_RUN_SIZE};
 pub use kll_hid;
 
 #[cfg(feature = "defmt")]
@@ -25,7 +37,9 @@ use defmt::{error, trace, warn};
 use log::{error, trace, warn};
 
 pub mod hid {
-    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    use super::{trigger, CapabilityEvent, TriggerEvent};
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
     #[cfg_attr(feature = "defmt", derive(defmt::Format))]
     #[repr(u8)]
     pub enum Protocol {
@@ -37,7 +51,7 @@ pub mod hid {
         Toggle = 3,
     }
 
-    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
     #[cfg_attr(feature = "defmt", derive(defmt::Format))]
     #[repr(u8)]
     pub enum State {
@@ -46,13 +60,67 @@ pub mod hid {
         /// Control is disabled / released
         Inactive = 1,
     }
+
+    /// Bit of a modifier key in a USB HID modifier bitmap (e.g. mod-morph mods)
+    /// LeftControl is bit 0 through RightGUI at bit 7, other keys are 0.
+    pub fn modifier_bit(id: kll_hid::Keyboard) -> u8 {
+        let id = id as u8;
+        if (kll_hid::Keyboard::LeftControl as u8..=kll_hid::Keyboard::RightGUI as u8).contains(&id)
+        {
+            1 << (id - kll_hid::Keyboard::LeftControl as u8)
+        } else {
+            0
+        }
+    }
+
+    /// Determines if a key has been held long enough to be auto-shifted
+    /// Only Switch hold events count, the release of a switch always has a last_state of 0.
+    pub fn auto_shift(event: TriggerEvent, threshold: u16) -> bool {
+        matches!(
+            event,
+            TriggerEvent::Switch {
+                state: trigger::Phro::Hold,
+                last_state,
+                ..
+            } if last_state >= threshold as u32
+        )
+    }
+
+    /// Key state of a repeating (typematic) key
+    /// Once held for delay scan loops, the key is released and pressed again on the following
+    /// scan loop, every rate scan loops (a rate below 2 repeats every 2 scan loops).
+    /// Press and release activations are converted to Initial and Last, hold events that do not
+    /// repeat are None.
+    pub fn repeat(state: CapabilityEvent, delay: u16, rate: u8) -> CapabilityEvent {
+        match state {
+            CapabilityEvent::Passthrough(TriggerEvent::Switch {
+                state: trigger::Phro::Hold,
+                last_state,
+                ..
+            }) => {
+                let delay = delay as u32;
+                if last_state < delay {
+                    return CapabilityEvent::None;
+                }
+                match (last_state - delay) % rate.max(2) as u32 {
+                    0 => CapabilityEvent::Last,
+                    1 => CapabilityEvent::Initial,
+                    _ => CapabilityEvent::None,
+                }
+            }
+            state => match state.activation() {
+                CapabilityEvent::Any => CapabilityEvent::None,
+                activation => activation,
+            },
+        }
+    }
 }
 
 pub mod layer {
     use core::ops::{BitAnd, BitAndAssign, BitOrAssign, Not};
     use num_traits::FromPrimitive;
 
-    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
     #[cfg_attr(feature = "defmt", derive(defmt::Format))]
     #[repr(u8)]
     pub enum Direction {
@@ -87,14 +155,14 @@ pub mod layer {
     impl State {
         /// Adds the given state to this state
         /// This is a bitwise or operation
-        pub fn add(mut self, state: State) {
-            self |= state;
+        pub fn add(&mut self, state: State) {
+            *self |= state;
         }
 
         /// Removes the given state from this state
         /// This is a bitwise nand operation
-        pub fn remove(mut self, state: State) {
-            self &= !(state);
+        pub fn remove(&mut self, state: State) {
+            *self &= !(state);
         }
 
         /// Determine if the given state is present in this state
@@ -152,13 +220,13 @@ pub mod layer {
         type Output = Self;
 
         fn not(self) -> Self::Output {
-            State::from_u32(!(self as u32)).unwrap()
+            State::from_u32(!(self as u32) & State::ShiftLatchLock as u32).unwrap()
         }
     }
 }
 
 pub mod pixel {
-    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
     #[cfg_attr(feature = "defmt", derive(defmt::Format))]
     #[repr(u8)]
     pub enum GammaControl {
@@ -170,7 +238,7 @@ pub mod pixel {
         Toggle = 3,
     }
 
-    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
     #[cfg_attr(feature = "defmt", derive(defmt::Format))]
     #[repr(u8)]
     pub enum AnimationControl {
@@ -193,7 +261,7 @@ pub mod pixel {
         Clear = 7,
     }
 
-    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
     #[cfg_attr(feature = "defmt", derive(defmt::Format))]
     #[repr(u8)]
     pub enum FadeCommand {
@@ -211,7 +279,7 @@ pub mod pixel {
         BrightnessDefault = 5,
     }
 
-    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
     #[cfg_attr(feature = "defmt", derive(defmt::Format))]
     #[repr(u8)]
     pub enum PixelTest {
@@ -246,7 +314,7 @@ pub mod pixel {
         PositionAllOn = 24,
     }
 
-    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
     #[cfg_attr(feature = "defmt", derive(defmt::Format))]
     #[repr(u8)]
     pub enum LedControl {
@@ -275,6 +343,112 @@ pub mod pixel {
     }
 }
 
+pub mod mouse {
+    use super::TriggerEvent;
+
+    /// Maximum speed multiplier reached by an accelerated mouse movement
+    pub const MAX_SPEED: i32 = 8;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(u8)]
+    pub enum Acceleration {
+        /// Constant speed
+        None = 0,
+        /// Speed increases linearly until the ramp is complete
+        Linear = 1,
+        /// Speed increases slowly at first, then quickly until the ramp is complete
+        Quadratic = 2,
+    }
+
+    impl Acceleration {
+        /// Scales a movement amount using the number of scan loops the switch has been held
+        /// ramp is the number of scan loops until MAX_SPEED is reached (0 disables acceleration).
+        pub fn scale(self, amount: i8, held: u32, ramp: u8) -> i16 {
+            let amount = amount as i32;
+            let ramp = ramp as i32;
+            if ramp == 0 {
+                return amount as i16;
+            }
+            let held = held.min(ramp as u32) as i32;
+            let extra = match self {
+                Acceleration::None => 0,
+                Acceleration::Linear => amount * (MAX_SPEED - 1) * held / ramp,
+                Acceleration::Quadratic => amount * (MAX_SPEED - 1) * held * held / (ramp * ramp),
+            };
+            (amount + extra) as i16
+        }
+
+        /// Scales a movement amount using the last_state of a TriggerEvent
+        /// Only Switch events are held, any other event uses the base amount.
+        pub fn scale_event(self, amount: i8, event: TriggerEvent, ramp: u8) -> i16 {
+            let held = match event {
+                TriggerEvent::Switch { last_state, .. } => last_state,
+                _ => 0,
+            };
+            self.scale(amount, held, ramp)
+        }
+    }
+}
+
+pub mod gamepad {
+    use super::{trigger, TriggerEvent};
+
+    /// Maximum value of a half-axis
+    pub const AXIS_MAX: u8 = 127;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(u8)]
+    pub enum Axis {
+        /// Left stick horizontal
+        X = 0,
+        /// Left stick vertical
+        Y = 1,
+        /// Left trigger
+        Z = 2,
+        /// Right stick horizontal
+        Rx = 3,
+        /// Right stick vertical
+        Ry = 4,
+        /// Right trigger
+        Rz = 5,
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(u8)]
+    pub enum Direction {
+        /// Positive half of the axis (e.g. right or down)
+        Positive = 0,
+        /// Negative half of the axis (e.g. left or up)
+        Negative = 1,
+    }
+
+    /// Converts a TriggerEvent into a half-axis value (0 to AXIS_MAX)
+    /// AnalogDistance is proportional to the distance, reaching AXIS_MAX at max (0 uses the
+    /// full range on any positive distance).
+    /// Switches are digital, AXIS_MAX while pressed or held.
+    pub fn axis_value(event: TriggerEvent, max: u16) -> u8 {
+        match event {
+            TriggerEvent::AnalogDistance { val, .. } => {
+                if val <= 0 {
+                    0
+                } else if max == 0 {
+                    AXIS_MAX
+                } else {
+                    (val as u32 * AXIS_MAX as u32 / max as u32).min(AXIS_MAX as u32) as u8
+                }
+            }
+            TriggerEvent::Switch {
+                state: trigger::Phro::Press | trigger::Phro::Hold,
+                ..
+            } => AXIS_MAX,
+            _ => 0,
+        }
+    }
+}
+
 /// Global capability list for KLL
 /// NOTE: Changing parameters and removing entries will require a firmware reflash.
 ///       At worst, KLL file and compiler definitions may also need to be updated.
@@ -394,8 +568,6 @@ pub enum Capability {
         id: kll_hid::SystemControl,
     } = 9,
 
-    // TODO Mouse Control
-    // TODO Joystick Control
     /// Enter Flash Mode
     /// Usually jumps to the bootloader
     /// 4 bytes
@@ -524,27 +696,498 @@ pub enum Capability {
         loop_condition_index: u16,
         unicode: char,
     },
+
+    /// Hold-tap (e.g. mod-tap or layer-tap)
+    /// Runs the tap result if the switch is released before being resolved as a hold.
+    /// Otherwise the hold result is run until the switch is released.
+    /// tap and hold are ResultGuide offsets, only the first combo of each is used.
+    /// See layout::HoldTapFlavor for how holds are resolved.
+    /// 8 bytes
+    HoldTap {
+        /// Capability state
+        state: CapabilityState,
+        /// Scanning loop condition (number of scanning loops attached to state condition)
+        /// Lookup index
+        loop_condition_index: u16,
+        tap: u16,
+        hold: u16,
+    },
+    /// Tap-dance
+    /// N successive taps (each within the hold-tap threshold of the previous release) run
+    /// combo N of the taps result. Holding on the Nth tap runs combo N of the holds result
+    /// instead (or combo N of the taps result if holds has no such combo).
+    /// taps and holds are ResultGuide offsets, see layout::HoldTapFlavor for how holds are
+    /// resolved.
+    /// 8 bytes
+    TapDance {
+        /// Capability state
+        state: CapabilityState,
+        /// Scanning loop condition (number of scanning loops attached to state condition)
+        /// Lookup index
+        loop_condition_index: u16,
+        taps: u16,
+        holds: u16,
+    },
+    /// One-shot (latched) USB HID keyboard key, usually a modifier
+    /// Pressed on activation and released after the next switch press (or the latch timeout).
+    /// Activating the same key again while latched releases it instead.
+    /// 5 bytes
+    HidKeyboardLatch {
+        /// Capability state
+        state: CapabilityState,
+        /// Scanning loop condition (number of scanning loops attached to state condition)
+        /// Lookup index
+        loop_condition_index: u16,
+        id: kll_hid::Keyboard,
+    },
+
+    /// Starts recording a dynamic macro into slot, or stops the recording in progress
+    /// Every following CapabilityRun is recorded with its timing.
+    /// Handled by macros::Macro
+    /// 5 bytes
+    MacroRecord {
+        /// Capability state
+        state: CapabilityState,
+        /// Scanning loop condition (number of scanning loops attached to state condition)
+        /// Lookup index
+        loop_condition_index: u16,
+        slot: u8,
+    },
+    /// Plays back the dynamic macro recorded in slot
+    /// delay is the number of processing loops between each recorded processing loop, 0 uses
+    /// the recorded timing.
+    /// Handled by macros::Macro
+    /// 8 bytes
+    MacroPlay {
+        /// Capability state
+        state: CapabilityState,
+        /// Scanning loop condition (number of scanning loops attached to state condition)
+        /// Lookup index
+        loop_condition_index: u16,
+        slot: u8,
+        delay: u16,
+    },
+
+    /// USB HID mouse button (1-8)
+    /// Handles press/released based on incoming state
+    /// 5 bytes
+    MouseButton {
+        /// Capability state
+        state: CapabilityState,
+        /// Scanning loop condition (number of scanning loops attached to state condition)
+        /// Lookup index
+        loop_condition_index: u16,
+        button: u8,
+    },
+    /// USB HID mouse relative movement
+    /// Moves every scan loop while the switch is held, use CapabilityState::Passthrough so
+    /// Hold events are sent. The speed ramps up to mouse::MAX_SPEED times x/y over ramp scan
+    /// loops (using the last_state of the switch) according to accel.
+    /// 8 bytes
+    MouseMove {
+        /// Capability state
+        state: CapabilityState,
+        /// Scanning loop condition (number of scanning loops attached to state condition)
+        /// Lookup index
+        loop_condition_index: u16,
+        x: i8,
+        y: i8,
+        accel: mouse::Acceleration,
+        ramp: u8,
+    },
+    /// USB HID mouse vertical wheel
+    /// Scrolls once per activation
+    /// 5 bytes
+    MouseWheel {
+        /// Capability state
+        state: CapabilityState,
+        /// Scanning loop condition (number of scanning loops attached to state condition)
+        /// Lookup index
+        loop_condition_index: u16,
+        amount: i8,
+    },
+    /// USB HID mouse horizontal wheel (AC Pan)
+    /// Scrolls once per activation
+    /// 5 bytes
+    MouseHorzWheel {
+        /// Capability state
+        state: CapabilityState,
+        /// Scanning loop condition (number of scanning loops attached to state condition)
+        /// Lookup index
+        loop_condition_index: u16,
+        amount: i8,
+    },
+
+    /// USB HID gamepad button (1-16)
+    /// Handles press/released based on incoming state
+    /// 5 bytes
+    GamepadButton {
+        /// Capability state
+        state: CapabilityState,
+        /// Scanning loop condition (number of scanning loops attached to state condition)
+        /// Lookup index
+        loop_condition_index: u16,
+        button: u8,
+    },
+    /// USB HID gamepad axis, controls one half (direction) of the axis
+    /// Use CapabilityState::Passthrough with an AnalogDistance trigger so the axis follows the
+    /// key (see gamepad::axis_value), max is the distance of a full deflection.
+    /// Switches are also supported (full deflection while held).
+    /// 8 bytes
+    GamepadAxis {
+        /// Capability state
+        state: CapabilityState,
+        /// Scanning loop condition (number of scanning loops attached to state condition)
+        /// Lookup index
+        loop_condition_index: u16,
+        axis: gamepad::Axis,
+        direction: gamepad::Direction,
+        max: u16,
+    },
+
+    /// Auto-shifted USB HID keyboard key
+    /// Tapping the key sends id, holding it for threshold scan loops (using the last_state of
+    /// the switch) sends LeftShift + id until released.
+    /// Use CapabilityState::Passthrough so Hold events are sent.
+    /// Handled internally by layout::LayerState
+    /// 7 bytes
+    HidKeyboardAutoShift {
+        /// Capability state
+        state: CapabilityState,
+        /// Scanning loop condition (number of scanning loops attached to state condition)
+        /// Lookup index
+        loop_condition_index: u16,
+        threshold: u16,
+        id: kll_hid::Keyboard,
+    },
+    /// Repeating (typematic) USB HID keyboard key, for hosts that do not repeat keys
+    /// Once held for delay scan loops (using the last_state of the switch) the key is released
+    /// and pressed again every rate scan loops, see hid::repeat.
+    /// Use CapabilityState::Passthrough so Hold events are sent.
+    /// Runs as CapabilityRun::HidKeyboard
+    /// 8 bytes
+    HidKeyboardRepeat {
+        /// Capability state
+        state: CapabilityState,
+        /// Scanning loop condition (number of scanning loops attached to state condition)
+        /// Lookup index
+        loop_condition_index: u16,
+        id: kll_hid::Keyboard,
+        rate: u8,
+        delay: u16,
+    },
+
+    /// Toggles caps word
+    /// While enabled, LeftShift is applied to alphas (and Minus) until a word-breaking key is
+    /// pressed. Numbers, Backspace, Delete and modifiers do not break the word.
+    /// Handled internally by layout::LayerState
+    /// 4 bytes
+    CapsWord {
+        /// Capability state
+        state: CapabilityState,
+        /// Scanning loop condition (number of scanning loops attached to state condition)
+        /// Lookup index
+        loop_condition_index: u16,
+    },
+    /// Mod-morph USB HID keyboard key
+    /// Sends morph instead of id if any of the mods (see hid::modifier_bit) are held when the
+    /// key is pressed (e.g. Shift+Backspace = Delete). The held mods are released while morph
+    /// is pressed.
+    /// Handled internally by layout::LayerState
+    /// 7 bytes
+    HidKeyboardModMorph {
+        /// Capability state
+        state: CapabilityState,
+        /// Scanning loop condition (number of scanning loops attached to state condition)
+        /// Lookup index
+        loop_condition_index: u16,
+        id: kll_hid::Keyboard,
+        mods: u8,
+        morph: kll_hid::Keyboard,
+    },
 }
 
 impl Capability {
     /// Generate a CapabilityRun using a Capability + TriggerEvent
     /// The TriggerEvent is only important when CapabilityState::Passthrough is set.
+    ///
+    /// The loop condition of the Capability is ignored, see Capability::generate_at for
+    /// scheduled generation.
     pub fn generate(&self, event: TriggerEvent, _loop_condition_lookup: &[u32]) -> CapabilityRun {
-        // TODO: Handle loop_condition_index
+        self.run(event)
+    }
+
+    /// Generate a scheduled CapabilityRun using a Capability + TriggerEvent
+    ///
+    /// time_offset is the number of scanning loops since the start of the combo the Capability
+    /// belongs to. A CapabilityRun is only generated when the time_offset matches the scheduled
+    /// loop condition of the Capability (see Capability::loop_condition).
+    pub fn generate_at(
+        &self,
+        event: TriggerEvent,
+        time_offset: u32,
+        loop_condition_lookup: &[u32],
+    ) -> Option<CapabilityRun> {
+        if Some(time_offset) == self.loop_condition(loop_condition_lookup) {
+            Some(self.run(event))
+        } else {
+            None
+        }
+    }
+
+    /// Number of scanning loops, from the start of the combo, before the Capability is scheduled
+    /// None if the loop_condition_index is not in the lookup (the Capability is never scheduled)
+    pub fn loop_condition(&self, loop_condition_lookup: &[u32]) -> Option<u32> {
+        loop_condition_lookup
+            .get(self.loop_condition_index() as usize)
+            .copied()
+    }
+
+    /// Convert the Capability into a CapabilityRun, ignoring scheduling
+    pub(crate) fn run(&self, event: TriggerEvent) -> CapabilityRun {
         match self {
             Capability::NoOp { state, .. } => CapabilityRun::NoOp {
                 state: state.event(event),
             },
+            Capability::Rotate {
+                state,
+                index,
+                increment,
+                ..
+            } => CapabilityRun::Rotate {
+                state: state.event(event),
+                index: *index,
+                increment: *increment,
+            },
+            Capability::LayerClear { state, .. } => CapabilityRun::LayerClear {
+                state: state.event(event),
+            },
+            Capability::LayerState {
+                state,
+                layer,
+                layer_state,
+                ..
+            } => CapabilityRun::LayerState {
+                state: state.event(event),
+                layer: *layer,
+                layer_state: *layer_state,
+            },
+            Capability::LayerRotate {
+                state, direction, ..
+            } => CapabilityRun::LayerRotate {
+                state: state.event(event),
+                direction: *direction,
+            },
+            Capability::HidProtocol { state, mode, .. } => CapabilityRun::HidProtocol {
+                state: state.event(event),
+                mode: *mode,
+            },
             Capability::HidKeyboard { state, id, .. } => CapabilityRun::HidKeyboard {
                 state: state.event(event),
                 id: *id,
             },
-            _ => {
-                panic!(
-                    "Missing implementation for Capability::generate: {:?}",
-                    self
-                );
+            Capability::HidKeyboardState {
+                state,
+                id,
+                key_state,
+                ..
+            } => CapabilityRun::HidKeyboardState {
+                state: state.event(event),
+                id: *id,
+                key_state: *key_state,
+            },
+            Capability::HidConsumerControl { state, id, .. } => CapabilityRun::HidConsumerControl {
+                state: state.event(event),
+                id: *id,
+            },
+            Capability::HidSystemControl { state, id, .. } => CapabilityRun::HidSystemControl {
+                state: state.event(event),
+                id: *id,
+            },
+            Capability::McuFlashMode { state, .. } => CapabilityRun::McuFlashMode {
+                state: state.event(event),
+            },
+            Capability::PixelAnimationControl { state, mode, .. } => {
+                CapabilityRun::PixelAnimationControl {
+                    state: state.event(event),
+                    mode: *mode,
+                }
+            }
+            Capability::PixelAnimationIndex { state, index, .. } => {
+                CapabilityRun::PixelAnimationIndex {
+                    state: state.event(event),
+                    index: *index,
+                }
+            }
+            Capability::PixelFadeControl {
+                state,
+                profile,
+                command,
+                arg,
+                ..
+            } => CapabilityRun::PixelFadeControl {
+                state: state.event(event),
+                profile: *profile,
+                command: *command,
+                arg: *arg,
+            },
+            Capability::PixelFadeLayer { state, layer, .. } => CapabilityRun::PixelFadeLayer {
+                state: state.event(event),
+                layer: *layer,
+            },
+            Capability::PixelFadeSet {
+                state,
+                profile,
+                config,
+                period,
+                ..
+            } => CapabilityRun::PixelFadeSet {
+                state: state.event(event),
+                profile: *profile,
+                config: *config,
+                period: *period,
+            },
+            Capability::PixelGammaControl { state, mode, .. } => CapabilityRun::PixelGammaControl {
+                state: state.event(event),
+                mode: *mode,
+            },
+            Capability::PixelLedControl {
+                state,
+                mode,
+                amount,
+                ..
+            } => CapabilityRun::PixelLedControl {
+                state: state.event(event),
+                mode: *mode,
+                amount: *amount,
+            },
+            Capability::PixelTest {
+                state, test, index, ..
+            } => CapabilityRun::PixelTest {
+                state: state.event(event),
+                test: *test,
+                index: *index,
+            },
+            Capability::HidioOpenUrl { state, index, .. } => CapabilityRun::HidioOpenUrl {
+                state: state.event(event),
+                index: *index,
+            },
+            Capability::HidioUnicodeString { state, index, .. } => {
+                CapabilityRun::HidioUnicodeString {
+                    state: state.event(event),
+                    index: *index,
+                }
             }
+            Capability::HidioUnicodeState { state, unicode, .. } => {
+                CapabilityRun::HidioUnicodeState {
+                    state: state.event(event),
+                    unicode: *unicode,
+                }
+            }
+            Capability::HoldTap {
+                state, tap, hold, ..
+            } => CapabilityRun::HoldTap {
+                state: state.event(event),
+                tap: *tap,
+                hold: *hold,
+            },
+            Capability::TapDance {
+                state, taps, holds, ..
+            } => CapabilityRun::TapDance {
+                state: state.event(event),
+                taps: *taps,
+                holds: *holds,
+            },
+            Capability::HidKeyboardLatch { state, id, .. } => CapabilityRun::HidKeyboardLatch {
+                state: state.event(event),
+                id: *id,
+            },
+            Capability::MacroRecord { state, slot, .. } => CapabilityRun::MacroRecord {
+                state: state.event(event),
+                slot: *slot,
+            },
+            Capability::MacroPlay {
+                state, slot, delay, ..
+            } => CapabilityRun::MacroPlay {
+                state: state.event(event),
+                slot: *slot,
+                delay: *delay,
+            },
+            Capability::MouseButton { state, button, .. } => CapabilityRun::MouseButton {
+                state: state.event(event),
+                button: *button,
+            },
+            Capability::MouseMove {
+                state,
+                x,
+                y,
+                accel,
+                ramp,
+                ..
+            } => CapabilityRun::MouseMove {
+                state: state.event(event),
+                x: accel.scale_event(*x, event, *ramp),
+                y: accel.scale_event(*y, event, *ramp),
+            },
+            Capability::MouseWheel { state, amount, .. } => CapabilityRun::MouseWheel {
+                state: state.event(event),
+                amount: *amount,
+            },
+            Capability::MouseHorzWheel { state, amount, .. } => CapabilityRun::MouseHorzWheel {
+                state: state.event(event),
+                amount: *amount,
+            },
+            Capability::GamepadButton { state, button, .. } => CapabilityRun::GamepadButton {
+                state: state.event(event),
+                button: *button,
+            },
+            Capability::GamepadAxis {
+                state,
+                axis,
+                direction,
+                max,
+                ..
+            } => CapabilityRun::GamepadAxis {
+                state: state.event(event),
+                axis: *axis,
+                direction: *direction,
+                value: gamepad::axis_value(event, *max),
+            },
+            Capability::HidKeyboardAutoShift {
+                state,
+                id,
+                threshold,
+                ..
+            } => CapabilityRun::HidKeyboardAutoShift {
+                state: state.event(event),
+                id: *id,
+                shift: hid::auto_shift(event, *threshold),
+            },
+            Capability::HidKeyboardRepeat {
+                state,
+                id,
+                rate,
+                delay,
+                ..
+            } => CapabilityRun::HidKeyboard {
+                state: hid::repeat(state.event(event), *delay, *rate),
+                id: *id,
+            },
+            Capability::CapsWord { state, .. } => CapabilityRun::CapsWord {
+                state: state.event(event),
+            },
+            Capability::HidKeyboardModMorph {
+                state,
+                id,
+                mods,
+                morph,
+                ..
+            } => CapabilityRun::HidKeyboardModMorph {
+                state: state.event(event),
+                id: *id,
+                mods: *mods,
+                morph: *morph,
+            },
         }
     }
 
@@ -623,19 +1266,79 @@ impl Capability {
                 loop_condition_index,
                 ..
             } => *loop_condition_index,
-            Capability::PixelTest {
+            Capability::PixelTest {
+                loop_condition_index,
+                ..
+            } => *loop_condition_index,
+            Capability::HidioOpenUrl {
+                loop_condition_index,
+                ..
+            } => *loop_condition_index,
+            Capability::HidioUnicodeString {
+                loop_condition_index,
+                ..
+            } => *loop_condition_index,
+            Capability::HidioUnicodeState {
+                loop_condition_index,
+                ..
+            } => *loop_condition_index,
+            Capability::HoldTap {
+                loop_condition_index,
+                ..
+            } => *loop_condition_index,
+            Capability::TapDance {
+                loop_condition_index,
+                ..
+            } => *loop_condition_index,
+            Capability::HidKeyboardLatch {
+                loop_condition_index,
+                ..
+            } => *loop_condition_index,
+            Capability::MacroRecord {
+                loop_condition_index,
+                ..
+            } => *loop_condition_index,
+            Capability::MacroPlay {
+                loop_condition_index,
+                ..
+            } => *loop_condition_index,
+            Capability::MouseButton {
+                loop_condition_index,
+                ..
+            } => *loop_condition_index,
+            Capability::MouseMove {
+                loop_condition_index,
+                ..
+            } => *loop_condition_index,
+            Capability::MouseWheel {
+                loop_condition_index,
+                ..
+            } => *loop_condition_index,
+            Capability::MouseHorzWheel {
+                loop_condition_index,
+                ..
+            } => *loop_condition_index,
+            Capability::GamepadButton {
+                loop_condition_index,
+                ..
+            } => *loop_condition_index,
+            Capability::GamepadAxis {
+                loop_condition_index,
+                ..
+            } => *loop_condition_index,
+            Capability::HidKeyboardAutoShift {
                 loop_condition_index,
                 ..
             } => *loop_condition_index,
-            Capability::HidioOpenUrl {
+            Capability::HidKeyboardRepeat {
                 loop_condition_index,
                 ..
             } => *loop_condition_index,
-            Capability::HidioUnicodeString {
+            Capability::CapsWord {
                 loop_condition_index,
                 ..
             } => *loop_condition_index,
-            Capability::HidioUnicodeState {
+            Capability::HidKeyboardModMorph {
                 loop_condition_index,
                 ..
             } => *loop_condition_index,
@@ -662,9 +1365,11 @@ pub enum CapabilityRun {
 
     /// Clears all layer states
     /// NOTE: Does not send trigger events
+    /// Handled internally by layout::LayerState
     /// 4 bytes
     LayerClear { state: CapabilityEvent } = 2,
     /// Updates layer to the specified state
+    /// Handled internally by layout::LayerState
     /// 6 bytes
     LayerState {
         state: CapabilityEvent,
@@ -673,6 +1378,7 @@ pub enum CapabilityRun {
     } = 3,
     /// Rotates through possible layers given the direction
     /// Uses internal state to keep track of the current layer
+    /// Handled internally by layout::LayerState
     /// 5 bytes
     LayerRotate {
         state: CapabilityEvent,
@@ -715,8 +1421,6 @@ pub enum CapabilityRun {
         id: kll_hid::SystemControl,
     } = 9,
 
-    // TODO Mouse Control
-    // TODO Joystick Control
     /// Enter Flash Mode
     /// Usually jumps to the bootloader
     /// 4 bytes
@@ -796,6 +1500,99 @@ pub enum CapabilityRun {
         state: CapabilityEvent,
         unicode: char,
     },
+
+    /// Hold-tap (e.g. mod-tap or layer-tap)
+    /// Handled internally by layout::LayerState
+    /// 8 bytes
+    HoldTap {
+        state: CapabilityEvent,
+        tap: u16,
+        hold: u16,
+    },
+    /// Tap-dance
+    /// Handled internally by layout::LayerState
+    /// 8 bytes
+    TapDance {
+        state: CapabilityEvent,
+        taps: u16,
+        holds: u16,
+    },
+    /// One-shot (latched) USB HID keyboard key
+    /// Handled internally by layout::LayerState
+    /// 5 bytes
+    HidKeyboardLatch {
+        state: CapabilityEvent,
+        id: kll_hid::Keyboard,
+    },
+
+    /// Starts (or stops) recording a dynamic macro
+    /// Handled by macros::Macro
+    /// 5 bytes
+    MacroRecord { state: CapabilityEvent, slot: u8 },
+    /// Plays back a dynamic macro
+    /// Handled by macros::Macro
+    /// 8 bytes
+    MacroPlay {
+        state: CapabilityEvent,
+        slot: u8,
+        delay: u16,
+    },
+
+    /// USB HID mouse button
+    /// Handles press/released based on incoming state
+    /// 5 bytes
+    MouseButton { state: CapabilityEvent, button: u8 },
+    /// USB HID mouse relative movement
+    /// x/y already include the acceleration ramp
+    /// 8 bytes
+    MouseMove {
+        state: CapabilityEvent,
+        x: i16,
+        y: i16,
+    },
+    /// USB HID mouse vertical wheel
+    /// 5 bytes
+    MouseWheel { state: CapabilityEvent, amount: i8 },
+    /// USB HID mouse horizontal wheel
+    /// 5 bytes
+    MouseHorzWheel { state: CapabilityEvent, amount: i8 },
+
+    /// USB HID gamepad button
+    /// Handles press/released based on incoming state
+    /// 5 bytes
+    GamepadButton { state: CapabilityEvent, button: u8 },
+    /// USB HID gamepad half-axis
+    /// value is 0 to gamepad::AXIS_MAX
+    /// 8 bytes
+    GamepadAxis {
+        state: CapabilityEvent,
+        axis: gamepad::Axis,
+        direction: gamepad::Direction,
+        value: u8,
+    },
+
+    /// Auto-shifted USB HID keyboard key
+    /// shift is set once the switch has been held past the threshold
+    /// Handled internally by layout::LayerState
+    /// 6 bytes
+    HidKeyboardAutoShift {
+        state: CapabilityEvent,
+        id: kll_hid::Keyboard,
+        shift: bool,
+    },
+    /// Toggles caps word
+    /// Handled internally by layout::LayerState
+    /// 4 bytes
+    CapsWord { state: CapabilityEvent },
+    /// Mod-morph USB HID keyboard key
+    /// Handled internally by layout::LayerState
+    /// 7 bytes
+    HidKeyboardModMorph {
+        state: CapabilityEvent,
+        id: kll_hid::Keyboard,
+        mods: u8,
+        morph: kll_hid::Keyboard,
+    },
 }
 
 impl CapabilityRun {
@@ -814,6 +1611,7 @@ impl CapabilityRun {
             CapabilityRun::McuFlashMode { state, .. } => *state,
             CapabilityRun::HidLed { state, .. } => *state,
             CapabilityRun::PixelAnimationControl { state, .. } => *state,
+            CapabilityRun::PixelAnimationIndex { state, .. } => *state,
             CapabilityRun::PixelFadeControl { state, .. } => *state,
             CapabilityRun::PixelFadeLayer { state, .. } => *state,
             CapabilityRun::PixelFadeSet { state, .. } => *state,
@@ -824,9 +1622,64 @@ impl CapabilityRun {
             CapabilityRun::HidioOpenUrl { state, .. } => *state,
             CapabilityRun::HidioUnicodeString { state, .. } => *state,
             CapabilityRun::HidioUnicodeState { state, .. } => *state,
-            _ => {
-                panic!("CapabilityRun type not handled for state({:?})", self)
-            }
+            CapabilityRun::HoldTap { state, .. } => *state,
+            CapabilityRun::TapDance { state, .. } => *state,
+            CapabilityRun::HidKeyboardLatch { state, .. } => *state,
+            CapabilityRun::MacroRecord { state, .. } => *state,
+            CapabilityRun::MacroPlay { state, .. } => *state,
+            CapabilityRun::MouseButton { state, .. } => *state,
+            CapabilityRun::MouseMove { state, .. } => *state,
+            CapabilityRun::MouseWheel { state, .. } => *state,
+            CapabilityRun::MouseHorzWheel { state, .. } => *state,
+            CapabilityRun::GamepadButton { state, .. } => *state,
+            CapabilityRun::GamepadAxis { state, .. } => *state,
+            CapabilityRun::HidKeyboardAutoShift { state, .. } => *state,
+            CapabilityRun::CapsWord { state } => *state,
+            CapabilityRun::HidKeyboardModMorph { state, .. } => *state,
+        }
+    }
+
+    /// Override the state of the CapabilityRun
+    pub fn set_state(&mut self, new_state: CapabilityEvent) {
+        match self {
+            CapabilityRun::NoOp { state }
+            | CapabilityRun::Rotate { state, .. }
+            | CapabilityRun::LayerClear { state, .. }
+            | CapabilityRun::LayerState { state, .. }
+            | CapabilityRun::LayerRotate { state, .. }
+            | CapabilityRun::HidProtocol { state, .. }
+            | CapabilityRun::HidKeyboard { state, .. }
+            | CapabilityRun::HidKeyboardState { state, .. }
+            | CapabilityRun::HidConsumerControl { state, .. }
+            | CapabilityRun::HidSystemControl { state, .. }
+            | CapabilityRun::McuFlashMode { state, .. }
+            | CapabilityRun::HidLed { state, .. }
+            | CapabilityRun::PixelAnimationControl { state, .. }
+            | CapabilityRun::PixelAnimationIndex { state, .. }
+            | CapabilityRun::PixelFadeControl { state, .. }
+            | CapabilityRun::PixelFadeLayer { state, .. }
+            | CapabilityRun::PixelFadeSet { state, .. }
+            | CapabilityRun::PixelGammaControl { state, .. }
+            | CapabilityRun::PixelLedControl { state, .. }
+            | CapabilityRun::PixelTest { state, .. }
+            | CapabilityRun::Analog { state }
+            | CapabilityRun::HidioOpenUrl { state, .. }
+            | CapabilityRun::HidioUnicodeString { state, .. }
+            | CapabilityRun::HidioUnicodeState { state, .. }
+            | CapabilityRun::HoldTap { state, .. }
+            | CapabilityRun::TapDance { state, .. }
+            | CapabilityRun::HidKeyboardLatch { state, .. }
+            | CapabilityRun::MacroRecord { state, .. }
+            | CapabilityRun::MacroPlay { state, .. }
+            | CapabilityRun::MouseButton { state, .. }
+            | CapabilityRun::MouseMove { state, .. }
+            | CapabilityRun::MouseWheel { state, .. }
+            | CapabilityRun::MouseHorzWheel { state, .. }
+            | CapabilityRun::GamepadButton { state, .. }
+            | CapabilityRun::GamepadAxis { state, .. }
+            | CapabilityRun::HidKeyboardAutoShift { state, .. }
+            | CapabilityRun::CapsWord { state }
+            | CapabilityRun::HidKeyboardModMorph { state, .. } => *state = new_state,
         }
     }
 }
@@ -854,6 +1707,7 @@ impl Capability {
 
     /// Convert slice of bytes to enum
     /// Aggressively casts the provide u8 slice to retrieve a Capability
+    /// Use try_from_bytes for untrusted data.
     /// # Safety
     pub const unsafe fn from_bytes(bytes: &[u8]) -> Self {
         core::ptr::read(bytes.as_ptr() as *const &[u8] as *const Self)
@@ -877,7 +1731,7 @@ pub mod trigger {
 
     /// PHRO - Press/Hold/Release/Off
     /// Generally used for momentary switches
-    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
     #[cfg_attr(feature = "defmt", derive(defmt::Format))]
     #[repr(u8)]
     pub enum Phro {
@@ -913,6 +1767,11 @@ pub mod trigger {
         /// Used when comparing TriggerEvents to TriggerConditions and whether the event
         /// satisfies the condition
         pub fn compare(&self, cond_time: u32, event_state: Self, event_time: u32) -> Vote {
+            // Passthrough conditions accept any incoming state
+            if *self == Phro::Passthrough {
+                return Vote::Positive;
+            }
+
             // Make sure states match
             if *self != event_state {
                 // When the condition is an Off state and the event is not
@@ -963,7 +1822,7 @@ pub mod trigger {
 
     /// AODO - Activate/On/Deactivate/Off
     /// Generally used for maintained switches
-    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
     #[cfg_attr(feature = "defmt", derive(defmt::Format))]
     #[repr(u8)]
     pub enum Aodo {
@@ -994,11 +1853,64 @@ pub mod trigger {
                 Aodo::Off
             }
         }
+
+        /// Compare states including time base
+        /// Mirrors Phro::compare, Activate/On/Deactivate/Off behave like Press/Hold/Release/Off
+        pub fn compare(&self, cond_time: u32, event_state: Self, event_time: u32) -> Vote {
+            // Passthrough conditions accept any incoming state
+            if *self == Aodo::Passthrough {
+                return Vote::Positive;
+            }
+
+            // Make sure states match
+            if *self != event_state {
+                // Off states need a reverse lookup (see Phro::compare)
+                if *self == Aodo::Off {
+                    return Vote::OffState;
+                } else {
+                    return Vote::Insufficient;
+                }
+            }
+
+            // Evaluate timing
+            match self {
+                Aodo::Activate => {
+                    if event_time >= cond_time {
+                        Vote::Positive
+                    } else {
+                        Vote::Negative
+                    }
+                }
+                Aodo::On => {
+                    if event_time >= cond_time {
+                        Vote::Positive
+                    } else {
+                        Vote::Insufficient
+                    }
+                }
+                Aodo::Deactivate => {
+                    if event_time <= cond_time {
+                        Vote::Positive
+                    } else {
+                        Vote::Negative
+                    }
+                }
+                Aodo::Off => {
+                    if event_time >= cond_time {
+                        Vote::Positive
+                    } else {
+                        Vote::Negative
+                    }
+                }
+                // Not enough information to determine a resolution
+                _ => Vote::Insufficient,
+            }
+        }
     }
 
     /// DRO - Done/Repeat/Off
     /// Generally used for an abstract process, such as an animation sequence.
-    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
     #[cfg_attr(feature = "defmt", derive(defmt::Format))]
     #[repr(u8)]
     pub enum Dro {
@@ -1011,6 +1923,100 @@ pub mod trigger {
         Passthrough = 8,
     }
 
+    impl Dro {
+        /// Compare states including time base
+        /// Done and Repeat are positive once the event has been in the state for cond_time
+        /// scanning loops.
+        pub fn compare(&self, cond_time: u32, event_state: Self, event_time: u32) -> Vote {
+            // Passthrough conditions accept any incoming state
+            if *self == Dro::Passthrough {
+                return Vote::Positive;
+            }
+
+            // Make sure states match
+            if *self != event_state {
+                // Off states need a reverse lookup (see Phro::compare)
+                if *self == Dro::Off {
+                    return Vote::OffState;
+                } else {
+                    return Vote::Insufficient;
+                }
+            }
+
+            // Evaluate timing
+            match self {
+                Dro::Done | Dro::Repeat => {
+                    if event_time >= cond_time {
+                        Vote::Positive
+                    } else {
+                        Vote::Insufficient
+                    }
+                }
+                Dro::Off => {
+                    if event_time >= cond_time {
+                        Vote::Positive
+                    } else {
+                        Vote::Negative
+                    }
+                }
+                // Not enough information to determine a resolution
+                _ => Vote::Insufficient,
+            }
+        }
+    }
+
+    /// Analog comparison
+    /// Stored in the low nibble of the mode byte of the analog TriggerConditions, the high
+    /// nibble is the band width (see AnalogCompare::Band).
+    /// Analog events are continuous, each matching event is a positive vote.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    #[repr(u8)]
+    pub enum AnalogCompare {
+        /// Event value is at least the condition value
+        GreaterEqual = 0,
+        /// Event value is at most the condition value
+        LessEqual = 1,
+        /// Event value reaches the condition value from below (previous value is below)
+        CrossUp = 2,
+        /// Event value reaches the condition value from above (previous value is above)
+        CrossDown = 3,
+        /// Event value is within 1 << width of the condition value
+        Band = 4,
+    }
+
+    impl AnalogCompare {
+        /// Build the mode byte of an analog TriggerCondition
+        /// width is only used by Band (0 to 15).
+        pub const fn mode(self, width: u8) -> u8 {
+            self as u8 | (width << 4)
+        }
+
+        /// Split the mode byte of an analog TriggerCondition into the comparison and band width
+        pub fn from_mode(mode: u8) -> Option<(Self, u8)> {
+            Some((Self::from_u8(mode & 0x0F)?, mode >> 4))
+        }
+
+        /// Compare an analog event value against the condition value
+        /// previous is the value of the previous event of the same input.
+        pub fn compare(&self, val: i16, width: u8, event_val: i16, previous: i16) -> Vote {
+            let met = match self {
+                AnalogCompare::GreaterEqual => event_val >= val,
+                AnalogCompare::LessEqual => event_val <= val,
+                AnalogCompare::CrossUp => previous < val && event_val >= val,
+                AnalogCompare::CrossDown => previous > val && event_val <= val,
+                AnalogCompare::Band => {
+                    (i32::from(event_val) - i32::from(val)).abs() <= 1i32 << width
+                }
+            };
+            if met {
+                Vote::Positive
+            } else {
+                Vote::Insufficient
+            }
+        }
+    }
+
     /// LayerState - AODO + Layer Info
     #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
     #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -1055,8 +2061,51 @@ pub mod trigger {
 
     impl LayerState {
         /// Mergers layer::State and Aodo for TriggerEvent::LayerState
-        pub fn from_layer(layer_state: layer::State, activity_state: Aodo) -> Self {
-            LayerState::from_u32(((layer_state as u32) << 1) | activity_state as u32).unwrap()
+        /// Returns None for layer::State::Off (there are no Off layer events).
+        pub fn from_layer(layer_state: layer::State, activity_state: Aodo) -> Option<Self> {
+            LayerState::from_u32(((layer_state as u32) << 4) | activity_state as u32)
+        }
+
+        /// layer::State portion of the LayerState
+        pub fn layer_state(&self) -> layer::State {
+            layer::State::from_u32(*self as u32 >> 4).unwrap()
+        }
+
+        /// Aodo portion of the LayerState
+        pub fn activity(&self) -> Aodo {
+            match *self as u8 & 0x0F {
+                0 => Aodo::Off,
+                1 => Aodo::Activate,
+                2 => Aodo::On,
+                3 => Aodo::Deactivate,
+                _ => Aodo::Passthrough,
+            }
+        }
+
+        /// Compare states including time base
+        /// The layer::State of the condition must be present in the event, the activity state is
+        /// then compared using Aodo::compare.
+        pub fn compare(&self, cond_time: u32, event_state: Self, event_time: u32) -> Vote {
+            // Passthrough conditions accept any incoming state
+            if *self == LayerState::Passthrough {
+                return Vote::Positive;
+            }
+
+            // Make sure the layer state matches (e.g. Shift vs. Lock)
+            let layer_state = self.layer_state();
+            if event_state == LayerState::Passthrough
+                || !event_state.layer_state().is_set(layer_state)
+            {
+                // Off conditions still need a reverse lookup
+                if self.activity() == Aodo::Off {
+                    return Vote::OffState;
+                } else {
+                    return Vote::Insufficient;
+                }
+            }
+
+            self.activity()
+                .compare(cond_time, event_state.activity(), event_time)
         }
     }
 }
@@ -1185,6 +2234,17 @@ impl TriggerEvent {
             TriggerEvent::Rotation { index, .. } => (*index).into(),
         }
     }
+
+    /// Value of an analog event (None for other events)
+    pub fn analog_val(&self) -> Option<i16> {
+        match self {
+            TriggerEvent::AnalogDistance { val, .. }
+            | TriggerEvent::AnalogVelocity { val, .. }
+            | TriggerEvent::AnalogAcceleration { val, .. }
+            | TriggerEvent::AnalogJerk { val, .. } => Some(*val),
+            _ => None,
+        }
+    }
 }
 
 // Size validation for TriggerEvent
@@ -1226,8 +2286,8 @@ pub enum TriggerCondition {
     } = 2,
     /// 6 bytes
     AnalogDistance {
-        /// Needed focontiguous packing
-        reserved: u8,
+        /// Comparison mode, see trigger::AnalogCompare
+        mode: u8,
         /// Switch identification index
         index: u16,
         /// Analog distance, units depend on the keyboard, KLL compiler handles unit conversion
@@ -1235,8 +2295,8 @@ pub enum TriggerCondition {
     } = 3,
     /// 6 bytes
     AnalogVelocity {
-        /// Needed for contiguous packing
-        reserved: u8,
+        /// Comparison mode, see trigger::AnalogCompare
+        mode: u8,
         /// Switch identification index
         index: u16,
         /// Analog velocity, units depend on the keyboard, KLL compiler handles unit conversion
@@ -1244,8 +2304,8 @@ pub enum TriggerCondition {
     } = 4,
     /// 6 bytes
     AnalogAcceleration {
-        /// Needed for contiguous packing
-        reserved: u8,
+        /// Comparison mode, see trigger::AnalogCompare
+        mode: u8,
         /// Switch identification index
         index: u16,
         /// Analog acceleration, units depend on the keyboard, KLL compiler handles unit conversion
@@ -1253,8 +2313,8 @@ pub enum TriggerCondition {
     } = 5,
     /// 6 bytes
     AnalogJerk {
-        /// Needed for contiguous packing
-        reserved: u8,
+        /// Comparison mode, see trigger::AnalogCompare
+        mode: u8,
         /// Switch identification index
         index: u16,
         /// Analog jerk, units depend on the keyboard, KLL compiler handles unit conversion
@@ -1351,6 +2411,7 @@ impl TriggerCondition {
 
     /// Convert slice of bytes to enum
     /// Aggressively casts the provide u8 slice to retrieve a TriggerCondition
+    /// Use try_from_bytes for untrusted data.
     /// # Safety
     pub const unsafe fn from_bytes(bytes: &[u8]) -> Self {
         core::ptr::read(bytes.as_ptr() as *const &[u8] as *const Self)
@@ -1377,11 +2438,41 @@ impl TriggerCondition {
         }
     }
 
+    /// Determine if the condition expects an off state (e.g. Switch Off, HidLed Off, layer off)
+    /// Off states do not generate events so they have to be queried using an off state lookup.
+    pub fn off_state(&self) -> bool {
+        match self {
+            TriggerCondition::Switch { state, .. } => *state == trigger::Phro::Off,
+            TriggerCondition::HidLed { state, .. }
+            | TriggerCondition::Sleep { state, .. }
+            | TriggerCondition::Resume { state, .. }
+            | TriggerCondition::Inactive { state, .. }
+            | TriggerCondition::Active { state, .. } => *state == trigger::Aodo::Off,
+            TriggerCondition::Layer { state, .. } => state.activity() == trigger::Aodo::Off,
+            TriggerCondition::Animation { state, .. } => *state == trigger::Dro::Off,
+            _ => false,
+        }
+    }
+
     /// Compare TriggerEvent to TriggerCondition
     /// NOTE: This is not a direct equivalent comparison each type and state can influence
     ///       how the loop_condition_index is evaluated.
     ///       In a way, this is similar to the voting scheme of the older C KLL implementation.
+    /// Analog crossing conditions assume the input was previously at rest (0), see
+    /// evaluate_analog.
     pub fn evaluate(&self, event: TriggerEvent, loop_condition_lookup: &[u32]) -> Vote {
+        self.evaluate_analog(event, 0, loop_condition_lookup)
+    }
+
+    /// Compare TriggerEvent to TriggerCondition
+    /// previous is the value of the previous analog event of the same input, used by the
+    /// crossing comparisons (see trigger::AnalogCompare).
+    pub fn evaluate_analog(
+        &self,
+        event: TriggerEvent,
+        previous: i16,
+        loop_condition_lookup: &[u32],
+    ) -> Vote {
         // Make sure the Id's match
         if u8::from(*self) != u8::from(event) {
             return Vote::Insufficient;
@@ -1392,6 +2483,9 @@ impl TriggerCondition {
             return Vote::Insufficient;
         }
 
+        // Conditions with a loop_condition_index outside of the lookup are never met
+        let loop_condition = |index: &u16| loop_condition_lookup.get(*index as usize).copied();
+
         // We only need to compare like events as they must match
         match self {
             TriggerCondition::None => Vote::Positive,
@@ -1406,17 +2500,146 @@ impl TriggerCondition {
                     ..
                 } = event
                 {
-                    state.compare(
-                        loop_condition_lookup[*loop_condition_index as usize],
-                        e_state,
-                        last_state,
-                    )
+                    let Some(time_cond) = loop_condition(loop_condition_index) else {
+                        return Vote::Insufficient;
+                    };
+                    state.compare(time_cond, e_state, last_state)
+                } else {
+                    Vote::Insufficient
+                }
+            }
+            TriggerCondition::HidLed {
+                state,
+                loop_condition_index,
+                ..
+            } => {
+                if let TriggerEvent::HidLed {
+                    state: e_state,
+                    last_state,
+                    ..
+                } = event
+                {
+                    let Some(time_cond) = loop_condition(loop_condition_index) else {
+                        return Vote::Insufficient;
+                    };
+                    state.compare(time_cond, e_state, last_state)
+                } else {
+                    Vote::Insufficient
+                }
+            }
+            TriggerCondition::AnalogDistance { mode, val, .. }
+            | TriggerCondition::AnalogVelocity { mode, val, .. }
+            | TriggerCondition::AnalogAcceleration { mode, val, .. }
+            | TriggerCondition::AnalogJerk { mode, val, .. } => {
+                let e_val = if let Some(e_val) = event.analog_val() {
+                    e_val
+                } else {
+                    return Vote::Insufficient;
+                };
+
+                // Analog events are continuous, wait until the threshold has been reached
+                if let Some((compare, width)) = trigger::AnalogCompare::from_mode(*mode) {
+                    compare.compare(*val, width, e_val, previous)
+                } else {
+                    Vote::Insufficient
+                }
+            }
+            TriggerCondition::Layer {
+                state,
+                loop_condition_index,
+                ..
+            } => {
+                if let TriggerEvent::Layer {
+                    state: e_state,
+                    last_state,
+                    ..
+                } = event
+                {
+                    let Some(time_cond) = loop_condition(loop_condition_index) else {
+                        return Vote::Insufficient;
+                    };
+                    state.compare(time_cond, e_state, last_state)
+                } else {
+                    Vote::Insufficient
+                }
+            }
+            TriggerCondition::Animation {
+                state,
+                loop_condition_index,
+                ..
+            } => {
+                if let TriggerEvent::Animation {
+                    state: e_state,
+                    last_state,
+                    ..
+                } = event
+                {
+                    let Some(time_cond) = loop_condition(loop_condition_index) else {
+                        return Vote::Insufficient;
+                    };
+                    state.compare(time_cond, e_state, last_state)
                 } else {
                     Vote::Insufficient
                 }
             }
-            _ => {
-                panic!("Unknown condition! Please fix.");
+            TriggerCondition::Sleep {
+                state,
+                loop_condition_index,
+            }
+            | TriggerCondition::Resume {
+                state,
+                loop_condition_index,
+            }
+            | TriggerCondition::Inactive {
+                state,
+                loop_condition_index,
+            }
+            | TriggerCondition::Active {
+                state,
+                loop_condition_index,
+            } => {
+                let (e_state, last_state) = match event {
+                    TriggerEvent::Sleep { state, last_state }
+                    | TriggerEvent::Resume { state, last_state }
+                    | TriggerEvent::Inactive { state, last_state }
+                    | TriggerEvent::Active { state, last_state } => (state, last_state),
+                    _ => {
+                        return Vote::Insufficient;
+                    }
+                };
+                let Some(time_cond) = loop_condition(loop_condition_index) else {
+                    return Vote::Insufficient;
+                };
+                state.compare(time_cond, e_state, last_state)
+            }
+            TriggerCondition::Rotation {
+                loop_condition_index,
+                position,
+                ..
+            } => {
+                if let TriggerEvent::Rotation {
+                    position: e_position,
+                    last_state,
+                    ..
+                } = event
+                {
+                    // A position of 0 matches either direction
+                    // Otherwise the rotation direction must match
+                    if *position != 0 && position.signum() != e_position.signum() {
+                        return Vote::Insufficient;
+                    }
+
+                    let Some(time_cond) = loop_condition(loop_condition_index) else {
+                        return Vote::Insufficient;
+                    };
+                    if last_state >= time_cond {
+                        Vote::Positive
+                    } else {
+                        Vote::Insufficient
+                    }
+                } else {
+                    Vote::Insufficient
+                }
             }
         }
     }
@@ -1428,7 +2651,7 @@ impl TriggerCondition {
 /// This mirrors CapabilityEvent, except that the Passthrough event is not stored as it is not
 /// known at compile time.
 /// If passthrough has been specified the final element of the last combo will be sent instead
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum CapabilityState {
@@ -1479,6 +2702,17 @@ pub enum CapabilityEvent {
     Passthrough(TriggerEvent) = 4,
 }
 
+impl CapabilityEvent {
+    /// Determine the activation of a capability
+    /// Passthrough events use the state of the original TriggerEvent (e.g. Press -> Initial).
+    pub fn activation(self) -> CapabilityEvent {
+        match self {
+            CapabilityEvent::Passthrough(event) => CapabilityRun::from(event).state(),
+            state => state,
+        }
+    }
+}
+
 /*
 /// Position
 /// Each position has 6 dimensions
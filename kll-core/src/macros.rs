@@ -6,20 +6,75 @@
 // copied, modified, or distributed except according to those terms.
 
 use heapless::spsc::Queue;
+use heapless::Vec;
 
-use crate::{CapabilityRun, TriggerEvent};
+#[cfg(feature = "defmt")]
+use defmt::{trace, warn};
+#[cfg(not(feature = "defmt"))]
+use log::{trace, warn};
 
-pub struct Macro<const TSIZE: usize, const CSIZE: usize> {
+use crate::{CapabilityEvent, CapabilityRun, TriggerEvent};
+
+/// Recorded CapabilityRun
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+struct MacroStep {
+    /// Processing loops since the previous step (or the start of the recording)
+    delay: u32,
+    run: CapabilityRun,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+struct Recording {
+    slot: usize,
+    /// Time instance of the previous step (or the start of the recording)
+    time_instance: u32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+struct Playback {
+    slot: usize,
+    /// Next step to play
+    pos: usize,
+    /// Time instance of the previous step (or the start of the playback)
+    time_instance: u32,
+    /// Fixed delay between recorded processing loops, 0 uses the recorded timing
+    delay: u16,
+}
+
+/// Macro processor
+/// Converts queued TriggerEvents into CapabilityRuns and records/plays back dynamic macros
+/// (see Capability::MacroRecord and Capability::MacroPlay).
+/// SLOTS is the number of dynamic macros, each holds up to MSIZE CapabilityRuns.
+pub struct Macro<
+    const TSIZE: usize,
+    const CSIZE: usize,
+    const SLOTS: usize = 1,
+    const MSIZE: usize = 64,
+> {
     inputs: Queue<TriggerEvent, TSIZE>,
     outputs: Queue<CapabilityRun, CSIZE>,
+    slots: [Vec<MacroStep, MSIZE>; SLOTS],
+    recording: Option<Recording>,
+    playback: Option<Playback>,
 }
 
-impl<const TSIZE: usize, const CSIZE: usize> Macro<TSIZE, CSIZE> {
+impl<const TSIZE: usize, const CSIZE: usize, const SLOTS: usize, const MSIZE: usize>
+    Macro<TSIZE, CSIZE, SLOTS, MSIZE>
+{
     pub fn new(
         inputs: Queue<TriggerEvent, TSIZE>,
         outputs: Queue<CapabilityRun, CSIZE>,
-    ) -> Macro<TSIZE, CSIZE> {
-        Macro { inputs, outputs }
+    ) -> Macro<TSIZE, CSIZE, SLOTS, MSIZE> {
+        Macro {
+            inputs,
+            outputs,
+            slots: core::array::from_fn(|_| Vec::new()),
+            recording: None,
+            playback: None,
+        }
     }
 
     pub fn process(&mut self) {
@@ -27,11 +82,181 @@ impl<const TSIZE: usize, const CSIZE: usize> Macro<TSIZE, CSIZE> {
             self.outputs.enqueue(input.into()).unwrap();
         }
     }
+
+    /// Processes the CapabilityRuns of a processing loop (e.g. from LayerState::finalize_triggers)
+    /// MacroRecord and MacroPlay are handled, every other CapabilityRun is recorded while a
+    /// recording is in progress.
+    /// Then any playback steps that are due are added to the output queue (see dequeue).
+    /// Call once per processing loop, even without any CapabilityRuns.
+    pub fn process_results(&mut self, time_instance: u32, results: &[CapabilityRun]) {
+        for run in results {
+            match *run {
+                CapabilityRun::MacroRecord { state, slot } => {
+                    if state.activation() == CapabilityEvent::Initial {
+                        self.toggle_recording(slot, time_instance);
+                    }
+                }
+                CapabilityRun::MacroPlay { state, slot, delay } => {
+                    if state.activation() == CapabilityEvent::Initial {
+                        self.play(slot, delay, time_instance);
+                    }
+                }
+                _ => {
+                    self.record(*run, time_instance);
+                }
+            }
+        }
+
+        self.process_playback(time_instance);
+    }
+
+    /// Next CapabilityRun to run
+    pub fn dequeue(&mut self) -> Option<CapabilityRun> {
+        self.outputs.dequeue()
+    }
+
+    /// Slot currently being recorded
+    pub fn recording(&self) -> Option<u8> {
+        self.recording.map(|recording| recording.slot as u8)
+    }
+
+    /// Slot currently being played back
+    pub fn playing(&self) -> Option<u8> {
+        self.playback.map(|playback| playback.slot as u8)
+    }
+
+    /// Number of recorded CapabilityRuns in slot
+    pub fn len(&self, slot: u8) -> usize {
+        self.slots.get(slot as usize).map_or(0, |steps| steps.len())
+    }
+
+    /// Determine if the slot has no recorded CapabilityRuns
+    pub fn is_empty(&self, slot: u8) -> bool {
+        self.len(slot) == 0
+    }
+
+    /// Starts recording slot, or stops the recording in progress
+    /// Starting a recording clears the slot and stops any playback.
+    fn toggle_recording(&mut self, slot: u8, time_instance: u32) {
+        if let Some(recording) = self.recording.take() {
+            trace!("Macro record stop: {}", recording.slot);
+            return;
+        }
+
+        let slot = slot as usize;
+        if slot >= SLOTS {
+            warn!("Invalid macro slot: {}", slot);
+            return;
+        }
+        trace!("Macro record start: {}", slot);
+        self.release_playback();
+        self.slots[slot].clear();
+        self.recording = Some(Recording {
+            slot,
+            time_instance,
+        });
+    }
+
+    /// Records a CapabilityRun if a recording is in progress
+    /// The recording is stopped once the slot is full.
+    fn record(&mut self, run: CapabilityRun, time_instance: u32) {
+        let Some(recording) = &mut self.recording else {
+            return;
+        };
+
+        let step = MacroStep {
+            delay: time_instance.wrapping_sub(recording.time_instance),
+            run,
+        };
+        if self.slots[recording.slot].push(step).is_err() {
+            warn!("Macro slot {} is full, recording stopped", recording.slot);
+            self.recording = None;
+            return;
+        }
+        recording.time_instance = time_instance;
+    }
+
+    /// Starts playing back slot (restarting any playback in progress)
+    /// Keys still pressed by the previous playback are released first.
+    /// Ignored while recording.
+    fn play(&mut self, slot: u8, delay: u16, time_instance: u32) {
+        let slot = slot as usize;
+        if slot >= SLOTS || self.recording.is_some() {
+            warn!("Cannot play macro slot: {}", slot);
+            return;
+        }
+        trace!("Macro play: {} delay {}", slot, delay);
+        self.release_playback();
+        self.playback = Some(Playback {
+            slot,
+            pos: 0,
+            time_instance,
+            delay,
+        });
+    }
+
+    /// Queues the playback steps that are due
+    /// Steps that do not fit in the output queue are retried on the next processing loop.
+    fn process_playback(&mut self, time_instance: u32) {
+        let Some(playback) = &mut self.playback else {
+            return;
+        };
+
+        while let Some(step) = self.slots[playback.slot].get(playback.pos) {
+            // Steps recorded in the same processing loop are played together
+            // The first step is played immediately when using a fixed delay
+            let delay = match (step.delay, playback.delay) {
+                (0, _) | (_, 0) => step.delay,
+                _ if playback.pos == 0 => 0,
+                (_, delay) => delay as u32,
+            };
+            if time_instance.wrapping_sub(playback.time_instance) < delay {
+                return;
+            }
+            if self.outputs.enqueue(step.run).is_err() {
+                return;
+            }
+            playback.pos += 1;
+            playback.time_instance = time_instance;
+        }
+
+        trace!("Macro play done: {}", playback.slot);
+        self.release_playback();
+    }
+
+    /// Stops the playback in progress, releasing the keys it pressed that are still down
+    /// (e.g. the playback was restarted, or a key was still held when the recording stopped)
+    fn release_playback(&mut self) {
+        let Some(playback) = self.playback.take() else {
+            return;
+        };
+
+        let steps = &self.slots[playback.slot][..playback.pos];
+        for (pos, step) in steps.iter().enumerate() {
+            if step.run.state() != CapabilityEvent::Initial {
+                continue;
+            }
+            let mut release = step.run;
+            release.set_state(CapabilityEvent::Last);
+
+            // Only the last press of a key is still down, unless it was released afterwards
+            if steps[pos + 1..]
+                .iter()
+                .any(|later| later.run == step.run || later.run == release)
+            {
+                continue;
+            }
+            if self.outputs.enqueue(release).is_err() {
+                warn!("Macro output queue full, dropped release: {:?}", release);
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use heapless::spsc::Queue;
+    use heapless::Vec;
     use kll_hid::Keyboard;
 
     use crate::macros::Macro;
@@ -89,4 +314,186 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn recorded_macro_is_played_back_with_the_recorded_timing() {
+        let key = |state, id| CapabilityRun::HidKeyboard { state, id };
+        let record = CapabilityRun::MacroRecord {
+            state: CapabilityEvent::Initial,
+            slot: 1,
+        };
+        let play = |delay| CapabilityRun::MacroPlay {
+            state: CapabilityEvent::Initial,
+            slot: 1,
+            delay,
+        };
+        let toggle = [record];
+        let press_a = [key(CapabilityEvent::Initial, Keyboard::A)];
+        let release_a_press_b = [
+            key(CapabilityEvent::Last, Keyboard::A),
+            key(CapabilityEvent::Initial, Keyboard::B),
+        ];
+        let release_b = [key(CapabilityEvent::Last, Keyboard::B)];
+        let loops = [
+            (10, toggle.as_slice()),
+            (12, &press_a),
+            (13, &release_a_press_b),
+            (17, &release_b),
+            (20, &toggle),
+        ];
+
+        let mut process_queue: Macro<5, 5, 2, 8> = Macro::new(Queue::new(), Queue::new());
+        for (time_instance, results) in loops {
+            process_queue.process_results(time_instance, results);
+            assert!(process_queue.dequeue().is_none());
+        }
+        assert_eq!(process_queue.recording(), None);
+        assert_eq!(process_queue.len(1), 4);
+
+        // Recorded timing
+        let mut played = Vec::<(u32, CapabilityRun), 8>::new();
+        process_queue.process_results(100, &[play(0)]);
+        for time_instance in 100..110 {
+            if time_instance > 100 {
+                process_queue.process_results(time_instance, &[]);
+            }
+            while let Some(run) = process_queue.dequeue() {
+                played.push((time_instance, run)).unwrap();
+            }
+        }
+        assert_eq!(
+            played,
+            [
+                (102, key(CapabilityEvent::Initial, Keyboard::A)),
+                (103, key(CapabilityEvent::Last, Keyboard::A)),
+                (103, key(CapabilityEvent::Initial, Keyboard::B)),
+                (107, key(CapabilityEvent::Last, Keyboard::B)),
+            ]
+        );
+        assert_eq!(process_queue.playing(), None);
+
+        // Fixed delay
+        played.clear();
+        process_queue.process_results(200, &[play(2)]);
+        for time_instance in 200..210 {
+            if time_instance > 200 {
+                process_queue.process_results(time_instance, &[]);
+            }
+            while let Some(run) = process_queue.dequeue() {
+                played.push((time_instance, run)).unwrap();
+            }
+        }
+        assert_eq!(
+            played,
+            [
+                (200, key(CapabilityEvent::Initial, Keyboard::A)),
+                (202, key(CapabilityEvent::Last, Keyboard::A)),
+                (202, key(CapabilityEvent::Initial, Keyboard::B)),
+                (204, key(CapabilityEvent::Last, Keyboard::B)),
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_still_pressed_by_the_macro_are_released() {
+        let key = |state, id| CapabilityRun::HidKeyboard { state, id };
+        let record = CapabilityRun::MacroRecord {
+            state: CapabilityEvent::Initial,
+            slot: 0,
+        };
+        let play = CapabilityRun::MacroPlay {
+            state: CapabilityEvent::Initial,
+            slot: 0,
+            delay: 0,
+        };
+
+        // B is still held when the recording stops
+        let mut process_queue: Macro<5, 5, 1, 8> = Macro::new(Queue::new(), Queue::new());
+        process_queue.process_results(0, &[record]);
+        process_queue.process_results(1, &[key(CapabilityEvent::Initial, Keyboard::A)]);
+        process_queue.process_results(2, &[key(CapabilityEvent::Initial, Keyboard::B)]);
+        process_queue.process_results(3, &[key(CapabilityEvent::Last, Keyboard::A)]);
+        process_queue.process_results(4, &[record]);
+        assert_eq!(process_queue.len(0), 3);
+
+        // Restarting the playback releases A
+        let mut played = Vec::<CapabilityRun, 8>::new();
+        process_queue.process_results(10, &[play]);
+        process_queue.process_results(11, &[]);
+        process_queue.process_results(12, &[play]);
+        while let Some(run) = process_queue.dequeue() {
+            played.push(run).unwrap();
+        }
+        assert_eq!(
+            played,
+            [
+                key(CapabilityEvent::Initial, Keyboard::A),
+                key(CapabilityEvent::Last, Keyboard::A),
+            ]
+        );
+
+        // B is released once the playback is done
+        played.clear();
+        for time_instance in 13..16 {
+            process_queue.process_results(time_instance, &[]);
+            while let Some(run) = process_queue.dequeue() {
+                played.push(run).unwrap();
+            }
+        }
+        assert_eq!(
+            played,
+            [
+                key(CapabilityEvent::Initial, Keyboard::A),
+                key(CapabilityEvent::Initial, Keyboard::B),
+                key(CapabilityEvent::Last, Keyboard::A),
+                key(CapabilityEvent::Last, Keyboard::B),
+            ]
+        );
+        assert_eq!(process_queue.playing(), None);
+    }
+
+    #[test]
+    fn recording_stops_when_the_slot_is_full() {
+        let record = CapabilityRun::MacroRecord {
+            state: CapabilityEvent::Initial,
+            slot: 0,
+        };
+        let key = CapabilityRun::HidKeyboard {
+            state: CapabilityEvent::Initial,
+            id: Keyboard::A,
+        };
+
+        let mut process_queue: Macro<5, 5, 1, 2> = Macro::new(Queue::new(), Queue::new());
+        process_queue.process_results(0, &[record]);
+        assert_eq!(process_queue.recording(), Some(0));
+        process_queue.process_results(1, &[key, key]);
+        assert_eq!(process_queue.recording(), Some(0));
+        process_queue.process_results(2, &[key]);
+        assert_eq!(process_queue.recording(), None);
+        assert_eq!(process_queue.len(0), 2);
+
+        // Invalid slots are ignored
+        process_queue.process_results(
+            3,
+            &[CapabilityRun::MacroRecord {
+                state: CapabilityEvent::Initial,
+                slot: 1,
+            }],
+        );
+        assert_eq!(process_queue.recording(), None);
+        assert_eq!(process_queue.len(1), 0);
+
+        // Playback is ignored while recording
+        process_queue.process_results(4, &[record]);
+        process_queue.process_results(
+            5,
+            &[CapabilityRun::MacroPlay {
+                state: CapabilityEvent::Initial,
+                slot: 0,
+                delay: 0,
+            }],
+        );
+        assert_eq!(process_queue.playing(), None);
+        assert!(process_queue.is_empty(0));
+    }
 }
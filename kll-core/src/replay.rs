@@ -0,0 +1,437 @@
+// Copyright 2021-2023 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! TriggerEvent trace recording and replay (std only)
+//!
+//! Records the TriggerEvents given to LayerState::process_trigger and the CapabilityRuns
+//! returned by LayerState::finalize_triggers (see Recorder), grouped by processing loop and
+//! tagged with the time_instance. A recorded trace can be replayed against a LayerState (e.g. to
+//! turn a bug report into a test fixture) and the results are diffed against the recording.
+//!
+//! Text format (one entry per line, # starts a comment line)
+//! - L <time_instance>: start of a processing loop
+//! - E <TriggerEvent as 16 hex digits> [# comment]: event given to process_trigger
+//! - R <CapabilityRun as 32 hex digits> [# comment]: result returned by finalize_triggers
+//!
+//! Events and results are encoded with TriggerEvent::to_bytes and CapabilityRun::to_bytes, the
+//! comment is the debug format and is ignored when parsing.
+
+// ----- Crates -----
+
+use crate::layout::{LayerState, ProcessError};
+use crate::{CapabilityRun, DecodeError, TriggerEvent, CAPABILITY_RUN_SIZE};
+use core::fmt;
+use core::fmt::Write;
+use std::string::String;
+use std::vec::Vec;
+
+// ----- Enums -----
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TraceError {
+    /// Line (1-based) is not a valid trace entry
+    InvalidLine(usize),
+    /// Event or result before the first L entry on the given line
+    MissingLoop(usize),
+    /// TriggerEvent or CapabilityRun on the given line could not be decoded
+    Decode(usize, DecodeError),
+}
+
+// ----- Structs -----
+
+/// Events and results of a single processing loop
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceLoop {
+    pub time_instance: u32,
+    pub events: Vec<TriggerEvent>,
+    pub results: Vec<CapabilityRun>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub loops: Vec<TraceLoop>,
+    /// Results of the last loop have not been recorded yet
+    open: bool,
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a TriggerEvent given to process_trigger
+    fn record_event(&mut self, time_instance: u32, event: TriggerEvent) {
+        self.open_loop(time_instance).events.push(event);
+    }
+
+    /// Records the results of finalize_triggers, closing the processing loop
+    fn record_results(&mut self, time_instance: u32, results: &[CapabilityRun]) {
+        self.open_loop(time_instance)
+            .results
+            .extend_from_slice(results);
+        self.open = false;
+    }
+
+    /// Parses a trace in text format
+    pub fn parse(text: &str) -> Result<Self, TraceError> {
+        let mut trace = Self::new();
+        for (num, line) in text.lines().enumerate() {
+            let num = num + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (kind, val) = line.split_once(' ').unwrap_or((line, ""));
+            let val = val.trim();
+            match kind {
+                "L" => {
+                    let time_instance = val.parse().map_err(|_| TraceError::InvalidLine(num))?;
+                    trace.loops.push(TraceLoop {
+                        time_instance,
+                        ..Default::default()
+                    });
+                }
+                "E" => {
+                    let hex = val.split_whitespace().next().unwrap_or("");
+                    let event = parse_hex::<{ core::mem::size_of::<TriggerEvent>() }>(hex)
+                        .ok_or(TraceError::InvalidLine(num))?;
+                    let event = TriggerEvent::try_from_bytes(&event)
+                        .map_err(|err| TraceError::Decode(num, err))?;
+                    let current = trace.loops.last_mut().ok_or(TraceError::MissingLoop(num))?;
+                    current.events.push(event);
+                }
+                "R" => {
+                    let hex = val.split_whitespace().next().unwrap_or("");
+                    let run = parse_hex::<CAPABILITY_RUN_SIZE>(hex)
+                        .ok_or(TraceError::InvalidLine(num))?;
+                    let run = CapabilityRun::try_from_bytes(&run)
+                        .map_err(|err| TraceError::Decode(num, err))?;
+                    let current = trace.loops.last_mut().ok_or(TraceError::MissingLoop(num))?;
+                    current.results.push(run);
+                }
+                _ => {
+                    return Err(TraceError::InvalidLine(num));
+                }
+            }
+        }
+        Ok(trace)
+    }
+
+    /// Returns the loop for time_instance, starting a new loop if needed
+    fn open_loop(&mut self, time_instance: u32) -> &mut TraceLoop {
+        let start = !self.open
+            || self
+                .loops
+                .last()
+                .is_none_or(|current| current.time_instance != time_instance);
+        if start {
+            self.loops.push(TraceLoop {
+                time_instance,
+                ..Default::default()
+            });
+            self.open = true;
+        }
+        self.loops.last_mut().unwrap()
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for current in &self.loops {
+            writeln!(f, "L {}", current.time_instance)?;
+            for event in &current.events {
+                f.write_str("E ")?;
+                write_hex(f, &event.to_bytes())?;
+                writeln!(f, " # {:?}", event)?;
+            }
+            for result in &current.results {
+                f.write_str("R ")?;
+                write_hex(f, &result.to_bytes())?;
+                writeln!(f, " # {:?}", result)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Records a trace while driving a LayerState
+/// Every event given to process_trigger and every result of finalize_triggers is recorded,
+/// tagged with the time_instance of the LayerState.
+pub struct Recorder<
+    'r,
+    'a,
+    const LAYOUT_SIZE: usize,
+    const STATE_SIZE: usize,
+    const MAX_LAYERS: usize,
+    const MAX_ACTIVE_LAYERS: usize,
+    const MAX_ACTIVE_TRIGGERS: usize,
+    const MAX_LAYER_STACK_CACHE: usize,
+    const MAX_OFF_STATE_LOOKUP: usize,
+    const MAX_HOLD_TAPS: usize,
+    const MAX_DEFERRED_EVENTS: usize,
+    const MAX_SUPPRESSED_EVENTS: usize,
+    const MAX_LATCHED_KEYS: usize,
+    const MAX_LAYER_RULES: usize,
+    const MAX_ANALOG_INPUTS: usize,
+    const MAX_AUTO_SHIFT_KEYS: usize,
+    const MAX_MOD_MORPH_KEYS: usize,
+> {
+    state: &'r mut LayerState<
+        'a,
+        LAYOUT_SIZE,
+        STATE_SIZE,
+        MAX_LAYERS,
+        MAX_ACTIVE_LAYERS,
+        MAX_ACTIVE_TRIGGERS,
+        MAX_LAYER_STACK_CACHE,
+        MAX_OFF_STATE_LOOKUP,
+        MAX_HOLD_TAPS,
+        MAX_DEFERRED_EVENTS,
+        MAX_SUPPRESSED_EVENTS,
+        MAX_LATCHED_KEYS,
+        MAX_LAYER_RULES,
+        MAX_ANALOG_INPUTS,
+        MAX_AUTO_SHIFT_KEYS,
+        MAX_MOD_MORPH_KEYS,
+    >,
+    trace: Trace,
+}
+
+impl<
+        'r,
+        'a,
+        const LAYOUT_SIZE: usize,
+        const STATE_SIZE: usize,
+        const MAX_LAYERS: usize,
+        const MAX_ACTIVE_LAYERS: usize,
+        const MAX_ACTIVE_TRIGGERS: usize,
+        const MAX_LAYER_STACK_CACHE: usize,
+        const MAX_OFF_STATE_LOOKUP: usize,
+        const MAX_HOLD_TAPS: usize,
+        const MAX_DEFERRED_EVENTS: usize,
+        const MAX_SUPPRESSED_EVENTS: usize,
+        const MAX_LATCHED_KEYS: usize,
+        const MAX_LAYER_RULES: usize,
+        const MAX_ANALOG_INPUTS: usize,
+        const MAX_AUTO_SHIFT_KEYS: usize,
+        const MAX_MOD_MORPH_KEYS: usize,
+    >
+    Recorder<
+        'r,
+        'a,
+        LAYOUT_SIZE,
+        STATE_SIZE,
+        MAX_LAYERS,
+        MAX_ACTIVE_LAYERS,
+        MAX_ACTIVE_TRIGGERS,
+        MAX_LAYER_STACK_CACHE,
+        MAX_OFF_STATE_LOOKUP,
+        MAX_HOLD_TAPS,
+        MAX_DEFERRED_EVENTS,
+        MAX_SUPPRESSED_EVENTS,
+        MAX_LATCHED_KEYS,
+        MAX_LAYER_RULES,
+        MAX_ANALOG_INPUTS,
+        MAX_AUTO_SHIFT_KEYS,
+        MAX_MOD_MORPH_KEYS,
+    >
+{
+    pub fn new(
+        state: &'r mut LayerState<
+            'a,
+            LAYOUT_SIZE,
+            STATE_SIZE,
+            MAX_LAYERS,
+            MAX_ACTIVE_LAYERS,
+            MAX_ACTIVE_TRIGGERS,
+            MAX_LAYER_STACK_CACHE,
+            MAX_OFF_STATE_LOOKUP,
+            MAX_HOLD_TAPS,
+            MAX_DEFERRED_EVENTS,
+            MAX_SUPPRESSED_EVENTS,
+            MAX_LATCHED_KEYS,
+            MAX_LAYER_RULES,
+            MAX_ANALOG_INPUTS,
+            MAX_AUTO_SHIFT_KEYS,
+            MAX_MOD_MORPH_KEYS,
+        >,
+    ) -> Self {
+        Self {
+            state,
+            trace: Trace::new(),
+        }
+    }
+
+    /// Records the event and calls LayerState::process_trigger
+    pub fn process_trigger<const LSIZE: usize>(
+        &mut self,
+        event: TriggerEvent,
+    ) -> Result<(), ProcessError> {
+        self.trace.record_event(self.state.time_instance(), event);
+        self.state.process_trigger::<LSIZE>(event)
+    }
+
+    /// Calls LayerState::finalize_triggers and records the results, closing the processing loop
+    pub fn finalize_triggers<const LSIZE: usize>(&mut self) -> heapless::Vec<CapabilityRun, LSIZE> {
+        let results = self.state.finalize_triggers::<LSIZE>();
+        self.trace
+            .record_results(self.state.time_instance(), &results);
+        results
+    }
+
+    /// Recorded LayerState (e.g. to increment the time_instance between processing loops)
+    pub fn state(
+        &mut self,
+    ) -> &mut LayerState<
+        'a,
+        LAYOUT_SIZE,
+        STATE_SIZE,
+        MAX_LAYERS,
+        MAX_ACTIVE_LAYERS,
+        MAX_ACTIVE_TRIGGERS,
+        MAX_LAYER_STACK_CACHE,
+        MAX_OFF_STATE_LOOKUP,
+        MAX_HOLD_TAPS,
+        MAX_DEFERRED_EVENTS,
+        MAX_SUPPRESSED_EVENTS,
+        MAX_LATCHED_KEYS,
+        MAX_LAYER_RULES,
+        MAX_ANALOG_INPUTS,
+        MAX_AUTO_SHIFT_KEYS,
+        MAX_MOD_MORPH_KEYS,
+    > {
+        self.state
+    }
+
+    /// Trace recorded so far
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+
+    /// Stops recording, returning the trace
+    pub fn into_trace(self) -> Trace {
+        self.trace
+    }
+}
+
+/// Processing loop where the replayed results differ from the trace
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceDiff {
+    /// Position of the loop in the trace
+    pub loop_index: usize,
+    pub time_instance: u32,
+    pub expected: Vec<CapabilityRun>,
+    pub found: Vec<CapabilityRun>,
+}
+
+impl fmt::Display for TraceDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Loop {} (time_instance {}):",
+            self.loop_index, self.time_instance
+        )?;
+        for result in &self.expected {
+            writeln!(f, "- {:?}", result)?;
+        }
+        for result in &self.found {
+            writeln!(f, "+ {:?}", result)?;
+        }
+        Ok(())
+    }
+}
+
+// ----- Functions -----
+
+fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let mut bytes = [0u8; N];
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+    for (pos, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[pos * 2..pos * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn write_hex(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    for byte in bytes {
+        write!(f, "{:02x}", byte)?;
+    }
+    Ok(())
+}
+
+/// Replays a trace, returning every processing loop with different results
+/// The LayerState should be configured (hold-tap flavor, combo timeouts, etc.) the same way as
+/// when the trace was recorded. The time_instance of each loop is set before processing.
+/// Capacity overflows while processing are not reported here, see LayerState::overflow_count.
+pub fn replay<
+    const LSIZE: usize,
+    const LAYOUT_SIZE: usize,
+    const STATE_SIZE: usize,
+    const MAX_LAYERS: usize,
+    const MAX_ACTIVE_LAYERS: usize,
+    const MAX_ACTIVE_TRIGGERS: usize,
+    const MAX_LAYER_STACK_CACHE: usize,
+    const MAX_OFF_STATE_LOOKUP: usize,
+    const MAX_HOLD_TAPS: usize,
+    const MAX_DEFERRED_EVENTS: usize,
+    const MAX_SUPPRESSED_EVENTS: usize,
+    const MAX_LATCHED_KEYS: usize,
+    const MAX_LAYER_RULES: usize,
+    const MAX_ANALOG_INPUTS: usize,
+    const MAX_AUTO_SHIFT_KEYS: usize,
+    const MAX_MOD_MORPH_KEYS: usize,
+>(
+    state: &mut LayerState<
+        '_,
+        LAYOUT_SIZE,
+        STATE_SIZE,
+        MAX_LAYERS,
+        MAX_ACTIVE_LAYERS,
+        MAX_ACTIVE_TRIGGERS,
+        MAX_LAYER_STACK_CACHE,
+        MAX_OFF_STATE_LOOKUP,
+        MAX_HOLD_TAPS,
+        MAX_DEFERRED_EVENTS,
+        MAX_SUPPRESSED_EVENTS,
+        MAX_LATCHED_KEYS,
+        MAX_LAYER_RULES,
+        MAX_ANALOG_INPUTS,
+        MAX_AUTO_SHIFT_KEYS,
+        MAX_MOD_MORPH_KEYS,
+    >,
+    trace: &Trace,
+) -> Vec<TraceDiff> {
+    let mut diffs = Vec::new();
+    for (loop_index, current) in trace.loops.iter().enumerate() {
+        state.set_time(current.time_instance);
+        for event in &current.events {
+            state.process_trigger::<LSIZE>(*event).ok();
+        }
+        let found = state.finalize_triggers::<LSIZE>().to_vec();
+        if found != current.results {
+            diffs.push(TraceDiff {
+                loop_index,
+                time_instance: current.time_instance,
+                expected: current.results.clone(),
+                found,
+            });
+        }
+    }
+    diffs
+}
+
+/// Formats a list of TraceDiffs, e.g. for an assert message
+pub fn format_diffs(diffs: &[TraceDiff]) -> String {
+    let mut out = String::new();
+    for diff in diffs {
+        write!(out, "{}", diff).ok();
+    }
+    out
+}
@@ -0,0 +1,247 @@
+// Copyright 2021-2023 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use heapless::Vec;
+
+#[cfg(feature = "defmt")]
+use defmt::trace;
+#[cfg(not(feature = "defmt"))]
+use log::trace;
+
+use crate::layout::TriggerEventIterator;
+use crate::trigger::Aodo;
+use crate::TriggerEvent;
+
+/// Maximum number of TriggerEvents generated by SystemState::update
+pub const MAX_SYSTEM_EVENTS: usize = 4;
+
+/// System TriggerEvent generator
+/// Tracks USB suspend (Sleep/Resume) and keyboard idle (Inactive/Active) state and generates
+/// the TriggerEvents when the state changes.
+/// Sleep and Resume (and Inactive and Active) are complementary, e.g. suspending activates
+/// Sleep and deactivates Resume.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SystemState {
+    suspended: bool,
+    idle: bool,
+    /// Scanning loops since the suspend state changed
+    suspended_loops: u32,
+    /// Scanning loops since the idle state changed
+    idle_loops: u32,
+}
+
+impl SystemState {
+    /// Starts out resumed and active
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Updates the system state, call once per scanning loop
+    /// suspended is the USB suspend state (e.g. UsbDeviceState::Suspend)
+    /// idle is true when no keys have been used for a while (e.g. every key of a
+    /// kiibohd_keyscanning::Matrix is idle)
+    ///
+    /// Returns the TriggerEvents of the state changes (none if nothing changed)
+    pub fn update(
+        &mut self,
+        suspended: bool,
+        idle: bool,
+    ) -> TriggerEventIterator<MAX_SYSTEM_EVENTS> {
+        let mut events = Vec::new();
+
+        if suspended != self.suspended {
+            trace!("Suspended: {}", suspended);
+            let sleep = Aodo::from_state(self.suspended, suspended);
+            let resume = Aodo::from_state(!self.suspended, !suspended);
+            self.suspended = suspended;
+            self.suspended_loops = 0;
+            events
+                .push(TriggerEvent::Sleep {
+                    state: sleep,
+                    last_state: 0,
+                })
+                .ok();
+            events
+                .push(TriggerEvent::Resume {
+                    state: resume,
+                    last_state: 0,
+                })
+                .ok();
+        } else {
+            self.suspended_loops = self.suspended_loops.saturating_add(1);
+        }
+
+        if idle != self.idle {
+            trace!("Idle: {}", idle);
+            let inactive = Aodo::from_state(self.idle, idle);
+            let active = Aodo::from_state(!self.idle, !idle);
+            self.idle = idle;
+            self.idle_loops = 0;
+            events
+                .push(TriggerEvent::Inactive {
+                    state: inactive,
+                    last_state: 0,
+                })
+                .ok();
+            events
+                .push(TriggerEvent::Active {
+                    state: active,
+                    last_state: 0,
+                })
+                .ok();
+        } else {
+            self.idle_loops = self.idle_loops.saturating_add(1);
+        }
+
+        TriggerEventIterator::new(events)
+    }
+
+    /// USB suspend state
+    pub fn suspended(&self) -> bool {
+        self.suspended
+    }
+
+    /// Keyboard idle state
+    pub fn idle(&self) -> bool {
+        self.idle
+    }
+
+    /// Current On/Off state of a system TriggerEvent type (see TriggerCondition)
+    /// Can be used to answer LayerState::process_off_state_lookups, other types (and indices)
+    /// generate no events.
+    pub fn generate_events<const MAX_EVENTS: usize>(
+        &self,
+        ttype: u8,
+        index: u16,
+    ) -> TriggerEventIterator<MAX_EVENTS> {
+        let state = |on| if on { Aodo::On } else { Aodo::Off };
+        let event = match (ttype, index) {
+            (9, 0) => Some(TriggerEvent::Sleep {
+                state: state(self.suspended),
+                last_state: self.suspended_loops,
+            }),
+            (10, 0) => Some(TriggerEvent::Resume {
+                state: state(!self.suspended),
+                last_state: self.suspended_loops,
+            }),
+            (11, 0) => Some(TriggerEvent::Inactive {
+                state: state(self.idle),
+                last_state: self.idle_loops,
+            }),
+            (12, 0) => Some(TriggerEvent::Active {
+                state: state(!self.idle),
+                last_state: self.idle_loops,
+            }),
+            _ => None,
+        };
+
+        let mut events = Vec::new();
+        if let Some(event) = event {
+            events.push(event).ok();
+        }
+        TriggerEventIterator::new(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::system::SystemState;
+    use crate::trigger::Aodo;
+    use crate::TriggerEvent;
+
+    #[test]
+    fn suspend_resume() {
+        let mut system = SystemState::new();
+
+        // Nothing changed
+        assert_eq!(system.update(false, false).count(), 0);
+
+        // Suspend
+        let events: heapless::Vec<TriggerEvent, 4> = system.update(true, false).collect();
+        assert!(events.contains(&TriggerEvent::Sleep {
+            state: Aodo::Activate,
+            last_state: 0,
+        }));
+        assert!(events.contains(&TriggerEvent::Resume {
+            state: Aodo::Deactivate,
+            last_state: 0,
+        }));
+        assert_eq!(events.len(), 2);
+        assert_eq!(system.update(true, false).count(), 0);
+        assert!(system.suspended());
+
+        // Current state for off state lookups
+        assert_eq!(
+            system.generate_events::<1>(9, 0).next(),
+            Some(TriggerEvent::Sleep {
+                state: Aodo::On,
+                last_state: 1,
+            })
+        );
+        assert_eq!(
+            system.generate_events::<1>(10, 0).next(),
+            Some(TriggerEvent::Resume {
+                state: Aodo::Off,
+                last_state: 1,
+            })
+        );
+        assert_eq!(system.generate_events::<1>(1, 0).next(), None);
+
+        // Resume
+        let events: heapless::Vec<TriggerEvent, 4> = system.update(false, false).collect();
+        assert!(events.contains(&TriggerEvent::Sleep {
+            state: Aodo::Deactivate,
+            last_state: 0,
+        }));
+        assert!(events.contains(&TriggerEvent::Resume {
+            state: Aodo::Activate,
+            last_state: 0,
+        }));
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn idle_active() {
+        let mut system = SystemState::new();
+
+        // Idle
+        let events: heapless::Vec<TriggerEvent, 4> = system.update(false, true).collect();
+        assert!(events.contains(&TriggerEvent::Inactive {
+            state: Aodo::Activate,
+            last_state: 0,
+        }));
+        assert!(events.contains(&TriggerEvent::Active {
+            state: Aodo::Deactivate,
+            last_state: 0,
+        }));
+        assert_eq!(events.len(), 2);
+        assert!(system.idle());
+
+        for _ in 0..5 {
+            system.update(false, true);
+        }
+        assert_eq!(
+            system.generate_events::<1>(11, 0).next(),
+            Some(TriggerEvent::Inactive {
+                state: Aodo::On,
+                last_state: 5,
+            })
+        );
+
+        // Key used again
+        let events: heapless::Vec<TriggerEvent, 4> = system.update(false, false).collect();
+        assert!(events.contains(&TriggerEvent::Inactive {
+            state: Aodo::Deactivate,
+            last_state: 0,
+        }));
+        assert!(events.contains(&TriggerEvent::Active {
+            state: Aodo::Activate,
+            last_state: 0,
+        }));
+    }
+}
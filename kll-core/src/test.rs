@@ -0,0 +1,1132 @@
+// Copyright 2021-2023 Jacob Alexander
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+#![cfg(test)]
+
+// ----- Crates -----
+
+use super::*;
+
+// ----- Functions -----
+
+/// One of each Capability, with the CapabilityRun generated from it
+fn all_capabilities(
+    state: CapabilityState,
+    loop_condition_index: u16,
+    run_state: CapabilityEvent,
+) -> [(Capability, CapabilityRun); 37] {
+    [
+        (
+            Capability::NoOp {
+                state,
+                loop_condition_index,
+            },
+            CapabilityRun::NoOp { state: run_state },
+        ),
+        (
+            Capability::Rotate {
+                state,
+                loop_condition_index,
+                index: 2,
+                increment: -1,
+            },
+            CapabilityRun::Rotate {
+                state: run_state,
+                index: 2,
+                increment: -1,
+            },
+        ),
+        (
+            Capability::LayerClear {
+                state,
+                loop_condition_index,
+            },
+            CapabilityRun::LayerClear { state: run_state },
+        ),
+        (
+            Capability::LayerState {
+                state,
+                loop_condition_index,
+                layer: 3,
+                layer_state: layer::State::Lock,
+            },
+            CapabilityRun::LayerState {
+                state: run_state,
+                layer: 3,
+                layer_state: layer::State::Lock,
+            },
+        ),
+        (
+            Capability::LayerRotate {
+                state,
+                loop_condition_index,
+                direction: layer::Direction::Previous,
+            },
+            CapabilityRun::LayerRotate {
+                state: run_state,
+                direction: layer::Direction::Previous,
+            },
+        ),
+        (
+            Capability::HidProtocol {
+                state,
+                loop_condition_index,
+                mode: hid::Protocol::Toggle,
+            },
+            CapabilityRun::HidProtocol {
+                state: run_state,
+                mode: hid::Protocol::Toggle,
+            },
+        ),
+        (
+            Capability::HidKeyboard {
+                state,
+                loop_condition_index,
+                id: kll_hid::Keyboard::A,
+            },
+            CapabilityRun::HidKeyboard {
+                state: run_state,
+                id: kll_hid::Keyboard::A,
+            },
+        ),
+        (
+            Capability::HidKeyboardState {
+                state,
+                loop_condition_index,
+                id: kll_hid::Keyboard::B,
+                key_state: hid::State::Inactive,
+            },
+            CapabilityRun::HidKeyboardState {
+                state: run_state,
+                id: kll_hid::Keyboard::B,
+                key_state: hid::State::Inactive,
+            },
+        ),
+        (
+            Capability::HidConsumerControl {
+                state,
+                loop_condition_index,
+                id: kll_hid::ConsumerControl::Mute,
+            },
+            CapabilityRun::HidConsumerControl {
+                state: run_state,
+                id: kll_hid::ConsumerControl::Mute,
+            },
+        ),
+        (
+            Capability::HidSystemControl {
+                state,
+                loop_condition_index,
+                id: kll_hid::SystemControl::Sleep,
+            },
+            CapabilityRun::HidSystemControl {
+                state: run_state,
+                id: kll_hid::SystemControl::Sleep,
+            },
+        ),
+        (
+            Capability::McuFlashMode {
+                state,
+                loop_condition_index,
+            },
+            CapabilityRun::McuFlashMode { state: run_state },
+        ),
+        (
+            Capability::PixelAnimationControl {
+                state,
+                loop_condition_index,
+                mode: pixel::AnimationControl::Pause,
+            },
+            CapabilityRun::PixelAnimationControl {
+                state: run_state,
+                mode: pixel::AnimationControl::Pause,
+            },
+        ),
+        (
+            Capability::PixelAnimationIndex {
+                state,
+                loop_condition_index,
+                index: 300,
+            },
+            CapabilityRun::PixelAnimationIndex {
+                state: run_state,
+                index: 300,
+            },
+        ),
+        (
+            Capability::PixelFadeControl {
+                state,
+                loop_condition_index,
+                profile: 1,
+                command: pixel::FadeCommand::BrightnessSet,
+                arg: 128,
+            },
+            CapabilityRun::PixelFadeControl {
+                state: run_state,
+                profile: 1,
+                command: pixel::FadeCommand::BrightnessSet,
+                arg: 128,
+            },
+        ),
+        (
+            Capability::PixelFadeLayer {
+                state,
+                loop_condition_index,
+                layer: 2,
+            },
+            CapabilityRun::PixelFadeLayer {
+                state: run_state,
+                layer: 2,
+            },
+        ),
+        (
+            Capability::PixelFadeSet {
+                state,
+                loop_condition_index,
+                profile: 1,
+                config: 2,
+                period: 3,
+            },
+            CapabilityRun::PixelFadeSet {
+                state: run_state,
+                profile: 1,
+                config: 2,
+                period: 3,
+            },
+        ),
+        (
+            Capability::PixelGammaControl {
+                state,
+                loop_condition_index,
+                mode: pixel::GammaControl::Enable,
+            },
+            CapabilityRun::PixelGammaControl {
+                state: run_state,
+                mode: pixel::GammaControl::Enable,
+            },
+        ),
+        (
+            Capability::PixelLedControl {
+                state,
+                loop_condition_index,
+                mode: pixel::LedControl::BrightnessSet,
+                amount: 42,
+            },
+            CapabilityRun::PixelLedControl {
+                state: run_state,
+                mode: pixel::LedControl::BrightnessSet,
+                amount: 42,
+            },
+        ),
+        (
+            Capability::PixelTest {
+                state,
+                loop_condition_index,
+                test: pixel::PixelTest::PixelRoll,
+                index: 7,
+            },
+            CapabilityRun::PixelTest {
+                state: run_state,
+                test: pixel::PixelTest::PixelRoll,
+                index: 7,
+            },
+        ),
+        (
+            Capability::HidioOpenUrl {
+                state,
+                loop_condition_index,
+                index: 4,
+            },
+            CapabilityRun::HidioOpenUrl {
+                state: run_state,
+                index: 4,
+            },
+        ),
+        (
+            Capability::HidioUnicodeString {
+                state,
+                loop_condition_index,
+                index: 5,
+            },
+            CapabilityRun::HidioUnicodeString {
+                state: run_state,
+                index: 5,
+            },
+        ),
+        (
+            Capability::HidioUnicodeState {
+                state,
+                loop_condition_index,
+                unicode: 'ö',
+            },
+            CapabilityRun::HidioUnicodeState {
+                state: run_state,
+                unicode: 'ö',
+            },
+        ),
+        (
+            Capability::HoldTap {
+                state,
+                loop_condition_index,
+                tap: 20,
+                hold: 40,
+            },
+            CapabilityRun::HoldTap {
+                state: run_state,
+                tap: 20,
+                hold: 40,
+            },
+        ),
+        (
+            Capability::TapDance {
+                state,
+                loop_condition_index,
+                taps: 20,
+                holds: 60,
+            },
+            CapabilityRun::TapDance {
+                state: run_state,
+                taps: 20,
+                holds: 60,
+            },
+        ),
+        (
+            Capability::HidKeyboardLatch {
+                state,
+                loop_condition_index,
+                id: kll_hid::Keyboard::LeftShift,
+            },
+            CapabilityRun::HidKeyboardLatch {
+                state: run_state,
+                id: kll_hid::Keyboard::LeftShift,
+            },
+        ),
+        (
+            Capability::MacroRecord {
+                state,
+                loop_condition_index,
+                slot: 2,
+            },
+            CapabilityRun::MacroRecord {
+                state: run_state,
+                slot: 2,
+            },
+        ),
+        (
+            Capability::MacroPlay {
+                state,
+                loop_condition_index,
+                slot: 2,
+                delay: 300,
+            },
+            CapabilityRun::MacroPlay {
+                state: run_state,
+                slot: 2,
+                delay: 300,
+            },
+        ),
+        (
+            Capability::MouseButton {
+                state,
+                loop_condition_index,
+                button: 3,
+            },
+            CapabilityRun::MouseButton {
+                state: run_state,
+                button: 3,
+            },
+        ),
+        (
+            Capability::MouseMove {
+                state,
+                loop_condition_index,
+                x: -4,
+                y: 2,
+                accel: mouse::Acceleration::Linear,
+                ramp: 50,
+            },
+            CapabilityRun::MouseMove {
+                state: run_state,
+                x: -4,
+                y: 2,
+            },
+        ),
+        (
+            Capability::MouseWheel {
+                state,
+                loop_condition_index,
+                amount: -1,
+            },
+            CapabilityRun::MouseWheel {
+                state: run_state,
+                amount: -1,
+            },
+        ),
+        (
+            Capability::MouseHorzWheel {
+                state,
+                loop_condition_index,
+                amount: 1,
+            },
+            CapabilityRun::MouseHorzWheel {
+                state: run_state,
+                amount: 1,
+            },
+        ),
+        (
+            Capability::GamepadButton {
+                state,
+                loop_condition_index,
+                button: 12,
+            },
+            CapabilityRun::GamepadButton {
+                state: run_state,
+                button: 12,
+            },
+        ),
+        (
+            Capability::GamepadAxis {
+                state,
+                loop_condition_index,
+                axis: gamepad::Axis::Ry,
+                direction: gamepad::Direction::Negative,
+                max: 400,
+            },
+            CapabilityRun::GamepadAxis {
+                state: run_state,
+                axis: gamepad::Axis::Ry,
+                direction: gamepad::Direction::Negative,
+                value: 0,
+            },
+        ),
+        (
+            Capability::HidKeyboardAutoShift {
+                state,
+                loop_condition_index,
+                id: kll_hid::Keyboard::A,
+                threshold: 200,
+            },
+            CapabilityRun::HidKeyboardAutoShift {
+                state: run_state,
+                id: kll_hid::Keyboard::A,
+                shift: false,
+            },
+        ),
+        (
+            Capability::HidKeyboardRepeat {
+                state,
+                loop_condition_index,
+                id: kll_hid::Keyboard::B,
+                rate: 30,
+                delay: 500,
+            },
+            CapabilityRun::HidKeyboard {
+                state: run_state,
+                id: kll_hid::Keyboard::B,
+            },
+        ),
+        (
+            Capability::CapsWord {
+                state,
+                loop_condition_index,
+            },
+            CapabilityRun::CapsWord { state: run_state },
+        ),
+        (
+            Capability::HidKeyboardModMorph {
+                state,
+                loop_condition_index,
+                id: kll_hid::Keyboard::Backspace,
+                mods: 0x22,
+                morph: kll_hid::Keyboard::Delete,
+            },
+            CapabilityRun::HidKeyboardModMorph {
+                state: run_state,
+                id: kll_hid::Keyboard::Backspace,
+                mods: 0x22,
+                morph: kll_hid::Keyboard::Delete,
+            },
+        ),
+    ]
+}
+
+/// One of each TriggerCondition
+fn all_trigger_conditions(loop_condition_index: u16) -> [TriggerCondition; 14] {
+    [
+        TriggerCondition::None,
+        TriggerCondition::Switch {
+            state: trigger::Phro::Release,
+            index: 0x1234,
+            loop_condition_index,
+        },
+        TriggerCondition::HidLed {
+            state: trigger::Aodo::Deactivate,
+            loop_condition_index,
+            index: 5,
+        },
+        TriggerCondition::AnalogDistance {
+            mode: 0,
+            index: 7,
+            val: -1500,
+        },
+        TriggerCondition::AnalogVelocity {
+            mode: trigger::AnalogCompare::Band.mode(15),
+            index: 8,
+            val: 300,
+        },
+        TriggerCondition::AnalogAcceleration {
+            mode: trigger::AnalogCompare::CrossDown.mode(0),
+            index: 0x0809,
+            val: -2,
+        },
+        TriggerCondition::AnalogJerk {
+            mode: 0,
+            index: 8,
+            val: 300,
+        },
+        TriggerCondition::Layer {
+            state: trigger::LayerState::LatchLockOn,
+            loop_condition_index,
+            layer: 4,
+        },
+        TriggerCondition::Animation {
+            state: trigger::Dro::Done,
+            index: 9,
+            loop_condition_index,
+        },
+        TriggerCondition::Sleep {
+            state: trigger::Aodo::On,
+            loop_condition_index,
+        },
+        TriggerCondition::Resume {
+            state: trigger::Aodo::Activate,
+            loop_condition_index,
+        },
+        TriggerCondition::Inactive {
+            state: trigger::Aodo::Deactivate,
+            loop_condition_index,
+        },
+        TriggerCondition::Active {
+            state: trigger::Aodo::Off,
+            loop_condition_index,
+        },
+        TriggerCondition::Rotation {
+            index: 1,
+            loop_condition_index,
+            position: -1,
+        },
+    ]
+}
+
+// ----- Tests -----
+
+#[test]
+fn capability_generate_all() {
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+    let event = TriggerEvent::None;
+    let run_state = CapabilityEvent::Initial;
+
+    for (cap, run) in all_capabilities(CapabilityState::Initial, 0, run_state) {
+        let generated = cap.generate_at(event, 0, LOOP_CONDITION_LOOKUP).unwrap();
+        assert_eq!(
+            generated, run,
+            "Capability::generate mismatch for {:?}",
+            cap
+        );
+        assert_eq!(generated.state(), run_state);
+
+        // Validation conversion must match generate
+        assert_eq!(CapabilityRun::from(cap), run);
+
+        // Checked decoding must match the original capability
+        assert_eq!(Capability::try_from_bytes(unsafe { cap.bytes() }), Ok(cap));
+
+        // Encoded results (e.g. replay traces) must decode to the same result
+        assert_eq!(CapabilityRun::try_from_bytes(&run.to_bytes()), Ok(run));
+    }
+}
+
+#[test]
+fn guide_round_trip() {
+    // Non-zero loop condition indices, so both bytes of the field are checked
+    for loop_condition_index in [0, 0x0102, u16::MAX] {
+        let conds = all_trigger_conditions(loop_condition_index);
+        for (ty, cond) in conds.into_iter().enumerate() {
+            // One of each type, in discriminant order
+            assert_eq!(u8::from(cond) as usize, ty);
+            assert_eq!(
+                TriggerCondition::try_from_bytes(unsafe { cond.bytes() }),
+                Ok(cond)
+            );
+        }
+
+        // Every type is covered
+        let mut bytes = [0; core::mem::size_of::<TriggerCondition>()];
+        bytes[0] = conds.len() as u8;
+        assert_eq!(
+            TriggerCondition::try_from_bytes(&bytes),
+            Err(DecodeError::InvalidType(conds.len() as u8))
+        );
+
+        for state in [
+            CapabilityState::None,
+            CapabilityState::Initial,
+            CapabilityState::Last,
+            CapabilityState::Any,
+            CapabilityState::Passthrough,
+        ] {
+            let caps = all_capabilities(state, loop_condition_index, CapabilityEvent::None);
+            for (ty, (cap, _run)) in caps.into_iter().enumerate() {
+                let bytes = unsafe { cap.bytes() };
+                assert_eq!(bytes[0] as usize, ty);
+                assert_eq!(Capability::try_from_bytes(bytes), Ok(cap));
+            }
+
+            // Every type is covered
+            let mut bytes = [0; core::mem::size_of::<Capability>()];
+            bytes[0] = caps.len() as u8;
+            assert_eq!(
+                Capability::try_from_bytes(&bytes),
+                Err(DecodeError::InvalidType(caps.len() as u8))
+            );
+        }
+    }
+}
+
+#[test]
+fn guide_decode() {
+    let switch = TriggerCondition::Switch {
+        state: trigger::Phro::Press,
+        index: 1,
+        loop_condition_index: 0,
+    };
+    let mut bytes = [0; 6];
+    bytes.copy_from_slice(unsafe { switch.bytes() });
+
+    // Truncated
+    assert_eq!(
+        TriggerCondition::try_from_bytes(&bytes[..5]),
+        Err(DecodeError::Truncated)
+    );
+
+    // Unknown Phro state
+    bytes[1] = 5;
+    assert_eq!(
+        TriggerCondition::try_from_bytes(&bytes),
+        Err(DecodeError::InvalidField(1))
+    );
+
+    // Unknown analog comparison
+    let mut analog = [0; 6];
+    analog.copy_from_slice(unsafe {
+        TriggerCondition::AnalogDistance {
+            mode: 5,
+            index: 1,
+            val: 0,
+        }
+        .bytes()
+    });
+    assert_eq!(
+        TriggerCondition::try_from_bytes(&analog),
+        Err(DecodeError::InvalidField(3))
+    );
+
+    // Unknown TriggerCondition
+    bytes[0] = 0xFF;
+    assert_eq!(
+        TriggerCondition::try_from_bytes(&bytes),
+        Err(DecodeError::InvalidType(0xFF))
+    );
+
+    let key = Capability::HidKeyboard {
+        state: CapabilityState::Initial,
+        loop_condition_index: 0,
+        id: kll_hid::Keyboard::A,
+    };
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(unsafe { key.bytes() });
+
+    // Reserved keyboard id
+    bytes[4] = 0xA5;
+    assert_eq!(
+        Capability::try_from_bytes(&bytes),
+        Err(DecodeError::InvalidField(6))
+    );
+
+    // Unknown CapabilityState
+    bytes[1] = 9;
+    assert_eq!(
+        Capability::try_from_bytes(&bytes),
+        Err(DecodeError::InvalidField(6))
+    );
+
+    let mouse = Capability::MouseMove {
+        state: CapabilityState::Passthrough,
+        loop_condition_index: 0,
+        x: 1,
+        y: 0,
+        accel: mouse::Acceleration::Quadratic,
+        ramp: 20,
+    };
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(unsafe { mouse.bytes() });
+
+    // Unknown acceleration curve
+    bytes[6] = 3;
+    assert_eq!(
+        Capability::try_from_bytes(&bytes),
+        Err(DecodeError::InvalidField(28))
+    );
+
+    // Mouse buttons are 1-8
+    let button = Capability::MouseButton {
+        state: CapabilityState::Initial,
+        loop_condition_index: 0,
+        button: 1,
+    };
+    bytes.copy_from_slice(unsafe { button.bytes() });
+    bytes[4] = 9;
+    assert_eq!(
+        Capability::try_from_bytes(&bytes),
+        Err(DecodeError::InvalidField(27))
+    );
+
+    // Unknown gamepad axis
+    let axis = Capability::GamepadAxis {
+        state: CapabilityState::Passthrough,
+        loop_condition_index: 0,
+        axis: gamepad::Axis::X,
+        direction: gamepad::Direction::Positive,
+        max: 400,
+    };
+    bytes.copy_from_slice(unsafe { axis.bytes() });
+    bytes[4] = 6;
+    assert_eq!(
+        Capability::try_from_bytes(&bytes),
+        Err(DecodeError::InvalidField(32))
+    );
+
+    // Layer capabilities cannot set Off
+    let shift = Capability::LayerState {
+        state: CapabilityState::Initial,
+        loop_condition_index: 0,
+        layer: 1,
+        layer_state: layer::State::Shift,
+    };
+    bytes.copy_from_slice(unsafe { shift.bytes() });
+    bytes[5] = layer::State::Off as u8;
+    assert_eq!(
+        Capability::try_from_bytes(&bytes),
+        Err(DecodeError::InvalidField(3))
+    );
+
+    // Invalid mod-morph key
+    let morph = Capability::HidKeyboardModMorph {
+        state: CapabilityState::Passthrough,
+        loop_condition_index: 0,
+        id: kll_hid::Keyboard::Backspace,
+        mods: 0x22,
+        morph: kll_hid::Keyboard::Delete,
+    };
+    bytes.copy_from_slice(unsafe { morph.bytes() });
+    bytes[6] = 0xA5;
+    assert_eq!(
+        Capability::try_from_bytes(&bytes),
+        Err(DecodeError::InvalidField(36))
+    );
+}
+
+#[test]
+fn capability_generate_state() {
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+    let event = TriggerEvent::Switch {
+        state: trigger::Phro::Press,
+        index: 4,
+        last_state: 0,
+    };
+
+    for (state, run_state) in [
+        (CapabilityState::None, CapabilityEvent::None),
+        (CapabilityState::Initial, CapabilityEvent::Initial),
+        (CapabilityState::Last, CapabilityEvent::Last),
+        (CapabilityState::Any, CapabilityEvent::Any),
+        (
+            CapabilityState::Passthrough,
+            CapabilityEvent::Passthrough(event),
+        ),
+    ] {
+        let cap = Capability::HidConsumerControl {
+            state,
+            loop_condition_index: 0,
+            id: kll_hid::ConsumerControl::VolumeUp,
+        };
+        let run = CapabilityRun::HidConsumerControl {
+            state: run_state,
+            id: kll_hid::ConsumerControl::VolumeUp,
+        };
+        assert_eq!(cap.generate_at(event, 0, LOOP_CONDITION_LOOKUP), Some(run));
+        assert_eq!(CapabilityRun::try_from_bytes(&run.to_bytes()), Ok(run));
+    }
+
+    let mut bytes = CapabilityRun::HidConsumerControl {
+        state: CapabilityEvent::Passthrough(event),
+        id: kll_hid::ConsumerControl::VolumeUp,
+    }
+    .to_bytes();
+
+    // Truncated
+    assert_eq!(
+        CapabilityRun::try_from_bytes(&bytes[..CAPABILITY_RUN_SIZE - 1]),
+        Err(DecodeError::Truncated)
+    );
+
+    // Invalid passthrough TriggerEvent
+    bytes[8] = 0xFF;
+    assert_eq!(
+        CapabilityRun::try_from_bytes(&bytes),
+        Err(DecodeError::InvalidType(0xFF))
+    );
+
+    // Unknown CapabilityRun
+    bytes[0] = 0xFF;
+    assert_eq!(
+        CapabilityRun::try_from_bytes(&bytes),
+        Err(DecodeError::InvalidType(0xFF))
+    );
+}
+
+#[test]
+fn keyboard_repeat() {
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+    let cap = Capability::HidKeyboardRepeat {
+        state: CapabilityState::Passthrough,
+        loop_condition_index: 0,
+        id: kll_hid::Keyboard::A,
+        rate: 3,
+        delay: 10,
+    };
+    let switch = |state, last_state| TriggerEvent::Switch {
+        state,
+        index: 4,
+        last_state,
+    };
+
+    for (event, state) in [
+        (switch(trigger::Phro::Press, 0), CapabilityEvent::Initial),
+        (switch(trigger::Phro::Hold, 9), CapabilityEvent::None),
+        // Released then pressed again every rate scan loops once the delay has passed
+        (switch(trigger::Phro::Hold, 10), CapabilityEvent::Last),
+        (switch(trigger::Phro::Hold, 11), CapabilityEvent::Initial),
+        (switch(trigger::Phro::Hold, 12), CapabilityEvent::None),
+        (switch(trigger::Phro::Hold, 13), CapabilityEvent::Last),
+        (switch(trigger::Phro::Hold, 14), CapabilityEvent::Initial),
+        (switch(trigger::Phro::Release, 0), CapabilityEvent::Last),
+    ] {
+        assert_eq!(
+            cap.generate_at(event, 0, LOOP_CONDITION_LOOKUP),
+            Some(CapabilityRun::HidKeyboard {
+                state,
+                id: kll_hid::Keyboard::A,
+            }),
+            "{:?}",
+            event
+        );
+    }
+
+    // Auto-shift only shifts held keys
+    assert!(!hid::auto_shift(switch(trigger::Phro::Hold, 9), 10));
+    assert!(hid::auto_shift(switch(trigger::Phro::Hold, 10), 10));
+    assert!(!hid::auto_shift(switch(trigger::Phro::Release, 0), 10));
+}
+
+#[test]
+fn mouse_move_acceleration() {
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+    let cap = |accel| Capability::MouseMove {
+        state: CapabilityState::Passthrough,
+        loop_condition_index: 0,
+        x: 2,
+        y: -1,
+        accel,
+        ramp: 10,
+    };
+    let held = |last_state| TriggerEvent::Switch {
+        state: trigger::Phro::Hold,
+        index: 4,
+        last_state,
+    };
+
+    for (accel, last_state, x, y) in [
+        // Press starts at the base speed
+        (mouse::Acceleration::Linear, 0, 2, -1),
+        (mouse::Acceleration::Linear, 5, 9, -4),
+        (mouse::Acceleration::Linear, 10, 16, -8),
+        // Clamped to MAX_SPEED after the ramp
+        (mouse::Acceleration::Linear, 500, 16, -8),
+        (mouse::Acceleration::Quadratic, 5, 5, -2),
+        (mouse::Acceleration::Quadratic, 10, 16, -8),
+        (mouse::Acceleration::None, 500, 2, -1),
+    ] {
+        let event = held(last_state);
+        assert_eq!(
+            cap(accel).generate_at(event, 0, LOOP_CONDITION_LOOKUP),
+            Some(CapabilityRun::MouseMove {
+                state: CapabilityEvent::Passthrough(event),
+                x,
+                y,
+            }),
+            "{:?} last_state {}",
+            accel,
+            last_state
+        );
+    }
+}
+
+#[test]
+fn capability_generate_passthrough() {
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0];
+
+    // Passthrough must forward the original TriggerEvent untouched
+    let event = TriggerEvent::Rotation {
+        index: 1,
+        position: -1,
+        last_state: 3,
+    };
+    let cap = Capability::Rotate {
+        state: CapabilityState::Passthrough,
+        loop_condition_index: 0,
+        index: 1,
+        increment: 1,
+    };
+    let run = cap.generate_at(event, 0, LOOP_CONDITION_LOOKUP).unwrap();
+    assert_eq!(
+        run,
+        CapabilityRun::Rotate {
+            state: CapabilityEvent::Passthrough(event),
+            index: 1,
+            increment: 1,
+        }
+    );
+    assert_eq!(run.state(), CapabilityEvent::Passthrough(event));
+}
+
+#[test]
+fn trigger_condition_evaluate() {
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0, 5];
+
+    let check = |cond: TriggerCondition, event: TriggerEvent, expected: Vote| {
+        let vote = cond.evaluate(event, LOOP_CONDITION_LOOKUP);
+        assert!(
+            core::mem::discriminant(&vote) == core::mem::discriminant(&expected),
+            "{:?} vs {:?}",
+            cond,
+            event
+        );
+    };
+
+    // HidLed
+    let cond = TriggerCondition::HidLed {
+        state: trigger::Aodo::On,
+        loop_condition_index: 1,
+        index: 2,
+    };
+    for (state, last_state, vote) in [
+        (trigger::Aodo::On, 5, Vote::Positive),
+        (trigger::Aodo::On, 2, Vote::Insufficient),
+        (trigger::Aodo::Activate, 0, Vote::Insufficient),
+    ] {
+        let event = TriggerEvent::HidLed {
+            state,
+            index: 2,
+            last_state,
+        };
+        check(cond, event, vote);
+    }
+    check(
+        TriggerCondition::HidLed {
+            state: trigger::Aodo::Off,
+            loop_condition_index: 0,
+            index: 2,
+        },
+        TriggerEvent::HidLed {
+            state: trigger::Aodo::On,
+            index: 2,
+            last_state: 0,
+        },
+        Vote::OffState,
+    );
+
+    // Analog
+    let cond = TriggerCondition::AnalogVelocity {
+        mode: 0,
+        index: 3,
+        val: -20,
+    };
+    for (val, vote) in [(-21, Vote::Insufficient), (-20, Vote::Positive)] {
+        check(cond, TriggerEvent::AnalogVelocity { index: 3, val }, vote);
+    }
+    check(
+        cond,
+        TriggerEvent::AnalogDistance { index: 3, val: 0 },
+        Vote::Insufficient,
+    );
+
+    // Layer
+    let cond = TriggerCondition::Layer {
+        state: trigger::LayerState::LockDeactivate,
+        loop_condition_index: 0,
+        layer: 2,
+    };
+    for (state, vote) in [
+        (trigger::LayerState::LockDeactivate, Vote::Positive),
+        (trigger::LayerState::ShiftLockDeactivate, Vote::Positive),
+        (trigger::LayerState::ShiftDeactivate, Vote::Insufficient),
+        (trigger::LayerState::LockActivate, Vote::Insufficient),
+    ] {
+        let event = TriggerEvent::Layer {
+            state,
+            layer: 2,
+            last_state: 0,
+        };
+        check(cond, event, vote);
+    }
+
+    // Animation
+    let cond = TriggerCondition::Animation {
+        state: trigger::Dro::Done,
+        index: 9,
+        loop_condition_index: 0,
+    };
+    for (state, vote) in [
+        (trigger::Dro::Done, Vote::Positive),
+        (trigger::Dro::Repeat, Vote::Insufficient),
+    ] {
+        let event = TriggerEvent::Animation {
+            state,
+            index: 9,
+            last_state: 0,
+        };
+        check(cond, event, vote);
+    }
+
+    // Sleep/Resume/Inactive/Active
+    let cond = TriggerCondition::Inactive {
+        state: trigger::Aodo::Activate,
+        loop_condition_index: 0,
+    };
+    let event = TriggerEvent::Inactive {
+        state: trigger::Aodo::Activate,
+        last_state: 0,
+    };
+    check(cond, event, Vote::Positive);
+    let event = TriggerEvent::Active {
+        state: trigger::Aodo::Activate,
+        last_state: 0,
+    };
+    check(cond, event, Vote::Insufficient);
+
+    // Rotation
+    let cond = TriggerCondition::Rotation {
+        index: 1,
+        loop_condition_index: 0,
+        position: 0,
+    };
+    for position in [-1, 1] {
+        let event = TriggerEvent::Rotation {
+            index: 1,
+            position,
+            last_state: 0,
+        };
+        check(cond, event, Vote::Positive);
+    }
+
+    // Loop condition index outside of the lookup
+    let cond = TriggerCondition::Switch {
+        state: trigger::Phro::Press,
+        index: 1,
+        loop_condition_index: 2,
+    };
+    let event = TriggerEvent::Switch {
+        state: trigger::Phro::Press,
+        index: 1,
+        last_state: 0,
+    };
+    check(cond, event, Vote::Insufficient);
+    let cond = TriggerCondition::Rotation {
+        index: 1,
+        loop_condition_index: 2,
+        position: 0,
+    };
+    let event = TriggerEvent::Rotation {
+        index: 1,
+        position: 1,
+        last_state: 0,
+    };
+    check(cond, event, Vote::Insufficient);
+}
+
+#[test]
+fn trigger_layer_state_conversion() {
+    for layer_state in [
+        layer::State::Shift,
+        layer::State::Latch,
+        layer::State::Lock,
+        layer::State::ShiftLatchLock,
+    ] {
+        for activity in [
+            trigger::Aodo::Activate,
+            trigger::Aodo::On,
+            trigger::Aodo::Deactivate,
+            trigger::Aodo::Off,
+        ] {
+            let state = trigger::LayerState::from_layer(layer_state, activity).unwrap();
+            assert_eq!(state.layer_state(), layer_state);
+            assert_eq!(state.activity(), activity);
+        }
+    }
+}
+
+#[test]
+fn capability_generate_schedule() {
+    const LOOP_CONDITION_LOOKUP: &[u32] = &[0, 50];
+    let cap = Capability::HidKeyboard {
+        state: CapabilityState::Last,
+        loop_condition_index: 1,
+        id: kll_hid::Keyboard::Esc,
+    };
+    assert_eq!(cap.loop_condition(LOOP_CONDITION_LOOKUP), Some(50));
+
+    // Only generated at the scheduled time offset
+    for time_offset in [0, 49, 51] {
+        assert_eq!(
+            cap.generate_at(TriggerEvent::None, time_offset, LOOP_CONDITION_LOOKUP),
+            None
+        );
+    }
+    assert_eq!(
+        cap.generate_at(TriggerEvent::None, 50, LOOP_CONDITION_LOOKUP),
+        Some(CapabilityRun::HidKeyboard {
+            state: CapabilityEvent::Last,
+            id: kll_hid::Keyboard::Esc,
+        })
+    );
+
+    // generate ignores the schedule
+    assert_eq!(
+        cap.generate(TriggerEvent::None, LOOP_CONDITION_LOOKUP),
+        CapabilityRun::HidKeyboard {
+            state: CapabilityEvent::Last,
+            id: kll_hid::Keyboard::Esc,
+        }
+    );
+
+    // Loop condition index outside of the lookup is never scheduled
+    assert_eq!(cap.loop_condition(&[0]), None);
+    for time_offset in [0, 50] {
+        assert_eq!(cap.generate_at(TriggerEvent::None, time_offset, &[0]), None);
+    }
+}
@@ -7,6 +7,9 @@
 
 #![no_std]
 
+#[macro_use]
+extern crate enum_primitive_derive;
+
 /// HID Locales
 /// Locales defined by the USB HID Spec v1.11
 /// <http://www.usb.org/developers/hidpage/HID1_11.pdf> (6.2.1) HID Descriptor
@@ -285,6 +288,14 @@ pub enum Keyboard {
     RightGUI = 0xE7,
 }
 
+impl Keyboard {
+    /// Determine if the given usage id has a Keyboard entry
+    /// (e.g. before using the unchecked u16 conversion)
+    pub const fn is_valid(id: u16) -> bool {
+        matches!(id, 0x00..=0xA4 | 0xB0..=0xDD | 0xE0..=0xE7)
+    }
+}
+
 /// Conversion from u16 indexes to Keyboard enum
 /// # Safety
 impl From<u16> for Keyboard {
@@ -393,7 +404,7 @@ pub enum LedIndicator {
 /// 0x94 - 0x9F Reserved
 /// 0xA9 - 0xAF Reserved
 /// 0xB8 - 0xFFFF Reserved
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
 #[repr(u8)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SystemControl {
@@ -461,7 +472,7 @@ pub enum SystemControl {
 /// Application Launch Buttons pg 79
 /// Generic GUI Application Controls pg 82
 /// TODO: Where does 0x29D come from?
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Primitive)]
 #[repr(u16)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum ConsumerControl {
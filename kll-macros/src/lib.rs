@@ -11,6 +11,8 @@ use std::iter::FromIterator;
 /// Takes a list of sequences of combos and turns it into a u8 array
 /// that can be stored in memory as a contiguous piece of data.
 /// This is necessary to store the trigger guide independently of rust compilation.
+/// Each sequence ends with a 0 length combo, matching the trigger guides generated by
+/// kll-compiler, so guide offsets are the same as in compiled layouts.
 ///
 /// ```
 /// use kll_core::{Capability, CapabilityState, TriggerCondition, trigger};
@@ -44,7 +46,7 @@ use std::iter::FromIterator;
 ///     ]],
 ///     [[
 ///         TriggerCondition::AnalogDistance {
-///             reserved: 0,
+///             mode: 0,
 ///             index: 8,
 ///             val: 1500,
 ///         },
@@ -135,6 +137,9 @@ pub fn trigger_guide(input: TokenStream) -> TokenStream {
                         }
                     }
                 }
+
+                // 0 length combo to indicate the sequence has finished
+                output.push("0,".to_string());
             }
             TokenTree::Punct(_) => {}
             _ => {
@@ -143,14 +148,15 @@ pub fn trigger_guide(input: TokenStream) -> TokenStream {
         }
     }
 
-    // Final 0 length sequence to indicate finished
-    output.push("0 ] }".to_string());
+    output.push("] }".to_string());
     String::from_iter(output).parse().unwrap()
 }
 
 /// Takes a list of sequences of combos of Capabilities and turns it into a u8 array
 /// that can be stored in memory as a contiguous piece of data.
 /// This is necessary to store the result guide independently of rust compilation.
+/// Each sequence ends with a 0 length combo, matching the result guides generated by
+/// kll-compiler, so guide offsets are the same as in compiled layouts.
 ///
 /// ```
 /// use kll_core::{Capability, CapabilityState};
@@ -229,14 +235,20 @@ pub fn result_guide(input: TokenStream) -> TokenStream {
                                                 elem_count += 1;
                                                 prefix_output = Vec::new();
                                             }
-                                            "LayerClear" | "McuFlashMode" | "NoOp" => {
+                                            "CapsWord" | "LayerClear" | "McuFlashMode" | "NoOp" => {
                                                 byte_count = 4;
                                             }
-                                            "HidKeyboard"
+                                            "GamepadButton"
+                                            | "HidKeyboard"
+                                            | "HidKeyboardLatch"
                                             | "HidProtocol"
                                             | "HidLed"
                                             | "HidSystemControl"
                                             | "LayerRotate"
+                                            | "MacroRecord"
+                                            | "MouseButton"
+                                            | "MouseHorzWheel"
+                                            | "MouseWheel"
                                             | "PixelAnimationControl"
                                             | "PixelFadeLayer"
                                             | "PixelGammaControl" => {
@@ -252,10 +264,16 @@ pub fn result_guide(input: TokenStream) -> TokenStream {
                                             | "Rotate" => {
                                                 byte_count = 6;
                                             }
-                                            "PixelFadeIndex" | "PixelFadeSet" | "PixelTest" => {
+                                            "HidKeyboardAutoShift"
+                                            | "HidKeyboardModMorph"
+                                            | "PixelFadeIndex"
+                                            | "PixelFadeSet"
+                                            | "PixelTest" => {
                                                 byte_count = 7;
                                             }
-                                            "HidioUnicodeState" => {
+                                            "GamepadAxis" | "HidKeyboardRepeat"
+                                            | "HidioUnicodeState" | "HoldTap" | "MacroPlay"
+                                            | "MouseMove" | "TapDance" => {
                                                 byte_count = 8;
                                             }
                                             _ => {
@@ -295,6 +313,9 @@ pub fn result_guide(input: TokenStream) -> TokenStream {
                         }
                     }
                 }
+
+                // 0 length combo to indicate the sequence has finished
+                output.push("0,".to_string());
             }
             TokenTree::Punct(_) => {}
             _ => {
@@ -303,8 +324,7 @@ pub fn result_guide(input: TokenStream) -> TokenStream {
         }
     }
 
-    // Final 0 length sequence to indicate finished
-    output.push("0 ] }".to_string());
+    output.push("] }".to_string());
     String::from_iter(output).parse().unwrap()
 }
 